@@ -29,10 +29,12 @@ impl ToString for ConnError {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy)]
 pub enum ErrorKind {
     TimedOut,
     InvalidData,
+    /// CONNECT with protocol other than MQTT 3.1.1 or 5
+    UnsupportedProtocol,
     BrokenPipe,
     ConnectionAborted
 }
@@ -43,6 +45,7 @@ impl ToString for ErrorKind {
             Self::BrokenPipe => "broken pipe",
             Self::ConnectionAborted => "connection aborted",
             Self::InvalidData => "invalid data",
+            Self::UnsupportedProtocol => "unsupported protocol",
             Self::TimedOut => "timeout"
         };
         String::from(bstr)
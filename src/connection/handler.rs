@@ -1,113 +1,221 @@
-use std::{io, sync::atomic::AtomicU32};
-use super::{errors::ConnError, handshake::{MqttConnectRequest, MqttConnectedResponse}, line::SocketConnection, ConnectionID};
+use std::{io, net::SocketAddr, sync::atomic::AtomicU32};
+use super::{errors::{ConnError, ErrorKind}, handshake::{MqttConnectRequest, MqttConnectedResponse}, line::SocketConnection, ConnectionID, SocketWriter};
 use tokio::net::TcpStream;
 use tokio_rustls::TlsAcceptor;
 use crate::{
+    error::BrokerError,
+    authentication::{AuthData, AuthenticationStore, Authenticator},
     message_broker::{
         client::{client::{Client, UpdateClient}, 
-        clobj::{ClientID, Limiter}}, 
-        mediator::BrokerMediator, MAX_QOS
-    }, protocol::v5::{
+        clobj::{ClientID, Limiter, Session}}, 
+        acl::Acl, mediator::BrokerMediator, InboundLimit, MAX_QOS, SUBS_ID_SUPPORT, TOPIC_ALIAS_MAXIMUM, WILDCARD_SUPPORT
+    }, protocol::{mqtt::Protocol, v5::{
         connack::{ConnackPacket, Properties}, 
         connect::ConnectPacket
-    }, server::Wire
+    }}, server::Wire
 };
 
 #[allow(dead_code)]
 pub struct Proxy {
     broker: BrokerMediator,
     access_total: AtomicU32,
+    /// user name and password is required when set
+    auth: Option<Authenticator>,
+}
+
+/// what is known about the client while CONNECT is processed
+struct Handshake {
+    peer: SocketAddr,
+    protocol: Protocol,
+    /// reason string is sent along with refused CONNACK
+    problem_information: bool,
 }
 
 impl Proxy {
-    pub async fn new(broker: BrokerMediator) -> io::Result<Self> {
+    pub async fn new(broker: BrokerMediator, auth: Option<Authenticator>) -> io::Result<Self> {
         let access_total = AtomicU32::new(1);
-        Ok(Self { access_total, broker })
+        Ok(Self { access_total, broker, auth })
     }
     
-    async fn establish_connection(&self, connid: ConnectionID, mut conn: SocketConnection) -> Result<(), ConnError> {
-        let req_ack = conn.read_request().await?;
+    async fn establish_connection(&self, connid: ConnectionID, peer: SocketAddr, mut conn: SocketConnection) -> Result<(), ConnError> {
+        let req_ack = match conn.read_request().await {
+            Ok(req) => req,
+            Err(err) => {
+                println!("[connect] {} from {} handshake error: {}", connid, peer, err.to_string());
+                let err = BrokerError::from(err);
+                // client older than 3.1.1 understand 3.1.1 CONNACK
+                let protocol = match err {
+                    BrokerError::UnsupportedProtocol => Protocol::V311,
+                    _ => Protocol::V5
+                };
+                let handshake = Handshake { peer, protocol, problem_information: true };
+                return reject(conn, &handshake, &err).await;
+            }
+        };
+
+        let handshake = Handshake {
+            peer,
+            protocol: Protocol::from_level(req_ack.protocol_level).unwrap_or_default(),
+            problem_information: req_ack.properties
+                .as_ref()
+                .and_then(|p| p.request_problem_information) != Some(0)
+        };
+
+        // liveness of previous session is read once for the whole handshake
+        let session = match req_ack.client_id.is_empty() {
+            true => None,
+            false => self.broker.is_still_alive(&ClientID::new(req_ack.client_id.clone())).await
+        };
+
+        let assigned = match self.admit(&req_ack, session).await {
+            Ok(assigned) => assigned,
+            Err(err) => return reject(conn, &handshake, &err).await
+        };
+
         let mut connack_packet = ConnackPacket::default();
+        let srv_var = match collect(req_ack, &mut connack_packet, self.broker.inbound_limit(), assigned) {
+            Ok(srv_var) => srv_var,
+            Err(err) => return reject(conn, &handshake, &err).await
+        };
+
+        if let Some(true) = session {
+            println!("[session] {} taken over", srv_var.clid);
+            if let Err(err) = self.broker.take_over(&srv_var.clid).await {
+                println!("[session] {} take over error: {}", srv_var.clid, err);
+                return reject(conn, &handshake, &BrokerError::ServerBusy).await;
+            }
+        }
 
-        let srv_var = collect(req_ack, &mut connack_packet).unwrap();
-        self.start_session(
-            connack_packet,
-            connid,
-            conn,
-            srv_var
-        ).await.unwrap();
+        let clid = srv_var.clid.clone();
+        let bucket = UpdateClient {
+            conid: Some(connid),
+            socket: Some(conn),
+            protocol: srv_var.protocol
+        };
+        if let Err(err) = self.start_session(connack_packet, &handshake, bucket, srv_var, session).await {
+            println!("[connect] {} from {} session error: {}", clid, peer, err);
+        }
         Ok(())
     }
 
-    // TODO: properties
+    /// check CONNECT against broker policy, identifier is assigned
+    /// for empty client id
+    async fn admit(&self, req: &ConnectPacket, session: Option<bool>) -> Result<Option<ClientID>, BrokerError> {
+        // assigned identifier has no session to resume
+        if req.client_id.is_empty() && !req.clean_start() {
+            return Err(BrokerError::ClientIdNotValid);
+        }
+
+        let admission = self.broker.admission();
+        if admission.banned.contains(&req.client_id) {
+            return Err(BrokerError::Banned);
+        }
+
+        if let Some(auth) = &self.auth {
+            let data = match (&req.username, &req.password) {
+                (Some(username), Some(password)) => String::from_utf8(password.clone())
+                    .map(|password| AuthData::new(username.clone(), password))
+                    .ok(),
+                _ => None
+            };
+
+            let authenticated = match data {
+                Some(data) => auth.authenticate(&data).await,
+                None => false
+            };
+            if !authenticated {
+                return Err(BrokerError::BadUserNameOrPassword);
+            }
+        }
+
+        // client taking over its own session is not counted twice
+        let is_reconnect = session == Some(true);
+        if admission.max_clients > 0 && !is_reconnect && self.broker.connected_clients().await >= admission.max_clients {
+            return Err(BrokerError::QuotaExceeded);
+        }
+
+        match req.client_id.is_empty() {
+            true => Ok(Some(self.broker.assign_client_id().await)),
+            false => Ok(None)
+        }
+    }
+
     async fn start_session(
         &self, 
         mut response: ConnackPacket,
-        connid: ConnectionID,
-        conn: SocketConnection,
-        srv_var: ServerVariable
-    ) -> Result<(), String> {
-        let mut conn = conn;
-        let session = self.broker.is_still_alive(&srv_var.clid).await;
-        if let Some(still_alive) = session {
-            if still_alive {
-                // TODO: send disconnect with response code
-                return Err("()".to_string());
-            }
-        }
+        handshake: &Handshake,
+        mut bucket: UpdateClient,
+        srv_var: ServerVariable,
+        session: Option<bool>
+    ) -> Result<(), BrokerError> {
+        // limit is given by this CONNECT, also for resumed session
+        let new_limit = || Limiter::new(
+            srv_var.receive_maximum, 
+            srv_var.maximum_packet_size,
+            srv_var.topic_alias_maximum
+        ).with_outbound(self.broker.outbound_limit(&srv_var.clid));
+        let new_session = || Session::new(srv_var.keep_alive, srv_var.expr_interval);
 
         if !srv_var.clean_start {
             println!("try restoring connection");
-            let mut bucket = UpdateClient {
-                conid: Some(connid.clone()),
-                socket: Some(conn)
-            };
-
             let restore_feedback = 
-            self.broker.try_restore_session(srv_var.clid.clone(), &mut bucket, |s| {
+            self.broker.try_restore_session(srv_var.clid.clone(), &mut bucket, new_session(), new_limit(), async |s| {
                 response.session_present = true;
-                s.connack(&response)
+                s.connack(&response).await
             }).await;
             
-            match restore_feedback {
+            let err = match restore_feedback {
                 Ok(fb) => {
-                    fb.await.unwrap();
+                    fb?;
                     println!("client restored");
+                    match self.broker.resume_inflight(&srv_var.clid).await {
+                        Ok(sent) => println!("[session] {} resend {} in-flight message", srv_var.clid, sent),
+                        Err(err) => println!("[session] {} resend error: {}", srv_var.clid, err)
+                    }
+                    match self.broker.replay_offline(&srv_var.clid).await {
+                        Ok(sent) => println!("[session] {} replay {} offline message", srv_var.clid, sent),
+                        Err(err) => println!("[session] {} replay error: {}", srv_var.clid, err)
+                    }
                     return  Ok(());
-                }, Err(err) => println!("{}", err.to_string())
-            }
-            
+                }, 
+                Err(err) => err
+            };
             
-            println!("failed to restore");
-            conn = bucket.socket
-                .take()
-                .unwrap();
+            println!("[session] {} failed to restore: {}", srv_var.clid, err);
+            // socket is already taken by the restored client, which answered the refusal
+            if bucket.socket.is_none() {
+                return Err(err);
+            }
         }
 
-        let mut limit = Limiter::default();
-        if let Some(ref v) = response.properties {
-            limit = Limiter::new(
-                v.receive_maximum, 
-                v.maximum_packet_size,
-                v.topic_alias_maximum
-            );
+        // previous session is not resumed, subscription must not be inherited
+        if session.is_some() {
+            if let Err(err) = self.broker.discard_session(&srv_var.clid).await {
+                println!("[session] {} discard error: {}", srv_var.clid, err);
+            }
         }
 
         let client = Client::new(
-            connid, 
-            conn, 
+            &mut bucket, 
             srv_var.clid.clone(), 
-            srv_var.keep_alive,
-            srv_var.expr_interval,
-            srv_var.protocol_level,
-            limit
+            new_session(),
+            new_limit(),
+            self.broker.session_store()
         ).await;
+        let client = match client {
+            Ok(client) => client,
+            Err(err) => {
+                let err = BrokerError::from(err);
+                if let Some(conn) = bucket.socket.take() {
+                    let _ = reject(conn, handshake, &err).await;
+                }
+                return Err(err);
+            }
+        };
 
-        let cb = self.broker.register(client, |s| async {
+        self.broker.register(client, async |s| {
             s.connack(&response).await
-        }).await.unwrap();
-        cb.await.unwrap();
-        Ok(())
+        }).await?.map_err(BrokerError::from)
     }
 
     fn request_id(&self) -> ConnectionID {
@@ -120,6 +228,13 @@ impl Wire for Proxy {
     async fn connect_with_tls(&self, stream: TcpStream, tls: TlsAcceptor) {
         let id = self.request_id();
         println!("[stream] process id {}", id);
+        let peer = match stream.peer_addr() {
+            Ok(v) => v,
+            Err(err) => {
+                eprintln!("[stream] conn {}, error: {}", id, err);
+                return ;
+            }
+        };
         let stream = match tls.accept(stream).await {
             Ok(v) => v,
             // TODO: Specify the error and response error message
@@ -131,52 +246,89 @@ impl Wire for Proxy {
         
         println!("[stream] secured");
         let stream = SocketConnection::Secure(stream);
-        let _ = self.establish_connection(id, stream).await;
+        let _ = self.establish_connection(id, peer, stream).await;
     }
 
     async fn connect(&self, stream: TcpStream) {
         let id = self.request_id();
         println!("[stream] process id {}", id);
-        let stream = stream;
+        let peer = match stream.peer_addr() {
+            Ok(v) => v,
+            Err(err) => {
+                eprintln!("[stream] conn {}, error: {}", id, err);
+                return ;
+            }
+        };
         let stream = SocketConnection::Plain(stream);
-        let _ = self.establish_connection(id, stream).await;
+        let _ = self.establish_connection(id, peer, stream).await;
     }
 }
 
+/// refuse the connection with CONNACK carrying the reason code of the error
+async fn reject(mut conn: SocketConnection, handshake: &Handshake, err: &BrokerError) -> Result<(), ConnError> {
+    println!("[connect] {} rejected, {}: {:#04x}", handshake.peer, err, err.code());
+    if !err.is_answerable() {
+        return Err(ConnError::new(ErrorKind::ConnectionAborted, Some(err.to_string())));
+    }
+
+    let properties = Properties {
+        reason_string: Some(err.to_string()).filter(|_| handshake.problem_information),
+        ..Default::default()
+    };
+    let ack = ConnackPacket { return_code: err.code(), properties: Some(properties), ..Default::default() };
+    let packet = handshake.protocol.connack(&ack)
+        .map_err(|e| ConnError::new(ErrorKind::InvalidData, Some(e)))?;
+    conn.write_all(&packet)
+        .await
+        .map_err(|e| ConnError::new(ErrorKind::BrokenPipe, Some(e.to_string())))
+}
+
 struct ServerVariable {
     clid: ClientID,
     clean_start: bool,
     keep_alive: u16,
-    protocol_level: u8,
+    protocol: Protocol,
     expr_interval: u32,
+    /// limit of packet sent by broker, given by the client
+    receive_maximum: Option<u16>,
+    maximum_packet_size: Option<u32>,
+    topic_alias_maximum: Option<u16>,
 }
 
 // TODO: on notes
-fn collect(req: ConnectPacket, res: &mut ConnackPacket) -> Result<ServerVariable, String> {
+fn collect(req: ConnectPacket, res: &mut ConnackPacket, limit: InboundLimit, assigned: Option<ClientID>) -> Result<ServerVariable, BrokerError> {
     let clean_start = req.clean_start();
 
-    let is_generate_clid = req.client_id.len() == 0;
-    let clid = match is_generate_clid {
-        true => ClientID::new("raw_clid".to_string()),
-        false => ClientID::new(req.client_id)
+    let is_generate_clid = assigned.is_some();
+    let clid = match assigned {
+        Some(clid) => clid,
+        None => ClientID::new(req.client_id)
     };
     
     let mut srv_var = ServerVariable {
         clid: clid.clone(),
         clean_start,
         keep_alive: req.keep_alive,
-        protocol_level: req.protocol_level,
+        protocol: Protocol::from_level(req.protocol_level).ok_or(BrokerError::UnsupportedProtocol)?,
         expr_interval: 0,
+        receive_maximum: None,
+        maximum_packet_size: None,
+        topic_alias_maximum: None,
     };
 
-    let req_prop = match req.properties {
-        Some(prop) => prop,
-        None => return Ok(srv_var)
-    };
+    // server limit is advertised even when client send no properties
+    let req_prop = req.properties.unwrap_or_default();
     
-    srv_var.expr_interval = req_prop
-        .session_expiry_interval
-        .unwrap_or_default();
+    srv_var.expr_interval = match srv_var.protocol {
+        // 3.1.1 session is kept until clean session is requested
+        Protocol::V311 if !clean_start => u32::MAX,
+        _ => req_prop.session_expiry_interval.unwrap_or_default()
+    };
+    srv_var.receive_maximum = req_prop.receive_maximum;
+    srv_var.maximum_packet_size = req_prop.maximum_packet_size;
+    // 3.1.1 has no topic alias
+    srv_var.topic_alias_maximum = req_prop.topic_alias_maximum
+        .filter(|_| srv_var.protocol == Protocol::V5);
 
     let mut res_prop = Properties::default();
     res_prop.session_expiry_interval = Some(srv_var.expr_interval); 
@@ -184,18 +336,20 @@ fn collect(req: ConnectPacket, res: &mut ConnackPacket) -> Result<ServerVariable
         res_prop.assigned_client_identifier = Some(clid.to_string())
     }
 
-    res_prop.receive_maximum = req_prop.receive_maximum;
+    res_prop.receive_maximum = Some(limit.receive_maximum);
     res_prop.maximum_qos = Some(MAX_QOS);
     // res_prop.retain_available
-    res_prop.maximum_packet_size = req_prop.maximum_packet_size;
-    res_prop.topic_alias_maximum = req_prop.topic_alias_maximum;
+    res_prop.maximum_packet_size = Some(limit.maximum_packet_size);
+    res_prop.topic_alias_maximum = Some(TOPIC_ALIAS_MAXIMUM);
     // res_prop.reason_string
     res_prop.user_properties = req_prop.user_properties;
-    // res_prop.wildcard_subscription_available
-    // res_prop.subscription_identifier_available
+    res_prop.wildcard_subscription_available = Some(WILDCARD_SUPPORT as u8);
+    res_prop.subscription_identifier_available = Some(SUBS_ID_SUPPORT as u8);
     // res_prop.shared_subscription_available
     res_prop.server_keep_alive = Some(req.keep_alive);
-    // res_prop.response_information
+    if req_prop.request_response_information == Some(1) {
+        res_prop.response_information = Some(Acl::response_information(&clid));
+    }
     // res_prop.server_reference
     // res_prop.authentication_method
     // res_prop.authentication_data
@@ -1,5 +1,5 @@
 use tokio::io;
-use crate::protocol::v5::{connack::ConnackPacket, connect::ConnectPacket};
+use crate::protocol::v5::{connack::ConnackPacket, connect::ConnectPacket, disconnect::DisconnectPacket};
 use super::{errors::ConnError, SocketReader, SocketWriter};
 
 pub(super) trait MqttConnectRequest: SocketReader {
@@ -8,4 +8,9 @@ pub(super) trait MqttConnectRequest: SocketReader {
 
 pub trait MqttConnectedResponse: SocketWriter {
     async fn connack<'a>(&'a mut self, ack: &'a ConnackPacket) -> io::Result<()>;
-}
\ No newline at end of file
+}
+
+pub trait MqttDisconnect: SocketWriter {
+    /// send disconnect packet then close the write side
+    async fn disconnect<'a>(&'a mut self, packet: &'a DisconnectPacket) -> io::Result<()>;
+}
@@ -1,12 +1,18 @@
-use std::time::Duration;
+use std::{io, time::Duration};
 use bytes::BytesMut;
 use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream};
 use tokio_rustls::server::TlsStream;
-use crate::protocol::v5::connect::ConnectPacket;
+use crate::protocol::v5::{
+    connect::{ConnectPacket, INVALID_PROTOCOL_NAME, UNSUPPORTED_PROTOCOL_LEVEL},
+    RemainingLength
+};
 use super::{errors::{ConnError, ErrorKind}, handshake::MqttConnectRequest, SocketReader, SocketWriter};
 
 pub type SecuredStream = TlsStream<TcpStream>;
 
+/// CONNECT bigger than this is refused as malformed
+const MAX_CONNECT_SIZE: usize = 64 * 1024;
+
 pub enum SocketConnection {
     Secure(SecuredStream),
     Plain(TcpStream)
@@ -32,16 +38,44 @@ impl SocketWriter for SocketConnection {
 
 impl MqttConnectRequest for SocketConnection {
     async fn read_request<'a>(&'a mut self) -> Result<ConnectPacket, ConnError> {
-        let mut buffer = {
-            let mut buffer = BytesMut::zeroed(256);
-            let dur = Duration::from_secs(3);
-            let read_len = self.read_timeout(&mut buffer, dur).await.unwrap();
-            buffer.split_to(read_len)
-        };
+        let dur = Duration::from_secs(3);
+        let mut buffer = BytesMut::zeroed(256);
+        let mut read_len = 0;
+        // CONNECT may arrive in more than one read
+        loop {
+            let packet_len = RemainingLength::packet_len(&buffer[..read_len]);
+            if packet_len.is_some_and(|len| len <= read_len) {
+                break;
+            }
+
+            let want = packet_len.unwrap_or(buffer.len()).max(read_len + 1);
+            if want > MAX_CONNECT_SIZE {
+                return Err(ConnError::new(ErrorKind::InvalidData, Some("CONNECT too large".to_string())));
+            }
+            if want > buffer.len() {
+                buffer.resize(want, 0);
+            }
+
+            let n = self.read_timeout(&mut buffer[read_len..], dur).await
+                .map_err(|e| match e.kind() {
+                    io::ErrorKind::TimedOut => ConnError::new(ErrorKind::TimedOut, None),
+                    _ => ConnError::new(ErrorKind::ConnectionAborted, Some(e.to_string()))
+                })?;
+            if n == 0 {
+                return Err(ConnError::new(ErrorKind::ConnectionAborted, None));
+            }
+            read_len += n;
+        }
+        buffer.truncate(read_len);
 
         let packet = ConnectPacket::decode(&mut buffer)
-            .map_err(|e| ConnError::new(ErrorKind::InvalidData, Some(String::from(e)))
-        )?;
+            .map_err(|e| {
+                let kind = match e.as_str() {
+                    INVALID_PROTOCOL_NAME | UNSUPPORTED_PROTOCOL_LEVEL => ErrorKind::UnsupportedProtocol,
+                    _ => ErrorKind::InvalidData
+                };
+                ConnError::new(kind, Some(e))
+            })?;
         Ok(packet)
     }
 }
\ No newline at end of file
@@ -4,11 +4,11 @@ use tokio::{io, time};
 pub mod line;
 pub mod handler;
 pub mod handshake;
-mod errors;
+pub mod errors;
 
 #[repr(transparent)]
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
-pub struct ConnectionID(u32);
+pub struct ConnectionID(pub(crate) u32);
 impl Display for ConnectionID {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.0)  
@@ -1,4 +1,4 @@
-use std::{collections::HashMap, ptr, sync::atomic::{AtomicPtr, Ordering}};
+use std::{collections::HashMap, sync::{atomic::{AtomicPtr, Ordering}, RwLock}};
 
 type ATrieChild<T> = AtomicPtr<Child<T>>;
 
@@ -6,7 +6,7 @@ struct Child<T>
     where T: Clone + PartialEq
 {
     child: HashMap<String, ATrieChild<T>>,
-    subscriber: AtomicPtr<T>
+    subscribers: RwLock<Vec<T>>
 }
 
 impl<T> Child<T> 
@@ -15,7 +15,7 @@ impl<T> Child<T>
     fn new() -> Self {
         Self {
             child: HashMap::new(), 
-            subscriber: AtomicPtr::default()
+            subscribers: RwLock::new(Vec::new())
         }
     }
 
@@ -27,51 +27,44 @@ impl<T> Child<T>
         );
     }
 
+    /// replace subscriber that equal with given value,
+    /// return false when it is replaced instead of added
     fn set_subscriber(&self, subscriber: T) -> bool {
-        let v_ptr = Box::into_raw(Box::new(subscriber));
-        let exw = self.subscriber.compare_exchange_weak(
-            ptr::null_mut(),
-            v_ptr, 
-            Ordering::AcqRel,
-            Ordering::Relaxed
-        );
-        exw.is_ok()
+        let mut subs = self.subscribers.write().unwrap();
+        match subs.iter_mut().find(|s| subscriber.eq(s)) {
+            Some(s) => {
+                *s = subscriber;
+                false
+            },
+            None => {
+                subs.push(subscriber);
+                true
+            }
+        }
     }
 
     fn delete_subscriber(&self, subscriber: T) -> bool {
-        let cur_psub = self.subscriber.load(Ordering::Acquire);
-        if cur_psub.is_null() {
-            return false;
-        }
-
-        unsafe {
-            if (*cur_psub).ne(&subscriber) {
-                return false;
-            }
-
-            let old_psub = self.subscriber.swap(ptr::null_mut(), Ordering::Relaxed);
-            drop(Box::from_raw(old_psub))
-        };
-        true
-
+        let mut subs = self.subscribers.write().unwrap();
+        let before = subs.len();
+        subs.retain(|s| !subscriber.eq(s));
+        before != subs.len()
     }
 
-    fn get_subscriber(&self) -> Option<T> {
-        unsafe {
-            let sub = self.subscriber.load(Ordering::Acquire);
-            if sub.is_null() {
-                return None;
-            }
+    fn get_subscribers(&self) -> Vec<T> {
+        self.subscribers.read().unwrap().clone()
+    }
 
-            Some((*sub).clone())
-        }
+    fn is_empty(&self) -> bool {
+        self.subscribers.read().unwrap().is_empty()
     }
 }
 
 pub struct Trie<T> 
     where T: Clone + PartialEq
 {
-    root: ATrieChild<T>
+    root: ATrieChild<T>,
+    /// shared for lookup, exclusive for any change on branch map
+    branch: RwLock<()>
 }
 
 impl<T> Trie<T> 
@@ -79,10 +72,11 @@ impl<T> Trie<T>
 {
     pub fn new() -> Self {
         let pchild = Box::into_raw(Box::new(Child::<T>::new()));
-        Self { root: AtomicPtr::new(pchild) }
+        Self { root: AtomicPtr::new(pchild), branch: RwLock::new(()) }
     }
     
     pub fn insert(&self, topic: &str, value: T) -> bool {
+        let _guard = self.branch.write().unwrap();
         let parts: Vec<&str> = topic.split('/').collect();
         let mut cur = &self.root;
         let mut i = 0;
@@ -113,6 +107,7 @@ impl<T> Trie<T>
     }
 
     fn get<R>(&self, topic: &str, f: impl FnOnce(&Child<T>) -> R) -> Option<R> {
+        let _guard = self.branch.read().unwrap();
         let parts: Vec<&str> = topic.split('/').collect();
         let mut cur = &self.root;
 
@@ -134,21 +129,75 @@ impl<T> Trie<T>
         Some(f(child))
     }
 
-    pub fn get_val(&self, topic: &str) -> Option<T> {
-        self.get(topic, |child| {
-            child.get_subscriber()
-        })?
+    /// every value registered on exact topic
+    pub fn get_val(&self, topic: &str) -> Option<Vec<T>> {
+        let subs = self.get(topic, |child| {
+            child.get_subscribers()
+        })?;
+
+        if subs.is_empty() {
+            return None;
+        }
+        Some(subs)
+    }
+
+    /// every value registered on filter that match the topic,
+    /// `+` match one level and `#` match the rest of topic
+    pub fn matches(&self, topic: &str) -> Vec<T> {
+        let parts: Vec<&str> = topic.split('/').collect();
+        let mut res = Vec::new();
+        let _guard = self.branch.read().unwrap();
+        let root = self.root.load(Ordering::Acquire);
+        if !root.is_null() {
+            // wildcard on first level does not match topic started with '$'
+            let wildcard = !topic.starts_with('$');
+            unsafe { Self::collect(&*root, &parts, wildcard, &mut res) };
+        }
+        res
+    }
+
+    unsafe fn collect(node: &Child<T>, parts: &[&str], wildcard: bool, res: &mut Vec<T>) {
+        let branch = |key: &str| {
+            node.child.get(key)
+                .map(|b| b.load(Ordering::Acquire))
+                .filter(|p| !p.is_null())
+        };
+
+        if wildcard {
+            if let Some(multi) = branch("#") {
+                res.extend((*multi).get_subscribers());
+            }
+        }
+
+        let (part, rest) = match parts.split_first() {
+            Some(v) => v,
+            None => {
+                res.extend(node.get_subscribers());
+                return;
+            }
+        };
+
+        if let Some(exact) = branch(part) {
+            Self::collect(&*exact, rest, true, res);
+        }
+
+        if wildcard {
+            if let Some(single) = branch("+") {
+                Self::collect(&*single, rest, true, res);
+            }
+        }
     }
 
-    #[allow(dead_code)]
     pub fn remove(&self, topic: &str, value: T) -> Option<bool> {
         self.get(topic, |child| {
             child.delete_subscriber(value)
         })
     }
 
-    #[allow(dead_code)]
+    /// drop and free every branch without subscriber,
+    /// lookup and insert wait until it is done
     pub fn clean_branch(&self) {
+        let _guard = self.branch.write().unwrap();
         unsafe {
             Self::dfs_empty_and_remove(self.root.load(Ordering::SeqCst));
         }
@@ -158,7 +207,6 @@ impl<T> Trie<T>
 impl<T> Trie<T> 
     where T: Clone + PartialEq
 {
-    #[allow(dead_code)]
     unsafe fn dfs_empty_and_remove(node_ptr: *mut Child<T>) -> bool {
         if node_ptr.is_null() {
             return true;
@@ -174,34 +222,24 @@ impl<T> Trie<T>
         }
 
         empty_keys.iter().for_each(|elm| {
-            node.child.remove(elm);
+            if let Some(child) = node.child.remove(elm) {
+                Self::free(child.load(Ordering::SeqCst));
+            }
         });
 
-        node.child.is_empty() && node.subscriber.load(Ordering::Acquire).is_null()
+        node.child.is_empty() && node.is_empty()
     }
 
-    unsafe fn dfs_drop(node_ptr: *mut Child<T>) {
+    /// free node and every branch below it
+    unsafe fn free(node_ptr: *mut Child<T>) {
         if node_ptr.is_null() {
             return;
         }
 
-        let node = &mut *node_ptr;
-        let mut empty_keys = Vec::new();
-
-        for (key, child_ptr) in node.child.iter() {
-            Self::dfs_drop(child_ptr.load(Ordering::SeqCst));
-            empty_keys.push(key.clone());
-            
+        let node = Box::from_raw(node_ptr);
+        for (_, child_ptr) in node.child.iter() {
+            Self::free(child_ptr.load(Ordering::SeqCst));
         }
-
-        if let Some(sub) = node.get_subscriber() {
-            node.delete_subscriber(sub);
-        }
-        
-        empty_keys.iter().for_each(|elm| {
-            println!("{}", elm);
-            node.child.remove(elm);
-        });
     }
 }
 
@@ -210,12 +248,7 @@ where T: Clone + PartialEq
 {
     fn drop(&mut self) {
         unsafe {
-            let inner = self.root.load(Ordering::SeqCst);
-            if inner.is_null() {
-                return;
-            }
-
-            Self::dfs_drop(inner);
+            Self::free(self.root.swap(std::ptr::null_mut(), Ordering::SeqCst));
         }
     }
 }
@@ -223,7 +256,7 @@ where T: Clone + PartialEq
 
 #[cfg(test)]
 mod tests {
-    use crate::{ds::trie::Trie, message_broker::client::clobj::ClientID, protocol::v5::{subscribe::Subscribe, ServiceLevel}};
+    use crate::{ds::trie::Trie, message_broker::client::clobj::ClientID, protocol::v5::{subscribe::{Subscribe, SubscriptionOptions}, ServiceLevel}};
 
     struct TrieTest {
         clid: ClientID,
@@ -240,9 +273,13 @@ mod tests {
                 value: vec![Subscribe {
                     topic: "home/bathroom/lamp".to_string(),
                     max_qos: ServiceLevel::QoS1,
+                    options: SubscriptionOptions::default(),
+                    subscription_identifier: None,
                 }, Subscribe {
                     topic: "home/kitchen".to_string(),
                     max_qos: ServiceLevel::QoS2,
+                    options: SubscriptionOptions::default(),
+                    subscription_identifier: None,
                 }],
             },
             TrieTest {
@@ -250,9 +287,13 @@ mod tests {
                 value: vec![Subscribe {
                     topic: "home/kitchen/topek".to_string(),
                     max_qos: ServiceLevel::QoS2,
+                    options: SubscriptionOptions::default(),
+                    subscription_identifier: None,
                 }, Subscribe {
                     topic: "home/livingroom/fan".to_string(),
                     max_qos: ServiceLevel::QoS2,
+                    options: SubscriptionOptions::default(),
+                    subscription_identifier: None,
                 }],
             },
         ];
@@ -265,7 +306,7 @@ mod tests {
 
             for sub in test.value.iter() {
                 let got = pref_tree.get_val(&sub.topic).unwrap();
-                assert_eq!(got, test.clid)
+                assert_eq!(got, vec![test.clid.clone()])
             }
         }
 
@@ -292,9 +333,13 @@ mod tests {
                 value: vec![Subscribe {
                     topic: "home/bathroom/lamp".to_string(),
                     max_qos: ServiceLevel::QoS1,
+                    options: SubscriptionOptions::default(),
+                    subscription_identifier: None,
                 }, Subscribe {
                     topic: "home/kitchen".to_string(),
                     max_qos: ServiceLevel::QoS2,
+                    options: SubscriptionOptions::default(),
+                    subscription_identifier: None,
                 }],
             },
             TrieTest {
@@ -302,9 +347,13 @@ mod tests {
                 value: vec![Subscribe {
                     topic: "home/kitchen/topek".to_string(),
                     max_qos: ServiceLevel::QoS2,
+                    options: SubscriptionOptions::default(),
+                    subscription_identifier: None,
                 }, Subscribe {
                     topic: "home/livingroom/fan".to_string(),
                     max_qos: ServiceLevel::QoS2,
+                    options: SubscriptionOptions::default(),
+                    subscription_identifier: None,
                 }],
             },
         ];
@@ -320,5 +369,74 @@ mod tests {
         pref_tree.remove(&target.value[1].topic, target.clid.clone());
         let got = pref_tree.get_val(&target.value[1].topic);
         assert!(got.is_none());
+
+        pref_tree.clean_branch();
+        assert!(pref_tree.get_val(&target.value[1].topic).is_none());
+        assert_eq!(pref_tree.get_val(&target.value[0].topic).unwrap(), vec![target.clid.clone()]);
+        assert_eq!(pref_tree.matches("home/bathroom/lamp"), vec![test_cases[0].clid.clone()]);
+
+        // pruned branch can be subscribed again
+        assert!(pref_tree.insert(&target.value[1].topic, target.clid.clone()));
+        assert_eq!(pref_tree.get_val(&target.value[1].topic).unwrap(), vec![target.clid.clone()]);
+    }
+
+    #[test]
+    fn clean_while_insert() {
+        let pref_tree: std::sync::Arc<Trie<ClientID>> = std::sync::Arc::new(Trie::new());
+        let cleaner = {
+            let tree = pref_tree.clone();
+            std::thread::spawn(move || for _ in 0..200 { tree.clean_branch() })
+        };
+
+        for i in 0..200 {
+            let topic = format!("home/{}/lamp", i);
+            assert!(pref_tree.insert(&topic, ClientID::new(i.to_string())));
+        }
+        cleaner.join().unwrap();
+
+        // no subscribed branch is pruned
+        for i in 0..200 {
+            let topic = format!("home/{}/lamp", i);
+            assert_eq!(pref_tree.matches(&topic), vec![ClientID::new(i.to_string())]);
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn wildcard_match() {
+        let pref_tree: Trie<ClientID> = Trie::new();
+        let clid = |id: &str| ClientID::new(id.to_string());
+
+        pref_tree.insert("sensors/#", clid("multi"));
+        pref_tree.insert("sensors/+/temp", clid("single"));
+        pref_tree.insert("sensors/room/temp", clid("exact"));
+        pref_tree.insert("#", clid("all"));
+
+        let mut got = pref_tree.matches("sensors/room/temp");
+        got.sort();
+        assert_eq!(got, vec![clid("all"), clid("exact"), clid("multi"), clid("single")]);
+
+        // parent level is matched by multi level wildcard
+        assert_eq!(pref_tree.matches("sensors"), vec![clid("all"), clid("multi")]);
+        assert_eq!(pref_tree.matches("sensors/room/humid"), vec![clid("all"), clid("multi")]);
+        assert!(pref_tree.matches("$SYS/uptime").is_empty());
+    }
+
+    #[test]
+    fn shared_topic() {
+        let pref_tree: Trie<ClientID> = Trie::new();
+        let clid1 = ClientID::new("clid1".to_string());
+        let clid2 = ClientID::new("clid2".to_string());
+
+        assert!(pref_tree.insert("home/kitchen", clid1.clone()));
+        assert!(pref_tree.insert("home/kitchen", clid2.clone()));
+        // same value is replaced instead of duplicated
+        assert!(!pref_tree.insert("home/kitchen", clid1.clone()));
+
+        let got = pref_tree.get_val("home/kitchen").unwrap();
+        assert_eq!(got, vec![clid1.clone(), clid2.clone()]);
+
+        pref_tree.remove("home/kitchen", clid1);
+        let got = pref_tree.get_val("home/kitchen").unwrap();
+        assert_eq!(got, vec![clid2]);
+    }
+}
@@ -0,0 +1,141 @@
+use std::{fmt::Display, io};
+use crate::{
+    connection::errors::{ConnError, ErrorKind},
+    protocol::v5::{
+        connack::{
+            BAD_USER_NAME_OR_PASSWORD,
+            BANNED,
+            CLIENT_IDENTIFIER_NOT_VALID,
+            QUOTA_EXCEEDED,
+            SERVER_BUSY,
+            UNSUPPORTED_PROTOCOL_VERSION
+        },
+        disconnect::TOPIC_ALIAS_INVALID,
+        malform::Malformed,
+        subsack::SubAckInvalid
+    }
+};
+
+// reason code shared by every acknowledgement
+pub const UNSPECIFIED_ERROR: u8 = 0x80;
+pub const IMPLEMENTATION_SPECIFIC_ERROR: u8 = 0x83;
+
+/// Error of every broker layer, each one is answered to the client
+/// with its v5 reason code on CONNACK, PUBACK, SUBACK or DISCONNECT
+#[derive(Debug)]
+pub enum BrokerError {
+    /// packet break the protocol
+    Malformed(Malformed),
+    UnsupportedProtocol,
+    ClientIdNotValid,
+    BadUserNameOrPassword,
+    /// session is still in use by other connection
+    ServerBusy,
+    Banned,
+    TopicAliasInvalid,
+    QuotaExceeded,
+    /// socket is closed or silent, client can not be answered
+    Connection(ErrorKind),
+    /// session or message is not persisted
+    Storage(io::Error),
+    /// packet is not encoded
+    Encode(String),
+}
+
+impl BrokerError {
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::Malformed(m) => m.code(),
+            Self::UnsupportedProtocol => UNSUPPORTED_PROTOCOL_VERSION,
+            Self::ClientIdNotValid => CLIENT_IDENTIFIER_NOT_VALID,
+            Self::BadUserNameOrPassword => BAD_USER_NAME_OR_PASSWORD,
+            Self::ServerBusy => SERVER_BUSY,
+            Self::Banned => BANNED,
+            Self::TopicAliasInvalid => TOPIC_ALIAS_INVALID,
+            Self::QuotaExceeded => QUOTA_EXCEEDED,
+            Self::Connection(_) | Self::Storage(_) => UNSPECIFIED_ERROR,
+            Self::Encode(_) => IMPLEMENTATION_SPECIFIC_ERROR,
+        }
+    }
+
+    /// false when the connection is gone, nothing can be sent
+    pub fn is_answerable(&self) -> bool {
+        !matches!(self, Self::Connection(_))
+    }
+}
+
+impl Display for BrokerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Malformed(m) => write!(f, "{}", m),
+            Self::UnsupportedProtocol => write!(f, "unsupported protocol version"),
+            Self::ClientIdNotValid => write!(f, "client identifier not valid"),
+            Self::BadUserNameOrPassword => write!(f, "bad user name or password"),
+            Self::ServerBusy => write!(f, "server busy"),
+            Self::Banned => write!(f, "banned"),
+            Self::TopicAliasInvalid => write!(f, "topic alias invalid"),
+            Self::QuotaExceeded => write!(f, "quota exceeded"),
+            Self::Connection(kind) => write!(f, "connection {}", kind.to_string()),
+            Self::Storage(err) => write!(f, "storage error: {}", err),
+            Self::Encode(err) => write!(f, "encode error: {}", err),
+        }
+    }
+}
+
+impl From<Malformed> for BrokerError {
+    fn from(value: Malformed) -> Self {
+        Self::Malformed(value)
+    }
+}
+
+impl From<io::Error> for BrokerError {
+    fn from(value: io::Error) -> Self {
+        Self::Storage(value)
+    }
+}
+
+impl From<ConnError> for BrokerError {
+    fn from(value: ConnError) -> Self {
+        match value.get_kind() {
+            ErrorKind::UnsupportedProtocol => Self::UnsupportedProtocol,
+            ErrorKind::InvalidData => Self::Malformed(Malformed::MalformedPacket),
+            kind => Self::Connection(kind)
+        }
+    }
+}
+
+impl From<&BrokerError> for SubAckInvalid {
+    fn from(value: &BrokerError) -> Self {
+        match value {
+            BrokerError::QuotaExceeded => Self::QuotaExceeded,
+            BrokerError::Encode(_) => Self::ImplSpecificError,
+            _ => Self::UnspecifiedError
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use crate::{
+        connection::errors::{ConnError, ErrorKind},
+        protocol::v5::malform::Malformed
+    };
+    use super::BrokerError;
+
+    #[test]
+    fn reason_code() {
+        let err = BrokerError::from(ConnError::new(ErrorKind::UnsupportedProtocol, None));
+        assert_eq!(err.code(), 0x84);
+        let err = BrokerError::from(ConnError::new(ErrorKind::InvalidData, Some("truncated".to_string())));
+        assert_eq!(err.code(), 0x81);
+        let err = BrokerError::from(ConnError::new(ErrorKind::TimedOut, None));
+        assert!(!err.is_answerable());
+
+        assert_eq!(BrokerError::from(Malformed::ReceiveMax).code(), 0x93);
+        let err = BrokerError::from(io::Error::other("disk full"));
+        assert_eq!(err.code(), 0x80);
+        assert!(err.is_answerable());
+        assert_eq!(BrokerError::Banned.code(), 0x8A);
+    }
+}
@@ -1 +1,2 @@
-pub mod time;
\ No newline at end of file
+pub mod time;
+pub mod crc;
@@ -1,5 +1,13 @@
 use std::time::{SystemTime, UNIX_EPOCH};
 
+#[inline]
+pub fn sys_now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
 #[inline]
 pub fn sys_now() -> u64 {
     SystemTime::now()
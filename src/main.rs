@@ -5,8 +5,11 @@ mod message_broker;
 mod protocol;
 mod helper;
 mod ds;
+mod error;
 
-use message_broker::mediator::BrokerMediator;
+use std::{env, time::Duration};
+use authentication::Authenticator;
+use message_broker::{mediator::BrokerMediator, BrokerConfig};
 use connection::handler::Proxy;
 use server::Server;
 use tokio::{join, runtime};
@@ -27,9 +30,118 @@ fn main() {
 async fn app() {
     println!("running mediator");
 
-    let mediator: BrokerMediator = BrokerMediator::new().await;
+    let mut config = BrokerConfig::default();
+    if let Ok(v) = env::var("WAL_FSYNC") {
+        config.wal.fsync = match v.parse() {
+            Ok(v) => v,
+            Err(e) => panic!("[wal] error: {}", e)
+        };
+    }
+
+    if let Ok(v) = env::var("MAXIMUM_PACKET_SIZE") {
+        config.inbound.maximum_packet_size = match v.parse() {
+            Ok(v) => v,
+            Err(e) => panic!("[inbound] error: {}", e)
+        };
+    }
+
+    if let Ok(v) = env::var("RECEIVE_MAXIMUM") {
+        config.inbound.receive_maximum = match v.parse() {
+            Ok(v) => v,
+            Err(e) => panic!("[inbound] error: {}", e)
+        };
+    }
+
+    if let Ok(v) = env::var("SLOW_CONSUMER") {
+        config.outbound.policy = match v.parse() {
+            Ok(v) => v,
+            Err(e) => panic!("[outbound] error: {}", e)
+        };
+    }
+
+    if let Ok(v) = env::var("OUTBOUND_MAX_MESSAGES") {
+        config.outbound.max_messages = match v.parse() {
+            Ok(v) => v,
+            Err(e) => panic!("[outbound] error: {}", e)
+        };
+    }
+
+    if let Ok(v) = env::var("OUTBOUND_MAX_BYTES") {
+        config.outbound.max_bytes = match v.parse() {
+            Ok(v) => v,
+            Err(e) => panic!("[outbound] error: {}", e)
+        };
+    }
+
+    // client_id=max_messages:max_bytes[:policy], separated by comma
+    if let Ok(v) = env::var("OUTBOUND_CLIENTS") {
+        for entry in v.split(',').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+            let (clid, limit) = match entry.rsplit_once('=') {
+                Some(v) => v,
+                None => panic!("[outbound] error: {} is not client_id=limit", entry)
+            };
+            let limit = match limit.parse() {
+                Ok(v) => v,
+                Err(e) => panic!("[outbound] error: {}", e)
+            };
+            config.outbound_clients.insert(clid.to_string(), limit);
+        }
+    }
+
+    if let Ok(v) = env::var("DISPATCH_WORKERS") {
+        config.dispatch_workers = match v.parse() {
+            Ok(v) => v,
+            Err(e) => panic!("[dispatch] error: {}", e)
+        };
+    }
+
+    if let Ok(v) = env::var("SWEEP_INTERVAL") {
+        config.sweep_interval = match v.parse() {
+            Ok(0) => panic!("[sweeper] error: interval must be at least 1 second"),
+            Ok(v) => Duration::from_secs(v),
+            Err(e) => panic!("[sweeper] error: {}", e)
+        };
+    }
+
+    if let Ok(v) = env::var("CLIENT_ID_PREFIX") {
+        config.client_id_prefix = v;
+    }
+
+    if let Ok(v) = env::var("MAX_CLIENTS") {
+        config.admission.max_clients = match v.parse() {
+            Ok(v) => v,
+            Err(e) => panic!("[admission] error: {}", e)
+        };
+    }
+
+    if let Ok(v) = env::var("BANNED_CLIENTS") {
+        config.admission.banned = v.split(',')
+            .map(|clid| clid.trim().to_string())
+            .filter(|clid| !clid.is_empty())
+            .collect();
+    }
+
+    if let Ok(v) = env::var("SESSION_STORE") {
+        config.storage = match v.parse() {
+            Ok(v) => v,
+            Err(e) => panic!("[storage] error: {}", e)
+        };
+    }
+
+    let mediator = match BrokerMediator::new(config).await {
+        Ok(v) => v,
+        Err(e) => panic!("[mediator] error: {}", e)
+    };
     let broker_task = mediator.join_handle();
-    let handler = Proxy::new(mediator).await.unwrap();
+    let auth = match env::var("AUTH_STORE") {
+        Ok(path) => match Authenticator::new(path).await {
+            Ok(v) => Some(v),
+            Err(e) => panic!("[auth] error: {}", e)
+        },
+        Err(_) => None
+    };
+
+    let handler = Proxy::new(mediator, auth).await.unwrap();
     let server = Server::new(None, handler).await;
 
     let addr = "127.0.0.1:3306".to_owned();
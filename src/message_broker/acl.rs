@@ -0,0 +1,99 @@
+use super::client::clobj::ClientID;
+
+/// root of response topic given to client on CONNACK
+pub const RESPONSE_ROOT: &str = "response";
+
+/// Topic access of client.
+///
+/// every client own a response prefix under [`RESPONSE_ROOT`], anyone may publish
+/// the response to it but only its owner can receive from it
+pub struct Acl;
+
+impl Acl {
+    /// response information given on CONNACK when requested by the client
+    pub fn response_information(clid: &ClientID) -> String {
+        format!("{}/{}", RESPONSE_ROOT, Self::owner_level(clid))
+    }
+
+    /// client id as a single topic level, separator, wildcard and `%`
+    /// are percent encoded so every id has its own distinct level
+    fn owner_level(clid: &ClientID) -> String {
+        let clid = clid.to_string();
+        let mut level = String::with_capacity(clid.len());
+        for c in clid.chars() {
+            match c {
+                '/' | '+' | '#' | '%' => level.push_str(&format!("%{:02X}", c as u8)),
+                _ => level.push(c)
+            }
+        }
+        level
+    }
+
+    /// filter under response prefix of another client is not authorized
+    pub fn can_subscribe(clid: &ClientID, filter: &str) -> bool {
+        Self::is_owner(clid, filter)
+    }
+
+    /// publish on response prefix only reach its owner,
+    /// even through wildcard subscription
+    pub fn can_receive(clid: &ClientID, topic: &str) -> bool {
+        Self::is_owner(clid, topic)
+    }
+
+    fn is_owner(clid: &ClientID, topic: &str) -> bool {
+        let mut levels = topic.split('/');
+        if levels.next() != Some(RESPONSE_ROOT) {
+            return true;
+        }
+
+        match levels.next() {
+            Some(owner) => owner == Self::owner_level(clid),
+            None => true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::message_broker::client::clobj::ClientID;
+    use super::Acl;
+
+    #[test]
+    fn own_response_prefix() {
+        let requester = ClientID::new("req".to_string());
+        let other = ClientID::new("other".to_string());
+        let prefix = Acl::response_information(&requester);
+        assert_eq!(prefix, "response/req");
+
+        let topic = format!("{}/reply", prefix);
+        assert!(Acl::can_subscribe(&requester, &format!("{}/#", prefix)));
+        assert!(!Acl::can_subscribe(&other, &format!("{}/#", prefix)));
+        assert!(!Acl::can_subscribe(&other, "response/+/reply"));
+        assert!(Acl::can_subscribe(&other, "sensors/#"));
+
+        assert!(Acl::can_receive(&requester, &topic));
+        assert!(!Acl::can_receive(&other, &topic));
+        assert!(Acl::can_receive(&other, "sensors/a"));
+    }
+
+    #[test]
+    fn escaped_response_prefix() {
+        let nested = ClientID::new("a/b".to_string());
+        let parent = ClientID::new("a".to_string());
+        let wildcard = ClientID::new("x+#%".to_string());
+
+        let prefix = Acl::response_information(&nested);
+        assert_eq!(prefix, "response/a%2Fb");
+        assert!(Acl::can_subscribe(&nested, &format!("{}/#", prefix)));
+        assert!(Acl::can_receive(&nested, &format!("{}/reply", prefix)));
+        assert!(!Acl::can_subscribe(&parent, &format!("{}/#", prefix)));
+        assert!(!Acl::can_receive(&parent, &format!("{}/reply", prefix)));
+        // prefix of `a` does not cover `a/b`
+        assert!(!Acl::can_receive(&nested, "response/a/b/reply"));
+
+        let prefix = Acl::response_information(&wildcard);
+        assert_eq!(prefix, "response/x%2B%23%25");
+        assert!(Acl::can_subscribe(&wildcard, &format!("{}/#", prefix)));
+        assert!(!Acl::can_subscribe(&parent, &format!("{}/#", prefix)));
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use crate::{error::BrokerError, protocol::v5::{malform::Malformed, publish::PublishPacket}};
+
+/// publish count before topic is given an alias
+const HOT_TOPIC: u32 = 2;
+/// topic counted at once, counter is reset when exceeded
+const MAX_TRACKED: usize = 1024;
+
+/// Alias set by the client, only valid for one connection.
+#[derive(Debug, Default)]
+pub struct InboundAlias {
+    max: u16,
+    map: HashMap<u16, String>,
+}
+
+impl InboundAlias {
+    pub fn new(max: u16) -> Self {
+        Self { max, map: HashMap::new() }
+    }
+
+    /// fill topic of aliased publish and remember new alias,
+    /// connection must be closed on error
+    pub fn resolve(&mut self, packet: &mut PublishPacket) -> Result<(), BrokerError> {
+        let alias = packet.properties
+            .as_mut()
+            .and_then(|p| p.topic_alias.take());
+
+        let alias = match alias {
+            Some(alias) => alias,
+            None if packet.topic.is_empty() => return Err(Malformed::ProtocolError.into()),
+            None => return Ok(())
+        };
+
+        if alias == 0 || alias > self.max {
+            return Err(BrokerError::TopicAliasInvalid);
+        }
+
+        if packet.topic.is_empty() {
+            packet.topic = self.map.get(&alias)
+                .cloned()
+                .ok_or(BrokerError::from(Malformed::ProtocolError))?;
+            return Ok(());
+        }
+
+        self.map.insert(alias, packet.topic.clone());
+        Ok(())
+    }
+}
+
+/// Alias given by broker to topic that is often sent to subscriber,
+/// up to the maximum advertised by the client.
+#[derive(Debug, Default)]
+pub struct OutboundAlias {
+    max: u16,
+    assigned: HashMap<String, u16>,
+    seen: HashMap<String, u32>,
+}
+
+impl OutboundAlias {
+    pub fn new(max: u16) -> Self {
+        Self { max, assigned: HashMap::new(), seen: HashMap::new() }
+    }
+
+    /// alias for the topic, flag is true when topic must be sent
+    /// along so the client learn the alias
+    pub fn alias(&mut self, topic: &str) -> Option<(u16, bool)> {
+        if let Some(alias) = self.assigned.get(topic) {
+            return Some((*alias, false));
+        }
+
+        if self.assigned.len() >= self.max as usize {
+            return None;
+        }
+
+        if self.seen.len() >= MAX_TRACKED && !self.seen.contains_key(topic) {
+            self.seen.clear();
+        }
+
+        let seen = self.seen.entry(topic.to_string()).or_default();
+        *seen += 1;
+        if *seen < HOT_TOPIC {
+            return None;
+        }
+
+        self.seen.remove(topic);
+        let alias = self.assigned.len() as u16 + 1;
+        self.assigned.insert(topic.to_string(), alias);
+        Some((alias, true))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::v5::{disconnect::{PROTOCOL_ERROR, TOPIC_ALIAS_INVALID}, publish::{Properties, PublishPacket}};
+    use super::{InboundAlias, OutboundAlias};
+
+    fn aliased(topic: &str, alias: u16) -> PublishPacket {
+        PublishPacket {
+            topic: topic.to_string(),
+            properties: Some(Properties { topic_alias: Some(alias), ..Default::default() }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn inbound_alias() {
+        let mut aliases = InboundAlias::new(4);
+        let mut packet = aliased("a/b", 1);
+        aliases.resolve(&mut packet).unwrap();
+        assert_eq!(packet.properties.unwrap().topic_alias, None);
+
+        let mut packet = aliased("", 1);
+        aliases.resolve(&mut packet).unwrap();
+        assert_eq!(packet.topic, "a/b");
+
+        assert_eq!(aliases.resolve(&mut aliased("", 2)).map_err(|e| e.code()), Err(PROTOCOL_ERROR));
+        assert_eq!(aliases.resolve(&mut aliased("a/c", 5)).map_err(|e| e.code()), Err(TOPIC_ALIAS_INVALID));
+        assert_eq!(aliases.resolve(&mut aliased("a/c", 0)).map_err(|e| e.code()), Err(TOPIC_ALIAS_INVALID));
+        assert_eq!(aliases.resolve(&mut PublishPacket::default()).map_err(|e| e.code()), Err(PROTOCOL_ERROR));
+    }
+
+    #[test]
+    fn outbound_hot_topic() {
+        let mut aliases = OutboundAlias::new(1);
+        assert_eq!(aliases.alias("a/b"), None);
+        assert_eq!(aliases.alias("a/b"), Some((1, true)));
+        assert_eq!(aliases.alias("a/b"), Some((1, false)));
+
+        // limit advertised by client is reached
+        assert_eq!(aliases.alias("a/c"), None);
+        assert_eq!(aliases.alias("a/c"), None);
+        assert_eq!(OutboundAlias::new(0).alias("a/b"), None);
+    }
+}
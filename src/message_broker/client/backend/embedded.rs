@@ -0,0 +1,608 @@
+use std::{collections::HashMap, io::{Read, Seek, SeekFrom}, path::{Path, PathBuf}, sync::{Arc, Mutex, RwLock}};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::{fs::{self, File, OpenOptions}, io::{self, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter}, sync::{mpsc, oneshot}};
+use crate::{helper::crc::crc32, message_broker::client::{
+    offline::{decode_offline, encode_offline, Queued},
+    storage::{
+        compact_wall, decode_inflight, decode_metadata, decode_subscriptions, decode_wall, decode_will,
+        encode_inflight, encode_inflight_changes, encode_subscriptions, encode_wall, encode_will,
+        InFlight, InFlightChange, MetaData, Will, WALL
+    }
+}, protocol::v5::subscribe::Subscribe};
+use super::{memory::Op, ClientID, SessionStore};
+
+const OP_CREATE: u8 = 0x01;
+const OP_SUBSCRIPTIONS: u8 = 0x02;
+const OP_LOG: u8 = 0x03;
+const OP_INFLIGHT: u8 = 0x04;
+const OP_WILL: u8 = 0x05;
+const OP_REMOVE: u8 = 0x06;
+const OP_OFFLINE: u8 = 0x07;
+const OP_OFFLINE_APPEND: u8 = 0x08;
+const OP_INFLIGHT_APPEND: u8 = 0x09;
+
+/// body length (4 bytes) | crc32 of body (4 bytes)
+const HEADER_LEN: usize = 8;
+
+/// file is never compacted below this size
+const COMPACT_MIN: u64 = 1024 * 1024;
+
+/// change written with single fsync at most
+const GROUP_MAX: usize = 256;
+
+/// change that wait to be written, acknowledged once it is durable
+struct Pending {
+    clid: String,
+    op: Op,
+    done: oneshot::Sender<io::Result<()>>,
+}
+
+/// position of record payload in the file
+#[derive(Debug, Clone, Copy, Default)]
+struct Extent {
+    offset: u64,
+    len: u32,
+}
+
+/// position of every part of single session, value itself stay on disk
+#[derive(Debug, Clone, Default)]
+struct Entry {
+    metadata: Extent,
+    subscriptions: Extent,
+    log: Vec<Extent>,
+    inflight: Vec<Extent>,
+    will: Extent,
+    offline: Vec<Extent>,
+}
+
+/// file that is read and position of every session in it,
+/// both are replaced together when the file is compacted
+struct Index {
+    file: Arc<Mutex<std::fs::File>>,
+    entries: HashMap<String, Entry>,
+}
+
+impl Index {
+    fn apply(entries: &mut HashMap<String, Entry>, clid: &str, op: &Op, at: Extent) -> io::Result<()> {
+        if let Op::Create(_) = op {
+            entries.insert(clid.to_string(), Entry { metadata: at, ..Default::default() });
+            return Ok(());
+        }
+
+        if let Op::Remove = op {
+            entries.remove(clid);
+            return Ok(());
+        }
+
+        let entry = entries.get_mut(clid)
+            .ok_or(io::Error::new(io::ErrorKind::NotFound, format!("no session for client id {}", clid)))?;
+        match op {
+            Op::Subscriptions(_) => entry.subscriptions = at,
+            Op::Log(_) => entry.log.push(at),
+            Op::InFlight(_) => entry.inflight = vec![at],
+            Op::InFlightAppend(_) => entry.inflight.push(at),
+            Op::Will(_) => entry.will = at,
+            Op::Offline(_) => entry.offline = vec![at],
+            Op::OfflineAppend(_) => entry.offline.push(at),
+            Op::Create(_) | Op::Remove => ()
+        }
+        Ok(())
+    }
+}
+
+/// Every session in single append only file.
+///
+/// each change is appended as record, only position of each value is
+/// kept in memory and the value is read back from the file. file is
+/// rewritten as snapshot once it grows twice as big as the last snapshot.
+/// change of every session is written by one writer task, that fsync
+/// once for every change already waiting.
+///
+/// record: body length (4 bytes) | crc32 (4 bytes) | op (1 byte) | client id | payload
+#[derive(Clone)]
+pub struct EmbeddedStore {
+    path: PathBuf,
+    index: Arc<RwLock<Index>>,
+    tx: mpsc::UnboundedSender<Pending>,
+}
+
+impl std::fmt::Debug for EmbeddedStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EmbeddedStore")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+impl EmbeddedStore {
+    pub async fn open(path: PathBuf) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        let (entries, valid) = replay(&path).await?;
+        if valid < file.metadata().await?.len() {
+            file.set_len(valid).await?;
+            file.sync_data().await?;
+        }
+
+        let index = Arc::new(RwLock::new(Index {
+            file: Arc::new(Mutex::new(std::fs::File::open(&path)?)),
+            entries
+        }));
+        let writer = Writer {
+            path: path.clone(),
+            index: index.clone(),
+            file,
+            written: valid,
+            compact_at: COMPACT_MIN.max(valid * 2)
+        };
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(writer.run(rx));
+        Ok(Self { path, index, tx })
+    }
+
+    /// queue the change, return once it is written and applied to index
+    async fn commit(&self, clid: &ClientID, op: Op) -> io::Result<()> {
+        let (done, wait) = oneshot::channel();
+        self.tx.send(Pending { clid: clid.to_string(), op, done })
+            .map_err(|_| closed())?;
+        wait.await.map_err(|_| closed())?
+    }
+
+    /// `NotFound` when there is no session for the client
+    fn exists(&self, clid: &ClientID) -> io::Result<()> {
+        self.extents(clid, |_| ()).map(|_| ())
+    }
+
+    fn extents<R>(&self, clid: &ClientID, f: impl FnOnce(&Entry) -> R) -> io::Result<(Arc<Mutex<std::fs::File>>, R)> {
+        let index = self.index.read().unwrap();
+        let entry = index.entries.get(&clid.to_string())
+            .ok_or(io::Error::new(io::ErrorKind::NotFound, format!("no session for client id {}", clid)))?;
+        Ok((index.file.clone(), f(entry)))
+    }
+
+    /// value of the session read back from the file
+    async fn read(&self, clid: &ClientID, f: impl FnOnce(&Entry) -> Vec<Extent>) -> io::Result<Bytes> {
+        let (file, extents) = self.extents(clid, f)?;
+        read_extents(file, extents).await
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "session store closed")
+}
+
+/// read every extent in order as single buffer
+async fn read_extents(file: Arc<Mutex<std::fs::File>>, extents: Vec<Extent>) -> io::Result<Bytes> {
+    tokio::task::spawn_blocking(move || {
+        let mut buffer = BytesMut::zeroed(extents.iter().map(|e| e.len as usize).sum());
+        let mut file = file.lock().unwrap();
+        let mut pos = 0;
+        for extent in extents {
+            let end = pos + extent.len as usize;
+            file.seek(SeekFrom::Start(extent.offset))?;
+            file.read_exact(&mut buffer[pos..end])?;
+            pos = end;
+        }
+        Ok(buffer.freeze())
+    }).await?
+}
+
+/// position of every session on the file and length of the valid part,
+/// torn or corrupted tail is left out
+async fn replay(path: &Path) -> io::Result<(HashMap<String, Entry>, u64)> {
+    let f = File::open(path).await?;
+    let total = f.metadata().await?.len();
+    let mut reader = BufReader::new(f);
+    let mut entries = HashMap::new();
+    let mut valid = 0;
+    loop {
+        let mut header = [0; HEADER_LEN];
+        match reader.read_exact(&mut header).await {
+            Ok(_) => (),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                if valid < total {
+                    println!("[storage] {} recovered: record header is truncated", path.display());
+                }
+                break;
+            },
+            Err(err) => return Err(err)
+        }
+
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64;
+        let crc = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        if valid + (HEADER_LEN as u64) + len > total {
+            println!("[storage] {} recovered: record is truncated", path.display());
+            break;
+        }
+
+        let mut body = BytesMut::zeroed(len as usize);
+        reader.read_exact(&mut body).await?;
+        if crc32(&body) != crc {
+            println!("[storage] {} recovered: record checksum mismatch", path.display());
+            break;
+        }
+
+        let (clid, op, at) = match deserialize(body.freeze()) {
+            Ok(record) => record,
+            Err(err) => {
+                println!("[storage] {} recovered: {}", path.display(), err);
+                break;
+            }
+        };
+
+        let at = Extent { offset: valid + (HEADER_LEN + at) as u64, len: op.payload().len() as u32 };
+        // change on unknown session is ignored
+        let _ = Index::apply(&mut entries, &clid, &op, at);
+        valid += HEADER_LEN as u64 + len;
+    }
+    Ok((entries, valid))
+}
+
+struct Writer {
+    path: PathBuf,
+    index: Arc<RwLock<Index>>,
+    file: File,
+    written: u64,
+    compact_at: u64,
+}
+
+impl Writer {
+    /// stop when every handle of the store is dropped
+    async fn run(mut self, mut rx: mpsc::UnboundedReceiver<Pending>) {
+        while let Some(first) = rx.recv().await {
+            let mut batch = vec![first];
+            while batch.len() < GROUP_MAX {
+                match rx.try_recv() {
+                    Ok(pending) => batch.push(pending),
+                    Err(_) => break
+                }
+            }
+            self.commit(batch).await;
+        }
+    }
+
+    /// write the whole batch with single fsync then apply it to index in order
+    async fn commit(&mut self, batch: Vec<Pending>) {
+        let mut buffer = BytesMut::new();
+        let mut extents = Vec::with_capacity(batch.len());
+        for pending in batch.iter() {
+            let at = serialize(&pending.clid, &pending.op, &mut buffer);
+            extents.push(Extent { offset: self.written + at as u64, len: pending.op.payload().len() as u32 });
+        }
+
+        let res = self.write(&buffer).await;
+        {
+            let mut index = self.index.write().unwrap();
+            for (Pending { clid, op, done }, at) in batch.into_iter().zip(extents) {
+                let res = match &res {
+                    Ok(_) => Index::apply(&mut index.entries, &clid, &op, at),
+                    Err(err) => Err(io::Error::new(err.kind(), err.to_string()))
+                };
+                let _ = done.send(res);
+            }
+        }
+
+        if res.is_ok() && self.written > self.compact_at {
+            if let Err(err) = self.compact().await {
+                println!("[storage] {} compact error: {}", self.path.display(), err);
+            }
+        }
+    }
+
+    async fn write(&mut self, buffer: &[u8]) -> io::Result<()> {
+        self.file.write_all(buffer).await?;
+        self.file.sync_data().await?;
+        self.written += buffer.len() as u64;
+        Ok(())
+    }
+
+    /// rewrite every session one at a time, so only single session
+    /// is held in memory
+    async fn compact(&mut self) -> io::Result<()> {
+        let (file, entries) = {
+            let index = self.index.read().unwrap();
+            (index.file.clone(), index.entries.clone())
+        };
+
+        let mut tmp = self.path.clone();
+        tmp.set_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp).await?);
+        let mut compacted = HashMap::with_capacity(entries.len());
+        let mut written = 0;
+        for (clid, entry) in entries {
+            let log = read_extents(file.clone(), entry.log).await?;
+            let log = decode_wall(&mut log.clone());
+            let log = compact_wall(&log).unwrap_or(log);
+            let inflight = decode_inflight(read_extents(file.clone(), entry.inflight).await?)?;
+            let ops = [
+                Op::Create(read_extents(file.clone(), vec![entry.metadata]).await?),
+                Op::Subscriptions(read_extents(file.clone(), vec![entry.subscriptions]).await?),
+                Op::Log(encode_wall(&log).freeze()),
+                Op::InFlight(encode_inflight(&inflight)),
+                Op::Will(read_extents(file.clone(), vec![entry.will]).await?),
+                Op::Offline(read_extents(file.clone(), entry.offline).await?),
+            ];
+
+            let mut buffer = BytesMut::new();
+            for op in ops.iter() {
+                let at = serialize(&clid, op, &mut buffer);
+                let at = Extent { offset: written + at as u64, len: op.payload().len() as u32 };
+                let _ = Index::apply(&mut compacted, &clid, op, at);
+            }
+            writer.write_all(&buffer).await?;
+            written += buffer.len() as u64;
+        }
+        writer.flush().await?;
+        writer.get_ref().sync_all().await?;
+        fs::rename(&tmp, &self.path).await?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path).await?;
+        self.written = written;
+        self.compact_at = COMPACT_MIN.max(self.written * 2);
+        *self.index.write().unwrap() = Index {
+            file: Arc::new(Mutex::new(std::fs::File::open(&self.path)?)),
+            entries: compacted
+        };
+        println!("[storage] {} compacted to {} bytes", self.path.display(), self.written);
+        Ok(())
+    }
+}
+
+/// offset of the payload from the start of the record is given back
+fn serialize(clid: &str, op: &Op, buffer: &mut BytesMut) -> usize {
+    let code = match op {
+        Op::Create(_) => OP_CREATE,
+        Op::Subscriptions(_) => OP_SUBSCRIPTIONS,
+        Op::Log(_) => OP_LOG,
+        Op::InFlight(_) => OP_INFLIGHT,
+        Op::InFlightAppend(_) => OP_INFLIGHT_APPEND,
+        Op::Will(_) => OP_WILL,
+        Op::Offline(_) => OP_OFFLINE,
+        Op::OfflineAppend(_) => OP_OFFLINE_APPEND,
+        Op::Remove => OP_REMOVE,
+    };
+    let payload = op.payload();
+
+    let mut body = BytesMut::with_capacity(3 + clid.len() + payload.len());
+    body.put_u8(code);
+    body.put_u16(clid.len() as u16);
+    body.put(clid.as_bytes());
+    body.put(payload);
+
+    let at = buffer.len() + HEADER_LEN + 3 + clid.len();
+    buffer.put_u32(body.len() as u32);
+    buffer.put_u32(crc32(&body));
+    buffer.put(body);
+    at
+}
+
+/// record body that already match its checksum,
+/// offset of the payload from the start of the body is given back
+fn deserialize(mut body: Bytes) -> io::Result<(String, Op, usize)> {
+    if body.len() < 3 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "record is too short"));
+    }
+    let code = body.get_u8();
+    let clid_len = body.get_u16() as usize;
+    if body.len() < clid_len {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid client id length"));
+    }
+    let clid = String::from_utf8(body.split_to(clid_len).to_vec())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+    let op = match code {
+        OP_CREATE => Op::Create(body),
+        OP_SUBSCRIPTIONS => Op::Subscriptions(body),
+        OP_LOG => Op::Log(body),
+        OP_INFLIGHT => Op::InFlight(body),
+        OP_INFLIGHT_APPEND => Op::InFlightAppend(body),
+        OP_WILL => Op::Will(body),
+        OP_OFFLINE => Op::Offline(body),
+        OP_OFFLINE_APPEND => Op::OfflineAppend(body),
+        OP_REMOVE => Op::Remove,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown record"))
+    };
+    Ok((clid, op, 3 + clid_len))
+}
+
+impl SessionStore for EmbeddedStore {
+    async fn create(&self, clid: &ClientID, mdata: &MetaData) -> io::Result<()> {
+        let mut buffer = BytesMut::with_capacity(mdata.est_len());
+        mdata.serialize(&mut buffer);
+        self.commit(clid, Op::Create(buffer.freeze())).await
+    }
+
+    async fn metadata(&self, clid: &ClientID) -> io::Result<MetaData> {
+        decode_metadata(self.read(clid, |e| vec![e.metadata]).await?)
+    }
+
+    async fn set_subscriptions(&self, clid: &ClientID, subs: &[Subscribe]) -> io::Result<()> {
+        self.exists(clid)?;
+        self.commit(clid, Op::Subscriptions(encode_subscriptions(subs))).await
+    }
+
+    async fn subscriptions(&self, clid: &ClientID) -> io::Result<Vec<Subscribe>> {
+        decode_subscriptions(&self.read(clid, |e| vec![e.subscriptions]).await?)
+    }
+
+    async fn append_log(&self, clid: &ClientID, wall: &[WALL]) -> io::Result<()> {
+        self.exists(clid)?;
+        self.commit(clid, Op::Log(encode_wall(wall).freeze())).await
+    }
+
+    async fn session_log(&self, clid: &ClientID) -> io::Result<Vec<WALL>> {
+        let mut log = self.read(clid, |e| e.log.clone()).await?;
+        Ok(decode_wall(&mut log))
+    }
+
+    async fn set_inflight(&self, clid: &ClientID, inflight: &[InFlight]) -> io::Result<()> {
+        self.exists(clid)?;
+        self.commit(clid, Op::InFlight(encode_inflight(inflight))).await
+    }
+
+    async fn append_inflight(&self, clid: &ClientID, changes: &[InFlightChange]) -> io::Result<()> {
+        self.exists(clid)?;
+        self.commit(clid, Op::InFlightAppend(encode_inflight_changes(changes))).await
+    }
+
+    async fn inflight(&self, clid: &ClientID) -> io::Result<Vec<InFlight>> {
+        decode_inflight(self.read(clid, |e| e.inflight.clone()).await?)
+    }
+
+    async fn set_will(&self, clid: &ClientID, will: Option<&Will>) -> io::Result<()> {
+        self.exists(clid)?;
+        self.commit(clid, Op::Will(encode_will(will))).await
+    }
+
+    async fn will(&self, clid: &ClientID) -> io::Result<Option<Will>> {
+        decode_will(self.read(clid, |e| vec![e.will]).await?)
+    }
+
+    async fn append_offline(&self, clid: &ClientID, queued: &[Queued]) -> io::Result<()> {
+        self.exists(clid)?;
+        self.commit(clid, Op::OfflineAppend(encode_offline(queued))).await
+    }
+
+    async fn set_offline(&self, clid: &ClientID, queued: &[Queued]) -> io::Result<()> {
+        self.exists(clid)?;
+        self.commit(clid, Op::Offline(encode_offline(queued))).await
+    }
+
+    async fn offline(&self, clid: &ClientID) -> io::Result<Vec<Queued>> {
+        decode_offline(self.read(clid, |e| e.offline.clone()).await?)
+    }
+
+    async fn sessions(&self) -> io::Result<Vec<ClientID>> {
+        let index = self.index.read().unwrap();
+        Ok(index.entries.keys().cloned().map(ClientID::new).collect())
+    }
+
+    async fn remove(&self, clid: &ClientID) -> io::Result<()> {
+        if self.exists(clid).is_err() {
+            return Ok(());
+        }
+        self.commit(clid, Op::Remove).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use tokio::{fs, io::AsyncWriteExt};
+    use crate::{
+        message_broker::client::{
+            backend::SessionStore, clobj::ClientID, offline::Queued,
+            storage::{EventType, MetaData, WALL}
+        },
+        protocol::v5::{subscribe::{Subscribe, SubscriptionOptions}, ServiceLevel}
+    };
+    use super::EmbeddedStore;
+
+    #[tokio::test]
+    async fn reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sessions.db");
+        let mdata = MetaData {
+            protocol_level: 5,
+            keep_alive_interval: 60,
+            expr_interval: 0,
+            receive_maximum: 0,
+            maximum_packet_size: 0,
+            topic_alias_maximum: 0,
+            user_properties: Vec::new()
+        };
+
+        let a = ClientID::new("a".to_string());
+        let b = ClientID::new("b".to_string());
+        let log = [WALL { time: 1, value: EventType::ClientCreated }];
+        {
+            let store = EmbeddedStore::open(path.clone()).await.unwrap();
+            store.create(&a, &mdata).await.unwrap();
+            store.create(&b, &mdata).await.unwrap();
+            store.append_log(&a, &log).await.unwrap();
+            let subs = [Subscribe { topic: "x/y".to_string(), max_qos: ServiceLevel::QoS2, options: SubscriptionOptions::default(), subscription_identifier: None }];
+            store.set_subscriptions(&a, &subs).await.unwrap();
+            store.remove(&b).await.unwrap();
+        }
+
+        // torn record at the end
+        let mut f = fs::OpenOptions::new().append(true).open(&path).await.unwrap();
+        f.write_all(&[0, 0, 0, 40, 1, 2]).await.unwrap();
+        drop(f);
+
+        let store = EmbeddedStore::open(path.clone()).await.unwrap();
+        let sessions = store.sessions().await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].to_string(), "a");
+        assert_eq!(store.session_log(&a).await.unwrap(), log.to_vec());
+        assert_eq!(store.metadata(&a).await.unwrap(), mdata);
+        let subs = store.subscriptions(&a).await.unwrap();
+        assert_eq!(subs[0].topic, "x/y");
+        assert!(store.subscriptions(&b).await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sessions.db");
+        let store = EmbeddedStore::open(path.clone()).await.unwrap();
+
+        let tasks: Vec<_> = (0..64).map(|i| {
+            let store = store.clone();
+            tokio::spawn(async move {
+                let clid = ClientID::new(format!("c{}", i));
+                store.create(&clid, &MetaData::default()).await.unwrap();
+                let log = [WALL { time: i, value: EventType::ClientCreated }];
+                store.append_log(&clid, &log).await.unwrap();
+            })
+        }).collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        drop(store);
+
+        let store = EmbeddedStore::open(path.clone()).await.unwrap();
+        assert_eq!(store.sessions().await.unwrap().len(), 64);
+        let log = store.session_log(&ClientID::new("c7".to_string())).await.unwrap();
+        assert_eq!(log, vec![WALL { time: 7, value: EventType::ClientCreated }]);
+    }
+
+    #[tokio::test]
+    async fn read_after_compact() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sessions.db");
+        let store = EmbeddedStore::open(path.clone()).await.unwrap();
+        let clid = ClientID::new("c".to_string());
+        store.create(&clid, &MetaData::default()).await.unwrap();
+        store.append_log(&clid, &[WALL { time: 1, value: EventType::ClientCreated }]).await.unwrap();
+
+        // big enough to compact the file more than once
+        let packet = Bytes::from(vec![7; 64 * 1024]);
+        for i in 0..40 {
+            if i % 10 == 0 {
+                store.set_offline(&clid, &[]).await.unwrap();
+            }
+            let queued = Queued { qos: ServiceLevel::QoS1, packet: packet.clone(), expire_at: None };
+            store.append_offline(&clid, &[queued]).await.unwrap();
+        }
+        store.set_offline(&clid, &[]).await.unwrap();
+        let queued = Queued { qos: ServiceLevel::QoS2, packet: packet.clone(), expire_at: None };
+        store.append_offline(&clid, &[queued]).await.unwrap();
+        assert!(fs::metadata(&path).await.unwrap().len() < 1536 * 1024);
+
+        for store in [store, EmbeddedStore::open(path.clone()).await.unwrap()] {
+            let offline = store.offline(&clid).await.unwrap();
+            assert_eq!(offline.len(), 1);
+            assert_eq!((offline[0].qos.clone(), offline[0].packet.clone()), (ServiceLevel::QoS2, packet.clone()));
+            assert_eq!(store.session_log(&clid).await.unwrap(), vec![WALL { time: 1, value: EventType::ClientCreated }]);
+            assert_eq!(store.metadata(&clid).await.unwrap(), MetaData::default());
+        }
+    }
+}
@@ -0,0 +1,485 @@
+use std::path::{Path, PathBuf};
+use bytes::{Bytes, BytesMut};
+use tokio::{fs::{self, File, OpenOptions}, io::{self, AsyncWriteExt}};
+use crate::{message_broker::client::{
+    offline::{decode_offline, encode_offline, Queued},
+    storage::{
+        compact_wall, decode_inflight, decode_legacy_subscriptions, decode_legacy_wall, decode_metadata,
+        decode_subscriptions, decode_wall, decode_will, encode_inflight, encode_inflight_changes, encode_subscriptions,
+        encode_wall, encode_will, is_legacy_metadata, is_legacy_wall, InFlight, InFlightChange, MetaData, Will,
+        COMPACT_SIZE, WALL
+    }
+}, protocol::v5::subscribe::Subscribe};
+use super::{ClientID, SessionStore};
+
+const METADATA: &str = "metadata";
+const SUBSCRIBE_DATA: &str = "subscribed";
+const SESSION_DATA: &str = "session";
+const INFLIGHT_DATA: &str = "inflight";
+const WILL_DATA: &str = "will";
+const OFFLINE_DATA: &str = "offline";
+/// suffix of session directory being migrated
+const MIGRATING: &str = ".migrating";
+
+/// Directory for each client, every part of session on its own file.
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    root: PathBuf,
+}
+
+impl FileStore {
+    pub async fn open(root: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&root).await?;
+        let store = Self { root };
+        store.migrate().await?;
+        Ok(store)
+    }
+
+    /// rewrite session of the first format, its directory is named by the
+    /// raw client id and moved under [`Self::dir_name`]. session that can
+    /// not be migrated is kept as is and refused when it is read
+    async fn migrate(&self) -> io::Result<()> {
+        let mut entries = fs::read_dir(&self.root).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let dir = entry.path();
+            if !dir.is_dir() {
+                continue;
+            }
+
+            // interrupted before migrated directory is renamed
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if let Some(target) = name.strip_suffix(MIGRATING) {
+                let target = self.root.join(target);
+                match target.exists() {
+                    true => fs::remove_dir_all(&dir).await?,
+                    false => fs::rename(&dir, &target).await?
+                }
+                continue;
+            }
+
+            let mdata = match fs::read(dir.join(METADATA)).await {
+                Ok(raw) if is_legacy_metadata(&raw) => raw,
+                _ => continue
+            };
+
+            let target = self.dir(&ClientID::new(name));
+            match Self::migrate_dir(&dir, &target, mdata).await {
+                Ok(()) => println!("[storage] session {} migrated to {}", dir.display(), target.display()),
+                Err(err) => println!("[storage] session {} not migrated: {}", dir.display(), err)
+            }
+        }
+        Ok(())
+    }
+
+    /// build migrated session beside the old one then swap them
+    async fn migrate_dir(dir: &Path, target: &Path, mdata: Vec<u8>) -> io::Result<()> {
+        let mdata = decode_metadata(Bytes::from(mdata))?;
+        let wall = match fs::read(dir.join(SESSION_DATA)).await? {
+            raw if is_legacy_wall(&raw) => decode_legacy_wall(&raw)?,
+            raw => decode_wall(&mut Bytes::from(raw))
+        };
+        let subs = match fs::read(dir.join(SUBSCRIBE_DATA)).await {
+            Ok(raw) => decode_legacy_subscriptions(&raw)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err)
+        };
+
+        let mut tmp = target.as_os_str().to_owned();
+        tmp.push(MIGRATING);
+        let tmp = PathBuf::from(tmp);
+        remove_dir(&tmp).await?;
+        fs::create_dir_all(&tmp).await?;
+
+        let mut buffer = BytesMut::with_capacity(mdata.est_len());
+        mdata.serialize(&mut buffer);
+        write_file(&tmp.join(METADATA), &buffer).await?;
+        write_file(&tmp.join(SESSION_DATA), &encode_wall(&wall)).await?;
+        write_file(&tmp.join(SUBSCRIBE_DATA), &encode_subscriptions(&subs)).await?;
+
+        // old directory is removed last, it is migrated again when interrupted
+        remove_dir(target).await?;
+        fs::rename(&tmp, target).await?;
+        if dir != target {
+            remove_dir(dir).await?;
+        }
+        Ok(())
+    }
+
+    /// directory name of the client id, every byte other than ascii
+    /// alphanumeric, `-` and `_` is percent encoded, so id like `..`
+    /// or `/etc` never point outside the root
+    pub fn dir_name(clid: &ClientID) -> String {
+        let clid = clid.to_string();
+        let mut name = String::with_capacity(clid.len());
+        for b in clid.bytes() {
+            match b {
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => name.push(b as char),
+                _ => name.push_str(&format!("%{:02X}", b))
+            }
+        }
+        name
+    }
+
+    /// client id of directory name, None when it is not made by [`Self::dir_name`]
+    fn parse_dir_name(name: &str) -> Option<ClientID> {
+        let mut raw = Vec::with_capacity(name.len());
+        let mut bytes = name.bytes();
+        while let Some(b) = bytes.next() {
+            match b {
+                b'%' => {
+                    let hex = [bytes.next()?, bytes.next()?];
+                    let hex = std::str::from_utf8(&hex).ok()?;
+                    raw.push(u8::from_str_radix(hex, 16).ok()?);
+                },
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => raw.push(b),
+                _ => return None
+            }
+        }
+        String::from_utf8(raw).ok().map(ClientID::new)
+    }
+
+    fn dir(&self, clid: &ClientID) -> PathBuf {
+        let mut dir = self.root.clone();
+        dir.push(Self::dir_name(clid));
+        dir
+    }
+
+    fn path(&self, clid: &ClientID, name: &str) -> PathBuf {
+        let mut path = self.dir(clid);
+        path.push(name);
+        path
+    }
+
+    /// append to file of existing session
+    async fn append(&self, clid: &ClientID, name: &str, buffer: &[u8]) -> io::Result<()> {
+        if !self.dir(clid).is_dir() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("no session for client id {}", clid)));
+        }
+
+        let mut f = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(self.path(clid, name))
+            .await?;
+        f.write_all(buffer).await?;
+        f.sync_data().await
+    }
+
+    /// read file of existing session, missing file is empty
+    async fn read(&self, clid: &ClientID, name: &str) -> io::Result<Bytes> {
+        let path = self.path(clid, name);
+        match fs::read(&path).await {
+            Ok(raw) => Ok(Bytes::from(raw)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let session_exists = path.parent()
+                    .map(|dir| dir.is_dir())
+                    .unwrap_or_default();
+                if !session_exists {
+                    return Err(err);
+                }
+                Ok(Bytes::new())
+            },
+            Err(err) => Err(err)
+        }
+    }
+}
+
+impl SessionStore for FileStore {
+    async fn create(&self, clid: &ClientID, mdata: &MetaData) -> io::Result<()> {
+        let dir = self.dir(clid);
+        remove_dir(&dir).await?;
+        fs::create_dir_all(&dir).await?;
+
+        let mut buffer = BytesMut::with_capacity(mdata.est_len());
+        mdata.serialize(&mut buffer);
+        write_file(&self.path(clid, METADATA), &buffer).await?;
+        write_file(&self.path(clid, SUBSCRIBE_DATA), &[]).await
+    }
+
+    async fn metadata(&self, clid: &ClientID) -> io::Result<MetaData> {
+        let raw = fs::read(self.path(clid, METADATA)).await?;
+        decode_metadata(Bytes::from(raw))
+    }
+
+    async fn set_subscriptions(&self, clid: &ClientID, subs: &[Subscribe]) -> io::Result<()> {
+        write_file(&self.path(clid, SUBSCRIBE_DATA), &encode_subscriptions(subs)).await
+    }
+
+    async fn subscriptions(&self, clid: &ClientID) -> io::Result<Vec<Subscribe>> {
+        let raw = self.read(clid, SUBSCRIBE_DATA).await?;
+        decode_subscriptions(&raw)
+    }
+
+    async fn append_log(&self, clid: &ClientID, wall: &[WALL]) -> io::Result<()> {
+        append_wall(&self.path(clid, SESSION_DATA), wall).await
+    }
+
+    async fn session_log(&self, clid: &ClientID) -> io::Result<Vec<WALL>> {
+        read_wall(&self.path(clid, SESSION_DATA)).await
+    }
+
+    async fn set_inflight(&self, clid: &ClientID, inflight: &[InFlight]) -> io::Result<()> {
+        write_file(&self.path(clid, INFLIGHT_DATA), &encode_inflight(inflight)).await
+    }
+
+    async fn append_inflight(&self, clid: &ClientID, changes: &[InFlightChange]) -> io::Result<()> {
+        self.append(clid, INFLIGHT_DATA, &encode_inflight_changes(changes)).await
+    }
+
+    async fn inflight(&self, clid: &ClientID) -> io::Result<Vec<InFlight>> {
+        let raw = self.read(clid, INFLIGHT_DATA).await?;
+        decode_inflight(raw)
+    }
+
+    async fn set_will(&self, clid: &ClientID, will: Option<&Will>) -> io::Result<()> {
+        write_file(&self.path(clid, WILL_DATA), &encode_will(will)).await
+    }
+
+    async fn will(&self, clid: &ClientID) -> io::Result<Option<Will>> {
+        let raw = self.read(clid, WILL_DATA).await?;
+        decode_will(raw)
+    }
+
+    async fn append_offline(&self, clid: &ClientID, queued: &[Queued]) -> io::Result<()> {
+        self.append(clid, OFFLINE_DATA, &encode_offline(queued)).await
+    }
+
+    async fn set_offline(&self, clid: &ClientID, queued: &[Queued]) -> io::Result<()> {
+        let path = self.path(clid, OFFLINE_DATA);
+        if !queued.is_empty() {
+            return write_file(&path, &encode_offline(queued)).await;
+        }
+
+        match fs::remove_file(&path).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(())
+        }
+    }
+
+    async fn offline(&self, clid: &ClientID) -> io::Result<Vec<Queued>> {
+        let raw = self.read(clid, OFFLINE_DATA).await?;
+        decode_offline(raw)
+    }
+
+    async fn sessions(&self) -> io::Result<Vec<ClientID>> {
+        let mut sessions = Vec::new();
+        let mut entries = fs::read_dir(&self.root).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let clid = path.file_name()
+                .and_then(|n| n.to_str())
+                .and_then(Self::parse_dir_name);
+            match clid {
+                Some(clid) if path.is_dir() => sessions.push(clid),
+                _ => continue
+            };
+        }
+        Ok(sessions)
+    }
+
+    async fn remove(&self, clid: &ClientID) -> io::Result<()> {
+        remove_dir(&self.dir(clid)).await
+    }
+}
+
+/// remove directory with everything in it, missing one is already removed
+async fn remove_dir(dir: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(dir).await {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(())
+    }
+}
+
+/// replace whole file, written to temporary file then renamed
+async fn write_file(path: &Path, buffer: &[u8]) -> io::Result<()> {
+    let mut tmp = path.to_path_buf();
+    tmp.set_extension("tmp");
+    let mut f = File::create(&tmp).await?;
+    f.write_all(buffer).await?;
+    f.sync_all().await?;
+    fs::rename(&tmp, path).await
+}
+
+/// read every valid record, torn or corrupted tail is truncated
+/// so next append continue after the last valid record.
+/// log of the first format is refused instead of truncated
+async fn read_wall(path: &Path) -> io::Result<Vec<WALL>> {
+    let raw = fs::read(path).await?;
+    if is_legacy_wall(&raw) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("session log {} is not migrated", path.display())));
+    }
+
+    let total = raw.len();
+    let mut b = Bytes::from(raw);
+    let wall = decode_wall(&mut b);
+
+    if !b.is_empty() {
+        let valid = total - b.len();
+        let f = OpenOptions::new().write(true).open(path).await?;
+        f.set_len(valid as u64).await?;
+        f.sync_data().await?;
+    }
+    Ok(wall)
+}
+
+async fn append_wall(path: &Path, wall: &[WALL]) -> io::Result<()> {
+    let buffer = encode_wall(wall);
+    let mut f = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .await?;
+    f.write_all(&buffer).await?;
+    f.sync_data().await?;
+
+    if f.metadata().await?.len() > COMPACT_SIZE as u64 {
+        let wall = read_wall(path).await?;
+        if let Some(snapshot) = compact_wall(&wall) {
+            write_file(path, &encode_wall(&snapshot)).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{BufMut, BytesMut};
+    use tokio::{fs, io::AsyncWriteExt};
+    use crate::{
+        message_broker::client::{backend::SessionStore, clobj::ClientID, storage::{compact_wall, encode_wall, EventType, MetaData, WALL}},
+        protocol::v5::{subscribe::{Subscribe, SubscriptionOptions}, ServiceLevel}
+    };
+    use super::{append_wall, read_wall, write_file, FileStore};
+
+    #[tokio::test]
+    async fn torn_tail_recovery() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session");
+
+        let created = WALL { time: 1, value: EventType::ClientCreated };
+        let disconnected = WALL { time: 2, value: EventType::ClientDisconnected(30) };
+        write_file(&path, &encode_wall(std::slice::from_ref(&created))).await.unwrap();
+        append_wall(&path, std::slice::from_ref(&disconnected)).await.unwrap();
+        let valid_len = fs::metadata(&path).await.unwrap().len();
+
+        // half written record
+        let mut f = fs::OpenOptions::new().append(true).open(&path).await.unwrap();
+        f.write_all(&[0, 0, 0, 17, 0xAB, 0xCD]).await.unwrap();
+        drop(f);
+
+        let wall = read_wall(&path).await.unwrap();
+        assert_eq!(wall, vec![created.clone(), disconnected.clone()]);
+        assert_eq!(fs::metadata(&path).await.unwrap().len(), valid_len);
+
+        let restored = WALL { time: 3, value: EventType::ClientRestored };
+        append_wall(&path, std::slice::from_ref(&restored)).await.unwrap();
+        let wall = read_wall(&path).await.unwrap();
+        assert_eq!(compact_wall(&wall), Some(vec![created, restored]));
+    }
+
+    #[tokio::test]
+    async fn missing_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileStore::open(dir.path().to_path_buf()).await.unwrap();
+        let clid = ClientID::new("nobody".to_string());
+        let err = store.subscriptions(&clid).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+
+        let subs = [Subscribe { topic: "a/b".to_string(), max_qos: ServiceLevel::QoS1, options: SubscriptionOptions::default(), subscription_identifier: None }];
+        assert!(store.set_subscriptions(&clid, &subs).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn client_id_stay_under_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path();
+        let root = base.join("sessions");
+        let store = FileStore::open(root.clone()).await.unwrap();
+        fs::write(base.join("keep"), b"keep").await.unwrap();
+        fs::write(root.join("other"), b"other").await.unwrap();
+
+        let ids = ["..", ".", "/etc", "a/../../b", "a\\b", "nul\0", "sensor-1"];
+        for id in ids {
+            let clid = ClientID::new(id.to_string());
+            store.create(&clid, &MetaData::default()).await.unwrap();
+        }
+
+        let mut sessions: Vec<String> = store.sessions().await.unwrap()
+            .iter()
+            .map(|c| c.to_string())
+            .collect();
+        sessions.sort();
+        let mut expected: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+        expected.sort();
+        assert_eq!(sessions, expected);
+
+        for id in ids {
+            store.remove(&ClientID::new(id.to_string())).await.unwrap();
+        }
+        assert_eq!(fs::read(base.join("keep")).await.unwrap(), b"keep");
+        assert_eq!(fs::read(root.join("other")).await.unwrap(), b"other");
+        assert!(store.sessions().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn legacy_session_migrated() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().to_path_buf();
+        let dir = root.join("sensor-1");
+        fs::create_dir_all(&dir).await.unwrap();
+
+        // first format: unversioned metadata, text session log and subscription
+        let mut mdata = BytesMut::new();
+        mdata.put_u8(4);
+        mdata.put_u16(30);
+        mdata.put_u32(120);
+        mdata.put_u16(5);
+        mdata.put_u16(1024);
+        mdata.put_u16(0);
+        mdata.put_u32(0);
+        fs::write(dir.join("metadata"), &mdata).await.unwrap();
+        fs::write(dir.join("session"), b"10 ClientCreated\n20 ClientDisconnected 4000000000\n").await.unwrap();
+        fs::write(dir.join("subscribed"), b"\x01a/b\n\x02c/#\n").await.unwrap();
+
+        // unknown event can not be migrated, it is kept as is
+        let broken = root.join("broken");
+        fs::create_dir_all(&broken).await.unwrap();
+        fs::write(broken.join("metadata"), &mdata).await.unwrap();
+        fs::write(broken.join("session"), b"10 ClientVanished\n").await.unwrap();
+
+        // directory of the first format is named by raw client id
+        let raw = root.join("home.sensor 2");
+        fs::create_dir_all(&raw).await.unwrap();
+        fs::write(raw.join("metadata"), &mdata).await.unwrap();
+        fs::write(raw.join("session"), b"10 ClientCreated\n").await.unwrap();
+
+        let store = FileStore::open(root.clone()).await.unwrap();
+        let mut sessions: Vec<String> = store.sessions().await.unwrap()
+            .iter()
+            .map(|c| c.to_string())
+            .collect();
+        sessions.sort();
+        assert_eq!(sessions, vec!["broken", "home.sensor 2", "sensor-1"]);
+        assert!(!raw.exists());
+        let clid = ClientID::new("home.sensor 2".to_string());
+        assert_eq!(store.session_log(&clid).await.unwrap(), vec![WALL { time: 10, value: EventType::ClientCreated }]);
+
+        let clid = ClientID::new("sensor-1".to_string());
+        let wall = store.session_log(&clid).await.unwrap();
+        assert_eq!(wall, vec![
+            WALL { time: 10, value: EventType::ClientCreated },
+            WALL { time: 20, value: EventType::ClientDisconnected(4000000000) },
+        ]);
+        let topics: Vec<(String, ServiceLevel)> = store.subscriptions(&clid).await.unwrap()
+            .into_iter()
+            .map(|s| (s.topic, s.max_qos))
+            .collect();
+        assert_eq!(topics, vec![("a/b".to_string(), ServiceLevel::QoS1), ("c/#".to_string(), ServiceLevel::QoS2)]);
+        let mdata = store.metadata(&clid).await.unwrap();
+        assert_eq!((mdata.protocol_level, mdata.maximum_packet_size), (4, 1024));
+
+        let clid = ClientID::new("broken".to_string());
+        assert!(store.session_log(&clid).await.is_err());
+        assert_eq!(fs::read(broken.join("session")).await.unwrap(), b"10 ClientVanished\n");
+    }
+}
@@ -0,0 +1,228 @@
+use std::{collections::HashMap, sync::{Arc, RwLock}};
+use bytes::{Bytes, BytesMut};
+use tokio::io;
+use crate::{message_broker::client::{
+    offline::{decode_offline, encode_offline, Queued},
+    storage::{
+        compact_wall, decode_inflight, decode_metadata, decode_subscriptions, decode_wall,
+        decode_will, encode_inflight, encode_inflight_changes, encode_subscriptions, encode_wall,
+        encode_will, InFlight, InFlightChange, MetaData, Will, COMPACT_SIZE, WALL
+    }
+}, protocol::v5::subscribe::Subscribe};
+use super::{ClientID, SessionStore};
+
+/// encoded session, same encoding as the file backend
+#[derive(Debug, Default, Clone)]
+pub(super) struct Slot {
+    pub(super) metadata: Bytes,
+    pub(super) subscriptions: Bytes,
+    pub(super) log: BytesMut,
+    pub(super) inflight: BytesMut,
+    pub(super) will: Bytes,
+    pub(super) offline: BytesMut,
+}
+
+/// single change of session, also used as record of embedded backend
+pub(super) enum Op {
+    Create(Bytes),
+    Subscriptions(Bytes),
+    Log(Bytes),
+    InFlight(Bytes),
+    InFlightAppend(Bytes),
+    Will(Bytes),
+    Offline(Bytes),
+    OfflineAppend(Bytes),
+    Remove,
+}
+
+impl Op {
+    pub(super) fn payload(&self) -> &[u8] {
+        match self {
+            Op::Create(b) | Op::Subscriptions(b) | Op::Log(b) | Op::InFlight(b) | Op::InFlightAppend(b)
+                | Op::Will(b) | Op::Offline(b) | Op::OfflineAppend(b) => b.as_ref(),
+            Op::Remove => &[],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    sessions: Arc<RwLock<HashMap<String, Slot>>>
+}
+
+impl MemoryStore {
+    pub(super) fn apply(&self, clid: &str, op: Op) -> io::Result<()> {
+        let mut sessions = self.sessions.write().unwrap();
+        if let Op::Create(metadata) = op {
+            sessions.insert(clid.to_string(), Slot { metadata, ..Default::default() });
+            return Ok(());
+        }
+
+        if let Op::Remove = op {
+            sessions.remove(clid);
+            return Ok(());
+        }
+
+        let slot = sessions.get_mut(clid)
+            .ok_or(io::Error::new(io::ErrorKind::NotFound, format!("no session for client id {}", clid)))?;
+        match op {
+            Op::Subscriptions(b) => slot.subscriptions = b,
+            Op::InFlight(b) => slot.inflight = BytesMut::from(b.as_ref()),
+            Op::InFlightAppend(b) => slot.inflight.extend_from_slice(&b),
+            Op::Will(b) => slot.will = b,
+            Op::Offline(b) => slot.offline = BytesMut::from(b.as_ref()),
+            Op::OfflineAppend(b) => slot.offline.extend_from_slice(&b),
+            Op::Log(b) => {
+                slot.log.extend_from_slice(&b);
+                if slot.log.len() > COMPACT_SIZE {
+                    let wall = decode_wall(&mut slot.log.clone().freeze());
+                    if let Some(snapshot) = compact_wall(&wall) {
+                        slot.log = encode_wall(&snapshot);
+                    }
+                }
+            },
+            Op::Create(_) | Op::Remove => ()
+        }
+        Ok(())
+    }
+
+    pub(super) fn read<R>(&self, clid: &ClientID, f: impl FnOnce(&Slot) -> R) -> io::Result<R> {
+        let sessions = self.sessions.read().unwrap();
+        let slot = sessions.get(&clid.to_string())
+            .ok_or(io::Error::new(io::ErrorKind::NotFound, format!("no session for client id {}", clid)))?;
+        Ok(f(slot))
+    }
+}
+
+impl SessionStore for MemoryStore {
+    async fn create(&self, clid: &ClientID, mdata: &MetaData) -> io::Result<()> {
+        let mut buffer = BytesMut::with_capacity(mdata.est_len());
+        mdata.serialize(&mut buffer);
+        self.apply(&clid.to_string(), Op::Create(buffer.freeze()))
+    }
+
+    async fn metadata(&self, clid: &ClientID) -> io::Result<MetaData> {
+        decode_metadata(self.read(clid, |s| s.metadata.clone())?)
+    }
+
+    async fn set_subscriptions(&self, clid: &ClientID, subs: &[Subscribe]) -> io::Result<()> {
+        self.apply(&clid.to_string(), Op::Subscriptions(encode_subscriptions(subs)))
+    }
+
+    async fn subscriptions(&self, clid: &ClientID) -> io::Result<Vec<Subscribe>> {
+        decode_subscriptions(&self.read(clid, |s| s.subscriptions.clone())?)
+    }
+
+    async fn append_log(&self, clid: &ClientID, wall: &[WALL]) -> io::Result<()> {
+        self.apply(&clid.to_string(), Op::Log(encode_wall(wall).freeze()))
+    }
+
+    async fn session_log(&self, clid: &ClientID) -> io::Result<Vec<WALL>> {
+        let mut log = self.read(clid, |s| s.log.clone().freeze())?;
+        Ok(decode_wall(&mut log))
+    }
+
+    async fn set_inflight(&self, clid: &ClientID, inflight: &[InFlight]) -> io::Result<()> {
+        self.apply(&clid.to_string(), Op::InFlight(encode_inflight(inflight)))
+    }
+
+    async fn append_inflight(&self, clid: &ClientID, changes: &[InFlightChange]) -> io::Result<()> {
+        self.apply(&clid.to_string(), Op::InFlightAppend(encode_inflight_changes(changes)))
+    }
+
+    async fn inflight(&self, clid: &ClientID) -> io::Result<Vec<InFlight>> {
+        decode_inflight(self.read(clid, |s| s.inflight.clone().freeze())?)
+    }
+
+    async fn set_will(&self, clid: &ClientID, will: Option<&Will>) -> io::Result<()> {
+        self.apply(&clid.to_string(), Op::Will(encode_will(will)))
+    }
+
+    async fn will(&self, clid: &ClientID) -> io::Result<Option<Will>> {
+        decode_will(self.read(clid, |s| s.will.clone())?)
+    }
+
+    async fn append_offline(&self, clid: &ClientID, queued: &[Queued]) -> io::Result<()> {
+        self.apply(&clid.to_string(), Op::OfflineAppend(encode_offline(queued)))
+    }
+
+    async fn set_offline(&self, clid: &ClientID, queued: &[Queued]) -> io::Result<()> {
+        self.apply(&clid.to_string(), Op::Offline(encode_offline(queued)))
+    }
+
+    async fn offline(&self, clid: &ClientID) -> io::Result<Vec<Queued>> {
+        decode_offline(self.read(clid, |s| s.offline.clone().freeze())?)
+    }
+
+    async fn sessions(&self) -> io::Result<Vec<ClientID>> {
+        let sessions = self.sessions.read().unwrap();
+        Ok(sessions.keys()
+            .map(|clid| ClientID::new(clid.clone()))
+            .collect())
+    }
+
+    async fn remove(&self, clid: &ClientID) -> io::Result<()> {
+        self.apply(&clid.to_string(), Op::Remove)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use crate::{
+        message_broker::client::{
+            backend::SessionBackend, clobj::ClientID,
+            storage::{ClientStore, EventType, InFlight, MetaData, WALL}
+        },
+        protocol::v5::{subscribe::{Subscribe, SubscriptionOptions}, ServiceLevel}
+    };
+    use super::MemoryStore;
+
+    #[tokio::test]
+    async fn session_lifecycle() {
+        let backend = SessionBackend::Memory(MemoryStore::default());
+        let clid = ClientID::new("memory_test".to_string());
+        let mdata = MetaData {
+            protocol_level: 5,
+            keep_alive_interval: 60,
+            expr_interval: 120,
+            receive_maximum: 10,
+            maximum_packet_size: 0,
+            topic_alias_maximum: 0,
+            user_properties: Vec::new()
+        };
+
+        let store = ClientStore::new(&backend, &clid, &mdata).await.unwrap();
+        let subs = [
+            Subscribe { 
+                topic: "a/b".to_string(), 
+                max_qos: ServiceLevel::QoS1, 
+                options: SubscriptionOptions { no_local: true, ..Default::default() },
+                subscription_identifier: Some(7)
+            },
+            Subscribe { topic: "a/c".to_string(), max_qos: ServiceLevel::QoS0, options: SubscriptionOptions::default(), subscription_identifier: None },
+        ];
+        store.clone().subscribe(&subs).await.unwrap();
+        store.clone().unsubscribe(&["a/c".to_string()]).await.unwrap();
+
+        let inflight = [InFlight { packet_id: 7, state: 1, packet: Bytes::from_static(b"pkt") }];
+        store.clone().save_inflight(&inflight).await.unwrap();
+        assert_eq!(store.clone().inflight().await.unwrap(), inflight.to_vec());
+        assert!(store.clone().will().await.unwrap().is_none());
+
+        let until = u64::MAX;
+        store.log_session(&[WALL { time: 1, value: EventType::ClientDisconnected(until) }]).await.unwrap();
+        assert_eq!(ClientStore::disconnected(&backend, &clid).await.unwrap(), until);
+
+        let restored = ClientStore::restore(&backend, &clid).await.unwrap();
+        assert_eq!(restored.mdata, mdata);
+        assert_eq!(restored.subs.len(), 1);
+        assert_eq!(restored.subs[0].topic, "a/b");
+        assert!(restored.subs[0].options.no_local);
+        assert_eq!(restored.subs[0].subscription_identifier, Some(7));
+        assert!(ClientStore::disconnected(&backend, &clid).await.is_err());
+
+        restored.storage.remove().await.unwrap();
+        assert!(ClientStore::restore(&backend, &clid).await.is_err());
+    }
+}
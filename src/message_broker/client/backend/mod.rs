@@ -0,0 +1,220 @@
+use std::{path::PathBuf, str::FromStr};
+use tokio::io;
+use crate::protocol::v5::subscribe::Subscribe;
+use super::{clobj::ClientID, offline::Queued, storage::{InFlight, InFlightChange, MetaData, Will, WALL}, DATA_STORE};
+
+mod file;
+mod memory;
+mod embedded;
+
+pub use file::FileStore;
+pub use memory::MemoryStore;
+pub use embedded::EmbeddedStore;
+
+/// where session state is kept
+#[derive(Debug, Clone)]
+pub enum StorageConfig {
+    /// one directory for each client under the root
+    File(PathBuf),
+    /// gone when broker stopped, for test and ephemeral broker
+    Memory,
+    /// every session in single append only file
+    Embedded(PathBuf),
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self::File(PathBuf::from(DATA_STORE))
+    }
+}
+
+/// parse `memory`, `file[:<dir>]` or `embedded:<path>`
+impl FromStr for StorageConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "memory" => Ok(Self::Memory),
+            None if s == "file" => Ok(Self::default()),
+            Some(("file", dir)) => Ok(Self::File(PathBuf::from(dir))),
+            Some(("embedded", path)) => Ok(Self::Embedded(PathBuf::from(path))),
+            _ => Err(format!("unknown session storage {}", s))
+        }
+    }
+}
+
+/// Persistence of session state.
+///
+/// every method is keyed by client id, reading session
+/// that does not exist give `NotFound` error
+pub trait SessionStore {
+    /// start new session, everything from previous session is dropped
+    async fn create(&self, clid: &ClientID, mdata: &MetaData) -> io::Result<()>;
+    async fn metadata(&self, clid: &ClientID) -> io::Result<MetaData>;
+    /// replace whole subscription list
+    async fn set_subscriptions(&self, clid: &ClientID, subs: &[Subscribe]) -> io::Result<()>;
+    async fn subscriptions(&self, clid: &ClientID) -> io::Result<Vec<Subscribe>>;
+    async fn append_log(&self, clid: &ClientID, wall: &[WALL]) -> io::Result<()>;
+    async fn session_log(&self, clid: &ClientID) -> io::Result<Vec<WALL>>;
+    /// replace whole in-flight state
+    async fn set_inflight(&self, clid: &ClientID, inflight: &[InFlight]) -> io::Result<()>;
+    async fn append_inflight(&self, clid: &ClientID, changes: &[InFlightChange]) -> io::Result<()>;
+    async fn inflight(&self, clid: &ClientID) -> io::Result<Vec<InFlight>>;
+    async fn set_will(&self, clid: &ClientID, will: Option<&Will>) -> io::Result<()>;
+    async fn will(&self, clid: &ClientID) -> io::Result<Option<Will>>;
+    async fn append_offline(&self, clid: &ClientID, queued: &[Queued]) -> io::Result<()>;
+    /// replace whole offline queue, empty queue is removed
+    async fn set_offline(&self, clid: &ClientID, queued: &[Queued]) -> io::Result<()>;
+    async fn offline(&self, clid: &ClientID) -> io::Result<Vec<Queued>>;
+    /// client id of every stored session
+    async fn sessions(&self) -> io::Result<Vec<ClientID>>;
+    async fn remove(&self, clid: &ClientID) -> io::Result<()>;
+}
+
+#[derive(Debug, Clone)]
+pub enum SessionBackend {
+    File(FileStore),
+    Memory(MemoryStore),
+    Embedded(EmbeddedStore),
+}
+
+impl SessionBackend {
+    pub async fn open(config: &StorageConfig) -> io::Result<Self> {
+        let backend = match config {
+            StorageConfig::File(root) => Self::File(FileStore::open(root.clone()).await?),
+            StorageConfig::Memory => Self::Memory(MemoryStore::default()),
+            StorageConfig::Embedded(path) => Self::Embedded(EmbeddedStore::open(path.clone()).await?),
+        };
+        Ok(backend)
+    }
+}
+
+impl SessionStore for SessionBackend {
+    async fn create(&self, clid: &ClientID, mdata: &MetaData) -> io::Result<()> {
+        match self {
+            Self::File(s) => s.create(clid, mdata).await,
+            Self::Memory(s) => s.create(clid, mdata).await,
+            Self::Embedded(s) => s.create(clid, mdata).await,
+        }
+    }
+
+    async fn metadata(&self, clid: &ClientID) -> io::Result<MetaData> {
+        match self {
+            Self::File(s) => s.metadata(clid).await,
+            Self::Memory(s) => s.metadata(clid).await,
+            Self::Embedded(s) => s.metadata(clid).await,
+        }
+    }
+
+    async fn set_subscriptions(&self, clid: &ClientID, subs: &[Subscribe]) -> io::Result<()> {
+        match self {
+            Self::File(s) => s.set_subscriptions(clid, subs).await,
+            Self::Memory(s) => s.set_subscriptions(clid, subs).await,
+            Self::Embedded(s) => s.set_subscriptions(clid, subs).await,
+        }
+    }
+
+    async fn subscriptions(&self, clid: &ClientID) -> io::Result<Vec<Subscribe>> {
+        match self {
+            Self::File(s) => s.subscriptions(clid).await,
+            Self::Memory(s) => s.subscriptions(clid).await,
+            Self::Embedded(s) => s.subscriptions(clid).await,
+        }
+    }
+
+    async fn append_log(&self, clid: &ClientID, wall: &[WALL]) -> io::Result<()> {
+        match self {
+            Self::File(s) => s.append_log(clid, wall).await,
+            Self::Memory(s) => s.append_log(clid, wall).await,
+            Self::Embedded(s) => s.append_log(clid, wall).await,
+        }
+    }
+
+    async fn session_log(&self, clid: &ClientID) -> io::Result<Vec<WALL>> {
+        match self {
+            Self::File(s) => s.session_log(clid).await,
+            Self::Memory(s) => s.session_log(clid).await,
+            Self::Embedded(s) => s.session_log(clid).await,
+        }
+    }
+
+    async fn set_inflight(&self, clid: &ClientID, inflight: &[InFlight]) -> io::Result<()> {
+        match self {
+            Self::File(s) => s.set_inflight(clid, inflight).await,
+            Self::Memory(s) => s.set_inflight(clid, inflight).await,
+            Self::Embedded(s) => s.set_inflight(clid, inflight).await,
+        }
+    }
+
+    async fn append_inflight(&self, clid: &ClientID, changes: &[InFlightChange]) -> io::Result<()> {
+        match self {
+            Self::File(s) => s.append_inflight(clid, changes).await,
+            Self::Memory(s) => s.append_inflight(clid, changes).await,
+            Self::Embedded(s) => s.append_inflight(clid, changes).await,
+        }
+    }
+
+    async fn inflight(&self, clid: &ClientID) -> io::Result<Vec<InFlight>> {
+        match self {
+            Self::File(s) => s.inflight(clid).await,
+            Self::Memory(s) => s.inflight(clid).await,
+            Self::Embedded(s) => s.inflight(clid).await,
+        }
+    }
+
+    async fn set_will(&self, clid: &ClientID, will: Option<&Will>) -> io::Result<()> {
+        match self {
+            Self::File(s) => s.set_will(clid, will).await,
+            Self::Memory(s) => s.set_will(clid, will).await,
+            Self::Embedded(s) => s.set_will(clid, will).await,
+        }
+    }
+
+    async fn will(&self, clid: &ClientID) -> io::Result<Option<Will>> {
+        match self {
+            Self::File(s) => s.will(clid).await,
+            Self::Memory(s) => s.will(clid).await,
+            Self::Embedded(s) => s.will(clid).await,
+        }
+    }
+
+    async fn append_offline(&self, clid: &ClientID, queued: &[Queued]) -> io::Result<()> {
+        match self {
+            Self::File(s) => s.append_offline(clid, queued).await,
+            Self::Memory(s) => s.append_offline(clid, queued).await,
+            Self::Embedded(s) => s.append_offline(clid, queued).await,
+        }
+    }
+
+    async fn set_offline(&self, clid: &ClientID, queued: &[Queued]) -> io::Result<()> {
+        match self {
+            Self::File(s) => s.set_offline(clid, queued).await,
+            Self::Memory(s) => s.set_offline(clid, queued).await,
+            Self::Embedded(s) => s.set_offline(clid, queued).await,
+        }
+    }
+
+    async fn offline(&self, clid: &ClientID) -> io::Result<Vec<Queued>> {
+        match self {
+            Self::File(s) => s.offline(clid).await,
+            Self::Memory(s) => s.offline(clid).await,
+            Self::Embedded(s) => s.offline(clid).await,
+        }
+    }
+
+    async fn sessions(&self) -> io::Result<Vec<ClientID>> {
+        match self {
+            Self::File(s) => s.sessions().await,
+            Self::Memory(s) => s.sessions().await,
+            Self::Embedded(s) => s.sessions().await,
+        }
+    }
+
+    async fn remove(&self, clid: &ClientID) -> io::Result<()> {
+        match self {
+            Self::File(s) => s.remove(clid).await,
+            Self::Memory(s) => s.remove(clid).await,
+            Self::Embedded(s) => s.remove(clid).await,
+        }
+    }
+}
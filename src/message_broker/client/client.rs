@@ -1,32 +1,42 @@
-use std::io;
-use tokio::net::TcpStream;
+use std::{io, sync::Mutex as StdMutex};
+use tokio::sync::Mutex;
 use crate::{
     connection::{
-        line::{SecuredStream, SocketConnection}, 
+        line::SocketConnection, 
         ConnectionID
     }, 
-    helper::time::sys_now
+    protocol::{mqtt::Protocol, v5::{publish::PublishFrame, subscribe::Subscribe}}
 };
 use super::{
+    alias::OutboundAlias,
     clobj::{
+        split_socket,
         ClientID, 
+        ClientReader,
+        ClientSender,
         Limiter, 
-        Session, 
-        Socket
+        Session
     }, storage::{
         ClientStore, 
         MetaData
     }, 
+    backend::SessionBackend,
     SessionController
 };
 
+/// Connected client, shared by listener task and every sender.
+/// 
+/// each part that can change is behind its own lock,
+/// so reading the connection does not block publishing to it
 #[allow(dead_code)]
 pub struct Client {
     pub(super) conid: ConnectionID,
     pub clid: ClientID,
-    pub socket: Socket<SecuredStream, TcpStream>,
-    protocol_level: u8,
-    session: Session,
+    pub reader: Mutex<ClientReader>,
+    pub sender: ClientSender,
+    pub protocol: Protocol,
+    session: StdMutex<Session>,
+    aliases: StdMutex<OutboundAlias>,
     pub limit: Limiter,
     pub storage: ClientStore
 }
@@ -34,106 +44,134 @@ pub struct Client {
 pub struct UpdateClient {
     pub conid: Option<ConnectionID>,
     pub socket: Option<SocketConnection>,
+    /// protocol of the new connection, may differ from the stored session
+    pub protocol: Protocol,
+}
+
+impl UpdateClient {
+    /// connection is taken all at once or left as it is
+    fn take(&mut self) -> io::Result<(SocketConnection, ConnectionID)> {
+        match (self.socket.take(), self.conid.take()) {
+            (Some(socket), Some(conid)) => Ok((socket, conid)),
+            (socket, conid) => {
+                self.socket = socket;
+                self.conid = conid;
+                Err(io::Error::new(io::ErrorKind::NotConnected, "connection is already taken"))
+            }
+        }
+    }
 }
 
 // keepalive min value: 60
 impl Client {
+    /// connection is taken from `bucket` only when the session is stored,
+    /// so the caller can still refuse it
     pub async fn new(
-        conid: ConnectionID,
-        socket: SocketConnection,
+        bucket: &mut UpdateClient,
         clid: ClientID,
-        keep_alive: u16,
-        expr_interval: u32,
-        protocol_level: u8,
-        limit: Limiter
-    ) -> Self {
-        let socket = Socket::new(socket);
-        let ttl = sys_now() + (keep_alive + keep_alive/2) as u64;
-        let keep_alive = keep_alive.max(60);
+        session: Session,
+        limit: Limiter,
+        store: &SessionBackend
+    ) -> io::Result<Self> {
+        let protocol = bucket.protocol;
         let mdata = MetaData {
-            expr_interval,
-            keep_alive_interval: keep_alive,
-            protocol_level,
+            expr_interval: session.expr_interval,
+            keep_alive_interval: session.keep_alive,
+            protocol_level: protocol.level(),
             maximum_packet_size: limit.maximum_packet_size.unwrap_or_default(),
             receive_maximum: limit.receive_maximum.unwrap_or_default(),
             topic_alias_maximum: limit.topic_alias_maximum.unwrap_or_default(),
             user_properties: Vec::new()
         };
 
-        let session =  Session {
-            expr_interval,
-            keep_alive,
-            ttl
-        };
-
-        let storage = ClientStore::new(&clid, &mdata).await.unwrap();
-        Self {
+        let storage = ClientStore::new(store, &clid, &mdata).await?;
+        let (socket, conid) = bucket.take()?;
+        let (reader, writer) = split_socket(socket);
+        Ok(Self {
             conid,
-            socket,
+            reader: Mutex::new(reader),
+            sender: ClientSender::spawn(writer, limit.outbound, protocol),
             clid,
-            session,
+            session: StdMutex::new(session),
+            aliases: StdMutex::new(OutboundAlias::new(limit.topic_alias_maximum.unwrap_or_default())),
             limit,
-            protocol_level,
+            protocol,
             storage
-        }
+        })
     }
 
-    pub async fn restore(clid: ClientID, bucket: &mut UpdateClient) -> io::Result<Self> {
-        let restored = ClientStore::restore(&clid).await?;
+    /// restore client from storage, it is held until the offline queue is replayed.
+    /// session and limit is the one of the new CONNECT,
+    /// persisted subscription is given back to be registered on router
+    pub async fn restore(
+        store: &SessionBackend, 
+        clid: ClientID, 
+        bucket: &mut UpdateClient, 
+        session: Session,
+        limit: Limiter
+    ) -> io::Result<(Self, Vec<Subscribe>)> {
+        let restored = ClientStore::restore(store, &clid).await?;
         println!("[Client] {} restored", clid);
-        let keep_alive = restored.mdata.keep_alive_interval;
-        let socket = Socket::new(bucket.socket.take().unwrap());
+        let (socket, conid) = bucket.take()?;
+        let (reader, writer) = split_socket(socket);
         
-        Ok(Self {
+        let client = Self {
             storage: restored.storage,
             clid,
-            conid: bucket.conid.take().unwrap(),
-            limit: Limiter { 
-                receive_maximum: to_opt(restored.mdata.receive_maximum), 
-                maximum_packet_size: to_opt(restored.mdata.maximum_packet_size), 
-                topic_alias_maximum: to_opt(restored.mdata.receive_maximum) 
-            },
-            protocol_level: restored.mdata.protocol_level,
-            session: Session { 
-                ttl: sys_now() + (keep_alive + keep_alive/2) as u64, 
-                keep_alive, 
-                expr_interval: restored.mdata.expr_interval 
-            },
-            socket
-        })
+            conid,
+            protocol: bucket.protocol,
+            session: StdMutex::new(session),
+            aliases: StdMutex::new(OutboundAlias::new(limit.topic_alias_maximum.unwrap_or_default())),
+            reader: Mutex::new(reader),
+            sender: ClientSender::spawn(writer, limit.outbound, bucket.protocol),
+            limit
+        };
+        client.sender.set_held(true);
+        Ok((client, restored.subs))
     }
-}
 
-fn to_opt<T: Eq + Default>(val: T) -> Option<T> {
-    if val == T::default() {
-        None
-    } else {
-        Some(val)
+    /// replace topic with alias when the topic is hot enough,
+    /// frame is sent as is when it can not be aliased
+    pub fn apply_alias(&self, frame: PublishFrame) -> PublishFrame {
+        let topic = match frame.topic() {
+            Ok(topic) if !topic.is_empty() => topic,
+            _ => return frame
+        };
+
+        let alias = self.aliases.lock().unwrap().alias(topic);
+        let aliased = alias.and_then(|(alias, with_topic)| {
+            frame.with_topic_alias(alias, with_topic).ok()
+        });
+        match aliased {
+            Some(aliased) if self.limit.fits(aliased.len()) => aliased,
+            _ => frame
+        }
     }
 }
 
-impl SessionController for Client {
-    fn is_alive(&self, t: u64) -> bool {
-        self.session.is_alive(t)
+/// same as [`SessionController`], session is locked only for the call
+impl Client {
+    pub fn is_alive(&self, t: u64) -> bool {
+        self.session.lock().unwrap().is_alive(t)
     }
 
-    fn is_expired(&self, t: u64) -> bool {
-        self.session.is_expired(t)
+    pub fn is_expired(&self, t: u64) -> bool {
+        self.session.lock().unwrap().is_expired(t)
     }
 
-    fn expiration_time(&self) -> u64 {
-        self.session.expiration_time()
+    pub fn expiration_time(&self) -> u64 {
+        self.session.lock().unwrap().expiration_time()
     }
 
-    fn keep_alive(&mut self, t: u64) -> Result<u64, String> {
-        self.session.keep_alive(t)
+    pub fn keep_alive(&self, t: u64) -> Result<u64, String> {
+        self.session.lock().unwrap().keep_alive(t)
     }
 
-    fn ttl(&self) -> u64 {
-        self.session.ttl
+    pub fn ttl(&self) -> u64 {
+        self.session.lock().unwrap().ttl()
     }
 
-    fn kill(&mut self) {
-        self.session.kill()
+    pub fn kill(&self) {
+        self.session.lock().unwrap().kill()
     }
-}
\ No newline at end of file
+}
@@ -1,196 +1,580 @@
-use std::sync::{atomic::{AtomicPtr, Ordering}, Arc};
-use tokio::{io, sync::RwLock};
-use crate::{connection::SocketWriter, helper::time::sys_now, message_broker::{cleanup::Cleanup, client::storage::{EventType, WALL}, Forwarder, SendStrategy}};
-use crate::protocol::v5::puback::{PubACKType, PubackPacket};
-use super::{client::Client, clobj::ClientID, SessionController};
+use std::{collections::HashMap, sync::{Arc, RwLock}};
+use bytes::Bytes;
+use tokio::io;
+use crate::{error::BrokerError, helper::time::sys_now, message_broker::{acl::Acl, cleanup::Cleanup, client::storage::{ClientStore, EventType, WALL}, message::ExpiredCounter, msg_state::{MessageCoordinator, MsgState}, retained::RetainedStore, Forwarder, SendStrategy}};
+use crate::protocol::{mqtt::Protocol, v5::{disconnect::{DisconnectPacket, QUOTA_EXCEEDED}, publish::PublishFrame, subscribe::Subscribe, ServiceLevel}};
+use super::{backend::SessionBackend, client::Client, clobj::{ClientID, QueueDepth, SlowConsumer}, offline::{OfflineLimit, OfflineQueue, QueueGuard, Queued}};
 
-pub type AtomicClient = Arc<AtomicPtr<Client>>;
-type MutexClients = RwLock<Vec<AtomicClient>>;
+/// number of registry shard, client is placed by hash of its id
+const SHARDS: usize = 16;
+
+type Shard = RwLock<HashMap<ClientID, Arc<Client>>>;
 
 pub struct Clients{
-    list: Arc<MutexClients>,
+    shards: Arc<[Shard]>,
+    offline: Arc<OfflineLimit>,
+    queue: OfflineQueue,
+    store: SessionBackend,
+    coordinator: MessageCoordinator,
+    expired: ExpiredCounter,
+    retained: RetainedStore,
 }
 
-impl<'lc, 'st> Clients {
-    pub async fn new() -> Self {
+impl Clients {
+    pub async fn new(
+        offline: OfflineLimit, 
+        store: SessionBackend, 
+        coordinator: MessageCoordinator, 
+        expired: ExpiredCounter, 
+        retained: RetainedStore
+    ) -> Self {
         Self{
-            list: Arc::new(RwLock::new(Vec::new())),
-        }
-    }
-
-    /// insert sort by conn number
-    pub async fn insert(&self, new_cl: Client) -> Result<(), String> {
-        let new_clid = new_cl.clid.clone();
-        let new_cl = Box::new(new_cl);
-        
-        let mut clients = self.list.write().await;
-        let mut found = clients.len();
-        let mut swap = false;
-        let mut i = 0;
-        'insert: while i < clients.len() {
-            let clid = unsafe {&(*clients[i].load(Ordering::Relaxed)).clid};
-            match new_clid.cmp(clid) {
-                std::cmp::Ordering::Equal => {
-                    let is_available = unsafe {(*clients[i].load(Ordering::Relaxed)).is_alive(sys_now())};
-                    if is_available {
-                        return Err("duplicate client id".to_string())
-                    }
-                    swap = true;
-                },
-                std::cmp::Ordering::Greater => {
-                    i += 1;
-                    continue 'insert
-                },
-                std::cmp::Ordering::Less => ()
+            shards: (0..SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
+            offline: Arc::new(offline),
+            queue: OfflineQueue::new(store.clone()),
+            store,
+            coordinator,
+            expired,
+            retained,
+        }
+    }
+
+    /// send retained messages matching new subscription
+    pub async fn deliver_retained(&self, clid: &ClientID, sub: &Subscribe) -> io::Result<()> {
+        for (qos, frame) in self.retained.frames(&sub.topic, &sub.max_qos, sys_now()) {
+            if !frame.topic().is_ok_and(|topic| Acl::can_receive(clid, topic)) {
+                continue;
             }
-            
-            found = i;
-            break 'insert;
+
+            let frame = match sub.subscription_identifier {
+                Some(id) => frame.with_subscription_identifiers(&[id])
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+                None => frame
+            };
+            self.deliver(clid, &qos, &frame).await?;
         }
+        Ok(())
+    }
 
-        let p = Box::into_raw(new_cl);
-        if swap {
-            let odl_cl = clients[i].swap(p, Ordering::AcqRel);
-            drop(clients);
-            unsafe{drop(Box::from_raw(odl_cl))}
-        } else {
-            let new_cl = AtomicPtr::new(p);
-            let new_cl = Arc::new(new_cl);
-            clients.insert(found, new_cl);
+    fn shard(&self, clid: &ClientID) -> &Shard {
+        &self.shards[clid.shard(self.shards.len())]
+    }
+
+    /// register client, replace session with the same id
+    /// only when it is no longer alive
+    pub async fn insert(&self, new_cl: Client) -> Result<Arc<Client>, BrokerError> {
+        // queue size of the session is read again on first access
+        self.queue.lock(&new_cl.clid).await.forget();
+        let mut shard = self.shard(&new_cl.clid).write().unwrap();
+        if let Some(old) = shard.get(&new_cl.clid) {
+            if old.is_alive(sys_now()) {
+                return Err(BrokerError::ServerBusy)
+            }
         }
 
-        Ok(())
+        let new_cl = Arc::new(new_cl);
+        shard.insert(new_cl.clid.clone(), new_cl.clone());
+        Ok(new_cl)
     }
 
-    /// binary search by client id
-    /// in action read guard from vector
-    /// give you access to mutable reference on client
-    pub async fn search_mut_client<R>(&self, clid: &ClientID, f: impl FnOnce(&'lc mut Client) -> R) -> Option<R> {
-        let clients = self.list.read().await;
+    pub async fn get(&self, clid: &ClientID) -> Option<Arc<Client>> {
+        let shard = self.shard(clid).read().unwrap();
+        shard.get(clid).cloned()
+    }
 
-        let idx = clients.binary_search_by(|c| unsafe {
-            (*c.load(Ordering::Relaxed)).clid.cmp(&clid)
-        }).ok()?;
-        
-        let cl = unsafe {&mut (*clients[idx].load(Ordering::Relaxed))};
-        Some(f(cl))
+    /// packet waiting to be written to the client
+    pub async fn outbound_depth(&self, clid: &ClientID) -> Option<QueueDepth> {
+        self.get(clid).await.map(|c| c.sender.depth())
     }
 
-    pub async unsafe fn get_client(&self, clid: &ClientID) -> Option<AtomicClient> {
-        let clients = self.list.read().await;
-        let idx = clients.binary_search_by(|c| {
-            (*c.load(Ordering::Relaxed)).clid.cmp(&clid)
-        }).ok()?;
+    /// number of client whose connection is still alive at `t`
+    pub async fn connected(&self, t: u64) -> usize {
+        self.shards.iter()
+            .map(|shard| shard.read().unwrap()
+                .values()
+                .filter(|c| c.is_alive(t))
+                .count())
+            .sum()
+    }
 
-        Some(clients[idx].clone())
+    /// client id of session that is already expired but still kept
+    pub async fn expired(&self, t: u64) -> Vec<ClientID> {
+        let mut expired = Vec::new();
+        for shard in self.shards.iter() {
+            let shard = shard.read().unwrap();
+            expired.extend(shard.values()
+                .filter(|c| c.is_expired(t))
+                .map(|c| c.clid.clone()));
+        }
+        expired
+    }
+
+    /// remove client when the session is expired at `t`
+    pub async fn evict(&self, clid: &ClientID, t: u64) -> Option<ClientStore> {
+        let cl = {
+            let mut shard = self.shard(clid).write().unwrap();
+            if !shard.get(clid)?.is_expired(t) {
+                return None;
+            }
+            shard.remove(clid)?
+        };
+
+        self.coordinator.forget(clid);
+        self.queue.lock(clid).await.forget();
+        Some(cl.storage.clone())
     }
 }
 
 impl SendStrategy for Clients
 {
-    async fn qos0(&self, subscriber: &ClientID, buffer: &[u8]) {
-        let _ = self.pubish(subscriber, buffer).await;
-    }
-
-    async fn qos1(
-        &self,
-        publisher: &ClientID,
-        subscriber: &ClientID,
-        packet_id: u16,
-        buffer: &[u8]
-    ) -> std::io::Result<()> {
-        self.pubish(subscriber, buffer).await?;
-        let puback = PubackPacket {
-            packet_id,
-            packet_type: PubACKType::PubAck,
-            properties: None,
-            reason_code: 0
+    async fn qos0(&self, subscriber: &ClientID, frame: &PublishFrame) {
+        let _ = self.deliver(subscriber, &ServiceLevel::QoS0, frame).await;
+    }
+
+    async fn qos1(&self, subscriber: &ClientID, frame: &PublishFrame) -> std::io::Result<()> {
+        self.deliver(subscriber, &ServiceLevel::QoS1, frame).await
+    }
+
+    async fn qos2(&self, subscriber: &ClientID, frame: &PublishFrame) -> std::io::Result<()> {
+        self.deliver(subscriber, &ServiceLevel::QoS2, frame).await
+    }
+}
+
+impl Forwarder for Clients {
+    async fn pubish(&self, con_id: &ClientID, packet: Bytes) -> io::Result<()> {
+        let client = self.get(con_id).await.ok_or(io::Error::new(
+            io::ErrorKind::NotFound, 
+            format!("client {} not found", con_id)
+        ))?;
+
+        if !client.is_alive(sys_now()) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected, 
+                format!("client {} not connected", con_id)
+            ));
+        }
+
+        client.sender.send(packet)
+    }
+
+    async fn deliver(&self, clid: &ClientID, qos: &ServiceLevel, frame: &PublishFrame) -> io::Result<()> {
+        let t = sys_now();
+        let err = match self.get(clid).await {
+            Some(client) if client.is_alive(t) => return self.deliver_connected(&client, qos, frame).await,
+            Some(_) => io::Error::new(
+                io::ErrorKind::NotConnected, 
+                format!("client {} not connected", clid)
+            ),
+            None => io::Error::new(
+                io::ErrorKind::NotFound, 
+                format!("client {} not found", clid)
+            )
+        };
+        self.keep_offline(clid, qos, frame, err).await
+    }
+}
+
+impl Clients {
+    /// queue to writer of connected client,
+    /// slow consumer policy is applied when the queue is full
+    async fn deliver_connected(&self, client: &Client, qos: &ServiceLevel, frame: &PublishFrame) -> io::Result<()> {
+        if !client.limit.fits(frame.len()) {
+            println!("[outbound] {} packet of {} byte is too large, dropped", client.clid, frame.len());
+            return Ok(());
+        }
+
+        if client.sender.is_held() {
+            let mut queue = self.queue.lock(&client.clid).await;
+            // replay is not done yet, message must wait behind the queued one
+            if client.sender.is_held() {
+                if !queue.push(Queued::new(qos, frame), &self.spill_limit()).await? {
+                    println!("[offline] {} message dropped", client.clid);
+                }
+                return Ok(());
+            }
+        }
+
+        if client.sender.is_spilled() {
+            self.unspill(client).await?;
+        }
+
+        // message wait behind the spilled one, or until the client acknowledge in-flight publish
+        if client.sender.is_spilled() || self.reach_receive_maximum(client, qos).await {
+            return self.spill(client, qos, frame).await;
+        }
+
+        if client.sender.has_room(frame.len()) {
+            return self.send_connected(client, qos, frame).await;
+        }
+
+        let depth = client.sender.depth();
+        match client.sender.policy() {
+            SlowConsumer::DropQoS0 if *qos == ServiceLevel::QoS0 => {
+                println!("[outbound] {} full at {} message, QoS 0 dropped", client.clid, depth.messages);
+                Ok(())
+            },
+            // QoS 1 and 2 is still queued until the hard bound
+            SlowConsumer::DropQoS0 if !client.sender.is_saturated() => self.send_connected(client, qos, frame).await,
+            SlowConsumer::DropQoS0 | SlowConsumer::Disconnect => {
+                println!("[outbound] {} full at {} message, disconnected", client.clid, depth.messages);
+                client.kill();
+                client.sender.disconnect(DisconnectPacket::new(QUOTA_EXCEEDED))?;
+                let err = io::Error::new(
+                    io::ErrorKind::NotConnected, 
+                    format!("client {} is slow consumer", client.clid)
+                );
+                self.keep_offline(&client.clid, qos, frame, err).await
+            },
+            SlowConsumer::Spill => self.spill(client, qos, frame).await
+        }
+    }
+
+    /// keep message on disk until the writer catch up
+    async fn spill(&self, client: &Client, qos: &ServiceLevel, frame: &PublishFrame) -> io::Result<()> {
+        let queued = Queued::new(qos, frame);
+        client.sender.set_spilled(true);
+        let mut queue = self.queue.lock(&client.clid).await;
+        if !queue.push(queued, &self.spill_limit()).await? {
+            println!("[outbound] {} spilled message dropped", client.clid);
+        }
+        Ok(())
+    }
+
+    /// client already has as many QoS 1 and 2 publish in-flight as its receive maximum
+    async fn reach_receive_maximum(&self, client: &Client, qos: &ServiceLevel) -> bool {
+        if *qos == ServiceLevel::QoS0 {
+            return false;
+        }
+        let max = client.limit.receive_maximum.unwrap_or(u16::MAX);
+        self.coordinator.outgoing_count(&client.clid).await >= max as usize
+    }
+
+    /// in-flight publish is acknowledged, message spilled while
+    /// the client was at its receive maximum is sent
+    pub async fn release(&self, client: &Client) -> io::Result<()> {
+        if !client.sender.is_spilled() {
+            return Ok(());
+        }
+        self.unspill(client).await
+    }
+
+    /// assign in-flight identifier for QoS 1 and 2 then queue to the writer
+    async fn send_connected(&self, client: &Client, qos: &ServiceLevel, frame: &PublishFrame) -> io::Result<()> {
+        // forwarded copy carry the remaining expiry interval
+        let t = sys_now();
+        let encoded;
+        let frame = match (frame.expire_at, client.protocol) {
+            (Some(at), _) if at <= t => {
+                self.expired.undelivered();
+                return Ok(());
+            },
+            (_, Protocol::V311) => {
+                encoded = client.protocol.publish(frame)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                &encoded
+            },
+            (Some(at), Protocol::V5) => {
+                encoded = frame.with_message_expiry((at - t) as u32)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                &encoded
+            },
+            (None, Protocol::V5) => frame
         };
 
-        let buffer = puback.encode().unwrap();
-        self.pubish(publisher, &buffer).await
-    }
-
-    async fn qos2(
-        &self, 
-        publisher: &ClientID,
-        subscriber: &ClientID,
-        packet_id: u16,
-        buffer: &[u8]
-    ) -> std::io::Result<()> {
-        let msg_buffer = buffer;
-
-        // Pub Rec
-        let mut ack = PubackPacket {
-            packet_id,
-            packet_type: PubACKType::PubRec,
-            properties: None,
-            reason_code: 0x00
+        let state = match qos {
+            ServiceLevel::QoS0 => None,
+            ServiceLevel::QoS1 => Some(MsgState::AwaitAck),
+            ServiceLevel::QoS2 => Some(MsgState::AwaitRec),
         };
-        let buffer = ack.encode().unwrap();
-        self.pubish(&publisher, &buffer).await?;
-
-        // Wait Pub Rel
-        // ...
-        println!("TODO: wait pubrel");
-
-        // Publish Message
-        self.pubish(&subscriber, &msg_buffer).await?;
-        
-        // Pub Comp
-        ack.packet_type = PubACKType::PubRel;
-        let buffer = ack.encode().unwrap();
-        self.pubish(&publisher, &buffer).await?;
+
+        let state = state.filter(|_| self.coordinator.is_tracked(&client.clid));
+        let state = match state {
+            Some(state) => state,
+            None => return client.sender.send_frame(client.apply_alias(frame.clone()))
+        };
+
+        // stored packet keep the topic, alias is only valid on this connection
+        let frame = self.coordinator.outgoing(&client.clid, state, frame).await?;
+        // packet is in-flight, resent when session is resumed
+        if let Err(err) = client.sender.send_frame(client.apply_alias(frame)) {
+            println!("[Client] {} in-flight kept: {}", client.clid, err);
+        }
         Ok(())
     }
-}
 
-impl Forwarder for Clients {
-    async fn pubish(&self, con_id: &ClientID, packet: &[u8]) -> io::Result<()> {
-        let found = self.search_mut_client(&con_id, |client| {
-            client.socket.write_all(packet)
-        }).await;
-    
-        let res = match found {
-            None => return Err(
-                io::Error::new(
-                    io::ErrorKind::NotFound, 
-                    format!("client {} not found", con_id)
-                )),
-            Some(fut) => fut.await
+    /// move spilled message back to the writer while there is room
+    async fn unspill(&self, client: &Client) -> io::Result<()> {
+        if !client.sender.has_room(0) {
+            return Ok(());
+        }
+
+        // message spilled meanwhile wait behind the drained one
+        let mut queue = self.queue.lock(&client.clid).await;
+        let mut spilled = queue.drain().await?.into_iter();
+        while let Some(msg) = spilled.next() {
+            if !client.sender.has_room(msg.packet.len()) || self.reach_receive_maximum(client, &msg.qos).await {
+                let limit = self.spill_limit();
+                queue.push(msg, &limit).await?;
+                for rest in spilled {
+                    queue.push(rest, &limit).await?;
+                }
+                return Ok(());
+            }
+            self.send_connected(client, &msg.qos, &msg.frame()).await?;
+        }
+
+        client.sender.set_spilled(false);
+        Ok(())
+    }
+
+    /// spilled message belong to connected client, so QoS 0 is kept too
+    fn spill_limit(&self) -> OfflineLimit {
+        OfflineLimit {
+            include_qos0: true,
+            ..self.offline.as_ref().clone()
+        }
+    }
+
+    /// keep message of persisted session on offline queue,
+    /// `err` is given back when the session is not persisted
+    async fn keep_offline(&self, clid: &ClientID, qos: &ServiceLevel, frame: &PublishFrame, err: io::Error) -> io::Result<()> {
+        let t = sys_now();
+        let persisted = self.get(clid).await.map(|c| !c.is_expired(t));
+        let persisted = match persisted {
+            Some(persisted) => persisted,
+            None => ClientStore::disconnected(&self.store, clid).await.is_ok()
         };
-    
-        res
+
+        let mut queue = self.queue.lock(clid).await;
+        if !persisted {
+            queue.remove().await?;
+            return Err(err);
+        }
+
+        // session is resumed and replayed while waiting for the lock
+        let resumed = self.get(clid)
+            .await
+            .filter(|c| c.is_alive(t) && !c.sender.is_held() && !c.sender.is_spilled());
+        if let Some(client) = resumed {
+            drop(queue);
+            if self.reach_receive_maximum(&client, qos).await {
+                return self.spill(&client, qos, frame).await;
+            }
+            return self.send_connected(&client, qos, frame).await;
+        }
+
+        let queued = Queued::new(qos, frame);
+
+        if !queue.push(queued, &self.offline).await? {
+            println!("[offline] {} message dropped", clid);
+        }
+        Ok(())
+    }
+}
+
+impl Clients {
+    /// send message queued while session was disconnected, in order,
+    /// then release the client so live message is sent directly.
+    /// queue is locked until every message is sent, so message kept meanwhile
+    /// wait behind it. message that is failed to send kept for the next session
+    pub async fn replay_offline(&self, clid: &ClientID) -> io::Result<usize> {
+        let client = self.get(clid).await.ok_or(io::Error::new(
+            io::ErrorKind::NotFound, 
+            format!("client {} not found", clid)
+        ))?;
+
+        let mut queue = self.queue.lock(clid).await;
+        let replayed = self.replay(&client, &mut queue).await;
+        client.sender.set_held(false);
+        replayed
+    }
+
+    async fn replay(&self, client: &Client, queue: &mut QueueGuard<'_>) -> io::Result<usize> {
+        let clid = &client.clid;
+        let mut queued = queue.drain().await?.into_iter();
+        let mut sent = 0;
+        while let Some(msg) = queued.next() {
+            if !client.limit.fits(msg.packet.len()) {
+                println!("[outbound] {} packet of {} byte is too large, dropped", clid, msg.packet.len());
+                continue;
+            }
+
+            // rest is sent once the client acknowledge in-flight publish
+            if self.reach_receive_maximum(client, &msg.qos).await {
+                client.sender.set_spilled(true);
+                let limit = self.spill_limit();
+                queue.push(msg, &limit).await?;
+                for rest in queued {
+                    queue.push(rest, &limit).await?;
+                }
+                return Ok(sent);
+            }
+
+            if let Err(err) = self.send_connected(client, &msg.qos, &msg.frame()).await {
+                queue.push(msg, &self.offline).await?;
+                for rest in queued {
+                    queue.push(rest, &self.offline).await?;
+                }
+                return Err(err);
+            }
+            sent += 1;
+        }
+        Ok(sent)
     }
 }
 
 impl Clone for Clients {
     fn clone(&self) -> Self {
-        Self { list: Arc::clone(&self.list) }
+        Self { 
+            shards: Arc::clone(&self.shards),
+            offline: Arc::clone(&self.offline),
+            queue: self.queue.clone(),
+            store: self.store.clone(),
+            coordinator: self.coordinator.clone(),
+            expired: self.expired.clone(),
+            retained: self.retained.clone()
+        }
     }
 }
 
 impl Cleanup for Clients {
     async fn clear(self) {
-        let mut clients = self.list.write().await;
-        if clients.is_empty() {
-            return ;
+        let mut taken = Vec::new();
+        for shard in self.shards.iter() {
+            let mut shard = shard.write().unwrap();
+            taken.extend(shard.drain().map(|(_, cl)| cl));
         }
 
-        for _ in 0..clients.len() {
-            let opc = clients.pop();
-            let _cl = match opc {
-                Some(cl) => cl,
-                None => continue
-            };
-            let _take_cl = unsafe {Box::from_raw(_cl.load(Ordering::Acquire))};
-            let expired_at = _take_cl.expiration_time();
+        for cl in taken {
+            let expired_at = cl.expiration_time();
             let _res = 
-            _take_cl.storage.log_session(&[WALL{
+            cl.storage.clone().log_session(&[WALL{
                     time: sys_now(), 
                     value: EventType::DisconnectByServer(expired_at)}
             ]).await;
-            println!("[Cleanup] {}", _take_cl.clid);
+            println!("[Cleanup] {}", cl.clid);
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use tokio::{io::AsyncReadExt, net::{TcpListener, TcpStream}};
+    use crate::{
+        connection::{line::SocketConnection, ConnectionID},
+        message_broker::{
+            client::{backend::{MemoryStore, SessionBackend}, client::{Client, UpdateClient}, clobj::{ClientID, Limiter, Session}, offline::{OfflineLimit, Queued}},
+            message::ExpiredCounter, msg_state::MessageCoordinator, retained::RetainedStore, Forwarder
+        },
+        protocol::{mqtt::Protocol, v5::{puback::{PubACKType, PubackPacket}, publish::{PublishFrame, PublishPacket}, ServiceLevel}}
+    };
+    use super::Clients;
+
+    fn frame(payload: &'static [u8]) -> PublishFrame {
+        let packet = PublishPacket { topic: "a/b".to_string(), payload: Bytes::from_static(payload), ..Default::default() };
+        packet.encode_frame().unwrap()
+    }
+
+    #[tokio::test]
+    async fn offline_replayed_before_live() {
+        let store = SessionBackend::Memory(MemoryStore::default());
+        let clients = Clients::new(
+            OfflineLimit::default(),
+            store.clone(),
+            MessageCoordinator::new(),
+            ExpiredCounter::default(),
+            RetainedStore::default()
+        ).await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = tokio::spawn(async move { listener.accept().await.unwrap().0 });
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut peer = peer.await.unwrap();
+
+        let clid = ClientID::new("held".to_string());
+        let mut bucket = UpdateClient {
+            conid: Some(ConnectionID(1)),
+            socket: Some(SocketConnection::Plain(stream)),
+            protocol: Protocol::V5
+        };
+        let client = Client::new(&mut bucket, clid.clone(), Session::new(60, 300), Limiter::default(), &store)
+            .await
+            .unwrap();
+        // resumed session, queued while it was disconnected
+        client.sender.set_held(true);
+        let queued = Queued::new(&ServiceLevel::QoS0, &frame(b"old"));
+        clients.queue.lock(&clid).await.push(queued, &clients.spill_limit()).await.unwrap();
+        clients.insert(client).await.unwrap();
+
+        clients.deliver(&clid, &ServiceLevel::QoS0, &frame(b"live")).await.unwrap();
+        assert_eq!(clients.replay_offline(&clid).await.unwrap(), 2);
+        clients.deliver(&clid, &ServiceLevel::QoS0, &frame(b"after")).await.unwrap();
+
+        let len = [b"old".as_slice(), b"live", b"after"].iter().map(|p| frame(p).len()).sum();
+        let mut received = vec![0u8; len];
+        peer.read_exact(&mut received).await.unwrap();
+        let at = |payload: &[u8]| received.windows(payload.len()).position(|w| w == payload).unwrap();
+        assert!(at(b"old") < at(b"live"));
+        assert!(at(b"live") < at(b"after"));
+    }
+
+    #[tokio::test]
+    async fn held_at_receive_maximum() {
+        let store = SessionBackend::Memory(MemoryStore::default());
+        let clients = Clients::new(
+            OfflineLimit::default(),
+            store.clone(),
+            MessageCoordinator::new(),
+            ExpiredCounter::default(),
+            RetainedStore::default()
+        ).await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = tokio::spawn(async move { listener.accept().await.unwrap().0 });
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut peer = peer.await.unwrap();
+
+        let clid = ClientID::new("receive_max".to_string());
+        let mut bucket = UpdateClient {
+            conid: Some(ConnectionID(2)),
+            socket: Some(SocketConnection::Plain(stream)),
+            protocol: Protocol::V5
+        };
+        let limit = Limiter::new(Some(1), None, None);
+        let client = Client::new(&mut bucket, clid.clone(), Session::new(60, 300), limit, &store)
+            .await
+            .unwrap();
+        clients.coordinator.load(&clid, client.storage.clone()).await.unwrap();
+        let client = clients.insert(client).await.unwrap();
+
+        let qos1 = |payload: &'static [u8]| PublishPacket {
+            qos: ServiceLevel::QoS1,
+            topic: "a/b".to_string(),
+            packet_id: Some(1),
+            payload: Bytes::from_static(payload),
+            ..Default::default()
+        }.encode_frame().unwrap();
+        clients.deliver(&clid, &ServiceLevel::QoS1, &qos1(b"one")).await.unwrap();
+        clients.deliver(&clid, &ServiceLevel::QoS1, &qos1(b"two")).await.unwrap();
+        // QoS 0 keep its order behind the held one
+        clients.deliver(&clid, &ServiceLevel::QoS0, &frame(b"three")).await.unwrap();
+        assert!(client.sender.is_spilled());
+        assert_eq!(clients.coordinator.outgoing_count(&clid).await, 1);
+
+        let ack = PubackPacket { packet_type: PubACKType::PubAck, packet_id: 1, reason_code: 0, properties: None };
+        clients.coordinator.acknowledge(&clid, &ack).await.unwrap();
+        clients.release(&client).await.unwrap();
+        assert!(!client.sender.is_spilled());
+        assert_eq!(clients.coordinator.outgoing_count(&clid).await, 1);
+
+        let len = qos1(b"one").len() + qos1(b"two").len() + frame(b"three").len();
+        let mut received = vec![0u8; len];
+        peer.read_exact(&mut received).await.unwrap();
+        let at = |payload: &[u8]| received.windows(payload.len()).position(|w| w == payload).unwrap();
+        assert!(at(b"one") < at(b"two"));
+        assert!(at(b"two") < at(b"three"));
+    }
+}
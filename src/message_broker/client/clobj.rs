@@ -1,12 +1,16 @@
-use std::fmt::Display;
+use std::{fmt::Display, str::FromStr, sync::{atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}, Arc}, time::Duration};
 
-use tokio::{io::{self, AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf}, net::TcpStream};
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::{io::{self, AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf}, net::TcpStream, select, sync::mpsc::{self, error::TrySendError}};
 
-use crate::{connection::{handshake::MqttConnectedResponse, line::{SecuredStream, SocketConnection}, SocketReader, SocketWriter}, helper::time::sys_now, protocol::v5::connack::ConnackPacket};
+use crate::{connection::{handshake::{MqttConnectedResponse, MqttDisconnect}, line::{SecuredStream, SocketConnection}, SocketReader, SocketWriter}, helper::time::{sys_now, sys_now_millis}, protocol::{mqtt::Protocol, v5::{connack::ConnackPacket, disconnect::DisconnectPacket, publish::PublishFrame, RemainingLength}}};
 
 use super::SessionController;
 
-#[derive(Debug, Eq, Ord, Clone)]
+/// Identifier of client, compared by the full id.
+/// 
+/// hash is only used to pick registry shard
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct ClientID {
     id: String,
     hash: u32,
@@ -15,13 +19,18 @@ pub struct ClientID {
 impl ClientID {
     pub fn new(raw_clid: String) -> ClientID {
         Self{
-            hash: Self::hash(&raw_clid),
+            hash: Self::murmur3(&raw_clid),
             id: raw_clid,
         }
     }
 
+    /// shard index in range `0..n`
+    pub fn shard(&self, n: usize) -> usize {
+        self.hash as usize % n
+    }
+
     /// murmurhash 3
-    fn hash(raw_clid: &str) -> u32 {
+    fn murmur3(raw_clid: &str) -> u32 {
         const C1: u32 = 0xcc9e2d51;
         const C2: u32 = 0x1b873593;
         const SEED: u32 = 0;
@@ -72,43 +81,23 @@ impl Display for ClientID {
     }
 }
 
-impl PartialEq for ClientID {
-    fn eq(&self, other: &Self) -> bool {
-        if self.id.len() != other.id.len() {
-            return false;
-        }
-        
-        self.hash == other.hash
-    }
-
-    fn ne(&self, other: &Self) -> bool {
-        if self.id.len() == other.id.len() {
-            return false;
-        }
-
-        self.hash != other.hash
-    }
+/// Identifier given to client that connect with empty id.
+///
+/// milliseconds since epoch followed by a counter, so identifier
+/// given on the same millisecond is still different and time ordered
+pub struct ClientIdGenerator {
+    prefix: String,
+    seq: AtomicU64,
 }
 
-impl PartialOrd for ClientID {
-    fn ge(&self, other: &Self) -> bool {
-        self.hash >= other.hash
-    }
-
-    fn gt(&self, other: &Self) -> bool {
-        self.hash > other.hash
-    }
-
-    fn le(&self, other: &Self) -> bool {
-        self.hash <= other.hash
-    }
-
-    fn lt(&self, other: &Self) -> bool {
-        self.hash < other.hash
+impl ClientIdGenerator {
+    pub fn new(prefix: String) -> Self {
+        Self { prefix, seq: AtomicU64::new(0) }
     }
 
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.hash.cmp(&other.hash))
+    pub fn next(&self) -> ClientID {
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed) & 0xFFFF;
+        ClientID::new(format!("{}{:012x}{:04x}", self.prefix, sys_now_millis(), seq))
     }
 }
 
@@ -118,20 +107,43 @@ pub struct Session {
     pub(super) keep_alive: u16,
 }
 
+impl Session {
+    /// keepalive min value: 60
+    pub fn new(keep_alive: u16, expr_interval: u32) -> Self {
+        let ttl = sys_now() + (keep_alive + keep_alive/2) as u64;
+        Self {
+            ttl,
+            expr_interval,
+            keep_alive: keep_alive.max(60)
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct Limiter {
     pub(super) receive_maximum: Option<u16>,
-    pub(super) maximum_packet_size: Option<u16>,
+    pub(super) maximum_packet_size: Option<u32>,
     pub(super) topic_alias_maximum: Option<u16>,
+    pub(super) outbound: OutboundLimit,
 }
 
 impl Limiter {
     pub fn new(
         receive_maximum: Option<u16>, 
-        maximum_packet_size: Option<u16>, 
+        maximum_packet_size: Option<u32>, 
         topic_alias_maximum: Option<u16>
     ) -> Self {
-        Self { maximum_packet_size, receive_maximum, topic_alias_maximum }
+        Self { maximum_packet_size, receive_maximum, topic_alias_maximum, outbound: OutboundLimit::default() }
+    }
+
+    pub fn with_outbound(mut self, outbound: OutboundLimit) -> Self {
+        self.outbound = outbound;
+        self
+    }
+
+    /// packet of `len` bytes is accepted by the client
+    pub fn fits(&self, len: usize) -> bool {
+        self.maximum_packet_size.is_none_or(|max| len <= max as usize)
     }
 }
 
@@ -166,60 +178,478 @@ impl SessionController for Session {
 }
 
 
-pub type ClientSocket = Socket<SecuredStream, TcpStream>;
-pub(super) enum SocketInner<S, P> {
+/// read side of client connection, owned by listener task
+pub type ClientReader = SocketInner<ReadHalf<SecuredStream>, ReadHalf<TcpStream>>;
+/// write side of client connection, shared by every sender
+pub type ClientWriter = SocketInner<WriteHalf<SecuredStream>, WriteHalf<TcpStream>>;
+
+pub enum SocketInner<S, P> {
     Secured(S),
     Plain(P)
 }
 
-pub struct Socket<S, P> {
-    pub(super) r: SocketInner<ReadHalf<S>, ReadHalf<P>>,
-    pub(super) w: SocketInner<WriteHalf<S>, WriteHalf<P>>
-}
-
-impl Socket<SecuredStream, TcpStream> {
-    pub(super) fn new(socket: SocketConnection) -> Self {
-        match socket {
-            SocketConnection::Plain(p) => {
-                let (r, w) = tokio::io::split(p);
-                Socket {
-                    r: SocketInner::Plain(r),
-                    w: SocketInner::Plain(w),
-                }
-            },
-            SocketConnection::Secure(s) => {
-                let (r, w) = tokio::io::split(s);
-                Socket {
-                    r: SocketInner::Secured(r),
-                    w: SocketInner::Secured(w),
-                }
-            }
+/// split connection so reading does not block writing
+pub(super) fn split_socket(socket: SocketConnection) -> (ClientReader, ClientWriter) {
+    match socket {
+        SocketConnection::Plain(p) => {
+            let (r, w) = tokio::io::split(p);
+            (SocketInner::Plain(r), SocketInner::Plain(w))
+        },
+        SocketConnection::Secure(s) => {
+            let (r, w) = tokio::io::split(s);
+            (SocketInner::Secured(r), SocketInner::Secured(w))
         }
     }
 }
 
-impl SocketWriter for Socket<SecuredStream, TcpStream> {
+impl SocketWriter for ClientWriter {
     async fn write_all(&mut self, buffer: &[u8]) -> tokio::io::Result<()> {
-        match &mut self.w {
+        match self {
             SocketInner::Plain(p) => p.write_all(buffer).await,
             SocketInner::Secured(s) => s.write_all(buffer).await
         }
     }
 }
 
-impl SocketReader for Socket<SecuredStream, TcpStream> {
+impl ClientWriter {
+    /// header and payload is written with vectored write when supported
+    async fn write_frame(&mut self, frame: PublishFrame) -> io::Result<()> {
+        let mut buffer = frame.head.chain(frame.payload);
+        match self {
+            SocketInner::Plain(p) => p.write_all_buf(&mut buffer).await,
+            SocketInner::Secured(s) => s.write_all_buf(&mut buffer).await
+        }
+    }
+
+    async fn shutdown(&mut self) -> io::Result<()> {
+        match self {
+            SocketInner::Plain(p) => p.shutdown().await,
+            SocketInner::Secured(s) => s.shutdown().await
+        }
+    }
+}
+
+impl SocketReader for ClientReader {
     async fn read(&mut self, buffer: &mut [u8]) -> tokio::io::Result<usize> {
-        match &mut self.r {
+        match self {
             SocketInner::Plain(p) => p.read(buffer).await,
             SocketInner::Secured(s) => s.read(buffer).await
         }
     }
 }
 
-impl MqttConnectedResponse for Socket<SecuredStream, TcpStream> {
+impl ClientReader {
+    /// read until one whole packet is buffered, leftover bytes stay in `buffer`
+    /// for the next call
+    pub async fn read_packet(&mut self, buffer: &mut BytesMut, max: usize, dur: Duration) -> io::Result<BytesMut> {
+        let mut chunk = [0u8; 4096];
+        loop {
+            match RemainingLength::packet_len(buffer) {
+                Some(len) if len > max => return Err(io::Error::new(io::ErrorKind::InvalidData, "packet too large")),
+                Some(len) if buffer.len() >= len => return Ok(buffer.split_to(len)),
+                Some(len) => buffer.reserve(len - buffer.len()),
+                None if buffer.len() > 4 => return Err(io::Error::new(io::ErrorKind::InvalidInput, "malformed remaining length")),
+                None => {}
+            }
+
+            let readed = self.read_timeout(&mut chunk, dur).await?;
+            if readed == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+            }
+            buffer.extend_from_slice(&chunk[..readed]);
+        }
+    }
+}
+
+impl MqttDisconnect for ClientWriter {
+    async fn disconnect<'a>(&'a mut self, packet: &'a DisconnectPacket) -> io::Result<()> {
+        let packet = packet.encode().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.write_all(&packet).await?;
+        self.shutdown().await
+    }
+}
+
+/// what to do when outbound queue of slow consumer is full
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlowConsumer {
+    /// QoS 0 message is dropped, QoS 1 and 2 is still queued
+    /// until the hard bound, then client is disconnected
+    DropQoS0,
+    /// client is disconnected with reason Quota Exceeded
+    Disconnect,
+    /// message is kept on disk, sent when the writer catch up
+    Spill,
+}
+
+/// parse `drop_qos0`, `disconnect` or `spill`
+impl FromStr for SlowConsumer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop_qos0" => Ok(Self::DropQoS0),
+            "disconnect" => Ok(Self::Disconnect),
+            "spill" => Ok(Self::Spill),
+            _ => Err(format!("unknown slow consumer policy {}", s))
+        }
+    }
+}
+
+/// bound of publish waiting for the writer task of each client,
+/// the queue itself can hold twice `max_messages`
+/// so QoS 1 and 2 still have room when it is full
+#[derive(Debug, Clone, Copy)]
+pub struct OutboundLimit {
+    pub max_messages: usize,
+    pub max_bytes: usize,
+    pub policy: SlowConsumer,
+}
+
+impl Default for OutboundLimit {
+    fn default() -> Self {
+        Self {
+            max_messages: 1000,
+            max_bytes: 1024 * 1024,
+            policy: SlowConsumer::DropQoS0
+        }
+    }
+}
+
+/// parse `max_messages:max_bytes` with optional `:policy`, `drop_qos0` when omitted
+impl FromStr for OutboundLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let mut limit = Self::default();
+        let (messages, bytes) = match (parts.next(), parts.next()) {
+            (Some(messages), Some(bytes)) => (messages, bytes),
+            _ => return Err(format!("outbound limit {} is not max_messages:max_bytes", s))
+        };
+        limit.max_messages = messages.parse().map_err(|e| format!("outbound max messages {}: {}", messages, e))?;
+        limit.max_bytes = bytes.parse().map_err(|e| format!("outbound max bytes {}: {}", bytes, e))?;
+        if let Some(policy) = parts.next() {
+            limit.policy = policy.parse()?;
+        }
+        if parts.next().is_some() {
+            return Err(format!("outbound limit {} has too many part", s));
+        }
+        Ok(limit)
+    }
+}
+
+impl OutboundLimit {
+    /// packet the writer queue can hold before sending is refused
+    fn capacity(&self) -> usize {
+        self.max_messages.saturating_mul(2).max(1)
+    }
+}
+
+/// packet waiting to be written
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct QueueDepth {
+    pub messages: usize,
+    pub bytes: usize,
+}
+
+#[derive(Debug, Default)]
+struct Depth {
+    messages: AtomicUsize,
+    bytes: AtomicUsize,
+    spilled: AtomicBool,
+    held: AtomicBool,
+}
+
+impl Depth {
+    fn add(&self, len: usize) {
+        self.messages.fetch_add(1, Ordering::AcqRel);
+        self.bytes.fetch_add(len, Ordering::AcqRel);
+    }
+
+    fn sub(&self, len: usize) {
+        self.messages.fetch_sub(1, Ordering::AcqRel);
+        self.bytes.fetch_sub(len, Ordering::AcqRel);
+    }
+}
+
+/// Send handle of client connection.
+/// 
+/// packet is written in order by writer task dedicated to the connection,
+/// so sending to one client never wait for another.
+/// control packet has its own unbounded queue written ahead of publish,
+/// so acknowledgement is never refused because of slow consumer
+#[derive(Clone)]
+pub struct ClientSender {
+    tx: mpsc::Sender<PublishFrame>,
+    control: mpsc::UnboundedSender<Bytes>,
+    close: mpsc::Sender<DisconnectPacket>,
+    depth: Arc<Depth>,
+    limit: OutboundLimit,
+    protocol: Protocol,
+}
+
+impl ClientSender {
+    /// start writer task, it stop when every handle is dropped,
+    /// writing is failed or connection is disconnected
+    pub(super) fn spawn(mut writer: ClientWriter, limit: OutboundLimit, protocol: Protocol) -> Self {
+        let (tx, mut rx) = mpsc::channel::<PublishFrame>(limit.capacity());
+        let (control, mut control_rx) = mpsc::unbounded_channel::<Bytes>();
+        let (close, mut close_rx) = mpsc::channel(1);
+        let depth = Arc::new(Depth::default());
+        let counter = depth.clone();
+        tokio::spawn(async move {
+            loop {
+                // disconnect skip the queued packet
+                let frame = select! {
+                    biased;
+                    Some(packet) = close_rx.recv() => {
+                        // server send no DISCONNECT on 3.1.1, connection is just closed
+                        let res = match protocol {
+                            Protocol::V311 => writer.shutdown().await,
+                            Protocol::V5 => writer.disconnect(&packet).await
+                        };
+                        if let Err(err) = res {
+                            println!("[writer] disconnect error: {}", err);
+                        }
+                        break;
+                    },
+                    Some(packet) = control_rx.recv() => PublishFrame::from(packet),
+                    frame = rx.recv() => match frame {
+                        Some(frame) => frame,
+                        None => break
+                    }
+                };
+
+                let len = frame.len();
+                let res = writer.write_frame(frame).await;
+                counter.sub(len);
+                if res.is_err() {
+                    break;
+                }
+            }
+        });
+        Self { tx, control, close, depth, limit, protocol }
+    }
+
+    /// queue control packet regardless of the limit
+    pub fn send(&self, packet: Bytes) -> io::Result<()> {
+        let len = packet.len();
+        self.depth.add(len);
+        self.control.send(packet).map_err(|_| {
+            self.depth.sub(len);
+            io::Error::new(io::ErrorKind::NotConnected, "connection writer is closed")
+        })
+    }
+
+    pub fn send_frame(&self, frame: PublishFrame) -> io::Result<()> {
+        let len = frame.len();
+        self.depth.add(len);
+        self.tx.try_send(frame).map_err(|err| {
+            self.depth.sub(len);
+            match err {
+                TrySendError::Full(_) => io::Error::new(io::ErrorKind::WouldBlock, "outbound queue is full"),
+                TrySendError::Closed(_) => io::Error::new(io::ErrorKind::NotConnected, "connection writer is closed")
+            }
+        })
+    }
+
+    /// only the first disconnect is sent
+    pub fn disconnect(&self, packet: DisconnectPacket) -> io::Result<()> {
+        self.close.try_send(packet)
+            .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "connection writer is closed"))
+    }
+
+    /// queue is at its hard bound, nothing more can be sent
+    pub fn is_saturated(&self) -> bool {
+        self.tx.capacity() == 0
+    }
+
+    /// packet of `len` bytes still fit on the queue
+    pub fn has_room(&self, len: usize) -> bool {
+        let depth = self.depth();
+        depth.messages < self.limit.max_messages && depth.bytes + len <= self.limit.max_bytes
+    }
+
+    pub fn depth(&self) -> QueueDepth {
+        QueueDepth {
+            messages: self.depth.messages.load(Ordering::Acquire),
+            bytes: self.depth.bytes.load(Ordering::Acquire)
+        }
+    }
+
+    pub fn policy(&self) -> SlowConsumer {
+        self.limit.policy
+    }
+
+    /// some message is kept on disk, next message must wait behind it
+    pub fn is_spilled(&self) -> bool {
+        self.depth.spilled.load(Ordering::Acquire)
+    }
+
+    pub fn set_spilled(&self, spilled: bool) {
+        self.depth.spilled.store(spilled, Ordering::Release)
+    }
+
+    /// resumed session is held until its offline queue is replayed,
+    /// live message is queued behind it meanwhile
+    pub fn is_held(&self) -> bool {
+        self.depth.held.load(Ordering::Acquire)
+    }
+
+    pub fn set_held(&self, held: bool) {
+        self.depth.held.store(held, Ordering::Release)
+    }
+}
+
+impl SocketWriter for ClientSender {
+    async fn write_all(&mut self, buffer: &[u8]) -> io::Result<()> {
+        self.send(Bytes::copy_from_slice(buffer))
+    }
+}
+
+impl MqttConnectedResponse for ClientSender {
     async fn connack<'a>(&'a mut self, ack: &'a ConnackPacket) -> io::Result<()> {
-        let mut packet = ack.encode().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-        let res = self.write_all(&mut packet).await;
-        res
+        let packet = self.protocol.connack(ack).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.send(packet.freeze())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use bytes::{Bytes, BytesMut};
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}};
+    use crate::{connection::line::SocketConnection, protocol::{mqtt::{ClientPacket, Protocol}, v5::{disconnect::{DisconnectPacket, NORMAL_DISCONNECTION}, publish::{PublishFrame, PublishPacket}, ServiceLevel}}};
+    use super::{split_socket, ClientID, ClientIdGenerator, ClientSender, OutboundLimit, QueueDepth, SlowConsumer};
+
+    #[test]
+    fn compare_full_id() {
+        let a = ClientID::new("sensor-a".to_string());
+        let b = ClientID::new("sensor-b".to_string());
+        assert_ne!(a, b);
+        assert!(a != b);
+        assert_eq!(a, ClientID::new("sensor-a".to_string()));
+        assert!(a.shard(16) < 16);
+    }
+
+    #[test]
+    fn generated_id() {
+        let ids = ClientIdGenerator::new("sps".to_string());
+        let a = ids.next().to_string();
+        let b = ids.next().to_string();
+        assert!(a.starts_with("sps"));
+        assert_eq!(a.len(), 19);
+        assert!(a < b);
+    }
+
+    #[test]
+    fn parse_outbound_limit() {
+        let limit: OutboundLimit = "10:4096:spill".parse().unwrap();
+        assert_eq!((limit.max_messages, limit.max_bytes), (10, 4096));
+        assert_eq!(limit.policy, SlowConsumer::Spill);
+        let limit: OutboundLimit = "10:4096".parse().unwrap();
+        assert_eq!(limit.policy, SlowConsumer::DropQoS0);
+        assert!("10".parse::<OutboundLimit>().is_err());
+        assert!("10:4096:spill:1".parse::<OutboundLimit>().is_err());
+    }
+
+    #[tokio::test]
+    async fn writer_keep_order() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = tokio::spawn(async move { listener.accept().await.unwrap().0 });
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut peer = peer.await.unwrap();
+
+        let limit = OutboundLimit { max_messages: 2, max_bytes: 8, ..Default::default() };
+        let (_reader, writer) = split_socket(SocketConnection::Plain(stream));
+        let sender = ClientSender::spawn(writer, limit, Protocol::V5);
+        assert!(!sender.has_room(9));
+        sender.send(Bytes::from_static(&[1, 2])).unwrap();
+        sender.clone().send(Bytes::from_static(&[3])).unwrap();
+
+        let mut received = [0u8; 3];
+        peer.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, [1, 2, 3]);
+
+        sender.disconnect(DisconnectPacket::new(NORMAL_DISCONNECTION)).unwrap();
+        let mut received = Vec::new();
+        peer.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received[0], 0xE0);
+        assert_eq!(sender.depth(), QueueDepth::default());
+    }
+
+    #[tokio::test]
+    async fn writer_queue_bounded() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = tokio::spawn(async move { listener.accept().await.unwrap().0 });
+        let stream = TcpStream::connect(addr).await.unwrap();
+        // peer never read, writer is stuck on the first packet
+        let _peer = peer.await.unwrap();
+
+        let limit = OutboundLimit { max_messages: 1, ..Default::default() };
+        let (_reader, writer) = split_socket(SocketConnection::Plain(stream));
+        let sender = ClientSender::spawn(writer, limit, Protocol::V5);
+        let packet = Bytes::from(vec![0u8; 32 * 1024 * 1024]);
+        let refused = (0..4)
+            .map(|_| sender.send_frame(PublishFrame::from(packet.clone())))
+            .filter_map(Result::err)
+            .collect::<Vec<_>>();
+        assert!(!refused.is_empty());
+        assert!(refused.iter().all(|err| err.kind() == std::io::ErrorKind::WouldBlock));
+        assert!(sender.is_saturated());
+        assert!(sender.depth().messages <= 3);
+
+        // control packet is still accepted
+        sender.send(Bytes::from_static(&[0xD0, 0x00])).unwrap();
+        assert!(sender.is_saturated());
+    }
+
+    #[tokio::test]
+    async fn read_packet_across_reads() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = tokio::spawn(async move { listener.accept().await.unwrap().0 });
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut peer = peer.await.unwrap();
+        let (mut reader, _writer) = split_socket(SocketConnection::Plain(stream));
+
+        let publish = PublishPacket {
+            dup: false,
+            qos: ServiceLevel::QoS0,
+            retain: false,
+            topic: "big".to_string(),
+            packet_id: None,
+            payload: Bytes::from(vec![7u8; 64 * 1024]),
+            properties: None,
+        }.encode().unwrap();
+        let head = publish[..1000].to_vec();
+        let mut tail = publish[1000..].to_vec();
+        tail.extend_from_slice(&[0xC0, 0x00]);
+        let writer = tokio::spawn(async move {
+            peer.write_all(&head).await.unwrap();
+            peer.flush().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            peer.write_all(&tail).await.unwrap();
+            peer
+        });
+
+        let dur = Duration::from_secs(5);
+        let mut buffer = BytesMut::new();
+        let mut packet = reader.read_packet(&mut buffer, 1 << 20, dur).await.unwrap();
+        assert_eq!(packet.len(), publish.len());
+        match ClientPacket::decode(&mut packet, Protocol::V5) {
+            Ok(ClientPacket::Publish(p)) => assert_eq!(p.payload.len(), 64 * 1024),
+            _ => panic!("expected publish"),
+        }
+        let mut packet = reader.read_packet(&mut buffer, 1 << 20, dur).await.unwrap();
+        assert!(matches!(ClientPacket::decode(&mut packet, Protocol::V5), Ok(ClientPacket::PingReq)));
+        assert!(buffer.is_empty());
+
+        let mut peer = writer.await.unwrap();
+        peer.write_all(&publish).await.unwrap();
+        let err = reader.read_packet(&mut buffer, 1024, dur).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
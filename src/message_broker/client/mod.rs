@@ -1,7 +1,10 @@
+pub mod alias;
 pub mod client;
 pub mod clients;
 pub mod storage;
 pub mod clobj;
+pub mod offline;
+pub mod backend;
 
 pub const DATA_STORE: &str = ".dbg_data/clients";
 
@@ -0,0 +1,335 @@
+use std::{collections::HashMap, sync::Arc};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::{io, sync::{Mutex, MutexGuard}};
+use crate::protocol::v5::{publish::PublishFrame, ServiceLevel};
+use super::{backend::{SessionBackend, SessionStore}, clobj::ClientID};
+
+/// what to do when offline queue reach the limit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DropPolicy {
+    /// remove oldest message until the new one fit
+    DropOldest,
+    /// keep queue as is, new message is discarded
+    DropNewest,
+}
+
+#[derive(Debug, Clone)]
+pub struct OfflineLimit {
+    pub max_messages: usize,
+    pub max_bytes: usize,
+    pub include_qos0: bool,
+    pub policy: DropPolicy,
+}
+
+impl Default for OfflineLimit {
+    fn default() -> Self {
+        Self {
+            max_messages: 1000,
+            max_bytes: 1024 * 1024,
+            include_qos0: false,
+            policy: DropPolicy::DropOldest
+        }
+    }
+}
+
+/// message queued while session is disconnected
+pub struct Queued {
+    pub qos: ServiceLevel,
+    pub packet: Bytes,
+    pub expire_at: Option<u64>,
+}
+
+impl Queued {
+    pub fn new(qos: &ServiceLevel, frame: &PublishFrame) -> Self {
+        Self { qos: qos.clone(), packet: frame.to_bytes(), expire_at: frame.expire_at }
+    }
+
+    pub fn frame(&self) -> PublishFrame {
+        let mut frame = PublishFrame::from(self.packet.clone());
+        frame.expire_at = self.expire_at;
+        frame
+    }
+
+    fn serialize(&self, buffer: &mut BytesMut) {
+        buffer.put_u8(self.qos.code());
+        buffer.put_u64(self.expire_at.unwrap_or_default());
+        buffer.put_u32(self.packet.len() as u32);
+        buffer.put(self.packet.as_ref());
+    }
+
+    fn est_len(&self) -> usize {
+        13 + self.packet.len()
+    }
+
+    fn deserialize(buffer: &mut Bytes) -> io::Result<Self> {
+        if buffer.len() < 13 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "offline record header is truncated"));
+        }
+
+        let qos = ServiceLevel::try_from(buffer.get_u8())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid QoS"))?;
+        let expire_at = Some(buffer.get_u64()).filter(|at| *at != 0);
+        let len = buffer.get_u32() as usize;
+        if buffer.len() < len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "offline record is truncated"));
+        }
+
+        Ok(Self { qos, packet: buffer.split_to(len), expire_at })
+    }
+}
+
+pub(super) fn encode_offline(queued: &[Queued]) -> Bytes {
+    let mut buffer = BytesMut::with_capacity(queued.iter().map(|q| q.est_len()).sum());
+    queued.iter().for_each(|q| q.serialize(&mut buffer));
+    buffer.freeze()
+}
+
+/// torn record at the end is discarded
+pub(super) fn decode_offline(mut buffer: Bytes) -> io::Result<Vec<Queued>> {
+    let mut queued = Vec::new();
+    while !buffer.is_empty() {
+        match Queued::deserialize(&mut buffer) {
+            Ok(q) => queued.push(q),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                println!("[offline] torn record discarded");
+                break;
+            },
+            Err(err) => return Err(err)
+        }
+    }
+    Ok(queued)
+}
+
+/// number of lock, queue of client on the same stripe is accessed one at a time
+const LOCKS: usize = 64;
+
+/// size of queue kept by the session backend
+#[derive(Debug, Clone, Copy, Default)]
+struct Usage {
+    count: usize,
+    bytes: usize,
+}
+
+impl Usage {
+    fn of(queued: &[Queued]) -> Self {
+        Self { count: queued.len(), bytes: queued.iter().map(|q| q.est_len()).sum() }
+    }
+}
+
+type Stripe = Mutex<HashMap<ClientID, Usage>>;
+
+/// Durable queue of each session, kept by the session backend.
+///
+/// every access go through [`OfflineQueue::lock`], so message pushed
+/// by dispatch worker is never lost between read and rewrite of another task.
+/// size of each queue is counted under the same lock, it is read
+/// from the backend only on first access after the session is registered.
+///
+/// record: qos (1 byte) | expire at (8 byte) | packet length (4 bytes) | encoded publish packet
+#[derive(Debug, Clone)]
+pub struct OfflineQueue {
+    store: SessionBackend,
+    locks: Arc<[Stripe]>,
+}
+
+impl OfflineQueue {
+    pub fn new(store: SessionBackend) -> Self {
+        Self { store, locks: (0..LOCKS).map(|_| Mutex::new(HashMap::new())).collect() }
+    }
+
+    /// queue of the session, other task wait until the guard is dropped
+    pub async fn lock<'a>(&'a self, clid: &'a ClientID) -> QueueGuard<'a> {
+        let usage = self.locks[clid.shard(self.locks.len())].lock().await;
+        QueueGuard { clid, store: &self.store, usage }
+    }
+}
+
+pub struct QueueGuard<'a> {
+    clid: &'a ClientID,
+    store: &'a SessionBackend,
+    usage: MutexGuard<'a, HashMap<ClientID, Usage>>,
+}
+
+impl QueueGuard<'_> {
+    /// size of the queue, read from backend once
+    async fn usage(&mut self) -> io::Result<Usage> {
+        if let Some(usage) = self.usage.get(self.clid) {
+            return Ok(*usage);
+        }
+
+        let usage = Usage::of(&self.store.offline(self.clid).await?);
+        self.usage.insert(self.clid.clone(), usage);
+        Ok(usage)
+    }
+
+    /// append message, return false when message is dropped by the policy
+    pub async fn push(&mut self, msg: Queued, limit: &OfflineLimit) -> io::Result<bool> {
+        if msg.qos == ServiceLevel::QoS0 && !limit.include_qos0 {
+            return Ok(false);
+        }
+
+        if msg.est_len() > limit.max_bytes || limit.max_messages == 0 {
+            return Ok(false);
+        }
+
+        let usage = self.usage().await?;
+        let fit = |count: usize, bytes: usize| {
+            count < limit.max_messages && bytes + msg.est_len() <= limit.max_bytes
+        };
+
+        if fit(usage.count, usage.bytes) {
+            self.store.append_offline(self.clid, std::slice::from_ref(&msg)).await?;
+            self.usage.insert(self.clid.clone(), Usage {
+                count: usage.count + 1,
+                bytes: usage.bytes + msg.est_len()
+            });
+            return Ok(true);
+        }
+
+        if limit.policy == DropPolicy::DropNewest {
+            return Ok(false);
+        }
+
+        // queue is only read back when the oldest message must be dropped
+        let mut queued = self.store.offline(self.clid).await?;
+        let mut bytes: usize = queued.iter().map(|q| q.est_len()).sum();
+        let mut dropped = 0;
+        while !fit(queued.len() - dropped, bytes) && dropped < queued.len() {
+            bytes -= queued[dropped].est_len();
+            dropped += 1;
+        }
+        queued.drain(..dropped);
+        queued.push(msg);
+        println!("[offline] {} drop {} oldest message", self.clid, dropped);
+        self.store.set_offline(self.clid, &queued).await?;
+        self.usage.insert(self.clid.clone(), Usage::of(&queued));
+        Ok(true)
+    }
+
+    /// take every queued message in order, queue is emptied
+    pub async fn drain(&mut self) -> io::Result<Vec<Queued>> {
+        let queued = self.store.offline(self.clid).await?;
+        if !queued.is_empty() {
+            self.store.set_offline(self.clid, &[]).await?;
+        }
+        self.usage.insert(self.clid.clone(), Usage::default());
+        Ok(queued)
+    }
+
+    /// drop queued message, session that does not exist has nothing to drop
+    pub async fn remove(&mut self) -> io::Result<()> {
+        self.usage.remove(self.clid);
+        match self.store.set_offline(self.clid, &[]).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(())
+        }
+    }
+
+    /// size is read again from backend on next access,
+    /// used when session is registered or dropped
+    pub fn forget(&mut self) {
+        self.usage.remove(self.clid);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use crate::{
+        message_broker::client::{backend::{SessionBackend, SessionStore, StorageConfig}, clobj::ClientID, storage::MetaData},
+        protocol::v5::ServiceLevel
+    };
+    use super::{DropPolicy, OfflineLimit, OfflineQueue, Queued};
+
+    fn queued(payload: &'static [u8]) -> Queued {
+        Queued { qos: ServiceLevel::QoS1, packet: Bytes::from_static(payload), expire_at: None }
+    }
+
+    #[tokio::test]
+    async fn bounded_queue() {
+        let dir = tempfile::tempdir().unwrap();
+        let configs = [
+            StorageConfig::Memory,
+            StorageConfig::File(dir.path().join("file")),
+            StorageConfig::Embedded(dir.path().join("sessions.db")),
+        ];
+        for config in configs {
+            let store = SessionBackend::open(&config).await.unwrap();
+            let clid = ClientID::new("offline_test".to_string());
+            let queue = OfflineQueue::new(store.clone());
+            assert!(queue.lock(&clid).await.push(queued(b"one"), &OfflineLimit::default()).await.is_err());
+            store.create(&clid, &MetaData::default()).await.unwrap();
+
+            let mut limit = OfflineLimit {
+                max_messages: 2,
+                ..Default::default()
+            };
+
+            let mut guard = queue.lock(&clid).await;
+            assert!(guard.push(queued(b"one"), &limit).await.unwrap());
+            assert!(guard.push(queued(b"two"), &limit).await.unwrap());
+            assert!(guard.push(queued(b"three"), &limit).await.unwrap());
+            let qos0 = Queued { qos: ServiceLevel::QoS0, packet: Bytes::from_static(b"zero"), expire_at: None };
+            assert!(!guard.push(qos0, &limit).await.unwrap());
+
+            limit.policy = DropPolicy::DropNewest;
+            assert!(!guard.push(queued(b"four"), &limit).await.unwrap());
+
+            let drained = guard.drain().await.unwrap();
+            let payloads: Vec<&[u8]> = drained.iter().map(|q| q.packet.as_ref()).collect();
+            assert_eq!(payloads, vec![b"two".as_slice(), b"three".as_slice()], "{:?}", config);
+            assert!(guard.drain().await.unwrap().is_empty());
+
+            store.remove(&clid).await.unwrap();
+            guard.remove().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn counted_once() {
+        let store = SessionBackend::open(&StorageConfig::Memory).await.unwrap();
+        let clid = ClientID::new("offline_counted".to_string());
+        store.create(&clid, &MetaData::default()).await.unwrap();
+        store.append_offline(&clid, &[queued(b"stored")]).await.unwrap();
+
+        let queue = OfflineQueue::new(store.clone());
+        let limit = OfflineLimit { max_messages: 2, policy: DropPolicy::DropNewest, ..Default::default() };
+        let mut guard = queue.lock(&clid).await;
+        assert!(guard.push(queued(b"one"), &limit).await.unwrap());
+
+        // size is kept by the queue, not read back from the backend
+        store.set_offline(&clid, &[]).await.unwrap();
+        assert!(!guard.push(queued(b"two"), &limit).await.unwrap());
+
+        guard.forget();
+        assert!(guard.push(queued(b"two"), &limit).await.unwrap());
+        assert!(guard.push(queued(b"three"), &limit).await.unwrap());
+        assert_eq!(guard.drain().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_push_and_drain() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = StorageConfig::File(dir.path().to_path_buf());
+        let store = SessionBackend::open(&config).await.unwrap();
+        let clid = ClientID::new("offline_concurrent".to_string());
+        store.create(&clid, &MetaData::default()).await.unwrap();
+        let queue = OfflineQueue::new(store);
+
+        let pushers: Vec<_> = (0..4).map(|_| {
+            let (queue, clid) = (queue.clone(), clid.clone());
+            tokio::spawn(async move {
+                for _ in 0..25 {
+                    queue.lock(&clid).await.push(queued(b"msg"), &OfflineLimit::default()).await.unwrap();
+                }
+            })
+        }).collect();
+
+        let mut drained = 0;
+        while pushers.iter().any(|p| !p.is_finished()) {
+            drained += queue.lock(&clid).await.drain().await.unwrap().len();
+        }
+        drained += queue.lock(&clid).await.drain().await.unwrap().len();
+        assert_eq!(drained, 100);
+    }
+}
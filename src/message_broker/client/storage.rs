@@ -1,306 +1,492 @@
 #![allow(dead_code)]
-use std::{collections::HashMap, env, path::PathBuf};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
-use tokio::{fs::{File, OpenOptions}, io::{self, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter}};
-use crate::{helper::time::sys_now, protocol::v5::{connect, subscribe::Subscribe, ServiceLevel}};
-use super::{clobj::ClientID, DATA_STORE};
+use tokio::io;
+use crate::{helper::{crc::crc32, time::sys_now}, protocol::v5::{connect, subscribe::{Subscribe, SubscriptionOptions}, ServiceLevel}};
+use super::{backend::{SessionBackend, SessionStore}, clobj::ClientID};
 
-const METADATA: &str = "metadata";
-const SUBSCRIBE_DATA: &str = "subscribed";
+/// session log bigger than this is compacted into snapshot
+pub(super) const COMPACT_SIZE: usize = 4 * 1024;
 
-/// Always clone when use, this case do for pass the borrow checker. 
+/// Handle of single session on the configured backend.
 /// 
-/// Still unoptimized
+/// Always clone when use, this case do for pass the borrow checker. 
 #[derive(Debug, Clone)]
 pub struct ClientStore {
-    path: PathBuf,
+    clid: ClientID,
+    backend: SessionBackend,
 }
 
 impl ClientStore {
-    pub(super) async fn new(clid: &ClientID, mdata: &MetaData) -> io::Result<Self> {
-        let mut dir = env::current_dir()?;
-        dir.push(DATA_STORE);
-        let clroot_exists = dir.exists();
-        
-        dir.push(clid.to_string());
-        let cldir_exists = dir.exists();
-        if !clroot_exists {
-            tokio::fs::create_dir_all(&dir).await?;
-        } else if !cldir_exists {
-            tokio::fs::create_dir(&dir).await?;
-        }
-
-        let mut fopt = OpenOptions::new();
-        fopt
-            .write(true)
-            .create(true);
-
-        {
-            let mut path = dir.clone();
-            path.push("session");
-            let f = fopt.open(&path).await?;
-            f.set_len(0).await?;
-
-            let mut writer = BufWriter::new(f);
-            write_wall(
-                &mut writer, 
-                &[WALL{time: sys_now(), value: EventType::ClientCreated}]
-            ).await?;
-        }
+    /// message queued for previous session is not inherited
+    pub(crate) async fn new(backend: &SessionBackend, clid: &ClientID, mdata: &MetaData) -> io::Result<Self> {
+        backend.create(clid, mdata).await?;
+        backend.append_log(
+            clid, 
+            &[WALL{time: sys_now(), value: EventType::ClientCreated}]
+        ).await?;
+        Ok(Self { clid: clid.clone(), backend: backend.clone() })
+    }
 
-        {
-            let mut path = dir.clone();
-            path.push(SUBSCRIBE_DATA);
-            let f = fopt.open(&path).await?;
-            f.set_len(0).await?;
-        }
+    pub(super) async fn restore(backend: &SessionBackend, clid: &ClientID) -> io::Result<Restored> {
+        let wall = backend.session_log(clid).await?;
+        disconnected_until(&wall, sys_now())?;
+        backend.append_log(
+            clid, 
+            &[WALL{time: sys_now(), value: EventType::ClientRestored}]
+        ).await?;
 
-        let mut buffer = BytesMut::with_capacity(mdata.est_len());
-        {
-            let mut path = dir.clone();
-            path.push(METADATA);
-            let mut f = fopt.open(&path).await?;
-            f.set_len(0).await?;
-            mdata.serialize(&mut buffer);
-            f.write_all(&buffer).await?;
-            f.flush().await?
-        }
+        let mdata = backend.metadata(clid).await?;
+        let storage = ClientStore { clid: clid.clone(), backend: backend.clone() };
+        let subbed = storage.clone()
+            .subscriptions()
+            .await?;
 
-        Ok(Self { path: dir.to_owned() })
+        Ok(Restored{
+            mdata,
+            subs: subbed,
+            storage
+        })
     }
 
-    pub(super) async fn restore(clid: &ClientID) -> io::Result<Restored> {
-        let mut dir = env::current_dir()?;
-        dir.push(DATA_STORE);
-        dir.push(clid.to_string());
-        if !dir.is_dir() {
-            return Err(io::Error::new(io::ErrorKind::NotFound, format!("no session for client id {}", clid)));
-        }
+    /// expiration time of disconnected session that is still persisted
+    pub async fn disconnected(backend: &SessionBackend, clid: &ClientID) -> io::Result<u64> {
+        let wall = backend.session_log(clid).await?;
+        disconnected_until(&wall, sys_now())
+    }
 
-        let mut fopt = OpenOptions::new();
-        fopt.read(true);
-        let mut buffer = BytesMut::zeroed(512);
+    /// scan backend for disconnected session that is not expired yet,
+    /// give back client id with their subscription
+    pub async fn persisted(backend: &SessionBackend) -> io::Result<Vec<(ClientID, Vec<Subscribe>)>> {
+        let now = sys_now();
+        let mut sessions = Vec::new();
+        for clid in backend.sessions().await? {
+            let wall = match backend.session_log(&clid).await {
+                Ok(wall) => wall,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => {
+                    println!("[storage] {} skipped: {}", clid, err);
+                    continue;
+                }
+            };
 
-        {
-            let mut path = dir.clone();
-            path.push("session");
-            if !path.is_file() {
-                return Err(io::Error::new(io::ErrorKind::NotFound, "no session"));
+            match disconnected_until(&wall, now) {
+                Ok(_) => (),
+                Err(err) => {
+                    if err.kind() == io::ErrorKind::TimedOut {
+                        let _ = backend.set_offline(&clid, &[]).await;
+                    }
+                    continue;
+                }
             }
-            fopt.write(true);
-            let f = fopt.open(&path).await?;
-            fopt.write(false);
-            let mut reader = BufReader::new(f);
-            let wall = read_wall(&mut reader, &mut buffer).await?;
-            let f = reader.into_inner();
-            let u = match wall[wall.len() - 1].value {
-                EventType::ClientRestored => return Err(io::Error::new(io::ErrorKind::AddrInUse, "session already restored")),
-                EventType::ClientDisconnected(u) | EventType::DisconnectByServer(u) => u, 
-                _ => return Err(io::Error::new(io::ErrorKind::Other, "session maybe still online"))
-            };
 
-            let is_expired = sys_now() >= u;
-            if is_expired {
-                return Err(io::Error::new(io::ErrorKind::TimedOut, "session expired")); 
+            let storage = ClientStore { clid: clid.clone(), backend: backend.clone() };
+            match storage.subscriptions().await {
+                Ok(subs) => sessions.push((clid, subs)),
+                Err(err) => println!("[storage] {} skipped: {}", clid, err)
             }
-            
-            let mut writer = BufWriter::new(f);
-            write_wall(
-                &mut writer, 
-                &[WALL{time: sys_now(), value: EventType::ClientRestored}]
-            ).await?;
         }
+        Ok(sessions)
+    }
 
-        
-        let mdata = {
-            let mut path = dir.clone();
-            path.push(METADATA);
-            let mut reader = BufReader::new(fopt.open(&path).await?);
-            let read_mdata = reader.read(&mut buffer).await?;
-            if read_mdata < 13 {
-                return Err(io::Error::new(io::ErrorKind::InvalidData, "metadata structure is invalid"));
-            }
-            let mut b = buffer.split_to(read_mdata).freeze();
-            MetaData::deserialize(&mut b)
-        };
-        
-        let subbed: Vec<Subscribe> = {
-            let mut path = dir.clone();
-            path.push(SUBSCRIBE_DATA);
-            let f = fopt.open(&path).await?;
-            let mut reader = BufReader::new(f);
-            let mut maps = HashMap::new();
-            get_subscribed(&mut reader, &mut maps).await?;
-            maps.into_iter().map(|(k, v)| {
-                Subscribe{
-                    topic: k,
-                    max_qos: v
+    /// scan backend for session that is already expired
+    pub async fn expired(backend: &SessionBackend) -> io::Result<Vec<(ClientID, ClientStore)>> {
+        let now = sys_now();
+        let mut sessions = Vec::new();
+        for clid in backend.sessions().await? {
+            let wall = match backend.session_log(&clid).await {
+                Ok(wall) => wall,
+                Err(_) => continue
+            };
+
+            if let Err(err) = disconnected_until(&wall, now) {
+                if err.kind() == io::ErrorKind::TimedOut {
+                    let storage = ClientStore { clid: clid.clone(), backend: backend.clone() };
+                    sessions.push((clid, storage));
                 }
-            }).collect()
-        };
+            }
+        }
+        Ok(sessions)
+    }
 
-        Ok(Restored{
-            mdata,
-            subs: subbed,
-            storage: ClientStore { path: dir }
-        })
+    /// delete everything of this session
+    pub async fn remove(self) -> io::Result<()> {
+        self.backend.remove(&self.clid).await
     }
 
     pub async fn subscribe(self, topics: &[Subscribe]) -> io::Result<()> {
-        let f =  {
-            let mut subs_path = self.path;
-            subs_path.push("subscribed");
-
-            let mut fopt = OpenOptions::new();
-            fopt
-                .read(true)
-                .write(true)
-                .append(true)
-                .create(true)
-                .open(subs_path)
-                .await?
-        };
-        
-        let mut reader = BufReader::new(f);
-        let mut map = HashMap::new();
-        let _readed = get_subscribed(&mut reader, &mut map).await?;
-        
+        let mut subs = self.backend.subscriptions(&self.clid).await?;
         for sub in topics.iter() {
-            match map.get_mut(&sub.topic) {
-                Some(v) => {
-                    let not_equal = sub.max_qos.ne(&v);
-                    if not_equal {
-                        *v = sub.max_qos.clone();
-                    }
-                },
-                None => {map.insert(sub.topic.clone(), sub.max_qos.clone());}
+            match subs.iter_mut().find(|s| s.topic == sub.topic) {
+                Some(v) => *v = sub.clone(),
+                None => subs.push(sub.clone())
             }
         }
+        self.backend.set_subscriptions(&self.clid, &subs).await
+    }
 
-        let f = reader.into_inner();
-        f.set_len(0).await?;
-        
-        let mut writer = BufWriter::new(f);
-        write_subscribed(&mut writer, &mut map).await.unwrap();
-        Ok(())
+    pub async fn subscriptions(self) -> io::Result<Vec<Subscribe>> {
+        self.backend.subscriptions(&self.clid).await
     }
 
     pub async fn log_session(self, wall: &[WALL]) -> io::Result<()> {
-        let mut path = self.path;
-        path.push("session");
-
-        let mut fopt = OpenOptions::new();
-        let f = fopt.append(true).open(path).await?;
-        let mut writer = BufWriter::new(f);
-        write_wall(&mut writer, wall).await?;
-        Ok(())
+        self.backend.append_log(&self.clid, wall).await
     }
 
     pub async fn unsubscribe(self, topics: &[String]) -> io::Result<()> {
-        let f =  {
-            let mut subs_path = self.path;
-            subs_path.push("subscribed");
-
-            let mut fopt = OpenOptions::new();
-            fopt
-                .read(true)
-                .append(true)
-                .write(true)
-                .open(subs_path)
-                .await?
-        };
+        let mut subs = self.backend.subscriptions(&self.clid).await?;
+        subs.retain(|s| !topics.contains(&s.topic));
+        self.backend.set_subscriptions(&self.clid, &subs).await
+    }
 
-        let mut reader = BufReader::new(f);
-        let mut map = HashMap::new();
-        let _readed = get_subscribed(&mut reader, &mut map).await?;
-        
-        for sub in topics.iter() {
-            map.remove(sub);
-        }
-        
-        let f = reader.into_inner();
-        f.set_len(0).await?;
-        
-        let mut writer = BufWriter::new(f);
-        write_subscribed(&mut writer, &mut map).await.unwrap();
-        Ok(())
+    pub async fn save_inflight(self, inflight: &[InFlight]) -> io::Result<()> {
+        self.backend.set_inflight(&self.clid, inflight).await
+    }
+
+    /// append change without rewriting the whole in-flight state
+    pub async fn change_inflight(self, changes: &[InFlightChange]) -> io::Result<()> {
+        self.backend.append_inflight(&self.clid, changes).await
+    }
+
+    pub async fn inflight(self) -> io::Result<Vec<InFlight>> {
+        self.backend.inflight(&self.clid).await
+    }
+
+    pub async fn save_will(self, will: Option<&Will>) -> io::Result<()> {
+        self.backend.set_will(&self.clid, will).await
+    }
+
+    pub async fn will(self) -> io::Result<Option<Will>> {
+        self.backend.will(&self.clid).await
     }
 }
 
-async fn get_subscribed(reader: &mut BufReader<File>, map: &mut HashMap<String, ServiceLevel>) -> io::Result<usize> {
-    let mut buf = BytesMut::zeroed(1024);
-    let mut readed = 0;
+/// expiration time of the last disconnect event,
+/// error when session still online, restored or expired at `t`
+fn disconnected_until(wall: &[WALL], t: u64) -> io::Result<u64> {
+    let last = wall.last()
+        .ok_or(io::Error::new(io::ErrorKind::InvalidData, "empty session log"))?;
+
+    let u = match last.value {
+        EventType::ClientRestored => return Err(io::Error::new(io::ErrorKind::AddrInUse, "session already restored")),
+        EventType::ClientDisconnected(u) | EventType::DisconnectByServer(u) => u, 
+        _ => return Err(io::Error::other("session maybe still online"))
+    };
+
+    let is_expired = t >= u;
+    if is_expired {
+        return Err(io::Error::new(io::ErrorKind::TimedOut, "session expired")); 
+    }
+    Ok(u)
+}
 
-    // TODO: check when looping
-    while let Ok(n) = reader.read(&mut buf).await {
-        if n == 0 {
-            break;
+/// record: subscription options (1 byte) | subscription identifier (4 byte)
+/// | topic length (2 byte) | topic
+pub(super) fn encode_subscriptions(subs: &[Subscribe]) -> Bytes {
+    let est_len = subs.iter().map(|s| 7 + s.topic.len()).sum();
+    let mut buf = BytesMut::with_capacity(est_len);
+    subs.iter().for_each(|s| {
+        buf.put_u8(s.options.encode(&s.max_qos));
+        buf.put_u32(s.subscription_identifier.unwrap_or_default());
+        buf.put_u16(s.topic.len() as u16);
+        buf.put(s.topic.as_bytes());
+    });
+    buf.freeze()
+}
+
+pub(super) fn decode_subscriptions(mut buf: &[u8]) -> io::Result<Vec<Subscribe>> {
+    let mut subs = Vec::new();
+    while !buf.is_empty() {
+        if buf.len() < 7 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "subscription record is truncated"));
         }
 
-        let r_buf = buf.split_to(n);
-        let mut c = 0;
-        for i in 0..n {
-            let is_eq = r_buf[i] == 0xA;
-            if !is_eq {
-                continue;
+        let (max_qos, options) = SubscriptionOptions::decode(buf.get_u8())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid subscription options"))?;
+        let subscription_identifier = Some(buf.get_u32()).filter(|id| *id != 0);
+        let len = buf.get_u16() as usize;
+        if buf.len() < len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "subscription record is truncated"));
+        }
+        let topic = String::from_utf8(buf[..len].to_vec())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        buf.advance(len);
+        subs.push(Subscribe { topic, max_qos, options, subscription_identifier });
+    }
+    Ok(subs)
+}
+
+pub(super) fn decode_metadata(mut buf: Bytes) -> io::Result<MetaData> {
+    MetaData::deserialize(&mut buf)
+}
+
+pub(super) fn encode_will(will: Option<&Will>) -> Bytes {
+    let will = match will {
+        Some(w) => w,
+        None => return Bytes::new()
+    };
+    let mut buf = BytesMut::with_capacity(will.est_len());
+    will.serialize(&mut buf);
+    buf.freeze()
+}
+
+/// empty buffer means no will
+pub(super) fn decode_will(mut buf: Bytes) -> io::Result<Option<Will>> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+
+    let is_valid = buf.len() >= 2 
+        && buf.len() >= 2 + u16::from_be_bytes([buf[0], buf[1]]) as usize;
+    if !is_valid {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "will structure is invalid"));
+    }
+    Will::deserialize(&mut buf).map(Some)
+}
+
+pub(super) fn encode_wall(wall: &[WALL]) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(wall.len() * 32);
+    wall.iter().for_each(|w| w.serialize(&mut buf));
+    buf
+}
+
+/// decode every valid record, invalid tail is left on buffer
+pub(super) fn decode_wall(buf: &mut Bytes) -> Vec<WALL> {
+    let mut wall = Vec::new();
+    while !buf.is_empty() {
+        match WALL::deserialize(buf) {
+            Ok(w) => wall.push(w),
+            Err(err) => {
+                println!("[storage] session log recovered: {}", err);
+                break;
             }
+        }
+    }
+    wall
+}
 
-            let k = String::from_utf8(r_buf[c+1..i].to_vec())
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+/// session log of the first format is text, one event on each line,
+/// binary record always start with zero length byte
+pub(super) fn is_legacy_wall(buf: &[u8]) -> bool {
+    buf.first().is_some_and(u8::is_ascii_digit)
+}
 
-            let v = ServiceLevel::try_from(r_buf[c])
-                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid QoS"))?;
+/// decode session log of the first format,
+/// line: time | event name | expiration (disconnect only)
+pub(super) fn decode_legacy_wall(buf: &[u8]) -> io::Result<Vec<WALL>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("legacy session log {}", msg));
+    let text = std::str::from_utf8(buf).map_err(|_| invalid("is not text"))?;
+    let mut wall = Vec::new();
+    // line without separator was not completely written
+    let mut lines: Vec<&str> = text.split('\n').collect();
+    lines.pop();
+    for line in lines {
+        let mut part = line.split_ascii_whitespace();
+        let time = part.next()
+            .and_then(|t| t.parse::<u64>().ok())
+            .ok_or(invalid("has invalid time"))?;
+        let event = part.next();
+        let mut expiration = || part.next()
+            .and_then(|u| u.parse::<u64>().ok())
+            .ok_or(invalid("has invalid expiration"));
+        let value = match event {
+            Some("ClientCreated") => EventType::ClientCreated,
+            Some("ClientDisconnected") => EventType::ClientDisconnected(expiration()?),
+            Some("DisconnectByServer") => EventType::DisconnectByServer(expiration()?),
+            Some("ClientRestored") => EventType::ClientRestored,
+            _ => return Err(invalid("has unknown event"))
+        };
+        wall.push(WALL { time, value });
+    }
+    Ok(wall)
+}
+
+/// decode subscription list of the first format,
+/// line: max qos (1 byte) | topic
+pub(super) fn decode_legacy_subscriptions(buf: &[u8]) -> io::Result<Vec<Subscribe>> {
+    let mut subs = Vec::new();
+    for line in buf.split(|b| *b == 0x0A).filter(|l| !l.is_empty()) {
+        let max_qos = ServiceLevel::try_from(line[0])
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid QoS"))?;
+        let topic = String::from_utf8(line[1..].to_vec())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        subs.push(Subscribe { topic, max_qos, options: SubscriptionOptions::default(), subscription_identifier: None });
+    }
+    Ok(subs)
+}
+
+/// metadata of the first format has no magic and version
+pub(super) fn is_legacy_metadata(buf: &[u8]) -> bool {
+    !buf.is_empty() && buf[0] != METADATA_MAGIC
+}
+
+/// session state only depend on creation and the last event,
+/// everything in between is dropped
+pub(super) fn compact_wall(wall: &[WALL]) -> Option<Vec<WALL>> {
+    match (wall.first(), wall.last()) {
+        (Some(first), Some(last)) if wall.len() > 2 => Some(vec![first.clone(), last.clone()]),
+        _ => None
+    }
+}
+
+pub(super) fn encode_inflight(inflight: &[InFlight]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(inflight.iter().map(|i| i.est_len()).sum());
+    inflight.iter().for_each(|i| i.serialize(&mut buf));
+    buf.freeze()
+}
+
+pub(super) fn encode_inflight_changes(changes: &[InFlightChange]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(changes.iter().map(|c| c.est_len()).sum());
+    changes.iter().for_each(|c| c.serialize(&mut buf));
+    buf.freeze()
+}
+
+/// replay every change in order, torn record at the end is discarded
+pub(super) fn decode_inflight(mut buf: Bytes) -> io::Result<Vec<InFlight>> {
+    let mut inflight: Vec<InFlight> = Vec::new();
+    // position of replaced entry, taken by the next record
+    let mut replaced = None;
+    while !buf.is_empty() {
+        let record = match InFlight::deserialize(&mut buf) {
+            Ok(record) => record,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                println!("[storage] torn in-flight record discarded");
+                break;
+            },
+            Err(err) => return Err(err)
+        };
+
+        let removed = record.state & INFLIGHT_REMOVED != 0;
+        let state = record.state & !(INFLIGHT_REMOVED | INFLIGHT_REPLACED);
+        let idx = inflight.iter().position(|i| i.packet_id == record.packet_id && i.state == state);
+        if let Some(idx) = idx {
+            inflight.remove(idx);
+        }
+        if record.state & INFLIGHT_REPLACED != 0 {
+            replaced = idx;
+        } else if !removed {
+            match replaced.take() {
+                Some(idx) => inflight.insert(idx, record),
+                None => inflight.push(record)
+            }
+        }
+    }
+    Ok(inflight)
+}
 
-            map.insert(k, v);
-            c = i + 1;
+/// flag on state of record that remove the in-flight entry
+const INFLIGHT_REMOVED: u8 = 0x80;
+/// flag on state of record that remove the in-flight entry,
+/// the next record takes its position
+const INFLIGHT_REPLACED: u8 = 0x40;
+
+/// single change of in-flight state.
+///
+/// removal is written as in-flight record with [`INFLIGHT_REMOVED`] on its
+/// state and no packet, so snapshot and change share the same record
+#[derive(Debug, Clone, PartialEq)]
+pub enum InFlightChange {
+    /// add entry, replace one with the same packet identifier and state
+    Put(InFlight),
+    Remove { packet_id: u16, state: u8 },
+    /// move entry to the next step of handshake, order is kept
+    Replace { state: u8, with: InFlight },
+}
+
+impl InFlightChange {
+    fn serialize(&self, buffer: &mut BytesMut) {
+        match self {
+            Self::Put(inflight) => inflight.serialize(buffer),
+            Self::Remove { packet_id, state } => InFlight {
+                packet_id: *packet_id,
+                state: state | INFLIGHT_REMOVED,
+                packet: Bytes::new()
+            }.serialize(buffer),
+            Self::Replace { state, with } => {
+                InFlight {
+                    packet_id: with.packet_id,
+                    state: state | INFLIGHT_REPLACED,
+                    packet: Bytes::new()
+                }.serialize(buffer);
+                with.serialize(buffer);
+            }
         }
+    }
 
-        readed += n;
-        if n != 1024 {
-            break;
+    pub fn est_len(&self) -> usize {
+        match self {
+            Self::Put(inflight) => inflight.est_len(),
+            Self::Remove { .. } => 7,
+            Self::Replace { with, .. } => 7 + with.est_len()
         }
-        buf.reserve(n);
     }
-    Ok(readed)
 }
 
-async fn write_subscribed(writer: &mut BufWriter<File>, map: &mut HashMap<String, ServiceLevel>) -> io::Result<usize> {
-    let mut est_leng = 0;
-    map.iter().for_each(|(each, _)| {
-        est_leng += 2 + each.len()
-    });
+/// publish packet that is not completely acknowledged yet
+#[derive(Debug, Clone, PartialEq)]
+pub struct InFlight {
+    pub packet_id: u16,
+    pub state: u8,
+    pub packet: Bytes,
+}
 
-    let mut buf = BytesMut::with_capacity(est_leng);
+impl InFlight {
+    fn serialize(&self, buffer: &mut BytesMut) {
+        buffer.put_u16(self.packet_id);
+        buffer.put_u8(self.state);
+        buffer.put_u32(self.packet.len() as u32);
+        buffer.put(self.packet.as_ref());
+    }
 
-    map.iter().for_each(|(each, slvl)| {
-        buf.put_u8(slvl.code());
-        buf.put(each.as_bytes());
-        buf.put_u8(0x0A);
-    });
+    pub fn est_len(&self) -> usize {
+        7 + self.packet.len()
+    }
+
+    fn deserialize(buffer: &mut Bytes) -> io::Result<Self> {
+        if buffer.len() < 7 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "in-flight record is truncated"));
+        }
 
-    writer.write_all(&buf).await?;
-    writer.flush().await?;
-    Ok(est_leng)
+        let packet_id = buffer.get_u16();
+        let state = buffer.get_u8();
+        let len = buffer.get_u32() as usize;
+        if buffer.len() < len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "in-flight record is truncated"));
+        }
+        Ok(Self { packet_id, state, packet: buffer.split_to(len) })
+    }
 }
 
-#[derive(Debug)]
-pub(super) struct MetaData {
+/// first byte of versioned metadata, record of the first format
+/// start with protocol level which is never this value
+const METADATA_MAGIC: u8 = 0xFF;
+/// maximum packet size is 4 bytes since version 2
+const METADATA_VERSION: u8 = 2;
+
+/// record: magic (1 byte) | version (1 byte) | protocol level (1 byte) | keep alive (2 bytes)
+/// | expiry interval (4 bytes) | receive maximum (2 bytes) | maximum packet size (4 bytes)
+/// | topic alias maximum (2 bytes) | user properties length (4 bytes) | user properties
+///
+/// first format has no magic and version, maximum packet size is 2 bytes
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MetaData {
     pub(super) protocol_level: u8,
     pub(super) keep_alive_interval: u16,
     pub(super) expr_interval: u32,
     pub(super) receive_maximum: u16,
-    pub(super) maximum_packet_size: u16,
+    pub(super) maximum_packet_size: u32,
     pub(super) topic_alias_maximum: u16,
     pub(super) user_properties: Vec<(String, String)>,
 }
 
 impl MetaData {
-    fn serialize(&self, buffer: &mut BytesMut) {
+    pub(super) fn serialize(&self, buffer: &mut BytesMut) {
+        buffer.put_u8(METADATA_MAGIC);
+        buffer.put_u8(METADATA_VERSION);
         buffer.put_u8(self.protocol_level);
         buffer.put_u16(self.keep_alive_interval);
         buffer.put_u32(self.expr_interval);
         buffer.put_u16(self.receive_maximum);
-        buffer.put_u16(self.maximum_packet_size);
+        buffer.put_u32(self.maximum_packet_size);
         buffer.put_u16(self.topic_alias_maximum);
         let prop_len = self.est_user_prop();
 
@@ -325,58 +511,73 @@ impl MetaData {
         prop_len
     }
 
-    fn est_len(&self) -> usize {
-        13 + 4 + self.est_user_prop()
+    pub(super) fn est_len(&self) -> usize {
+        2 + 15 + 4 + self.est_user_prop()
     }
 
+    pub(super) fn deserialize(buffer: &mut Bytes) -> io::Result<Self> {
+        let truncated = || io::Error::new(io::ErrorKind::InvalidData, "metadata structure is invalid");
+        let is_versioned = buffer.first() == Some(&METADATA_MAGIC);
+        if is_versioned {
+            if buffer.len() < 2 {
+                return Err(truncated());
+            }
+            buffer.advance(1);
+            let version = buffer.get_u8();
+            if version != METADATA_VERSION {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown metadata version {}", version)));
+            }
+        }
+
+        // fixed part and user properties length
+        let fixed_len = if is_versioned { 15 + 4 } else { 13 + 4 };
+        if buffer.len() < fixed_len {
+            return Err(truncated());
+        }
 
-    fn deserialize(buffer: &mut Bytes) -> Self {
         let mut new = Self {
             protocol_level: buffer.get_u8(),
             keep_alive_interval: buffer.get_u16(),
             expr_interval: buffer.get_u32(),
             receive_maximum: buffer.get_u16(),
-            maximum_packet_size: buffer.get_u16(),
+            maximum_packet_size: match is_versioned {
+                true => buffer.get_u32(),
+                false => buffer.get_u16() as u32
+            },
             topic_alias_maximum: buffer.get_u16(),
             user_properties:  Vec::new(),
         };
 
-        if buffer.len() < 2 {
-            return new;
-        }
-        
-        let uprop_len = buffer.get_u32();
-        if buffer.len() < uprop_len as usize {
-            return new;
+        let uprop_len = buffer.get_u32() as usize;
+        if buffer.len() < uprop_len {
+            return Err(truncated());
         }
-        
-        new.user_properties = {
-            let uprop_buf = buffer.split_to(uprop_len as usize);
-            let mut prop = Vec::new();
-            while uprop_buf.len() != 0 {
-                prop.push((
-                    deserialize_string(buffer),
-                    deserialize_string(buffer)
-                ));
-                let _lf = buffer.get_u8();
+
+        let mut uprop_buf = buffer.split_to(uprop_len);
+        while !uprop_buf.is_empty() {
+            let key = deserialize_string(&mut uprop_buf)?;
+            let value = deserialize_string(&mut uprop_buf)?;
+            if uprop_buf.is_empty() {
+                return Err(truncated());
             }
-            prop
-        };
+            let _lf = uprop_buf.get_u8();
+            new.user_properties.push((key, value));
+        }
 
         buffer.clear();
-        new
+        Ok(new)
     }
 }
 
 #[derive(Default)]
-pub(super) struct Will {
+pub struct Will {
     pub(super) property: Option<connect::Properties>,
     pub(super) topic: String,
     pub(super) payload: Vec<u8>
 }
 
 impl Will {
-    fn serialize(&self, buffer: &mut BytesMut) {
+    pub(super) fn serialize(&self, buffer: &mut BytesMut) {
         serialize_string(buffer, &self.topic);
         buffer.put(self.payload.as_slice());
     }
@@ -385,11 +586,9 @@ impl Will {
         2 + self.topic.len() + self.payload.len()
     }
 
-    fn deserialize(buffer: &mut Bytes) -> Self {
-        let mut new = Self::default();
-        new.topic = deserialize_string(buffer);
-        new.payload = buffer.to_vec();
-        new
+    pub(super) fn deserialize(buffer: &mut Bytes) -> io::Result<Self> {
+        let topic = deserialize_string(buffer)?;
+        Ok(Self { topic, payload: buffer.to_vec(), ..Default::default() })
     }
 }
 
@@ -398,10 +597,17 @@ fn serialize_string(buffer: &mut BytesMut, val: &str) {
     buffer.put(val.as_bytes());
 }
 
-fn deserialize_string(buffer: &mut Bytes) -> String {
+fn deserialize_string(buffer: &mut Bytes) -> io::Result<String> {
+    let is_valid = buffer.len() >= 2
+        && buffer.len() >= 2 + u16::from_be_bytes([buffer[0], buffer[1]]) as usize;
+    if !is_valid {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "string is truncated"));
+    }
+
     let len = buffer.get_u16();
     let b = buffer.split_to(len as usize);
-    String::from_utf8(b.to_vec()).unwrap()
+    String::from_utf8(b.to_vec())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
 }
 
 #[derive(Debug)]
@@ -412,7 +618,15 @@ pub struct Restored {
     pub(super) storage: ClientStore
 }
 
-#[derive(Debug)]
+const EVENT_CREATED: u8 = 0x01;
+const EVENT_DISCONNECTED: u8 = 0x02;
+const EVENT_DISCONNECT_BY_SERVER: u8 = 0x03;
+const EVENT_RESTORED: u8 = 0x04;
+
+/// body length (4 bytes) | crc32 of body (4 bytes)
+const WALL_HEADER: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum EventType {
     ClientCreated,
     // disconnect value is expiration time
@@ -421,153 +635,119 @@ pub enum EventType {
     ClientRestored,
 }
 
-#[derive(Debug)]
+/// Session log record.
+///
+/// record: body length (4 bytes) | crc32 (4 bytes) | event (1 byte) | time (8 bytes) | expiration (8 bytes, disconnect only)
+#[derive(Debug, Clone, PartialEq)]
 pub struct WALL {
     pub time: u64,
     pub value: EventType,
 }
 
 impl WALL {
-    fn deserialize(b: &mut Bytes) -> io::Result<Self> {
-        let value = unsafe{String::from_utf8_unchecked(b.to_vec())};
-        let value = value.trim();
-        let mut part = value.split_ascii_whitespace();
-        let time = part.next().unwrap();
-        let time = time.parse::<u64>().unwrap();
-
-        let part1 = part.next()
-            .ok_or(
-                io::Error::new(
-                    io::ErrorKind::InvalidData,
-                     "not registered eventt"
-                )
-            )?;
-
-        let value = match part1 {
-            "ClientCreated" => EventType::ClientCreated,
-            "ClientDisconnected" => EventType::ClientDisconnected({
-                let part2 = part.next().ok_or(io::Error::new(io::ErrorKind::InvalidData, "no value for set expiration"))?;
-                part2.parse::<u64>().map_err(|_|io::Error::new(io::ErrorKind::InvalidData, "invalid expiration number"))?
-            }),
-            "DisconnectByServer" => EventType::DisconnectByServer({
-                let part2 = part.next().ok_or(io::Error::new(io::ErrorKind::InvalidData, "no value for set expiration"))?;
-                part2.parse::<u64>().map_err(|_|io::Error::new(io::ErrorKind::InvalidData, "invalid expiration number"))?
-            }),
-            "ClientRestored" => EventType::ClientRestored,
-            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "not registered eventt"))
+    /// buffer only advanced when the record is complete and checksum match
+    pub(super) fn deserialize(b: &mut Bytes) -> io::Result<Self> {
+        if b.len() < WALL_HEADER {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "session record header is truncated"));
+        }
+
+        let len = u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize;
+        let crc = u32::from_be_bytes([b[4], b[5], b[6], b[7]]);
+        if b.len() < WALL_HEADER + len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "session record is truncated"));
+        }
+
+        let body = &b[WALL_HEADER..WALL_HEADER + len];
+        if crc32(body) != crc {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "session record checksum mismatch"));
+        }
+
+        let mut body = Bytes::copy_from_slice(body);
+        if body.len() < 9 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "session record is too short"));
+        }
+
+        let kind = body.get_u8();
+        let time = body.get_u64();
+        let value = match kind {
+            EVENT_CREATED => EventType::ClientCreated,
+            EVENT_RESTORED => EventType::ClientRestored,
+            EVENT_DISCONNECTED | EVENT_DISCONNECT_BY_SERVER => {
+                if body.len() < 8 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "no value for set expiration"));
+                }
+                let u = body.get_u64();
+                match kind {
+                    EVENT_DISCONNECTED => EventType::ClientDisconnected(u),
+                    _ => EventType::DisconnectByServer(u)
+                }
+            },
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "not registered event"))
         };
 
+        b.advance(WALL_HEADER + len);
         Ok(Self { time, value })
     }
 
-    fn serialize(&self, buffer: &mut BytesMut) {
-        buffer.put(format!("{} ",self.time).as_bytes());
-        let val = match self.value {
-            EventType::ClientCreated => "ClientCreated",
-            EventType::ClientDisconnected(u) => &format!("ClientDisconnected {}", u),
-            EventType::DisconnectByServer(u) => &format!("DisconnectByServer {}", u),
-            EventType::ClientRestored => "ClientRestored",
-        };
+    pub(super) fn serialize(&self, buffer: &mut BytesMut) {
+        let mut body = BytesMut::with_capacity(17);
+        match self.value {
+            EventType::ClientCreated => body.put_u8(EVENT_CREATED),
+            EventType::ClientDisconnected(_) => body.put_u8(EVENT_DISCONNECTED),
+            EventType::DisconnectByServer(_) => body.put_u8(EVENT_DISCONNECT_BY_SERVER),
+            EventType::ClientRestored => body.put_u8(EVENT_RESTORED),
+        }
+        body.put_u64(self.time);
+        if let EventType::ClientDisconnected(u) | EventType::DisconnectByServer(u) = self.value {
+            body.put_u64(u);
+        }
 
-        buffer.put(val.as_bytes());
-        buffer.put_u8(0x0A);
+        buffer.put_u32(body.len() as u32);
+        buffer.put_u32(crc32(&body));
+        buffer.put(body);
     }
 }
 
-async fn read_wall(reader: &mut BufReader<File>, buffer: &mut BytesMut) -> io::Result<Vec<WALL>> {
-    let mut wall = Vec::new();
-    while let Ok(n) = reader.read(buffer).await {
-        if n == 0 {
-            break;
-        }
 
-        let mut read = buffer.split_to(n);
-        let mut i = 0;
-        while read.len() > 0 {
-            let is_separator = read[i] == 0x0A;
-            i += 1;
-            if !is_separator {
-                continue;
-            }
-
-            let mut b = read.split_to(i).freeze();
-            let des = WALL::deserialize(&mut b)?;
-            wall.push(des);
-            i = 0;
+#[cfg(test)]
+mod tests {
+    use bytes::{BufMut, Bytes, BytesMut};
+    use super::{decode_metadata, MetaData};
+
+    #[test]
+    fn metadata_format() {
+        let mdata = MetaData {
+            protocol_level: 5,
+            keep_alive_interval: 60,
+            expr_interval: 300,
+            receive_maximum: 10,
+            maximum_packet_size: 256 * 1024,
+            topic_alias_maximum: 4,
+            user_properties: vec![("k".to_string(), "v".to_string())]
+        };
+        let mut buffer = BytesMut::new();
+        mdata.serialize(&mut buffer);
+        assert_eq!(buffer.len(), mdata.est_len());
+        let encoded = buffer.freeze();
+        assert_eq!(decode_metadata(encoded.clone()).unwrap(), mdata);
+
+        for len in 0..encoded.len() {
+            assert!(decode_metadata(encoded.slice(..len)).is_err(), "truncated at {}", len);
         }
 
-        buffer.reserve(n);
-        buffer.put_bytes(0, n);
+        // first format, maximum packet size is 2 bytes
+        let mut old = BytesMut::new();
+        old.put_u8(4);
+        old.put_u16(30);
+        old.put_u32(120);
+        old.put_u16(5);
+        old.put_u16(1024);
+        old.put_u16(0);
+        old.put_u32(0);
+        let old = decode_metadata(old.freeze()).unwrap();
+        assert_eq!(old.protocol_level, 4);
+        assert_eq!(old.maximum_packet_size, 1024);
+        assert_eq!(old.topic_alias_maximum, 0);
+        assert!(decode_metadata(Bytes::from_static(&[4, 0, 30])).is_err());
     }
-    Ok(wall)
 }
-
-async fn write_wall(writer: &mut BufWriter<File>, wall: &[WALL]) -> io::Result<()> {
-    let mut buffer = BytesMut::with_capacity(wall.len() * 64);
-
-    for w in wall.iter() {
-        w.serialize(&mut buffer);
-    }
-
-    writer.write_all(&buffer).await?;
-    writer.flush().await?;
-    Ok(())
-}
-
-// async fn write_metadata(writer: &mut BufWriter<File>, mdata: &MetaData) -> io::Result<usize> {
-//     let mut buf = BytesMut::with_capacity(15);
-//     mdata.expr_interval;
-
-//     map.iter().for_each(|(each, slvl)| {
-//         buf.put_u8(slvl.code());
-//         buf.put(each.as_bytes());
-//         buf.put_u8(0x0A);
-//     });
-
-//     writer.write_all(&buf).await?;
-//     writer.flush().await?;
-//     Ok(est_leng)
-// }
-
-
-// #[cfg(test)]
-// mod tests {
-//     use crate::protocol::v5::{subscribe::Subscribe, ServiceLevel};
-
-//     use super::{ClientID, ClientStore};
-
-//     #[tokio::test]
-//     async fn log_path() {
-//         let clid = ClientID::new("raw_clid".to_owned());
-//         let l = ClientStore::new(&clid).await;
-//         let logs = l.unwrap();
-//         tokio::fs::remove_dir(logs.path).await.unwrap();
-//     }
-
-//     #[tokio::test]
-//     async fn append() {
-//         let clid = ClientID::new("raw_clid".to_owned());
-//         let l = ClientStore::new(&clid).await;
-//         let logs = l.unwrap();
-        
-//         let v = vec![
-//             Subscribe{
-//                 max_qos: ServiceLevel::QoS1,
-//                 topic: "topic/a".to_owned()
-//             },Subscribe{
-//                 max_qos: ServiceLevel::QoS1,
-//                 topic: "topic/b".to_owned()
-//             }, Subscribe{
-//                 max_qos: ServiceLevel::QoS1,
-//                 topic: "topic/c".to_owned()
-//             },
-//         ];
-//         let subs_log = logs.clone();
-//         subs_log.subscribe(&v)
-//             .await
-//             .unwrap();
-    
-//         let p = [v[2].topic.clone()];
-//         logs.unsubscribe(&p).await.unwrap();
-//     }
-// }
\ No newline at end of file
@@ -1,117 +1,315 @@
-use std::{sync::{atomic::Ordering, Arc}, time::Duration};
-use bytes::{BufMut, BytesMut};
+use std::{future::Future, sync::Arc, time::Duration};
+use bytes::{Bytes, BytesMut};
 use tokio::{io, select, signal, sync::Mutex, task::JoinHandle};
 use crate::{
-    connection::SocketWriter, ds::{
+    connection::handshake::MqttConnectedResponse,
+    error::BrokerError,
+    ds::{
         trie::Trie, GetFromQueue, InsertQueue 
     }, helper::time::sys_now, 
-    message_broker::client::storage::{EventType, WALL}, 
+    message_broker::client::storage::{ClientStore, EventType, WALL}, 
     protocol::{
-        mqtt::{ClientPacketV5, PING_RES}, 
+        mqtt::{ClientPacket, PING_RES}, 
         v5::{
-            self,
-            publish::PublishPacket, 
+            connack::ConnackPacket,
+            disconnect::{DisconnectPacket, SESSION_TAKEN_OVER},
+            malform::Malformed,
+            puback::{PubACKType, PubackPacket},
+            publish::{PublishFrame, PublishPacket}, 
             subsack::SubsAck, 
-            subscribe::SubscribePacket, 
+            subscribe::{RetainHandling, Subscribe, SubscribePacket}, 
             ServiceLevel
         }
     }
 };
-use crate::connection::SocketReader;
 use super::{
     cleanup::Cleanup, client::{
+        alias::InboundAlias,
+        backend::SessionBackend,
         client::{Client, UpdateClient}, 
-        clients::{AtomicClient, Clients}, 
-        clobj::{ClientID, ClientSocket}, 
-        SessionController
-    }, message::{Message, Queue}, 
-    router::{SubscriberInstance, TopicRouter}, 
-    SendStrategy
+        clients::Clients, 
+        clobj::{ClientID, ClientIdGenerator, ClientSender, Limiter, OutboundLimit, QueueDepth, Session}
+    }, message::{ExpiredCounter, Message, ShardedQueue}, 
+    msg_state::MessageCoordinator,
+    retained::RetainedStore,
+    router::{Recipient, SubscriberInstance, TopicRouter}, 
+    wal::MessageLog,
+    Admission, BrokerConfig, Forwarder, InboundLimit, SendStrategy, TOPIC_ALIAS_MAXIMUM
 };
 
 pub type RouterTree = Arc<Trie<SubscriberInstance>>;
 
 pub struct BrokerMediator {
+    config: BrokerConfig,
     clients: Clients,
     tasks: Tasks,
-    message_queue: Queue,
+    message_queue: ShardedQueue,
+    message_log: MessageLog,
     router: RouterTree,
+    store: SessionBackend,
+    coordinator: MessageCoordinator,
+    expired: ExpiredCounter,
+    retained: RetainedStore,
+    client_ids: ClientIdGenerator,
 }
 
 impl BrokerMediator {
-    pub async fn new() -> Self {
-        let clients = Clients::new().await;
-        let message_queue = Queue::new();
-        let router = Arc::new(Trie::new());
+    pub async fn new(config: BrokerConfig) -> io::Result<Self> {
+        let store = SessionBackend::open(&config.storage).await?;
+        let coordinator = MessageCoordinator::new();
+        let expired = ExpiredCounter::default();
+        let retained = RetainedStore::default();
+        let clients = Clients::new(
+            config.offline.clone(), 
+            store.clone(), 
+            coordinator.clone(), 
+            expired.clone(), 
+            retained.clone()
+        ).await;
+        let message_queue = ShardedQueue::new(config.dispatch_workers);
+        let (message_log, undelivered) = MessageLog::open(config.wal.clone()).await?;
+        for msg in undelivered {
+            message_queue.enqueue(msg);
+        }
+
+        let router: RouterTree = Arc::new(Trie::new());
+        match ClientStore::persisted(&store).await {
+            Ok(sessions) => for (clid, subs) in sessions {
+                println!("[router] restore {} subscription of {}", subs.len(), clid);
+                let _ = router.subscribe(&clid, &subs);
+            },
+            Err(err) => println!("[router] failed to load persisted session: {}", err)
+        }
+
         let tasks = Tasks::new();
-        Self{ clients, message_queue, tasks, router }
+        let client_ids = ClientIdGenerator::new(config.client_id_prefix.clone());
+        Ok(Self{ config, clients, message_queue, message_log, tasks, router, store, coordinator, expired, retained, client_ids })
     }
 }
 
-impl<'cp> BrokerMediator {
-    pub async fn register<CB, R>(&self, new_cl: Client, callback: CB) -> Result<R, String>
-    where CB: FnOnce(&'cp mut ClientSocket) -> R
+impl BrokerMediator {
+    /// callback is given the connection before listener is started,
+    /// client that can not be registered is refused with CONNACK
+    pub async fn register<CB, R>(&self, new_cl: Client, callback: CB) -> Result<R, BrokerError>
+    where CB: AsyncFnOnce(&mut ClientSender) -> R
     {
         let clid = new_cl.clid.clone();
+        let mut sender = new_cl.sender.clone();
         println!("[register] client {:?}", clid);
-        self.clients.insert(new_cl).await?;
+        let registered = match self.clients.insert(new_cl).await {
+            Ok(client) => match self.coordinator.load(&clid, client.storage.clone()).await {
+                Ok(_) => Ok(client),
+                Err(err) => {
+                    client.kill();
+                    Err(BrokerError::from(err))
+                }
+            },
+            Err(err) => Err(err)
+        };
 
-        let client = unsafe{self.clients.get_client(&clid)}.await.unwrap();
-        let ret = callback(unsafe {
-            &mut (*client.load(Ordering::Acquire)).socket
-        });
+        let client = match registered {
+            Ok(client) => client,
+            Err(err) => {
+                refuse(&mut sender, &clid, &err).await;
+                return Err(err);
+            }
+        };
+        let ret = callback(&mut client.sender.clone()).await;
 
-        self.tasks.spawn(
-            client, 
-            self.message_queue.clone(), 
-            self.router.clone()
-        ).await;
+        self.listen(client).await;
         Ok(ret)
     }
 
-    pub async fn try_restore_session<CB, R>(&self, clid: ClientID, bucket: &mut UpdateClient, callback: CB) -> io::Result<R> 
-    where CB: FnOnce(&'cp mut ClientSocket) -> R
+    /// resume stored session on the new connection. once the socket is taken
+    /// from `bucket` the failure is answered here with refused CONNACK
+    pub async fn try_restore_session<CB, R>(
+        &self, 
+        clid: ClientID, 
+        bucket: &mut UpdateClient, 
+        session: Session, 
+        limit: Limiter, 
+        callback: CB
+    ) -> Result<R, BrokerError> 
+    where CB: AsyncFnOnce(&mut ClientSender) -> R
     {
-        let restored_client = Client::restore(clid.clone(), bucket).await?;
-        self.clients.insert(restored_client).await.unwrap();
-        let client = unsafe{self.clients.get_client(&clid).await};
-        let client = client.ok_or(io::Error::new(io::ErrorKind::Other, "unknown error"))?;
-        let ret = callback(unsafe {
-            &mut (*client.load(Ordering::Acquire)).socket
-        });
+        let (restored_client, subs) = Client::restore(&self.store, clid.clone(), bucket, session, limit).await?;
+        let mut sender = restored_client.sender.clone();
+        let client = match self.clients.insert(restored_client).await {
+            Ok(client) => client,
+            Err(err) => {
+                refuse(&mut sender, &clid, &err).await;
+                return Err(err);
+            }
+        };
+
+        let resumed = match self.coordinator.load(&clid, client.storage.clone()).await {
+            Ok(_) => self.router.subscribe(&clid, &subs)
+                .map(|_| ())
+                .map_err(|_| BrokerError::Storage(io::Error::new(io::ErrorKind::InvalidData, "invalid persisted subscription"))),
+            Err(err) => Err(BrokerError::from(err))
+        };
+        if let Err(err) = resumed {
+            client.kill();
+            refuse(&mut sender, &clid, &err).await;
+            return Err(err);
+        }
+
+        let ret = callback(&mut client.sender.clone()).await;
+        self.listen(client).await;
+        Ok(ret)
+    }
 
-        let client = unsafe{self.clients.get_client(&clid)}.await.unwrap();
-        self.tasks.spawn(
+    /// start listener task of registered client
+    async fn listen(&self, client: Arc<Client>) {
+        let clid = client.clid.clone();
+        self.tasks.spawn(clid, spawn_client(
             client, 
             self.message_queue.clone(), 
-            self.router.clone()
-        ).await;
-        Ok(ret)
+            self.message_log.clone(),
+            self.coordinator.clone(),
+            self.router.clone(),
+            self.clients.clone(),
+            self.config.inbound
+        )).await;
+    }
+
+    /// close live connection owned by `clid` with reason Session Taken Over,
+    /// the session itself is kept and can be resumed by the new connection
+    pub async fn take_over(&self, clid: &ClientID) -> io::Result<()> {
+        self.tasks.abort(clid).await;
+
+        let client = self.clients.get(clid)
+            .await
+            .ok_or(io::Error::new(io::ErrorKind::NotFound, format!("client {} not found", clid)))?;
+
+        if let Some(depth) = self.outbound_depth(clid).await {
+            println!("[take over] {} drop {} queued packet", clid, depth.messages);
+        }
+
+        client.kill();
+        let sevent = WALL{
+            time: sys_now(), 
+            value: EventType::DisconnectByServer(client.expiration_time())
+        };
+
+        let packet = DisconnectPacket::new(SESSION_TAKEN_OVER);
+        if let Err(err) = client.sender.disconnect(packet) {
+            println!("[take over] {} disconnect error: {}", clid, err);
+        }
+        client.storage.clone().log_session(&[sevent]).await
+    }
+
+    /// resend QoS handshake that is not complete on previous connection
+    pub async fn resume_inflight(&self, clid: &ClientID) -> io::Result<usize> {
+        let protocol = self.clients.get(clid)
+            .await
+            .map(|c| c.protocol)
+            .unwrap_or_default();
+        let pending = self.coordinator.pending(clid, protocol).await?;
+        for packet in pending.iter() {
+            self.clients.pubish(clid, packet.clone()).await?;
+        }
+        Ok(pending.len())
+    }
+
+    /// send message queued while session was disconnected, in order
+    pub async fn replay_offline(&self, clid: &ClientID) -> io::Result<usize> {
+        self.clients.replay_offline(clid).await
+    }
+
+    /// drop every subscription of previous session from router
+    pub async fn discard_session(&self, clid: &ClientID) -> io::Result<()> {
+        let storage = match self.clients.get(clid).await {
+            Some(c) => c.storage.clone(),
+            None => return Ok(())
+        };
+
+        let topics: Vec<String> = storage.subscriptions()
+            .await?
+            .into_iter()
+            .map(|s| s.topic)
+            .collect();
+        self.router.unsubscribe(clid, &topics);
+        Ok(())
+    }
+
+    /// identifier for client that connect with empty id,
+    /// not used by any live or persisted session
+    pub async fn assign_client_id(&self) -> ClientID {
+        loop {
+            let clid = self.client_ids.next();
+            let is_live = self.clients.get(&clid).await.is_some();
+            if !is_live && ClientStore::disconnected(&self.store, &clid).await.is_err() {
+                return clid;
+            }
+            println!("[session] assigned id {} is taken", clid);
+        }
     }
 
     pub async fn is_still_alive(&self, clid: &ClientID) -> Option<bool> {
         let t = sys_now();
-        self.clients.search_mut_client(clid, |c| {
-            c.is_alive(t)
-        })
-        .await
+        self.clients.get(clid)
+            .await
+            .map(|c| c.is_alive(t))
+    }
+
+    /// packet waiting to be written to connected client
+    pub async fn outbound_depth(&self, clid: &ClientID) -> Option<QueueDepth> {
+        self.clients.outbound_depth(clid).await
+    }
+
+    pub async fn connected_clients(&self) -> usize {
+        self.clients.connected(sys_now()).await
+    }
+
+    pub fn admission(&self) -> &Admission {
+        &self.config.admission
+    }
+
+    /// limit of the client if it is overridden, the broker one otherwise
+    pub fn outbound_limit(&self, clid: &ClientID) -> OutboundLimit {
+        self.config.outbound_clients
+            .get(&clid.to_string())
+            .copied()
+            .unwrap_or(self.config.outbound)
+    }
+
+    pub fn inbound_limit(&self) -> InboundLimit {
+        self.config.inbound
+    }
+
+    pub fn session_store(&self) -> &SessionBackend {
+        &self.store
     }
 
     pub fn join_handle(&self) -> JoinHandle<()> {
         let clients = self.clients.clone();
+        tokio::task::spawn(sweeper(
+            self.tasks.clone(),
+            clients.clone(),
+            self.router.clone(),
+            self.store.clone(),
+            self.expired.clone(),
+            self.config.sweep_interval
+        ));
         
         tokio::task::spawn(observer(
             self.tasks.clone(),
             self.router.clone(),
             self.message_queue.clone(),
+            self.message_log.clone(),
+            self.expired.clone(),
+            self.retained.clone(),
             clients.clone(),
         ))
     }
 }
 
+type ClientTask = (ClientID, JoinHandle<()>);
+
 #[derive(Clone)]
 struct Tasks{
-    t: Arc<Mutex<Vec<JoinHandle<()>>>>
+    t: Arc<Mutex<Vec<ClientTask>>>
 }
 
 impl Tasks {
@@ -119,28 +317,53 @@ impl Tasks {
         Self { t: Arc::new(Mutex::new(Vec::new())) }
     }
 
-    async fn spawn<IQ, RO>(
-        &self,
-        client: AtomicClient, 
-        msg_queue: IQ, 
-        router: RO
-    ) where 
-        IQ: InsertQueue<Message> + Send + Sync + 'static,
-        RO: TopicRouter + Send + Sync + 'static
+    async fn spawn<L>(&self, clid: ClientID, listener: L) 
+    where L: Future<Output = ()> + Send + 'static
     {
         let mut t = self.t.lock().await;
-        t.push(tokio::spawn(spawn_client(
-            client, 
-            msg_queue, 
-            router
-        )));
+        t.retain(|(_, handle)| !handle.is_finished());
+        t.push((clid, tokio::spawn(listener)));
+    }
+
+    /// stop listener task owned by `clid`, wait until it is gone
+    async fn abort(&self, clid: &ClientID) {
+        let handle = {
+            let mut t = self.t.lock().await;
+            let idx = t.iter().position(|(id, _)| id.eq(clid));
+            match idx {
+                Some(idx) => t.swap_remove(idx).1,
+                None => return
+            }
+        };
+
+        handle.abort();
+        let _ = handle.await;
+    }
+
+    /// evict client expired at `t` and stop its listener task,
+    /// listener of client that reconnect meanwhile is kept
+    async fn evict(&self, clients: &Clients, clid: &ClientID, t: u64) -> Option<ClientStore> {
+        let (storage, handle) = {
+            let mut tasks = self.t.lock().await;
+            let storage = clients.evict(clid, t).await?;
+            let handle = tasks.iter()
+                .position(|(id, _)| id.eq(clid))
+                .map(|idx| tasks.swap_remove(idx).1);
+            (storage, handle)
+        };
+
+        if let Some(handle) = handle {
+            handle.abort();
+            let _ = handle.await;
+        }
+        Some(storage)
     }
 }
 
 impl Cleanup for Tasks {
     async fn clear(self) {
         self.t.lock().await.iter()
-        .for_each(|v| {
+        .for_each(|(_, v)| {
             v.abort() 
         });
         tokio::time::sleep(Duration::from_secs(1)).await;
@@ -149,18 +372,22 @@ impl Cleanup for Tasks {
 
 
 async fn spawn_client<IQ, RO>(
-    client: AtomicClient, 
+    client: Arc<Client>, 
     msg_queue: IQ, 
-    router: RO
+    msg_log: MessageLog,
+    coordinator: MessageCoordinator,
+    router: RO,
+    clients: Clients,
+    limit: InboundLimit
 ) where 
     IQ: InsertQueue<Message> + Send + Sync + 'static,
     RO: TopicRouter + Send + Sync + 'static
 {
-    let mut buffer = BytesMut::zeroed(1024);
-    println!("[Client] {} spawned", unsafe{&mut (*client.load(std::sync::atomic::Ordering::Relaxed))}.clid);
+    let mut buffer = BytesMut::with_capacity(1024);
+    println!("[Client] {} spawned", client.clid);
+    let mut reader = client.reader.lock().await;
+    let mut aliases = InboundAlias::new(TOPIC_ALIAS_MAXIMUM);
     'lis: loop {
-        let client = unsafe {&mut *client.load(std::sync::atomic::Ordering::Relaxed)};
-
         let t = sys_now();
         if !client.is_alive(t) {
             let log = client.storage.clone();
@@ -170,9 +397,9 @@ async fn spawn_client<IQ, RO>(
                 value: EventType::DisconnectByServer(client.expiration_time())
             };
 
-            log.log_session(&[sevent])
-            .await
-            .unwrap();
+            if let Err(err) = log.log_session(&[sevent]).await {
+                println!("[Client] {} session log error: {}", client.clid, err);
+            }
 
             println!("[Client] {} dead", client.clid);
             break 'lis;
@@ -180,11 +407,24 @@ async fn spawn_client<IQ, RO>(
 
         let dur = Duration::from_secs(client.ttl() - t);
         
-        let readed = match client.socket.read_timeout(&mut buffer, dur).await {
-            Ok(readed) => readed,
+        let mut packet_buf = match reader.read_packet(&mut buffer, limit.maximum_packet_size as usize, dur).await {
+            Ok(packet_buf) => packet_buf,
             Err(err) => {
-                if err.kind() == io::ErrorKind::TimedOut {
-                    continue 'lis;
+                match err.kind() {
+                    io::ErrorKind::TimedOut => continue 'lis,
+                    io::ErrorKind::UnexpectedEof => {
+                        client.kill();
+                        continue 'lis;
+                    },
+                    io::ErrorKind::InvalidData => {
+                        close_with(&client, &Malformed::PacketTooLarge.into());
+                        continue 'lis;
+                    },
+                    io::ErrorKind::InvalidInput => {
+                        close_with(&client, &Malformed::MalformedPacket.into());
+                        continue 'lis;
+                    },
+                    _ => {}
                 }
 
                 let log = client.storage.clone();
@@ -193,162 +433,481 @@ async fn spawn_client<IQ, RO>(
                     value: EventType::ClientDisconnected(client.expiration_time())
                 };
 
-                log.log_session(&[sevent])
-                    .await
-                    .unwrap();
+                if let Err(err) = log.log_session(&[sevent]).await {
+                    println!("[Client] {} session log error: {}", client.clid, err);
+                }
 
                 break 'lis;
             }
         };
-        
-        if readed == 0 {
-            client.kill();
-            continue 'lis;
-        }
 
-        let mut packet_buf = buffer.split_to(readed);
-        let incoming_packet = ClientPacketV5::decode(&mut packet_buf);
-        let packet_received = incoming_packet.unwrap();
+        let packet_received = match ClientPacket::decode(&mut packet_buf, client.protocol) {
+            Ok(packet) => packet,
+            Err(err) => {
+                close_with(&client, &err.into());
+                continue 'lis;
+            }
+        };
         match client.keep_alive(t+1) {
             Ok(_) => {},
             Err(_) => continue
         };
 
         match packet_received {
-            ClientPacketV5::PingReq => { let _ = client.socket.write_all(&PING_RES).await; },
-            ClientPacketV5::Publish(pub_packet) => queue_message(&msg_queue, &client.clid, pub_packet),
-            ClientPacketV5::Subscribe(sub_packet) => subscribe_topics(&router, client, sub_packet).await
+            ClientPacket::PingReq => { let _ = client.sender.send(Bytes::from_static(&PING_RES)); },
+            ClientPacket::Publish(mut pub_packet) => {
+                if let Err(err) = aliases.resolve(&mut pub_packet) {
+                    close_with(&client, &err);
+                    continue 'lis;
+                }
+                if exceed_receive_maximum(&coordinator, &client, &pub_packet, limit.receive_maximum).await {
+                    close_with(&client, &Malformed::ReceiveMax.into());
+                    continue 'lis;
+                }
+                receive_publish(&msg_queue, &msg_log, &coordinator, &client, pub_packet).await
+            },
+            ClientPacket::Ack(ack) => {
+                match coordinator.acknowledge(&client.clid, &ack).await {
+                    Ok(Some(res)) => send_encoded(&client, client.protocol.ack(&res)),
+                    Ok(None) => (),
+                    Err(err) => println!("[Client] {} acknowledge error: {}", client.clid, err)
+                }
+                if let Err(err) = clients.release(&client).await {
+                    println!("[Client] {} release error: {}", client.clid, err);
+                }
+            },
+            ClientPacket::Subscribe(sub_packet) => subscribe_topics(&router, &clients, &client, sub_packet).await
         };
-        
-        buffer.reserve(readed);
-        buffer.put_bytes(0, readed);
     }
 
-    println!("[Client] {} despawn", unsafe{&mut (*client.load(std::sync::atomic::Ordering::Relaxed))}.clid);
+    println!("[Client] {} despawn", client.clid);
+}
+
+/// answer CONNACK with reason code of the error, connection is dropped with the sender
+async fn refuse(sender: &mut ClientSender, clid: &ClientID, err: &BrokerError) {
+    let ack = ConnackPacket { return_code: err.code(), ..Default::default() };
+    if let Err(e) = sender.connack(&ack).await {
+        println!("[register] {} refuse error: {}", clid, e);
+    }
+}
+
+/// disconnect client with reason code of the error
+fn close_with(client: &Client, err: &BrokerError) {
+    println!("[Client] {} {}: {:#04x}", client.clid, err, err.code());
+    let _ = client.sender.disconnect(DisconnectPacket::new(err.code()));
+    client.kill();
+}
+
+/// packet that can not be encoded close the connection
+fn send_encoded(client: &Client, packet: Result<BytesMut, String>) {
+    match packet {
+        Ok(packet) => { let _ = client.sender.send(packet.freeze()); },
+        Err(err) => close_with(client, &BrokerError::Encode(err))
+    }
+}
+
+/// new QoS 2 publish while the client already has `max` publish
+/// waiting for PUBREL
+async fn exceed_receive_maximum(coordinator: &MessageCoordinator, client: &Client, packet: &PublishPacket, max: u16) -> bool {
+    let packet_id = match (&packet.qos, packet.packet_id) {
+        (ServiceLevel::QoS2, Some(id)) => id,
+        _ => return false
+    };
+
+    if coordinator.is_received(&client.clid, packet_id).await {
+        return false;
+    }
+    coordinator.received_count(&client.clid).await >= max as usize
+}
+
+/// publisher is acknowledged once the message is durable, QoS 2 identifier
+/// is kept until released so retransmitted publish is not routed twice,
+/// message that is not accepted is acknowledged with the error reason code
+async fn receive_publish<IQ>(
+    msg_queue: &IQ, 
+    msg_log: &MessageLog, 
+    coordinator: &MessageCoordinator, 
+    client: &Client, 
+    packet: PublishPacket
+) where IQ: InsertQueue<Message>
+{
+    let qos = packet.qos.clone();
+    let packet_id = packet.packet_id;
+    let is_duplicate = match (&qos, packet_id) {
+        (ServiceLevel::QoS2, Some(id)) => coordinator.is_received(&client.clid, id).await,
+        _ => false
+    };
+
+    let mut received = Ok(());
+    if !is_duplicate {
+        received = queue_message(msg_queue, msg_log, &client.clid, packet).await;
+    }
+
+    let (packet_type, packet_id) = match (qos, packet_id) {
+        (ServiceLevel::QoS1, Some(id)) => (PubACKType::PubAck, id),
+        (ServiceLevel::QoS2, Some(id)) => {
+            if received.is_ok() {
+                received = coordinator.received(&client.clid, id).await;
+            }
+            (PubACKType::PubRec, id)
+        },
+        _ => {
+            if let Err(err) = received {
+                println!("[Client] {} publish is not accepted: {}", client.clid, err);
+            }
+            return;
+        }
+    };
+
+    let reason_code = match received.map_err(BrokerError::from) {
+        Ok(_) => 0x00,
+        Err(err) => {
+            println!("[Client] {} publish is not accepted: {}", client.clid, err);
+            err.code()
+        }
+    };
+
+    let ack = PubackPacket {
+        packet_type,
+        packet_id,
+        reason_code,
+        properties: None
+    };
+    send_encoded(client, client.protocol.ack(&ack));
 }
 
-fn queue_message<IQ>(msg_queue: &IQ, clid: &ClientID, packet: PublishPacket)
+/// QoS 1 and 2 message is written to message log before queued,
+/// so the publisher only acknowledged after message is durable
+async fn queue_message<IQ>(msg_queue: &IQ, msg_log: &MessageLog, clid: &ClientID, packet: PublishPacket) -> io::Result<()>
 where IQ: InsertQueue<Message>
 {
+    // expiry is counted from the time broker receive the message
+    let expire_at = packet.properties
+        .as_ref()
+        .and_then(|p| p.message_expiry_interval)
+        .map(|interval| sys_now() + interval as u64);
+
     let mut msg = Message {
-        packet: packet,
-        publisher: None
+        packet,
+        publisher: Some(clid.clone()),
+        seq: None,
+        expire_at
     };
 
     if msg.packet.qos.code() > 0 {
-        msg.publisher = Some(clid.clone());
+        msg.seq = Some(msg_log.append(&msg).await?);
     }
 
-    msg_queue.enqueue(msg)
+    msg_queue.enqueue(msg);
+    Ok(())
 }
 
-async fn subscribe_topics<RO>(router: &RO, client: &mut Client, sub_packet: SubscribePacket) 
+async fn subscribe_topics<RO>(router: &RO, clients: &Clients, client: &Client, sub_packet: SubscribePacket) 
 where RO: TopicRouter
 {
-    let res = router.subscribe(&client.clid, &sub_packet.list);
-    
-    let recode = match res {
+    let existed: Vec<bool> = sub_packet.list.iter()
+        .map(|sub| router.is_subscribed(&client.clid, &sub.topic))
+        .collect();
+    let mut return_codes = match router.subscribe(&client.clid, &sub_packet.list) {
         Ok(res) => res,
-        Err(_err) => {
-            println!("Malformed");
-            unimplemented!()
-        }
+        Err(err) => return close_with(client, &err.into())
     };
 
+    // rejected topic filter is not kept in the session
+    let (accepted, existed): (Vec<Subscribe>, Vec<bool>) = sub_packet.list.into_iter()
+        .zip(existed)
+        .zip(return_codes.iter())
+        .filter(|(_, code)| code.is_ok())
+        .map(|(sub, _)| sub)
+        .unzip();
+
+    // subscription that is not persisted is refused as well
+    let persisted = client.storage.clone()
+        .subscribe(&accepted)
+        .await
+        .map_err(BrokerError::from);
+    if let Err(err) = &persisted {
+        println!("[Client] {} subscription is not persisted: {}", client.clid, err);
+        // subscription made before this packet is still persisted
+        let topics: Vec<String> = accepted.iter()
+            .zip(existed.iter())
+            .filter(|(_, existed)| !**existed)
+            .map(|(s, _)| s.topic.clone())
+            .collect();
+        router.unsubscribe(&client.clid, &topics);
+        return_codes.iter_mut()
+            .filter(|code| code.is_ok())
+            .for_each(|code| *code = Err(err.into()));
+    }
+
     let response = SubsAck{
         id: sub_packet.id,
         properties: None,
-        return_codes: recode
+        return_codes
     };
+    send_encoded(client, client.protocol.suback(&response));
+    if persisted.is_err() {
+        return;
+    }
+
+    // retained message is sent after SUBACK
+    for (sub, existed) in accepted.iter().zip(existed) {
+        let send = match sub.options.retain_handling {
+            RetainHandling::OnSubscribe => true,
+            RetainHandling::OnNewSubscribe => !existed,
+            RetainHandling::Never => false
+        };
 
-    let buffer = response.encode().unwrap();
-    let save = client.storage.clone();
-    let save = save.subscribe(&sub_packet.list);
-    let net = client.socket.write_all(&buffer);
-    let (save, net) = tokio::join!(net, save);
-    net.unwrap();
-    save.unwrap();
+        if !send {
+            continue;
+        }
+
+        if let Err(err) = clients.deliver_retained(&client.clid, sub).await {
+            println!("[Client] {} retained message error: {}", client.clid, err);
+        }
+    }
 }
 
-async fn observer<RO, DM, F, S> (
+/// start dispatch worker for each queue shard, stop them on shutdown
+async fn observer<RO, F, S> (
     spawner: S,
     router: RO, 
-    msg_queue: DM,
+    msg_queue: ShardedQueue,
+    msg_log: MessageLog,
+    expired: ExpiredCounter,
+    retained: RetainedStore,
     forwarder: F,
 ) where 
     S: Cleanup,
-    RO: TopicRouter + Send + Sync + 'static,
-    DM: GetFromQueue<Message> + Send + Sync + Cleanup + 'static,
+    RO: TopicRouter + Clone + Send + Sync + 'static,
     F: SendStrategy + Send + Sync + Clone + Cleanup + 'static,
 {
-    println!("[observer] start");
-    'observer: loop {
-        select! {
-            _ = signal::ctrl_c() => {
-                spawner.clear().await;
-                forwarder.clear().await;
-                msg_queue.clear().await;
-                break 'observer;
+    println!("[observer] start {} dispatch worker", msg_queue.len());
+    let workers: Vec<JoinHandle<()>> = (0..msg_queue.len())
+        .map(|i| tokio::spawn(dispatch(
+            msg_queue.shard(i),
+            router.clone(),
+            msg_log.clone(),
+            expired.clone(),
+            retained.clone(),
+            forwarder.clone()
+        )))
+        .collect();
+
+    let _ = signal::ctrl_c().await;
+    workers.iter().for_each(|w| w.abort());
+    spawner.clear().await;
+    forwarder.clear().await;
+    msg_queue.clear().await;
+    msg_log.clear().await;
+    println!("[observer] shutdown");
+}
+
+/// forward message one by one, next message wait until
+/// every subscriber of the previous one is served
+async fn dispatch<RO, DM, F>(
+    msg_queue: DM, 
+    router: RO, 
+    msg_log: MessageLog, 
+    expired: ExpiredCounter, 
+    retained: RetainedStore, 
+    forwarder: F
+)
+where 
+    RO: TopicRouter + Send + Sync + 'static,
+    DM: GetFromQueue<Message> + Send + Sync + 'static,
+    F: SendStrategy + Send + Sync + Clone + 'static,
+{
+    loop {
+        let msg = match msg_queue.dequeue().await {
+            Ok(v) => v,
+            Err(_) => continue
+        };
+
+        let subs = match msg.is_expired(sys_now()) {
+            true => {
+                expired.queued();
+                Vec::new()
             },
-            msg = msg_queue.dequeue() => {
-                let msg = match msg {
-                    Ok(v) => v,
-                    Err(_) => continue 'observer
-                };
-                println!("{:?}", msg.packet);
-                let subs = match router.route(&msg.packet.topic) {
-                    None => {
-                        println!("no subscriber");
-                        continue 'observer
-                    },
-                    Some(s) => s
-                };
-                
-                let fwd = forwarder.clone();
-                let order = Publish{
-                    msg, subs
-                };
+            false => {
+                // worker own the topic, retained message is replaced in publish order
+                if msg.packet.retain {
+                    retained.store(&msg);
+                }
+                router.route(&msg.packet.topic, msg.publisher.as_ref())
+            }
+        };
+        if subs.is_empty() {
+            if let Some(seq) = msg.seq {
+                msg_log.delivered(seq);
+            }
+            continue
+        }
+
+        let order = Publish{
+            msg, subs
+        };
+        order.forward(forwarder.clone(), msg_log.clone()).await;
+    }
+}
+
+#[derive(Debug, Default)]
+struct SweepStats {
+    clients: usize,
+    subscriptions: usize,
+    sessions: usize,
+}
 
-                tokio::spawn(order.forward(fwd));
+/// periodically drop expired session from memory, router and disk
+async fn sweeper<RO>(tasks: Tasks, clients: Clients, router: RO, store: SessionBackend, expired: ExpiredCounter, interval: Duration)
+where RO: TopicRouter + Send + Sync + 'static
+{
+    let mut tick = tokio::time::interval(interval);
+    // first tick is completed immediately
+    tick.tick().await;
+    println!("[sweeper] start, every {:?}", interval);
+    loop {
+        select! {
+            _ = signal::ctrl_c() => break,
+            _ = tick.tick() => {
+                let stats = sweep(&tasks, &clients, &router, &store).await;
+                println!(
+                    "[sweeper] evicted {} client, {} subscription, {} session",
+                    stats.clients, stats.subscriptions, stats.sessions
+                );
+                let expired = expired.stats();
+                println!(
+                    "[sweeper] expired {} queued, {} undelivered message",
+                    expired.queued, expired.undelivered
+                );
             }
         }
     }
-    println!("[observer] shutdown");
+    println!("[sweeper] shutdown");
+}
+
+async fn sweep<RO>(tasks: &Tasks, clients: &Clients, router: &RO, store: &SessionBackend) -> SweepStats 
+where RO: TopicRouter
+{
+    let t = sys_now();
+    let mut stats = SweepStats::default();
+    let mut expired = Vec::new();
+    for clid in clients.expired(t).await {
+        if let Some(storage) = tasks.evict(clients, &clid, t).await {
+            stats.clients += 1;
+            expired.push((clid, storage));
+        }
+    }
+
+    // session only persisted on storage, like one from previous run
+    match ClientStore::expired(store).await {
+        Ok(stored) => for (clid, storage) in stored {
+            if !expired.iter().any(|(id, _)| id.eq(&clid)) {
+                expired.push((clid, storage));
+            }
+        },
+        Err(err) => println!("[sweeper] failed to scan session: {}", err)
+    }
+
+    for (clid, storage) in expired {
+        match storage.clone().subscriptions().await {
+            Ok(subs) => {
+                let topics: Vec<String> = subs.into_iter()
+                    .map(|s| s.topic)
+                    .collect();
+                stats.subscriptions += topics.len();
+                router.unsubscribe(&clid, &topics);
+            },
+            Err(err) => println!("[sweeper] {} subscription error: {}", clid, err)
+        }
+
+        match storage.remove().await {
+            Ok(()) => stats.sessions += 1,
+            Err(err) => println!("[sweeper] {} remove error: {}", clid, err)
+        }
+    }
+
+    router.clean();
+    stats
 }
 
 struct Publish {
     msg: Message, 
-    subs: SubscriberInstance
+    subs: Vec<Recipient>
 }
 
 impl Publish {
-    async fn forward<F>(self, forwarder: F)
+    async fn forward<F>(self, forwarder: F, msg_log: MessageLog)
     where 
         F: SendStrategy + Send + Sync + Clone + 'static,
     {
-        let publisher_id = self.msg.publisher;
-        let packet = self.msg.packet;
-        let subs = self.subs;
+        let seq = self.msg.seq;
+        let expire_at = self.msg.expire_at;
+        let mut packet = self.msg.packet;
+        let published_retain = packet.retain;
+        // encoded once for each QoS and retain flag, payload is shared by every frame
+        let mut frames: [[Option<PublishFrame>; 2]; 3] = Default::default();
+
+        for subs in self.subs.iter() {
+            // Downgrade qos by max qos
+            let qos = packet.qos.code().min(subs.max_qos.code());
+            let qos = ServiceLevel::try_from(qos)
+                .unwrap_or_default();
+            let retain = published_retain && subs.retain_as_published;
+
+            let frame = match &mut frames[qos.code() as usize][retain as usize] {
+                Some(frame) => frame,
+                slot => {
+                    // packet identifier is replaced by the subscriber session
+                    packet.packet_id = match qos {
+                        ServiceLevel::QoS0 => None,
+                        _ => Some(0)
+                    };
+                    packet.qos = qos.clone();
+                    packet.retain = retain;
+                    let mut frame = match packet.encode_frame() {
+                        Ok(frame) => frame,
+                        Err(err) => {
+                            println!("[forward] {} {}", subs.clid, BrokerError::Encode(err));
+                            continue;
+                        }
+                    };
+                    frame.expire_at = expire_at;
+                    slot.insert(frame)
+                }
+            };
 
-        // Downgrade qos by max qos
-        let qos = packet.qos.code().min(subs.max_qos.code());
-        let qos = ServiceLevel::try_from(qos)
-            .unwrap_or_default();
+            let tagged;
+            let frame = match subs.subscription_identifiers.is_empty() {
+                true => frame,
+                false => match frame.with_subscription_identifiers(&subs.subscription_identifiers) {
+                    Ok(frame) => {
+                        tagged = frame;
+                        &tagged
+                    },
+                    Err(err) => {
+                        println!("[forward] {} subscription identifier error: {}", subs.clid, err);
+                        continue;
+                    }
+                }
+            };
 
-        if let v5::ServiceLevel::QoS0 = &qos {
-            let buffer = packet.encode().unwrap();
-            forwarder.qos0(&subs.clid, &buffer).await;
-            return ;
-        }
+            let res = match qos {
+                ServiceLevel::QoS0 => {
+                    forwarder.qos0(&subs.clid, frame).await;
+                    Ok(())
+                },
+                ServiceLevel::QoS1 => forwarder.qos1(&subs.clid, frame).await,
+                ServiceLevel::QoS2 => forwarder.qos2(&subs.clid, frame).await,
+            };
 
-        let publisher_id = publisher_id.unwrap();
-        let packet_id = packet.packet_id.unwrap();
-        let buffer = packet.encode().unwrap();
+            if let Err(err) = res {
+                println!("[forward] {} error: {}", subs.clid, err);
+            }
+        }
 
-        let _res = match qos {
-            ServiceLevel::QoS1 => forwarder.qos1(&publisher_id, &subs.clid, packet_id, &buffer).await,
-            ServiceLevel::QoS2 => forwarder.qos2(&publisher_id, &subs.clid, packet_id, &buffer).await,
-            _ => {return ;}
-        };
+        if let Some(seq) = seq {
+            msg_log.delivered(seq);
+        }
     }
-}
\ No newline at end of file
+}
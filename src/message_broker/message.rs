@@ -1,4 +1,4 @@
-use std::{future::Future, io, marker::PhantomPinned, pin::Pin, ptr, sync::{atomic::{AtomicPtr, Ordering}, Arc}, task::{Context, Poll, Waker}};
+use std::{future::Future, hash::{DefaultHasher, Hash, Hasher}, io, marker::PhantomPinned, pin::Pin, ptr, sync::{atomic::{AtomicPtr, AtomicU64, Ordering}, Arc}, task::{Context, Poll, Waker}};
 
 use pin_project_lite::pin_project;
 
@@ -8,7 +8,49 @@ use super::{cleanup::Cleanup, client::clobj::ClientID};
 #[derive(Default)]
 pub struct Message {
     pub publisher: Option<ClientID>,
-    pub packet: PublishPacket
+    pub packet: PublishPacket,
+    /// sequence on message log, none when message is not logged
+    pub seq: Option<u64>,
+    /// time the message expire, from message expiry interval when received
+    pub expire_at: Option<u64>,
+}
+
+impl Message {
+    pub fn is_expired(&self, t: u64) -> bool {
+        self.expire_at.is_some_and(|at| at <= t)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ExpiryStats {
+    /// expired on broker queue, before routed to any subscriber
+    pub queued: u64,
+    /// expired while waiting for subscriber
+    pub undelivered: u64,
+}
+
+/// Number of message discarded because its expiry interval elapsed.
+#[derive(Debug, Clone, Default)]
+pub struct ExpiredCounter {
+    queued: Arc<AtomicU64>,
+    undelivered: Arc<AtomicU64>,
+}
+
+impl ExpiredCounter {
+    pub fn queued(&self) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn undelivered(&self) {
+        self.undelivered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> ExpiryStats {
+        ExpiryStats {
+            queued: self.queued.load(Ordering::Relaxed),
+            undelivered: self.undelivered.load(Ordering::Relaxed)
+        }
+    }
 }
 
 pub struct Queue{
@@ -55,6 +97,51 @@ impl GetFromQueue<Message> for Queue {
     }
 }
 
+/// Queue for each dispatch worker.
+/// 
+/// message is placed by hash of its topic, so message on the same topic
+/// is always handled by the same worker in publish order
+#[derive(Clone)]
+pub struct ShardedQueue {
+    shards: Arc<[Queue]>
+}
+
+impl ShardedQueue {
+    pub fn new(n: usize) -> Self {
+        Self { shards: (0..n.max(1)).map(|_| Queue::new()).collect() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// queue consumed by worker `i`
+    pub fn shard(&self, i: usize) -> Queue {
+        self.shards[i].clone()
+    }
+
+    pub fn shard_of(&self, topic: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        topic.hash(&mut hasher);
+        hasher.finish() as usize % self.shards.len()
+    }
+}
+
+impl InsertQueue<Message> for ShardedQueue {
+    fn enqueue(&self, val: Message) {
+        let i = self.shard_of(&val.packet.topic);
+        self.shards[i].enqueue(val)
+    }
+}
+
+impl Cleanup for ShardedQueue {
+    async fn clear(self) {
+        for shard in self.shards.iter() {
+            shard.clone().clear().await;
+        }
+    }
+}
+
 
 impl MQueue {
     fn get<'a>(&'a self) -> DequeueMessage {
@@ -101,4 +188,40 @@ impl Future for DequeueMessage<'_> {
         }
         Poll::Pending
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use crate::{ds::{GetFromQueue, InsertQueue}, protocol::v5::publish::PublishPacket};
+    use super::{Message, ShardedQueue};
+
+    fn message(topic: &str, payload: u8) -> Message {
+        Message {
+            packet: PublishPacket {
+                topic: topic.to_string(),
+                payload: Bytes::from(vec![payload]),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn same_topic_same_worker() {
+        let queue = ShardedQueue::new(4);
+        for i in 0..3 {
+            queue.enqueue(message("a/b", i));
+            queue.enqueue(message("c/d", i));
+        }
+
+        let shard = queue.shard(queue.shard_of("a/b"));
+        let mut payloads = Vec::new();
+        while payloads.len() < 3 {
+            let msg = shard.dequeue().await.unwrap();
+            if msg.packet.topic == "a/b" {
+                payloads.push(msg.packet.payload[0]);
+            }
+        }
+        assert_eq!(payloads, vec![0, 1, 2]);
+    }
+}
@@ -1,39 +1,103 @@
-use std::{future::Future, io};
+use std::{collections::HashMap, future::Future, io, thread, time::Duration};
 
-use client::clobj::ClientID;
+use client::{backend::StorageConfig, clobj::{ClientID, OutboundLimit}, offline::OfflineLimit};
+use wal::WalConfig;
+use bytes::Bytes;
+use crate::protocol::v5::{publish::PublishFrame, ServiceLevel};
 
-mod msg_state;
+pub mod acl;
+pub mod msg_state;
 pub mod client;
 pub mod mediator;
 pub mod cleanup;
 mod message;
+mod retained;
 mod router;
+pub mod wal;
 
 pub const MAX_QOS: u8 = 2;
-pub const WILDCARD_SUPPORT: bool = false;
-pub const SUBS_ID_SUPPORT: bool = false;
+/// alias accepted from each client
+pub const TOPIC_ALIAS_MAXIMUM: u16 = 16;
+pub const WILDCARD_SUPPORT: bool = true;
+pub const SUBS_ID_SUPPORT: bool = true;
 pub const SHARED_SUBS_SUPPORT: bool = false;
 
+/// limit the broker advertise to every client on CONNACK
+#[derive(Debug, Clone, Copy)]
+pub struct InboundLimit {
+    pub maximum_packet_size: u32,
+    /// QoS 2 publish waiting for PUBREL at once
+    pub receive_maximum: u16,
+}
+
+impl Default for InboundLimit {
+    fn default() -> Self {
+        Self {
+            maximum_packet_size: 256 * 1024,
+            receive_maximum: 100
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BrokerConfig {
+    pub inbound: InboundLimit,
+    pub offline: OfflineLimit,
+    /// packet waiting to be written to each connected client
+    pub outbound: OutboundLimit,
+    /// outbound limit of client id that replace the default one
+    pub outbound_clients: HashMap<String, OutboundLimit>,
+    pub wal: WalConfig,
+    pub storage: StorageConfig,
+    /// how often expired session is swept
+    pub sweep_interval: Duration,
+    /// number of task forwarding published message
+    pub dispatch_workers: usize,
+    /// prefix of identifier assigned to client that connect with empty id
+    pub client_id_prefix: String,
+    pub admission: Admission,
+}
+
+/// client accepted on CONNECT
+#[derive(Debug, Clone, Default)]
+pub struct Admission {
+    /// connected client at once, 0 is unlimited
+    pub max_clients: usize,
+    /// client id refused with banned reason code
+    pub banned: Vec<String>,
+}
+
+impl Default for BrokerConfig {
+    fn default() -> Self {
+        Self {
+            inbound: InboundLimit::default(),
+            offline: OfflineLimit::default(),
+            outbound: OutboundLimit::default(),
+            outbound_clients: HashMap::new(),
+            wal: WalConfig::default(),
+            storage: StorageConfig::default(),
+            sweep_interval: Duration::from_secs(60),
+            dispatch_workers: thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(4),
+            client_id_prefix: "sipusu".to_string(),
+            admission: Admission::default()
+        }
+    }
+}
+
 pub trait SendStrategy: Forwarder + Send + Sync
 {
-    fn qos0(&self, subscriber: &ClientID, buffer: &[u8]) -> impl Future<Output = ()> + Send;
-    fn qos1(
-        &self, 
-        publisher: &ClientID, 
-        subscriber: &ClientID, 
-        packet_id: u16, 
-        buffer: &[u8]
-    ) -> impl Future<Output = io::Result<()>> + Send;
-
-    fn qos2(
-        &self, 
-        publisher: &ClientID, 
-        subscriber: &ClientID, 
-        packet_id: u16, 
-        buffer: &[u8]
-    ) -> impl Future<Output = io::Result<()>> + Send;
+    fn qos0(&self, subscriber: &ClientID, frame: &PublishFrame) -> impl Future<Output = ()> + Send;
+    /// packet identifier is assigned by the subscriber session
+    fn qos1(&self, subscriber: &ClientID, frame: &PublishFrame) -> impl Future<Output = io::Result<()>> + Send;
+    /// packet identifier is assigned by the subscriber session
+    fn qos2(&self, subscriber: &ClientID, frame: &PublishFrame) -> impl Future<Output = io::Result<()>> + Send;
 }
 
 pub trait Forwarder {
-    fn pubish(&self, clid: &ClientID, packet: &[u8]) -> impl Future<Output = io::Result<()>> + Send;
+    fn pubish(&self, clid: &ClientID, packet: Bytes) -> impl Future<Output = io::Result<()>> + Send;
+    /// publish to subscriber, when the subscriber session is persisted 
+    /// but not connected message is kept on offline queue
+    fn deliver(&self, clid: &ClientID, qos: &ServiceLevel, frame: &PublishFrame) -> impl Future<Output = io::Result<()>> + Send;
 }
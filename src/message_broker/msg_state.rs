@@ -1,121 +1,394 @@
-use std::sync::Arc;
-use tokio::sync::RwLock;
-use crate::helper::time::sys_now;
+use std::{collections::HashMap, sync::{Arc, RwLock}};
+use bytes::{Bytes, BytesMut};
+use tokio::{io, sync::Mutex};
+use crate::protocol::{mqtt::Protocol, v5::{puback::{PubACKType, PubackPacket}, publish::{set_packet_id, PublishFrame}}};
+use super::client::{clobj::ClientID, storage::{ClientStore, InFlight, InFlightChange}};
 
-use super::client::clobj::ClientID;
-
-#[derive(Clone)]
-pub struct MessageCoordinator {
-    data: Arc<RwLock<Vec<Map>>>
-}
-
-impl MessageCoordinator {
-    pub fn new() -> Self {
-        Self { 
-            data: Arc::new(RwLock::new(Vec::new())) 
-        }
-    }
-}
+/// PUBREC or PUBREL refer to packet identifier that is not in-flight
+const PACKET_ID_NOT_FOUND: u8 = 0x92;
 
+/// change appended below this size is never rewritten as snapshot
+const SNAPSHOT_MIN: usize = 64 * 1024;
 
+/// step of QoS handshake that is not complete yet
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MsgState {
-    PubRec,
-    PubRel,
-    Publish,
+    /// QoS 2 publish received and PUBREC sent, wait PUBREL from publisher
+    Received,
+    /// publish sent to subscriber, wait PUBACK
+    AwaitAck,
+    /// QoS 2 publish sent to subscriber, wait PUBREC
+    AwaitRec,
+    /// PUBREL sent to subscriber, wait PUBCOMP
+    AwaitComp,
 }
 
 impl MsgState {
-    fn step(&self) -> u8 {
-        match &self {
-            Self::PubRec => 0,
-            Self::PubRel => 1,
-            Self::Publish => 2
+    fn code(&self) -> u8 {
+        match self {
+            Self::Received => 0x01,
+            Self::AwaitAck => 0x02,
+            Self::AwaitRec => 0x03,
+            Self::AwaitComp => 0x04,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0x01 => Some(Self::Received),
+            0x02 => Some(Self::AwaitAck),
+            0x03 => Some(Self::AwaitRec),
+            0x04 => Some(Self::AwaitComp),
+            _ => None
         }
     }
 
-    fn last_step() -> u8 {
-        MsgState::Publish.step()
+    /// handshake started by broker, identifier is owned by broker
+    fn is_outgoing(&self) -> bool {
+        !matches!(self, Self::Received)
     }
 }
 
-pub struct Map {
-    clid: ClientID,
-    val: RwLock<Vec<SaveState>>
+struct Map {
+    storage: ClientStore,
+    next_id: u16,
+    inflight: Vec<InFlight>,
+    /// size of change appended since the last snapshot
+    logged: usize,
 }
 
-pub struct SaveState {
-    packet_id: u16,
-    ack: MsgState,
-    expired_at: u64
+impl Map {
+    fn position(&self, packet_id: u16, state: MsgState) -> Option<usize> {
+        self.inflight.iter().position(|i| {
+            i.packet_id == packet_id && i.state == state.code()
+        })
+    }
+
+    /// apply change then write it through to session storage, only the change
+    /// is appended until it grows twice as big as the state itself
+    async fn change(&mut self, changes: Vec<InFlightChange>) -> io::Result<()> {
+        for change in changes.iter() {
+            match change {
+                InFlightChange::Put(inflight) => self.inflight.push(inflight.clone()),
+                InFlightChange::Remove { packet_id, state } => self.inflight
+                    .retain(|i| i.packet_id != *packet_id || i.state != *state),
+                InFlightChange::Replace { state, with } => {
+                    let found = self.inflight.iter_mut()
+                        .find(|i| i.packet_id == with.packet_id && i.state == *state);
+                    match found {
+                        Some(inflight) => *inflight = with.clone(),
+                        None => self.inflight.push(with.clone())
+                    }
+                }
+            }
+        }
+
+        let live: usize = self.inflight.iter().map(|i| i.est_len()).sum();
+        self.logged += changes.iter().map(|c| c.est_len()).sum::<usize>();
+        if self.logged > SNAPSHOT_MIN.max(live * 2) {
+            self.logged = live;
+            return self.storage.clone().save_inflight(&self.inflight).await;
+        }
+        self.storage.clone().change_inflight(&changes).await
+    }
+
+    fn next_packet_id(&mut self) -> io::Result<u16> {
+        for _ in 0..u16::MAX {
+            let id = self.next_id;
+            self.next_id = self.next_id.checked_add(1).unwrap_or(1);
+            let in_use = self.inflight.iter().any(|i| {
+                i.packet_id == id && MsgState::from_code(i.state).is_some_and(|s| s.is_outgoing())
+            });
+            if !in_use {
+                return Ok(id);
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::WouldBlock, "no packet identifier available"))
+    }
 }
 
-pub enum MsgAckErrors {
-    NotFound,
-    AlreadyExists,
-    StateExpired,
-    InvalidResolveState
+/// In-flight QoS 1 and 2 state of every session, both direction.
+///
+/// every change is written through to session storage,
+/// so handshake can be continued after broker restart
+#[derive(Clone)]
+pub struct MessageCoordinator {
+    data: Arc<RwLock<HashMap<String, Arc<Mutex<Map>>>>>
 }
 
 impl MessageCoordinator {
-    pub async fn create(&self, clid: ClientID, packet_id: u16, expr_intrval_sec: u64) -> Result<(), MsgAckErrors> {
-        let state = SaveState {
-            packet_id,
-            ack: MsgState::PubRec,
-            expired_at: sys_now() + expr_intrval_sec
+    pub fn new() -> Self {
+        Self {
+            data: Arc::new(RwLock::new(HashMap::new()))
+        }
+    }
+
+    fn entry(&self, clid: &ClientID) -> io::Result<Arc<Mutex<Map>>> {
+        let data = self.data.read().unwrap();
+        data.get(&clid.to_string())
+            .cloned()
+            .ok_or(io::Error::new(io::ErrorKind::NotFound, format!("no in-flight state for {}", clid)))
+    }
+
+    /// start tracking session, state of resumed session is loaded from storage
+    pub async fn load(&self, clid: &ClientID, storage: ClientStore) -> io::Result<()> {
+        let inflight = storage.clone().inflight().await?;
+        let next_id = inflight.iter()
+            .map(|i| i.packet_id)
+            .max()
+            .unwrap_or_default()
+            .checked_add(1)
+            .unwrap_or(1);
+
+        let logged = inflight.iter().map(|i| i.est_len()).sum();
+        let map = Map { storage, next_id, inflight, logged };
+        let mut data = self.data.write().unwrap();
+        data.insert(clid.to_string(), Arc::new(Mutex::new(map)));
+        Ok(())
+    }
+
+    pub fn forget(&self, clid: &ClientID) {
+        let mut data = self.data.write().unwrap();
+        data.remove(&clid.to_string());
+    }
+
+    pub fn is_tracked(&self, clid: &ClientID) -> bool {
+        self.entry(clid).is_ok()
+    }
+
+    /// QoS 2 publish is already received and waiting for PUBREL
+    pub async fn is_received(&self, clid: &ClientID, packet_id: u16) -> bool {
+        let entry = match self.entry(clid) {
+            Ok(entry) => entry,
+            Err(_) => return false
         };
-    
-        let mut data = self.data.write().await;
-        for v in data.iter() {
-            if !v.clid.eq(&clid) {
-                continue;
-            }
-            let mut mval = v.val.write().await;
-            for smv in mval.iter() {
-                if smv.packet_id == packet_id {
-                    return Err(MsgAckErrors::AlreadyExists);
-                }
-            }
-            mval.push(state);
+        let map = entry.lock().await;
+        map.position(packet_id, MsgState::Received).is_some()
+    }
+
+    /// QoS 1 and 2 publish sent to the session that is not completely acknowledged
+    pub async fn outgoing_count(&self, clid: &ClientID) -> usize {
+        let entry = match self.entry(clid) {
+            Ok(entry) => entry,
+            Err(_) => return 0
+        };
+        let map = entry.lock().await;
+        map.inflight.iter()
+            .filter(|i| MsgState::from_code(i.state).is_some_and(|s| s.is_outgoing()))
+            .count()
+    }
+
+    /// QoS 2 publish of the session that is not released yet
+    pub async fn received_count(&self, clid: &ClientID) -> usize {
+        let entry = match self.entry(clid) {
+            Ok(entry) => entry,
+            Err(_) => return 0
+        };
+        let map = entry.lock().await;
+        map.inflight.iter()
+            .filter(|i| i.state == MsgState::Received.code())
+            .count()
+    }
+
+    /// keep QoS 2 publish identifier until publisher release it
+    pub async fn received(&self, clid: &ClientID, packet_id: u16) -> io::Result<()> {
+        let entry = self.entry(clid)?;
+        let mut map = entry.lock().await;
+        if map.position(packet_id, MsgState::Received).is_some() {
             return Ok(());
         }
-        
-        data.push(Map {
-            clid,
-            val: RwLock::new(vec![state])
-        });
-        Ok(())
+
+        map.change(vec![InFlightChange::Put(InFlight {
+            packet_id,
+            state: MsgState::Received.code(),
+            packet: Bytes::new()
+        })]).await
     }
 
-    pub async fn resolve(&self, clid: ClientID, packet_id: u16, ack: MsgState) -> Result<(), MsgAckErrors> {
-        let data = self.data.read().await;
-        for v in data.iter() {
-            if v.clid.eq(&clid) {
-                continue;
-            }
+    /// assign packet identifier of the receiver session to encoded publish,
+    /// packet is kept until the handshake complete
+    pub async fn outgoing(&self, clid: &ClientID, state: MsgState, frame: &PublishFrame) -> io::Result<PublishFrame> {
+        let entry = self.entry(clid)?;
+        let mut map = entry.lock().await;
+        let packet_id = map.next_packet_id()?;
+        let frame = frame.with_packet_id(packet_id, false)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
 
-            let now = sys_now();
-            let mut mval = v.val.write().await;
-            
-            for smv in mval.iter_mut() {
-                if smv.packet_id == packet_id {
-                    let next_step = smv.ack.step() + 1;
-                    if next_step == ack.step() {
-                        if MsgState::last_step() == next_step {
-                            // TODO: Remove
-                        }
-
-                        if now > smv.expired_at {
-                            // Remove
-                            return Err(MsgAckErrors::StateExpired);
-                        }
-                        smv.ack = ack;
-                        return Ok(());
-                    } else {
-                        return Err(MsgAckErrors::InvalidResolveState);
-                    }
+        map.change(vec![InFlightChange::Put(InFlight {
+            packet_id,
+            state: state.code(),
+            packet: frame.to_bytes()
+        })]).await?;
+        Ok(frame)
+    }
+
+    /// move handshake forward, give back the response that must be sent
+    pub async fn acknowledge(&self, clid: &ClientID, ack: &PubackPacket) -> io::Result<Option<PubackPacket>> {
+        let entry = self.entry(clid)?;
+        let mut map = entry.lock().await;
+        let response = |packet_type, reason_code| Some(PubackPacket {
+            packet_type,
+            packet_id: ack.packet_id,
+            reason_code,
+            properties: None
+        });
+
+        let (state, reply) = match ack.packet_type {
+            PubACKType::PubAck => (MsgState::AwaitAck, None),
+            PubACKType::PubComp => (MsgState::AwaitComp, None),
+            PubACKType::PubRel => (MsgState::Received, Some(PubACKType::PubComp)),
+            PubACKType::PubRec => {
+                if map.position(ack.packet_id, MsgState::AwaitRec).is_none() {
+                    return Ok(response(PubACKType::PubRel, PACKET_ID_NOT_FOUND));
                 }
+
+                // receiver refuse the message, handshake is ended
+                if ack.reason_code >= 0x80 {
+                    map.change(vec![InFlightChange::Remove {
+                        packet_id: ack.packet_id,
+                        state: MsgState::AwaitRec.code()
+                    }]).await?;
+                    return Ok(None);
+                }
+
+                map.change(vec![InFlightChange::Replace {
+                    state: MsgState::AwaitRec.code(),
+                    with: InFlight {
+                        packet_id: ack.packet_id,
+                        state: MsgState::AwaitComp.code(),
+                        packet: Bytes::new()
+                    }
+                }]).await?;
+                return Ok(response(PubACKType::PubRel, 0x00));
             }
+        };
+
+        let found = map.position(ack.packet_id, state).is_some();
+        if found {
+            map.change(vec![InFlightChange::Remove { packet_id: ack.packet_id, state: state.code() }]).await?;
         }
-        Err(MsgAckErrors::NotFound)
+
+        Ok(reply.and_then(|packet_type| {
+            let reason_code = if found { 0x00 } else { PACKET_ID_NOT_FOUND };
+            response(packet_type, reason_code)
+        }))
+    }
+
+    /// packet that must be resent when session is resumed, in order.
+    /// PUBREL is encoded for the protocol of the new connection
+    pub async fn pending(&self, clid: &ClientID, protocol: Protocol) -> io::Result<Vec<Bytes>> {
+        let entry = self.entry(clid)?;
+        let map = entry.lock().await;
+        let mut pending = Vec::with_capacity(map.inflight.len());
+        for i in map.inflight.iter() {
+            match MsgState::from_code(i.state) {
+                Some(MsgState::AwaitAck) | Some(MsgState::AwaitRec) => {
+                    let mut packet = BytesMut::from(i.packet.as_ref());
+                    set_packet_id(&mut packet, i.packet_id, true)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    pending.push(packet.freeze());
+                },
+                Some(MsgState::AwaitComp) => {
+                    let pubrel = PubackPacket {
+                        packet_type: PubACKType::PubRel,
+                        packet_id: i.packet_id,
+                        reason_code: 0x00,
+                        properties: None
+                    };
+                    let packet = protocol.ack(&pubrel)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    pending.push(packet.freeze());
+                },
+                _ => continue
+            }
+        }
+        Ok(pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use crate::{
+        message_broker::client::{backend::{MemoryStore, SessionBackend}, clobj::ClientID, storage::{ClientStore, MetaData}},
+        protocol::{mqtt::Protocol, v5::{puback::{PubACKType, PubackPacket}, publish::PublishPacket, ServiceLevel}}
+    };
+    use super::{MessageCoordinator, MsgState};
+
+    fn ack(packet_type: PubACKType, packet_id: u16) -> PubackPacket {
+        PubackPacket { packet_type, packet_id, reason_code: 0, properties: None }
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn survive_restart() {
+        let backend = SessionBackend::Memory(MemoryStore::default());
+        let clid = ClientID::new("inflight_test".to_string());
+        let mdata = MetaData::default();
+        let storage = ClientStore::new(&backend, &clid, &mdata).await.unwrap();
+
+        let coordinator = MessageCoordinator::new();
+        coordinator.load(&clid, storage.clone()).await.unwrap();
+        coordinator.received(&clid, 10).await.unwrap();
+
+        let publish = PublishPacket {
+            qos: ServiceLevel::QoS2,
+            topic: "a".to_string(),
+            packet_id: Some(99),
+            payload: Bytes::from_static(b"x"),
+            ..Default::default()
+        }.encode_frame().unwrap();
+        coordinator.outgoing(&clid, MsgState::AwaitRec, &publish).await.unwrap();
+        coordinator.outgoing(&clid, MsgState::AwaitAck, &publish).await.unwrap();
+        let pubrel = coordinator.acknowledge(&clid, &ack(PubACKType::PubRec, 1)).await.unwrap();
+        assert_eq!(pubrel.unwrap().packet_type, PubACKType::PubRel);
+
+        // broker restarted, state loaded from storage
+        let coordinator = MessageCoordinator::new();
+        coordinator.load(&clid, storage).await.unwrap();
+        assert!(coordinator.is_received(&clid, 10).await);
+        assert_eq!(coordinator.pending(&clid, Protocol::V5).await.unwrap().len(), 2);
+        // 3.1.1 PUBREL has no reason code and properties
+        let pending = coordinator.pending(&clid, Protocol::V311).await.unwrap();
+        assert_eq!(&pending[0][..], &[0x62, 0x02, 0x00, 0x01]);
+
+        let pubcomp = coordinator.acknowledge(&clid, &ack(PubACKType::PubRel, 10)).await.unwrap().unwrap();
+        assert_eq!(pubcomp.packet_type, PubACKType::PubComp);
+        assert_eq!(pubcomp.reason_code, 0);
+        let pubcomp = coordinator.acknowledge(&clid, &ack(PubACKType::PubRel, 10)).await.unwrap().unwrap();
+        assert_eq!(pubcomp.reason_code, 0x92);
+
+        coordinator.acknowledge(&clid, &ack(PubACKType::PubComp, 1)).await.unwrap();
+        coordinator.acknowledge(&clid, &ack(PubACKType::PubAck, 2)).await.unwrap();
+        assert!(coordinator.pending(&clid, Protocol::V5).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn change_replayed() {
+        let backend = SessionBackend::Memory(MemoryStore::default());
+        let clid = ClientID::new("inflight_change_test".to_string());
+        let storage = ClientStore::new(&backend, &clid, &MetaData::default()).await.unwrap();
+
+        let coordinator = MessageCoordinator::new();
+        coordinator.load(&clid, storage.clone()).await.unwrap();
+        let publish = PublishPacket {
+            qos: ServiceLevel::QoS1,
+            topic: "a".to_string(),
+            packet_id: Some(1),
+            payload: Bytes::from(vec![0; 1024]),
+            ..Default::default()
+        }.encode_frame().unwrap();
+
+        // enough change to be rewritten as snapshot a few times
+        for packet_id in 1..=500 {
+            coordinator.outgoing(&clid, MsgState::AwaitAck, &publish).await.unwrap();
+            coordinator.acknowledge(&clid, &ack(PubACKType::PubAck, packet_id)).await.unwrap();
+        }
+        coordinator.outgoing(&clid, MsgState::AwaitAck, &publish).await.unwrap();
+        let stored = storage.clone().inflight().await.unwrap();
+        assert_eq!(stored.len(), 1);
+
+        let coordinator = MessageCoordinator::new();
+        coordinator.load(&clid, storage).await.unwrap();
+        assert_eq!(coordinator.pending(&clid, Protocol::V5).await.unwrap().len(), 1);
+    }
+}
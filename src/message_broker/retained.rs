@@ -0,0 +1,126 @@
+use std::{collections::HashMap, sync::{Arc, RwLock}};
+use crate::protocol::v5::{publish::{PublishFrame, PublishPacket}, ServiceLevel};
+use super::{message::Message, router::filter_matches};
+
+struct Retained {
+    packet: PublishPacket,
+    expire_at: Option<u64>,
+}
+
+/// Last retained message of each topic.
+///
+/// kept in memory only, retained message is gone when broker stopped
+#[derive(Clone, Default)]
+pub struct RetainedStore {
+    messages: Arc<RwLock<HashMap<String, Retained>>>
+}
+
+impl RetainedStore {
+    /// replace retained message of the topic,
+    /// retained publish with empty payload remove it
+    pub fn store(&self, msg: &Message) {
+        let mut messages = self.messages.write().unwrap();
+        if msg.packet.payload.is_empty() {
+            messages.remove(&msg.packet.topic);
+            return;
+        }
+
+        let retained = Retained {
+            packet: msg.packet.clone(),
+            expire_at: msg.expire_at
+        };
+        messages.insert(msg.packet.topic.clone(), retained);
+    }
+
+    /// retained message of every topic matching the filter encoded
+    /// for new subscriber, expired message is dropped
+    pub fn frames(&self, filter: &str, max_qos: &ServiceLevel, t: u64) -> Vec<(ServiceLevel, PublishFrame)> {
+        let mut expired = Vec::new();
+        let mut frames = Vec::new();
+        {
+            let messages = self.messages.read().unwrap();
+            for (topic, retained) in messages.iter() {
+                if !filter_matches(filter, topic) {
+                    continue;
+                }
+
+                if retained.expire_at.is_some_and(|at| at <= t) {
+                    expired.push(topic.clone());
+                    continue;
+                }
+
+                if let Some(frame) = Self::encode(retained.packet.clone(), retained.expire_at, max_qos) {
+                    frames.push(frame);
+                }
+            }
+        }
+
+        if !expired.is_empty() {
+            let mut messages = self.messages.write().unwrap();
+            expired.iter().for_each(|topic| { messages.remove(topic); });
+        }
+        frames
+    }
+
+    fn encode(mut packet: PublishPacket, expire_at: Option<u64>, max_qos: &ServiceLevel) -> Option<(ServiceLevel, PublishFrame)> {
+        let qos = ServiceLevel::try_from(packet.qos.code().min(max_qos.code()))
+            .unwrap_or_default();
+        // packet identifier is replaced by the subscriber session
+        packet.packet_id = match qos {
+            ServiceLevel::QoS0 => None,
+            _ => Some(0)
+        };
+        packet.qos = qos.clone();
+        packet.dup = false;
+        packet.retain = true;
+
+        let mut frame = packet.encode_frame().ok()?;
+        frame.expire_at = expire_at;
+        Some((qos, frame))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use crate::{message_broker::message::Message, protocol::v5::{publish::PublishPacket, ServiceLevel}};
+    use super::RetainedStore;
+
+    fn retained(payload: &'static [u8], expire_at: Option<u64>) -> Message {
+        Message {
+            packet: PublishPacket {
+                qos: ServiceLevel::QoS2,
+                retain: true,
+                topic: "a/b".to_string(),
+                packet_id: Some(9),
+                payload: Bytes::from_static(payload),
+                ..Default::default()
+            },
+            expire_at,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn last_retained_message() {
+        let store = RetainedStore::default();
+        store.store(&retained(b"old", None));
+        store.store(&retained(b"new", None));
+
+        let frames = store.frames("a/b", &ServiceLevel::QoS1, 10);
+        let (qos, frame) = &frames[0];
+        assert_eq!(*qos, ServiceLevel::QoS1);
+        assert_eq!(frame.payload, Bytes::from_static(b"new"));
+        // retain flag and QoS 1
+        assert_eq!(frame.head[0], 0x33);
+        assert!(store.frames("a/c", &ServiceLevel::QoS1, 10).is_empty());
+        assert_eq!(store.frames("a/+", &ServiceLevel::QoS1, 10).len(), 1);
+
+        store.store(&retained(b"", None));
+        assert!(store.frames("#", &ServiceLevel::QoS1, 10).is_empty());
+
+        store.store(&retained(b"short", Some(20)));
+        assert_eq!(store.frames("a/b", &ServiceLevel::QoS1, 10).len(), 1);
+        assert!(store.frames("a/b", &ServiceLevel::QoS1, 20).is_empty());
+    }
+}
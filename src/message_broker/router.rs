@@ -1,11 +1,13 @@
 use std::sync::Arc;
-use crate::{ds::trie::Trie, protocol::v5::{malform::Malformed, subsack::SubAckResult, subscribe::Subscribe, ServiceLevel}};
-use super::client::clobj::ClientID;
+use crate::{ds::trie::Trie, protocol::v5::{malform::Malformed, subsack::{SubAckInvalid, SubAckResult}, subscribe::{Subscribe, SubscriptionOptions}, ServiceLevel}};
+use super::{acl::Acl, client::clobj::ClientID};
 
 #[derive(Clone)]
 pub struct SubscriberInstance {
     pub clid: ClientID,
-    pub max_qos: ServiceLevel
+    pub max_qos: ServiceLevel,
+    pub options: SubscriptionOptions,
+    pub subscription_identifier: Option<u32>,
 }
 
 impl PartialEq for SubscriberInstance {
@@ -18,18 +20,111 @@ impl PartialEq for SubscriberInstance {
     }
 }
 
+/// Every subscription of one client that match a topic,
+/// collapsed so the client get the message once.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recipient {
+    pub clid: ClientID,
+    /// highest QoS granted by the matched subscriptions
+    pub max_qos: ServiceLevel,
+    pub retain_as_published: bool,
+    pub subscription_identifiers: Vec<u32>,
+}
+
+/// collapse matched subscriptions per client, subscription with no local
+/// option is skipped when the client is the publisher
+pub fn collapse(subs: Vec<SubscriberInstance>, publisher: Option<&ClientID>) -> Vec<Recipient> {
+    let mut recipients: Vec<Recipient> = Vec::with_capacity(subs.len());
+    for sub in subs {
+        if sub.options.no_local && publisher == Some(&sub.clid) {
+            continue;
+        }
+
+        let recipient = match recipients.iter_mut().find(|r| r.clid.eq(&sub.clid)) {
+            Some(recipient) => recipient,
+            None => {
+                recipients.push(Recipient {
+                    clid: sub.clid.clone(),
+                    max_qos: sub.max_qos.clone(),
+                    retain_as_published: false,
+                    subscription_identifiers: Vec::new()
+                });
+                recipients.last_mut().unwrap()
+            }
+        };
+
+        if sub.max_qos.code() > recipient.max_qos.code() {
+            recipient.max_qos = sub.max_qos;
+        }
+        recipient.retain_as_published |= sub.options.retain_as_published;
+        if let Some(id) = sub.subscription_identifier {
+            recipient.subscription_identifiers.push(id);
+        }
+    }
+    recipients
+}
+
+/// topic filter match the topic name,
+/// `+` match one level and `#` match the rest of topic
+pub fn filter_matches(filter: &str, topic: &str) -> bool {
+    // wildcard on first level does not match topic started with '$'
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut topic_parts = topic.split('/');
+    for part in filter.split('/') {
+        if part == "#" {
+            return true;
+        }
+
+        match topic_parts.next() {
+            Some(level) if part == "+" || part == level => continue,
+            _ => return false
+        }
+    }
+    topic_parts.next().is_none()
+}
+
+/// `#` must be the last level and wildcard must occupy whole level
+fn valid_filter(filter: &str) -> bool {
+    let levels: Vec<&str> = filter.split('/').collect();
+    !filter.is_empty() && levels.iter().enumerate().all(|(i, level)| match *level {
+        "#" => i == levels.len() - 1,
+        "+" => true,
+        level => !level.contains(['#', '+'])
+    })
+}
+
 pub trait TopicRouter {
     fn subscribe(&self, clid: &ClientID, subs: &[Subscribe]) -> Result<Vec<SubAckResult>, Malformed>;
-    fn route(&self, topic: &str) -> Option<SubscriberInstance>;
+    fn unsubscribe(&self, clid: &ClientID, topics: &[String]);
+    fn is_subscribed(&self, clid: &ClientID, topic: &str) -> bool;
+    /// subscriber of the topic, one for each client
+    fn route(&self, topic: &str, publisher: Option<&ClientID>) -> Vec<Recipient>;
+    /// drop branch that has no subscriber
+    fn clean(&self);
 }
 
 impl TopicRouter for Arc<Trie<SubscriberInstance>> {
     fn subscribe(&self, clid: &ClientID, subs: &[Subscribe]) -> Result<Vec<SubAckResult>, Malformed> {
         let mut res = Vec::with_capacity(subs.len());
         for sub in subs {
+            if !valid_filter(&sub.topic) {
+                res.push(Err(SubAckInvalid::TopicFilterInvalid));
+                continue;
+            }
+
+            if !Acl::can_subscribe(clid, &sub.topic) {
+                res.push(Err(SubAckInvalid::NotAuthorized));
+                continue;
+            }
+
             let instance = SubscriberInstance {
                 clid: clid.clone(),
-                max_qos: sub.max_qos.clone()
+                max_qos: sub.max_qos.clone(),
+                options: sub.options.clone(),
+                subscription_identifier: sub.subscription_identifier
             };
 
             self.insert(&sub.topic, instance);
@@ -38,8 +133,82 @@ impl TopicRouter for Arc<Trie<SubscriberInstance>> {
 
         Ok(res)
     }
-    
-    fn route(&self, topic: &str) -> Option<SubscriberInstance> {
+
+    fn unsubscribe(&self, clid: &ClientID, topics: &[String]) {
+        for topic in topics {
+            let instance = SubscriberInstance {
+                clid: clid.clone(),
+                max_qos: ServiceLevel::default(),
+                options: SubscriptionOptions::default(),
+                subscription_identifier: None
+            };
+            self.remove(topic, instance);
+        }
+    }
+
+    fn is_subscribed(&self, clid: &ClientID, topic: &str) -> bool {
         self.get_val(topic)
+            .is_some_and(|subs| subs.iter().any(|s| s.clid.eq(clid)))
+    }
+
+    fn route(&self, topic: &str, publisher: Option<&ClientID>) -> Vec<Recipient> {
+        let mut recipients = collapse(self.matches(topic), publisher);
+        recipients.retain(|r| Acl::can_receive(&r.clid, topic));
+        recipients
     }
-}
\ No newline at end of file
+
+    fn clean(&self) {
+        self.clean_branch()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crate::{ds::trie::Trie, message_broker::client::clobj::ClientID, protocol::v5::{subscribe::{Subscribe, SubscriptionOptions}, ServiceLevel}};
+    use super::{filter_matches, TopicRouter};
+
+    fn sub(topic: &str, max_qos: ServiceLevel, id: u32, no_local: bool) -> Subscribe {
+        Subscribe {
+            topic: topic.to_string(),
+            max_qos,
+            options: SubscriptionOptions { no_local, ..Default::default() },
+            subscription_identifier: Some(id),
+        }
+    }
+
+    #[test]
+    fn overlapping_subscriptions() {
+        let router = Arc::new(Trie::new());
+        let clid = ClientID::new("clid1".to_string());
+        router.subscribe(&clid, &[
+            sub("sensors/#", ServiceLevel::QoS0, 1, false),
+            sub("sensors/+/temp", ServiceLevel::QoS2, 2, false),
+            sub("sensors/room/temp", ServiceLevel::QoS1, 3, true),
+        ]).unwrap();
+
+        let got = router.route("sensors/room/temp", None);
+        assert_eq!(got.len(), 1);
+        assert_eq!(got[0].max_qos, ServiceLevel::QoS2);
+        let mut ids = got[0].subscription_identifiers.clone();
+        ids.sort();
+        assert_eq!(ids, vec![1, 2, 3]);
+
+        // no local subscription is skipped for its own publish
+        let got = router.route("sensors/room/temp", Some(&clid));
+        let mut ids = got[0].subscription_identifiers.clone();
+        ids.sort();
+        assert_eq!(ids, vec![1, 2]);
+
+        let res = router.subscribe(&clid, &[sub("a/#/b", ServiceLevel::QoS0, 4, false)]).unwrap();
+        assert!(res[0].is_err());
+    }
+
+    #[test]
+    fn topic_filter() {
+        assert!(filter_matches("a/+/c", "a/b/c"));
+        assert!(filter_matches("a/#", "a"));
+        assert!(!filter_matches("a/+", "a/b/c"));
+        assert!(!filter_matches("#", "$SYS/uptime"));
+        assert!(filter_matches("$SYS/#", "$SYS/uptime"));
+    }
+}
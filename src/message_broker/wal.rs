@@ -0,0 +1,695 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    env,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{atomic::{AtomicU64, Ordering}, Arc},
+    time::Duration
+};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{self, AsyncWriteExt},
+    select,
+    sync::{mpsc, oneshot},
+    time
+};
+use crate::{helper::crc::crc32, protocol::v5::publish::PublishPacket};
+use super::{cleanup::Cleanup, client::clobj::ClientID, message::Message};
+
+pub const WAL_STORE: &str = ".dbg_data/wal";
+const SEGMENT_EXT: &str = "wal";
+
+const RECORD_PUBLISH: u8 = 0x01;
+const RECORD_DELIVERED: u8 = 0x02;
+
+/// body length (4 bytes) | crc32 of body (4 bytes)
+const HEADER_LEN: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FsyncPolicy {
+    /// fsync every appended message
+    Always,
+    /// group commit, fsync once for up to n message that already waiting
+    Batched(usize),
+    /// fsync on interval, append is acknowledged on the next tick
+    Interval(Duration),
+}
+
+/// parse `always`, `batched:<n>` or `interval:<ms>`
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, arg) = match s.split_once(':') {
+            Some((kind, arg)) => (kind, Some(arg)),
+            None => (s, None)
+        };
+
+        let num = |default: u64| -> Result<u64, String> {
+            match arg {
+                Some(v) => v.parse().map_err(|_| format!("invalid fsync argument {}", v)),
+                None => Ok(default)
+            }
+        };
+
+        match kind {
+            "always" => Ok(Self::Always),
+            "batched" => Ok(Self::Batched(num(64)? as usize)),
+            "interval" => Ok(Self::Interval(Duration::from_millis(num(100)?))),
+            _ => Err(format!("unknown fsync policy {}", kind))
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WalConfig {
+    pub fsync: FsyncPolicy,
+    pub segment_size: u64,
+}
+
+impl Default for WalConfig {
+    fn default() -> Self {
+        Self {
+            fsync: FsyncPolicy::Batched(64),
+            segment_size: 16 * 1024 * 1024
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Record {
+    Publish {
+        seq: u64,
+        publisher: Option<ClientID>,
+        /// zero when message never expire
+        expire_at: u64,
+        packet: Bytes
+    },
+    Delivered {
+        seq: u64
+    },
+}
+
+impl Record {
+    fn serialize(&self, buffer: &mut BytesMut) {
+        let mut body = BytesMut::new();
+        match self {
+            Record::Publish { seq, publisher, expire_at, packet } => {
+                let publisher = publisher.as_ref()
+                    .map(|p| p.to_string())
+                    .unwrap_or_default();
+
+                body.put_u8(RECORD_PUBLISH);
+                body.put_u64(*seq);
+                body.put_u16(publisher.len() as u16);
+                body.put(publisher.as_bytes());
+                body.put_u64(*expire_at);
+                body.put(packet.as_ref());
+            },
+            Record::Delivered { seq } => {
+                body.put_u8(RECORD_DELIVERED);
+                body.put_u64(*seq);
+            }
+        }
+
+        buffer.put_u32(body.len() as u32);
+        buffer.put_u32(crc32(&body));
+        buffer.put(body);
+    }
+
+    /// buffer only advanced when a whole valid record is read,
+    /// torn or corrupted record give None
+    fn deserialize(buffer: &mut Bytes) -> Option<Self> {
+        if buffer.len() < HEADER_LEN {
+            return None;
+        }
+
+        let len = u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as usize;
+        let crc = u32::from_be_bytes([buffer[4], buffer[5], buffer[6], buffer[7]]);
+        if buffer.len() < HEADER_LEN + len {
+            return None;
+        }
+
+        let mut body = buffer.slice(HEADER_LEN..HEADER_LEN + len);
+        if crc32(&body) != crc || body.len() < 9 {
+            return None;
+        }
+
+        let kind = body.get_u8();
+        let seq = body.get_u64();
+        let record = match kind {
+            RECORD_PUBLISH => {
+                if body.len() < 2 {
+                    return None;
+                }
+                let plen = body.get_u16() as usize;
+                if body.len() < plen {
+                    return None;
+                }
+                let publisher = String::from_utf8(body.split_to(plen).to_vec()).ok()?;
+                let publisher = match publisher.is_empty() {
+                    true => None,
+                    false => Some(ClientID::new(publisher))
+                };
+                if body.len() < 8 {
+                    return None;
+                }
+                let expire_at = body.get_u64();
+                Record::Publish { seq, publisher, expire_at, packet: body }
+            },
+            RECORD_DELIVERED => Record::Delivered { seq },
+            _ => return None
+        };
+
+        buffer.advance(HEADER_LEN + len);
+        Some(record)
+    }
+}
+
+enum Command {
+    Append {
+        record: Record,
+        done: oneshot::Sender<io::Result<()>>
+    },
+    Delivered(u64),
+    Close(oneshot::Sender<()>),
+}
+
+/// Segmented append only log for published message.
+///
+/// message is appended before it is queued, the append return after
+/// the record is durable by the fsync policy. once message is forwarded
+/// a delivered record is written. segment is removed once it and every older
+/// segment has no undelivered message, since delivered record of older
+/// segment is written on the newer one.
+#[derive(Clone)]
+pub struct MessageLog {
+    tx: mpsc::UnboundedSender<Command>,
+    seq: Arc<AtomicU64>,
+}
+
+impl MessageLog {
+    /// open log under data directory,
+    /// undelivered message from previous run is given back in publish order
+    pub async fn open(config: WalConfig) -> io::Result<(Self, Vec<Message>)> {
+        let mut dir = env::current_dir()?;
+        dir.push(WAL_STORE);
+        Self::open_dir(dir, config).await
+    }
+
+    async fn open_dir(dir: PathBuf, config: WalConfig) -> io::Result<(Self, Vec<Message>)> {
+        fs::create_dir_all(&dir).await?;
+        let replayed = replay(&dir).await?;
+
+        let active_id = replayed.last_segment + 1;
+        let active = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(&dir, active_id))
+            .await?;
+        let mut pending = replayed.pending;
+        pending.insert(active_id, HashSet::new());
+
+        let writer = Writer {
+            dir,
+            config,
+            active,
+            active_id,
+            active_size: 0,
+            pending,
+            location: replayed.location,
+            waiting: Vec::new(),
+        };
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(writer.run(rx));
+
+        let log = Self {
+            tx,
+            seq: Arc::new(AtomicU64::new(replayed.next_seq)),
+        };
+        Ok((log, replayed.messages))
+    }
+
+    /// write message, return sequence number once durable
+    pub async fn append(&self, msg: &Message) -> io::Result<u64> {
+        let packet = msg.packet
+            .encode()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+            .freeze();
+
+        let seq = self.seq.fetch_add(1, Ordering::AcqRel);
+        let record = Record::Publish {
+            seq,
+            publisher: msg.publisher.clone(),
+            expire_at: msg.expire_at.unwrap_or_default(),
+            packet
+        };
+
+        let (done, wait) = oneshot::channel();
+        self.tx.send(Command::Append { record, done })
+            .map_err(|_| closed())?;
+
+        wait.await.map_err(|_| closed())??;
+        Ok(seq)
+    }
+
+    /// mark message as forwarded, it will not be replayed
+    pub fn delivered(&self, seq: u64) {
+        let _ = self.tx.send(Command::Delivered(seq));
+    }
+}
+
+impl Cleanup for MessageLog {
+    async fn clear(self) {
+        let (done, wait) = oneshot::channel();
+        if self.tx.send(Command::Close(done)).is_ok() {
+            let _ = wait.await;
+        }
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "message log closed")
+}
+
+fn segment_path(dir: &Path, id: u64) -> PathBuf {
+    let mut path = dir.to_path_buf();
+    path.push(format!("{:020}.{}", id, SEGMENT_EXT));
+    path
+}
+
+struct Writer {
+    dir: PathBuf,
+    config: WalConfig,
+    active: File,
+    active_id: u64,
+    active_size: u64,
+    /// undelivered sequence of each segment that is not removed yet
+    pending: BTreeMap<u64, HashSet<u64>>,
+    /// segment of undelivered sequence
+    location: HashMap<u64, u64>,
+    /// append that wait for fsync
+    waiting: Vec<oneshot::Sender<io::Result<()>>>,
+}
+
+impl Writer {
+    async fn run(mut self, mut rx: mpsc::UnboundedReceiver<Command>) {
+        let (batch, tick) = match self.config.fsync {
+            FsyncPolicy::Always => (1, Duration::from_secs(1)),
+            FsyncPolicy::Batched(n) => (n.max(1), Duration::from_secs(1)),
+            FsyncPolicy::Interval(d) => (usize::MAX, d),
+        };
+        let mut interval = time::interval(tick);
+
+        'writer: loop {
+            select! {
+                cmd = rx.recv() => {
+                    let mut cmd = match cmd {
+                        Some(cmd) => cmd,
+                        None => break 'writer
+                    };
+
+                    loop {
+                        if let Command::Close(done) = cmd {
+                            self.sync().await;
+                            let _ = done.send(());
+                            break 'writer;
+                        }
+                        self.handle(cmd).await;
+
+                        if self.waiting.len() >= batch {
+                            break;
+                        }
+                        cmd = match rx.try_recv() {
+                            Ok(cmd) => cmd,
+                            Err(_) => break
+                        };
+                    }
+
+                    if let FsyncPolicy::Interval(_) = self.config.fsync {
+                        continue 'writer;
+                    }
+                    self.sync().await;
+                },
+                _ = interval.tick() => {
+                    self.sync().await;
+                }
+            }
+        }
+        println!("[wal] closed");
+    }
+
+    async fn handle(&mut self, cmd: Command) {
+        match cmd {
+            Command::Append { record, done } => {
+                let seq = match &record {
+                    Record::Publish { seq, .. } => *seq,
+                    Record::Delivered { seq } => *seq
+                };
+
+                if let Err(err) = self.write(&record).await {
+                    let _ = done.send(Err(err));
+                    return;
+                }
+
+                self.location.insert(seq, self.active_id);
+                self.pending.entry(self.active_id)
+                    .or_default()
+                    .insert(seq);
+                self.waiting.push(done);
+
+                if self.active_size >= self.config.segment_size {
+                    if let Err(err) = self.roll().await {
+                        println!("[wal] roll segment error: {}", err);
+                    }
+                }
+            },
+            Command::Delivered(seq) => {
+                let segment = match self.location.remove(&seq) {
+                    Some(segment) => segment,
+                    None => return
+                };
+
+                if let Err(err) = self.write(&Record::Delivered { seq }).await {
+                    println!("[wal] delivered record error: {}", err);
+                }
+
+                let is_empty = match self.pending.get_mut(&segment) {
+                    Some(p) => {
+                        p.remove(&seq);
+                        p.is_empty()
+                    },
+                    None => false
+                };
+
+                if is_empty {
+                    self.truncate().await;
+                }
+            },
+            Command::Close(done) => {
+                let _ = done.send(());
+            }
+        }
+    }
+
+    async fn write(&mut self, record: &Record) -> io::Result<()> {
+        let mut buffer = BytesMut::new();
+        record.serialize(&mut buffer);
+        self.active.write_all(&buffer).await?;
+        self.active_size += buffer.len() as u64;
+        Ok(())
+    }
+
+    async fn sync(&mut self) {
+        if self.waiting.is_empty() {
+            return;
+        }
+
+        let res = self.active.sync_data().await;
+        for done in self.waiting.drain(..) {
+            let res = match &res {
+                Ok(_) => Ok(()),
+                Err(err) => Err(io::Error::new(err.kind(), err.to_string()))
+            };
+            let _ = done.send(res);
+        }
+    }
+
+    async fn roll(&mut self) -> io::Result<()> {
+        self.sync().await;
+        let next_id = self.active_id + 1;
+        let next = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(&self.dir, next_id))
+            .await?;
+
+        self.active = next;
+        self.active_id = next_id;
+        self.active_size = 0;
+        self.pending.insert(next_id, HashSet::new());
+
+        self.truncate().await;
+        Ok(())
+    }
+
+    /// remove the oldest segments which all message is delivered,
+    /// stop at the first one that still has undelivered message
+    async fn truncate(&mut self) {
+        while let Some(entry) = self.pending.first_entry() {
+            if !entry.get().is_empty() || *entry.key() == self.active_id {
+                break;
+            }
+
+            let segment = entry.remove_entry().0;
+            match fs::remove_file(segment_path(&self.dir, segment)).await {
+                Ok(_) => println!("[wal] segment {} truncated", segment),
+                Err(err) => println!("[wal] segment {} truncate error: {}", segment, err)
+            }
+        }
+    }
+}
+
+struct Replayed {
+    messages: Vec<Message>,
+    pending: BTreeMap<u64, HashSet<u64>>,
+    location: HashMap<u64, u64>,
+    next_seq: u64,
+    last_segment: u64,
+}
+
+async fn replay(dir: &Path) -> io::Result<Replayed> {
+    let mut segments = Vec::new();
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXT) {
+            continue;
+        }
+
+        let id = path.file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse::<u64>().ok());
+        if let Some(id) = id {
+            segments.push(id);
+        }
+    }
+    segments.sort();
+
+    let mut publishes = BTreeMap::new();
+    let mut delivered = HashSet::new();
+    let mut next_seq = 0;
+
+    for id in segments.iter() {
+        let path = segment_path(dir, *id);
+        let raw = Bytes::from(fs::read(&path).await?);
+        let mut buffer = raw.clone();
+
+        while !buffer.is_empty() {
+            let record = match Record::deserialize(&mut buffer) {
+                Some(record) => record,
+                None => {
+                    let valid = raw.len() - buffer.len();
+                    println!("[wal] segment {} torn at {}, truncated", id, valid);
+                    let f = OpenOptions::new().write(true).open(&path).await?;
+                    f.set_len(valid as u64).await?;
+                    f.sync_data().await?;
+                    break;
+                }
+            };
+
+            match record {
+                Record::Publish { seq, publisher, expire_at, packet } => {
+                    next_seq = next_seq.max(seq + 1);
+                    publishes.insert(seq, (*id, publisher, expire_at, packet));
+                },
+                Record::Delivered { seq } => {
+                    delivered.insert(seq);
+                }
+            }
+        }
+    }
+
+    let mut pending: BTreeMap<u64, HashSet<u64>> = BTreeMap::new();
+    let mut location = HashMap::new();
+    let mut messages = Vec::new();
+    for (seq, (segment, publisher, expire_at, packet)) in publishes {
+        if delivered.contains(&seq) {
+            continue;
+        }
+
+        let packet = match PublishPacket::decode(&mut BytesMut::from(packet.as_ref())) {
+            Ok(packet) => packet,
+            Err(err) => {
+                println!("[wal] message {} discarded: {}", seq, err);
+                continue;
+            }
+        };
+
+        pending.entry(segment).or_default().insert(seq);
+        location.insert(seq, segment);
+        let expire_at = Some(expire_at).filter(|at| *at != 0);
+        messages.push(Message { publisher, packet, seq: Some(seq), expire_at });
+    }
+
+    // segment after the oldest undelivered one may hold its delivered record
+    let live = segments.iter()
+        .position(|id| pending.contains_key(id))
+        .unwrap_or(segments.len());
+    for id in segments[..live].iter() {
+        fs::remove_file(segment_path(dir, *id)).await?;
+    }
+    for id in segments[live..].iter() {
+        pending.entry(*id).or_default();
+    }
+
+    println!("[wal] {} undelivered message replayed", messages.len());
+    Ok(Replayed {
+        messages,
+        pending,
+        location,
+        next_seq,
+        last_segment: segments.last().copied().unwrap_or_default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use bytes::{Bytes, BytesMut};
+    use crate::{message_broker::{client::clobj::ClientID, message::Message}, protocol::v5::{publish::PublishPacket, ServiceLevel}};
+    use super::{FsyncPolicy, MessageLog, Record, WalConfig};
+
+    #[test]
+    fn parse_fsync_policy() {
+        assert_eq!("always".parse(), Ok(FsyncPolicy::Always));
+        assert_eq!("batched:8".parse(), Ok(FsyncPolicy::Batched(8)));
+        assert_eq!("interval".parse(), Ok(FsyncPolicy::Interval(Duration::from_millis(100))));
+        assert!("never".parse::<FsyncPolicy>().is_err());
+    }
+
+    #[test]
+    fn record_roundtrip() {
+        let record = Record::Publish {
+            seq: 7,
+            publisher: Some(ClientID::new("clid".to_string())),
+            expire_at: 120,
+            packet: Bytes::from_static(b"packet")
+        };
+
+        let mut buffer = BytesMut::new();
+        record.serialize(&mut buffer);
+        Record::Delivered { seq: 7 }.serialize(&mut buffer);
+        let mut buffer = buffer.freeze();
+
+        // torn tail
+        let mut torn = buffer.slice(..buffer.len() - 1);
+        assert_eq!(Record::deserialize(&mut torn), Some(record));
+        assert_eq!(Record::deserialize(&mut torn), None);
+
+        // corrupted body
+        let mut corrupted = BytesMut::from(buffer.as_ref());
+        corrupted[10] ^= 0xFF;
+        assert_eq!(Record::deserialize(&mut corrupted.freeze()), None);
+
+        Record::deserialize(&mut buffer).unwrap();
+        assert_eq!(Record::deserialize(&mut buffer), Some(Record::Delivered { seq: 7 }));
+        assert!(buffer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn replay_undelivered() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_path_buf();
+
+        let config = WalConfig {
+            fsync: FsyncPolicy::Interval(Duration::from_millis(10)),
+            segment_size: 64
+        };
+
+        let (log, replayed) = MessageLog::open_dir(dir.clone(), config.clone()).await.unwrap();
+        assert!(replayed.is_empty());
+
+        let mut seqs = Vec::new();
+        for i in 0..4u16 {
+            let msg = Message {
+                publisher: Some(ClientID::new("publisher".to_string())),
+                packet: PublishPacket {
+                    qos: ServiceLevel::QoS1,
+                    topic: "sensor/temp".to_string(),
+                    packet_id: Some(i + 1),
+                    payload: Bytes::from(vec![i as u8; 32]),
+                    ..Default::default()
+                },
+                seq: None,
+                expire_at: Some(1000 + i as u64)
+            };
+            seqs.push(log.append(&msg).await.unwrap());
+        }
+
+        log.delivered(seqs[0]);
+        log.delivered(seqs[2]);
+        crate::message_broker::cleanup::Cleanup::clear(log).await;
+
+        let (_log, replayed) = MessageLog::open_dir(dir.clone(), config).await.unwrap();
+        let ids: Vec<Option<u16>> = replayed.iter().map(|m| m.packet.packet_id).collect();
+        assert_eq!(ids, vec![Some(2), Some(4)]);
+        assert_eq!(replayed[0].seq, Some(seqs[1]));
+        assert_eq!(replayed[1].expire_at, Some(1003));
+    }
+
+    #[tokio::test]
+    async fn delivered_record_kept_behind_pending_segment() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_path_buf();
+
+        let msg = |i: u16| Message {
+            publisher: Some(ClientID::new("publisher".to_string())),
+            packet: PublishPacket {
+                qos: ServiceLevel::QoS1,
+                topic: "sensor/temp".to_string(),
+                packet_id: Some(i),
+                payload: Bytes::from(vec![i as u8; 16]),
+                ..Default::default()
+            },
+            seq: None,
+            expire_at: None
+        };
+
+        // segment is rolled after two publish, or one delivered and one publish
+        let mut record = BytesMut::new();
+        Record::Publish {
+            seq: 0,
+            publisher: msg(1).publisher,
+            expire_at: 0,
+            packet: msg(1).packet.encode().unwrap().freeze()
+        }.serialize(&mut record);
+        let config = WalConfig {
+            fsync: FsyncPolicy::Interval(Duration::from_millis(10)),
+            segment_size: record.len() as u64 + 1
+        };
+
+        let (log, _) = MessageLog::open_dir(dir.clone(), config.clone()).await.unwrap();
+        // first segment: publish 1, publish 2
+        let first = log.append(&msg(1)).await.unwrap();
+        log.append(&msg(2)).await.unwrap();
+        // second segment: delivered 1, publish 3
+        log.delivered(first);
+        let third = log.append(&msg(3)).await.unwrap();
+        // second segment is fully delivered while the first is not
+        log.delivered(third);
+        crate::message_broker::cleanup::Cleanup::clear(log).await;
+
+        let (log, replayed) = MessageLog::open_dir(dir.clone(), config.clone()).await.unwrap();
+        let ids: Vec<Option<u16>> = replayed.iter().map(|m| m.packet.packet_id).collect();
+        assert_eq!(ids, vec![Some(2)]);
+        crate::message_broker::cleanup::Cleanup::clear(log).await;
+
+        // not delivered on this run either, replayed again
+        let (_log, replayed) = MessageLog::open_dir(dir.clone(), config).await.unwrap();
+        let ids: Vec<Option<u16>> = replayed.iter().map(|m| m.packet.packet_id).collect();
+        assert_eq!(ids, vec![Some(2)]);
+    }
+}
@@ -1,2 +1,3 @@
 pub mod mqtt;
+pub mod v3;
 pub mod v5;
\ No newline at end of file
@@ -1,25 +1,90 @@
 use bytes::BytesMut;
 
-use super::v5::{malform::Malformed, publish::PublishPacket, subscribe::SubscribePacket};
+use super::{
+    v3::{self, PROTOCOL_LEVEL as PROTOCOL_LEVEL_V311},
+    v5::{connack::ConnackPacket, malform::Malformed, puback::PubackPacket, publish::{PublishFrame, PublishPacket}, subsack::SubsAck, subscribe::SubscribePacket}
+};
 
 pub const PING_RES: [u8; 1] = [0x0D];
+pub const PROTOCOL_LEVEL_V5: u8 = 5;
 
+/// Protocol version chosen by CONNECT,
+/// every packet of the connection is encoded with it
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Protocol {
+    V311,
+    #[default]
+    V5
+}
+
+impl Protocol {
+    pub fn from_level(level: u8) -> Option<Self> {
+        match level {
+            PROTOCOL_LEVEL_V311 => Some(Self::V311),
+            PROTOCOL_LEVEL_V5 => Some(Self::V5),
+            _ => None
+        }
+    }
+
+    pub fn level(&self) -> u8 {
+        match self {
+            Self::V311 => PROTOCOL_LEVEL_V311,
+            Self::V5 => PROTOCOL_LEVEL_V5
+        }
+    }
+
+    pub fn connack(&self, ack: &ConnackPacket) -> Result<BytesMut, String> {
+        match self {
+            Self::V311 => Ok(v3::connack::encode(ack)),
+            Self::V5 => ack.encode()
+        }
+    }
 
-pub enum ClientPacketV5 {
+    /// PUBACK, PUBREC, PUBREL or PUBCOMP
+    pub fn ack(&self, ack: &PubackPacket) -> Result<BytesMut, String> {
+        match self {
+            Self::V311 => Ok(v3::puback::encode(ack)),
+            Self::V5 => ack.encode()
+        }
+    }
+
+    pub fn suback(&self, ack: &SubsAck) -> Result<BytesMut, String> {
+        match self {
+            Self::V311 => v3::subsack::encode(ack),
+            Self::V5 => ack.encode()
+        }
+    }
+
+    /// forwarded publish is encoded as v5, properties is stripped for 3.1.1
+    pub fn publish(&self, frame: &PublishFrame) -> Result<PublishFrame, String> {
+        match self {
+            Self::V311 => frame.without_properties(),
+            Self::V5 => Ok(frame.clone())
+        }
+    }
+}
+
+pub enum ClientPacket {
     Publish(PublishPacket),
     Subscribe(SubscribePacket),
+    /// PUBACK, PUBREC, PUBREL or PUBCOMP
+    Ack(PubackPacket),
     PingReq
 }
 
-impl ClientPacketV5 {
-    pub fn decode(buffer: &mut BytesMut) -> Result<Self, Malformed> {
-        let ctrl_packet = buffer[0] >> 4;
-        let pv = match ctrl_packet {
-            0x08 => Self::Subscribe(SubscribePacket::decode(buffer)?),
-            0x03 => Self::Publish(PublishPacket::decode(buffer).map_err(|_| Malformed::MalformedPacket)?),
-            0x0C => Self::PingReq,
+impl ClientPacket {
+    pub fn decode(buffer: &mut BytesMut, protocol: Protocol) -> Result<Self, Malformed> {
+        let ctrl_packet = buffer.first().ok_or(Malformed::MalformedPacket)? >> 4;
+        let pv = match (ctrl_packet, protocol) {
+            (0x08, Protocol::V5) => Self::Subscribe(SubscribePacket::decode(buffer)?),
+            (0x08, Protocol::V311) => Self::Subscribe(v3::subscribe::decode(buffer)?),
+            (0x03, Protocol::V5) => Self::Publish(PublishPacket::decode(buffer)?),
+            (0x03, Protocol::V311) => Self::Publish(v3::publish::decode(buffer)?),
+            (0x04..=0x07, Protocol::V5) => Self::Ack(PubackPacket::decode(buffer)?),
+            (0x04..=0x07, Protocol::V311) => Self::Ack(v3::puback::decode(buffer)?),
+            (0x0C, _) => Self::PingReq,
             _ => return Err(Malformed::ProtocolError)
         };
         Ok(pv)
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,52 @@
+use bytes::{BufMut, BytesMut};
+use crate::protocol::v5::connack::ConnackPacket;
+
+/// 3.1.1 CONNACK has no properties,
+/// reason code is mapped to the closest return code
+pub fn encode(ack: &ConnackPacket) -> BytesMut {
+    let mut buffer = BytesMut::with_capacity(4);
+    buffer.put_u8(0x20);
+    buffer.put_u8(0x02);
+    buffer.put_u8(ack.session_present as u8);
+    buffer.put_u8(return_code(ack.return_code));
+    buffer
+}
+
+fn return_code(reason_code: u8) -> u8 {
+    match reason_code {
+        0x00 => 0x00,
+        // unsupported protocol version
+        0x84 => 0x01,
+        // client identifier not valid
+        0x85 => 0x02,
+        // bad user name or password
+        0x86 => 0x04,
+        // not authorized or banned
+        0x87 | 0x8A => 0x05,
+        // server unavailable for the rest
+        _ => 0x03
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::v5::connack::{ConnackPacket, Properties};
+    use super::encode;
+
+    #[test]
+    fn connack_without_properties() {
+        let ack = ConnackPacket {
+            session_present: true,
+            return_code: 0x00,
+            properties: Some(Properties { receive_maximum: Some(10), ..Default::default() })
+        };
+        assert_eq!(&encode(&ack)[..], &[0x20, 0x02, 0x01, 0x00]);
+
+        let ack = ConnackPacket { return_code: 0x87, ..Default::default() };
+        assert_eq!(&encode(&ack)[..], &[0x20, 0x02, 0x00, 0x05]);
+        let ack = ConnackPacket { return_code: 0x8A, ..Default::default() };
+        assert_eq!(&encode(&ack)[..], &[0x20, 0x02, 0x00, 0x05]);
+        let ack = ConnackPacket { return_code: 0x97, ..Default::default() };
+        assert_eq!(&encode(&ack)[..], &[0x20, 0x02, 0x00, 0x03]);
+    }
+}
@@ -0,0 +1,10 @@
+// MQTT 3.1.1 codec, packet is decoded into the same structure as v5
+// so the broker only work with one representation, properties is left empty.
+// CONNECT of both version is decoded by v5 ConnectPacket.
+pub mod connack;
+pub mod publish;
+pub mod puback;
+pub mod subscribe;
+pub mod subsack;
+
+pub const PROTOCOL_LEVEL: u8 = 4;
@@ -0,0 +1,68 @@
+use bytes::{Buf, BufMut, BytesMut};
+use crate::protocol::v5::{malform::Malformed, puback::{PubACKType, PubackPacket}};
+
+/// PUBACK, PUBREC, PUBREL or PUBCOMP carry only packet identifier
+pub fn decode(buffer: &mut BytesMut) -> Result<PubackPacket, Malformed> {
+    if buffer.len() < 4 {
+        return Err(Malformed::MalformedPacket);
+    }
+
+    let packet_type = match buffer.get_u8() {
+        0x40 => PubACKType::PubAck,
+        0x50 => PubACKType::PubRec,
+        0x62 => PubACKType::PubRel,
+        0x70 => PubACKType::PubComp,
+        _ => return Err(Malformed::MalformedPacket)
+    };
+
+    if buffer.get_u8() != 0x02 {
+        return Err(Malformed::MalformedPacket);
+    }
+
+    Ok(PubackPacket {
+        packet_type,
+        packet_id: buffer.get_u16(),
+        reason_code: 0x00,
+        properties: None
+    })
+}
+
+/// reason code and properties is dropped
+pub fn encode(ack: &PubackPacket) -> BytesMut {
+    let header = match ack.packet_type {
+        PubACKType::PubAck => 0x40,
+        PubACKType::PubRec => 0x50,
+        PubACKType::PubRel => 0x62,
+        PubACKType::PubComp => 0x70
+    };
+
+    let mut buffer = BytesMut::with_capacity(4);
+    buffer.put_u8(header);
+    buffer.put_u8(0x02);
+    buffer.put_u16(ack.packet_id);
+    buffer
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use crate::protocol::v5::{disconnect::PROTOCOL_ERROR, puback::{PubACKType, PubackPacket}};
+    use super::{decode, encode};
+
+    #[test]
+    fn ack_without_reason_code() {
+        let mut buffer = BytesMut::from(&[0x62, 0x02, 0x00, 0x07][..]);
+        let ack = decode(&mut buffer).unwrap();
+        assert_eq!(ack.packet_type, PubACKType::PubRel);
+        assert_eq!(ack.packet_id, 7);
+
+        let ack = PubackPacket {
+            packet_type: PubACKType::PubComp,
+            packet_id: 7,
+            reason_code: PROTOCOL_ERROR,
+            properties: None
+        };
+        assert_eq!(&encode(&ack)[..], &[0x70, 0x02, 0x00, 0x07]);
+        assert!(decode(&mut BytesMut::from(&[0x40, 0x03, 0x00, 0x07, 0x00][..])).is_err());
+    }
+}
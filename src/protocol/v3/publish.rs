@@ -0,0 +1,84 @@
+use bytes::{Buf, BytesMut};
+use crate::protocol::v5::{decode_utf8_string, malform::Malformed, publish::PublishPacket, RemainingLength, ServiceLevel};
+
+/// 3.1.1 PUBLISH, variable header end right after packet identifier
+pub fn decode(buffer: &mut BytesMut) -> Result<PublishPacket, Malformed> {
+    if buffer.len() < 2 {
+        return Err(Malformed::MalformedPacket);
+    }
+
+    let header = buffer.get_u8();
+    if header >> 4 != 3 {
+        return Err(Malformed::ProtocolError);
+    }
+
+    let dup = (header & 0x08) != 0;
+    let qos = ServiceLevel::try_from((header & 0x06) >> 1)
+        .map_err(|_| Malformed::MalformedPacket)?;
+    let retain = (header & 0x01) != 0;
+
+    let remaining_length = RemainingLength::decode(buffer)
+        .map_err(|_| Malformed::MalformedPacket)? as usize;
+    if buffer.len() < remaining_length {
+        return Err(Malformed::MalformedPacket);
+    }
+    let mut packet = buffer.split_to(remaining_length);
+
+    let topic = decode_utf8_string(&mut packet)
+        .map_err(|_| Malformed::MalformedPacket)?;
+    let packet_id = match qos.code() > 0 {
+        true if packet.len() >= 2 => Some(packet.get_u16()),
+        true => return Err(Malformed::MalformedPacket),
+        false => None
+    };
+
+    Ok(PublishPacket {
+        dup,
+        qos,
+        retain,
+        topic,
+        packet_id,
+        payload: packet.freeze(),
+        properties: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Bytes, BytesMut};
+    use crate::protocol::v5::{publish::{Properties, PublishPacket}, ServiceLevel};
+    use super::decode;
+
+    #[test]
+    fn decode_publish() {
+        let mut buffer = BytesMut::from(&[
+            0x32, 0x08,
+            0x00, 0x03, b'a', b'/', b'b',
+            0x00, 0x05,
+            b'x'
+        ][..]);
+        let packet = decode(&mut buffer).unwrap();
+        assert_eq!(packet.qos, ServiceLevel::QoS1);
+        assert_eq!(packet.topic, "a/b");
+        assert_eq!(packet.packet_id, Some(5));
+        assert_eq!(packet.payload, Bytes::from_static(b"x"));
+        assert!(packet.properties.is_none());
+    }
+
+    #[test]
+    fn strip_properties() {
+        let packet = PublishPacket {
+            qos: ServiceLevel::QoS1,
+            topic: "a/b".to_string(),
+            packet_id: Some(5),
+            payload: Bytes::from_static(b"x"),
+            properties: Some(Properties { message_expiry_interval: Some(30), ..Default::default() }),
+            ..Default::default()
+        };
+
+        let frame = packet.encode_frame().unwrap()
+            .without_properties().unwrap();
+        let decoded = decode(&mut BytesMut::from(frame.to_bytes().as_ref())).unwrap();
+        assert_eq!(decoded, PublishPacket { properties: None, ..packet });
+    }
+}
@@ -0,0 +1,36 @@
+use bytes::{BufMut, BytesMut};
+use crate::protocol::v5::{subsack::SubsAck, RemainingLength};
+
+/// 3.1.1 SUBACK, every rejected subscription is reported as failure
+pub fn encode(ack: &SubsAck) -> Result<BytesMut, String> {
+    let rml_num = 2 + ack.return_codes.len();
+    let (rml, sz) = RemainingLength::encode(rml_num as u32)?;
+
+    let mut buffer = BytesMut::with_capacity(1 + sz + rml_num);
+    buffer.put_u8(0x90);
+    buffer.put(&rml[..sz]);
+    buffer.put_u16(ack.id);
+    for code in ack.return_codes.iter() {
+        match code {
+            Ok(qos) => buffer.put_u8(qos.code()),
+            Err(_) => buffer.put_u8(0x80)
+        }
+    }
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::v5::{subsack::{SubAckInvalid, SubAckProperties, SubsAck}, ServiceLevel};
+    use super::encode;
+
+    #[test]
+    fn suback_return_codes() {
+        let ack = SubsAck {
+            id: 3,
+            properties: Some(SubAckProperties { reason_string: Some("ok".to_string()), user_properties: None }),
+            return_codes: vec![Ok(ServiceLevel::QoS1), Err(SubAckInvalid::TopicFilterInvalid)]
+        };
+        assert_eq!(&encode(&ack).unwrap()[..], &[0x90, 0x04, 0x00, 0x03, 0x01, 0x80]);
+    }
+}
@@ -0,0 +1,81 @@
+use bytes::{Buf, BytesMut};
+use crate::protocol::v5::{
+    malform::Malformed, 
+    subscribe::{Subscribe, SubscribePacket, SubscriptionOptions}, 
+    RemainingLength, 
+    ServiceLevel
+};
+
+/// 3.1.1 SUBSCRIBE, each topic filter only has requested QoS
+pub fn decode(buffer: &mut BytesMut) -> Result<SubscribePacket, Malformed> {
+    if buffer.len() < 2 || buffer.get_u8() != 0x82 {
+        return Err(Malformed::MalformedPacket);
+    }
+
+    let remaining_length = RemainingLength::decode(buffer)
+        .map_err(|_| Malformed::MalformedPacket)? as usize;
+    if remaining_length < 2 || buffer.len() < remaining_length {
+        return Err(Malformed::MalformedPacket);
+    }
+    let mut packet = buffer.split_to(remaining_length);
+
+    let id = packet.get_u16();
+    let mut list = Vec::new();
+    while !packet.is_empty() {
+        if packet.len() < 2 {
+            return Err(Malformed::MalformedPacket);
+        }
+        let len = packet.get_u16() as usize;
+        if packet.len() < len + 1 {
+            return Err(Malformed::MalformedPacket);
+        }
+
+        let topic = String::from_utf8(packet.split_to(len).to_vec())
+            .map_err(|_| Malformed::MalformedPacket)?;
+        let requested = packet.get_u8();
+        // upper bit of requested QoS is reserved
+        if requested & 0xFC != 0 {
+            return Err(Malformed::MalformedPacket);
+        }
+
+        list.push(Subscribe {
+            topic,
+            max_qos: ServiceLevel::try_from(requested)?,
+            options: SubscriptionOptions::default(),
+            subscription_identifier: None
+        });
+    }
+
+    if list.is_empty() {
+        return Err(Malformed::ProtocolError);
+    }
+    Ok(SubscribePacket { id, list })
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use crate::protocol::v5::ServiceLevel;
+    use super::decode;
+
+    #[test]
+    fn decode_subscribe() {
+        let mut buffer = BytesMut::from(&[
+            0x82, 0x0B,
+            0x00, 0x01,
+            0x00, 0x01, b'a', 0x01,
+            0x00, 0x02, b'b', b'/', 0x02,
+        ][..]);
+        let packet = decode(&mut buffer).unwrap();
+        assert_eq!(packet.id, 1);
+        assert_eq!(packet.list[0].topic, "a");
+        assert_eq!(packet.list[0].max_qos, ServiceLevel::QoS1);
+        assert_eq!(packet.list[1].topic, "b/");
+        assert_eq!(packet.list[1].max_qos, ServiceLevel::QoS2);
+        assert!(packet.list[1].subscription_identifier.is_none());
+
+        // reserved bit on requested QoS
+        let mut buffer = BytesMut::from(&[0x82, 0x06, 0x00, 0x01, 0x00, 0x01, b'a', 0x05][..]);
+        assert!(decode(&mut buffer).is_err());
+    }
+}
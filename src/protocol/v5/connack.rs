@@ -1,11 +1,37 @@
 use bytes::{BufMut, BytesMut};
 
 use super::{
-    encode_binary_data,
-    encode_utf8_string,
+    property::{
+        PacketKind,
+        PropertyList,
+        Value,
+        ASSIGNED_CLIENT_IDENTIFIER,
+        AUTHENTICATION_DATA,
+        AUTHENTICATION_METHOD,
+        MAXIMUM_PACKET_SIZE,
+        MAXIMUM_QOS,
+        REASON_STRING,
+        RECEIVE_MAXIMUM,
+        RESPONSE_INFORMATION,
+        RETAIN_AVAILABLE,
+        SERVER_KEEP_ALIVE,
+        SERVER_REFERENCE,
+        SESSION_EXPIRY_INTERVAL,
+        SHARED_SUBSCRIPTION_AVAILABLE,
+        SUBSCRIPTION_IDENTIFIER_AVAILABLE,
+        TOPIC_ALIAS_MAXIMUM,
+        WILDCARD_SUBSCRIPTION_AVAILABLE
+    },
     RemainingLength
 };
 
+pub const UNSUPPORTED_PROTOCOL_VERSION: u8 = 0x84;
+pub const CLIENT_IDENTIFIER_NOT_VALID: u8 = 0x85;
+pub const BAD_USER_NAME_OR_PASSWORD: u8 = 0x86;
+pub const SERVER_BUSY: u8 = 0x89;
+pub const BANNED: u8 = 0x8A;
+pub const QUOTA_EXCEEDED: u8 = 0x97;
+
 #[derive(Default)]
 pub struct ConnackPacket {
     pub session_present: bool,  
@@ -13,14 +39,13 @@ pub struct ConnackPacket {
     pub properties: Option<Properties>,
 }
 
-// TODO: use map with rules
 #[derive(Debug, PartialEq, Default)]
 pub struct Properties {
     pub session_expiry_interval: Option<u32>,
     pub receive_maximum: Option<u16>,
     pub maximum_qos: Option<u8>,
     pub retain_available: Option<u8>,
-    pub maximum_packet_size: Option<u16>,
+    pub maximum_packet_size: Option<u32>,
     pub assigned_client_identifier: Option<String>,
     pub topic_alias_maximum: Option<u16>,
     pub reason_string: Option<String>,
@@ -36,15 +61,38 @@ pub struct Properties {
 }
 
 
+impl Properties {
+    fn to_list(&self) -> PropertyList {
+        let mut list = PropertyList::default();
+        list.put(SESSION_EXPIRY_INTERVAL, self.session_expiry_interval.map(Value::FourByte));
+        list.put(RECEIVE_MAXIMUM, self.receive_maximum.map(Value::TwoByte));
+        list.put(MAXIMUM_QOS, self.maximum_qos.map(Value::Byte));
+        list.put(RETAIN_AVAILABLE, self.retain_available.map(Value::Byte));
+        list.put(MAXIMUM_PACKET_SIZE, self.maximum_packet_size.map(Value::FourByte));
+        list.put(ASSIGNED_CLIENT_IDENTIFIER, self.assigned_client_identifier.clone().map(Value::Utf8));
+        list.put(TOPIC_ALIAS_MAXIMUM, self.topic_alias_maximum.map(Value::TwoByte));
+        list.put(REASON_STRING, self.reason_string.clone().map(Value::Utf8));
+        list.put_user_properties(&self.user_properties);
+        list.put(WILDCARD_SUBSCRIPTION_AVAILABLE, self.wildcard_subscription_available.map(Value::Byte));
+        list.put(SUBSCRIPTION_IDENTIFIER_AVAILABLE, self.subscription_identifier_available.map(Value::Byte));
+        list.put(SHARED_SUBSCRIPTION_AVAILABLE, self.shared_subscription_available.map(Value::Byte));
+        list.put(SERVER_KEEP_ALIVE, self.server_keep_alive.map(Value::TwoByte));
+        list.put(RESPONSE_INFORMATION, self.response_information.clone().map(Value::Utf8));
+        list.put(SERVER_REFERENCE, self.server_reference.clone().map(Value::Utf8));
+        list.put(AUTHENTICATION_METHOD, self.authentication_method.clone().map(Value::Utf8));
+        list.put(AUTHENTICATION_DATA, self.authentication_data.clone().map(Value::Binary));
+        list
+    }
+}
+
 impl ConnackPacket {
     #[cfg(test)]
     pub fn decode(buffer: &mut BytesMut) -> Result<Self, String> {
         use bytes::Buf;
+        use super::ensure;
         
         {
-            if buffer.len() < 4 {
-                return Err("Buffer too short".to_string());
-            }
+            ensure(buffer, 2)?;
     
             // Fixed header
             let header = buffer.get_u8();
@@ -54,21 +102,18 @@ impl ConnackPacket {
             }
         }
         
-        {
-            // Remaining length
-            let remaining_length = RemainingLength::decode(buffer).map_err(|_| "Failed to decode remaining length")? as usize;
-            
-            if buffer.remaining() < remaining_length {
-                return Err("Buffer too short for remaining length".to_string());
-            }
-        }
+        // Remaining length
+        let remaining_length = RemainingLength::decode(buffer).map_err(|_| "Failed to decode remaining length")? as usize;
+        ensure(buffer, remaining_length)?;
+        let mut buffer = buffer.split_to(remaining_length);
 
         // Variable header
+        ensure(&buffer, 2)?;
         let session_present = (buffer.get_u8() & 0x01) != 0;
         let return_code = buffer.get_u8();
 
         // Properties
-        let properties = decode_properties(buffer)?;
+        let properties = decode_properties(&mut buffer)?;
 
         Ok(ConnackPacket {
             session_present,
@@ -86,92 +131,14 @@ impl ConnackPacket {
 
         // Properties
         let mut buf_prop = BytesMut::new();
-
-        if let Some(properties) = &self.properties {
-
-            if let Some(session_expiry_interval) = properties.session_expiry_interval {
-                buf_prop.put_u8(0x11);
-                buf_prop.put_u32(session_expiry_interval);
-            }
-            if let Some(receive_maximum) = properties.receive_maximum {
-                buf_prop.put_u8(0x21);
-                buf_prop.put_u16(receive_maximum);
-            }
-            if let Some(maximum_qos) = properties.maximum_qos {
-                buf_prop.put_u8(0x24);
-                buf_prop.put_u8(maximum_qos);
-            }
-            if let Some(retain_available) = properties.retain_available {
-                buf_prop.put_u8(0x25);
-                buf_prop.put_u8(retain_available);
-            }
-            if let Some(maximum_packet_size) = properties.maximum_packet_size {
-                buf_prop.put_u8(0x27);
-                buf_prop.put_u16(maximum_packet_size);
-            }
-            if let Some(assigned_client_identifier) = &properties.assigned_client_identifier {
-                buf_prop.put_u8(0x12);
-                encode_utf8_string(&mut buf_prop, assigned_client_identifier)?;
-            }
-            if let Some(topic_alias_maximum) = properties.topic_alias_maximum {
-                buf_prop.put_u8(0x22);
-                buf_prop.put_u16(topic_alias_maximum);
-            }
-            if let Some(reason_string) = &properties.reason_string {
-                buf_prop.put_u8(0x1F);
-                encode_utf8_string(&mut buf_prop, reason_string)?;
-            }
-            if let Some(user_properties) = &properties.user_properties {
-                for (key, value) in user_properties {
-                    buf_prop.put_u8(0x26);
-                    encode_utf8_string(&mut buf_prop, key)?;
-                    encode_utf8_string(&mut buf_prop, value)?;
-                }
-            }
-            if let Some(wildcard_subscription_available) = properties.wildcard_subscription_available {
-                buf_prop.put_u8(0x28);
-                buf_prop.put_u8(wildcard_subscription_available);
-            }
-            if let Some(subscription_identifier_available) = properties.subscription_identifier_available {
-                buf_prop.put_u8(0x29);
-                buf_prop.put_u8(subscription_identifier_available);
-            }
-            if let Some(shared_subscription_available) = properties.shared_subscription_available {
-                buf_prop.put_u8(0x2A);
-                buf_prop.put_u8(shared_subscription_available);
-            }
-            if let Some(server_keep_alive) = properties.server_keep_alive {
-                buf_prop.put_u8(0x13);
-                buf_prop.put_u16(server_keep_alive);
-            }
-            if let Some(response_information) = &properties.response_information {
-                buf_prop.put_u8(0x1A);
-                encode_utf8_string(&mut buf_prop, response_information)?;
-            }
-            if let Some(server_reference) = &properties.server_reference {
-                buf_prop.put_u8(0x1C);
-                encode_utf8_string(&mut buf_prop, server_reference)?;
-            }
-            if let Some(authentication_method) = &properties.authentication_method {
-                buf_prop.put_u8(0x15);
-                encode_utf8_string(&mut buf_prop, authentication_method)?;
-            }
-            if let Some(authentication_data) = &properties.authentication_data {
-                buf_prop.put_u8(0x16);
-                encode_binary_data(&mut buf_prop, authentication_data)?;
-            }
-        } else {
-            // No properties
-            buf_prop.put_u8(0);
-        }
-        
-        // Properties remaining length
-        let (prop_remleng, plsize) = RemainingLength::encode(buf_prop.len() as u32)?;
-        let (prop_remleng, _) = prop_remleng.split_at(plsize);
-        
+        self.properties
+            .as_ref()
+            .map(Properties::to_list)
+            .unwrap_or_default()
+            .encode(&mut buf_prop, PacketKind::Connack)?;
         
         // Packet remaining length
-        let remaining_leng = buf_prop.len() + buf_vrheader.len() + prop_remleng.len();
+        let remaining_leng = buf_prop.len() + buf_vrheader.len();
         let (packet_remleng, rsize) = RemainingLength::encode(remaining_leng as u32)?;
         let (packet_remleng, _) = packet_remleng.split_at(rsize);
         
@@ -182,83 +149,42 @@ impl ConnackPacket {
         // Remaining length
         buffer.put(packet_remleng);
         buffer.put(buf_vrheader.as_slice());
-        buffer.put(prop_remleng);
         buffer.put(buf_prop);
 
-
         Ok(buffer)
     }
 }
 
 #[cfg(test)]
 fn decode_properties(buffer: &mut BytesMut) -> Result<Option<Properties>, String> {
-    use super::{
-        decode_binary_data, 
-        decode_string_pair, 
-        decode_utf8_string
-    };
-    use bytes::Buf;
+    use super::property::USER_PROPERTY;
 
-    if buffer.is_empty() {
-        return Ok(None);
-    }
-
-    let property_length = RemainingLength::decode(buffer).map_err(|_| "Failed to decode property length")? as usize;
-    if property_length == 0 {
+    let list = PropertyList::decode(buffer, PacketKind::Connack).map_err(|e| e.to_string())?;
+    if list.is_empty() {
         return Ok(None);
     }
 
     let mut properties = Properties::default();
-
-
-    while buffer.remaining() > 0 {
-        let identifier = buffer.get_u8();
-
-        match identifier {
-            0x11 => properties.session_expiry_interval = Some(buffer.get_u32()),
-            0x21 => properties.receive_maximum = Some(buffer.get_u16()),
-            0x24 => properties.maximum_qos = Some(buffer.get_u8()),
-            0x25 => properties.retain_available = Some(buffer.get_u8()),
-            0x27 =>  properties.maximum_packet_size = Some(buffer.get_u16()),
-            0x12 => {
-                let value = decode_utf8_string(buffer)?;
-                properties.assigned_client_identifier = Some(value);
-            }
-            0x22 => properties.topic_alias_maximum = Some(buffer.get_u16()),
-            0x1F => {
-                let value = decode_utf8_string(buffer)?;
-                properties.reason_string = Some(value);
-            }
-            0x26 => {
-                let user_props= decode_string_pair(buffer)?;
-                match &mut properties.user_properties {
-                    None => properties.user_properties = Some(vec![user_props]),
-                    Some(v) => v.push(user_props)
-                }
-            }   
-            0x28 => properties.wildcard_subscription_available = Some(buffer.get_u8()),
-            0x29 => properties.subscription_identifier_available = Some(buffer.get_u8()),
-            0x2A => properties.shared_subscription_available = Some(buffer.get_u8()),
-            0x13 => properties.server_keep_alive = Some(buffer.get_u16()),
-            0x1A => {
-                let value = decode_utf8_string(buffer)?;
-                properties.response_information = Some(value);
-            }
-            0x1C => {
-                let value = decode_utf8_string(buffer)?;
-                properties.server_reference = Some(value);
-            }
-            0x15 => {
-                let value = decode_utf8_string(buffer)?;
-                properties.authentication_method = Some(value);
-            }
-            0x16 => {
-                let value= decode_binary_data(buffer)?;
-                properties.authentication_data = Some(value);
-            }
-            _ => {
-                return Err("Unknown property identifier".to_string());
-            }
+    for (id, value) in list {
+        match (id, value) {
+            (SESSION_EXPIRY_INTERVAL, Value::FourByte(v)) => properties.session_expiry_interval = Some(v),
+            (RECEIVE_MAXIMUM, Value::TwoByte(v)) => properties.receive_maximum = Some(v),
+            (MAXIMUM_QOS, Value::Byte(v)) => properties.maximum_qos = Some(v),
+            (RETAIN_AVAILABLE, Value::Byte(v)) => properties.retain_available = Some(v),
+            (MAXIMUM_PACKET_SIZE, Value::FourByte(v)) => properties.maximum_packet_size = Some(v),
+            (ASSIGNED_CLIENT_IDENTIFIER, Value::Utf8(v)) => properties.assigned_client_identifier = Some(v),
+            (TOPIC_ALIAS_MAXIMUM, Value::TwoByte(v)) => properties.topic_alias_maximum = Some(v),
+            (REASON_STRING, Value::Utf8(v)) => properties.reason_string = Some(v),
+            (USER_PROPERTY, Value::StringPair(k, v)) => properties.user_properties.get_or_insert_with(Vec::new).push((k, v)),
+            (WILDCARD_SUBSCRIPTION_AVAILABLE, Value::Byte(v)) => properties.wildcard_subscription_available = Some(v),
+            (SUBSCRIPTION_IDENTIFIER_AVAILABLE, Value::Byte(v)) => properties.subscription_identifier_available = Some(v),
+            (SHARED_SUBSCRIPTION_AVAILABLE, Value::Byte(v)) => properties.shared_subscription_available = Some(v),
+            (SERVER_KEEP_ALIVE, Value::TwoByte(v)) => properties.server_keep_alive = Some(v),
+            (RESPONSE_INFORMATION, Value::Utf8(v)) => properties.response_information = Some(v),
+            (SERVER_REFERENCE, Value::Utf8(v)) => properties.server_reference = Some(v),
+            (AUTHENTICATION_METHOD, Value::Utf8(v)) => properties.authentication_method = Some(v),
+            (AUTHENTICATION_DATA, Value::Binary(v)) => properties.authentication_data = Some(v),
+            _ => ()
         }
     }
 
@@ -363,5 +289,27 @@ mod tests {
         assert_eq!(decoded.session_present, packet.session_present);
         assert_eq!(decoded.properties, packet.properties)
     }
+
+    #[test]
+    fn test_encode_connack_refused() {
+        let packet = ConnackPacket { return_code: BANNED, ..Default::default() };
+        assert_eq!(&packet.encode().unwrap()[..], &[0x20, 0x03, 0x00, 0x8A, 0x00]);
+
+        let packet = ConnackPacket { return_code: QUOTA_EXCEEDED, properties: Some(Properties::default()), ..Default::default() };
+        assert_eq!(&packet.encode().unwrap()[..], &[0x20, 0x03, 0x00, 0x97, 0x00]);
+    }
+
+    #[test]
+    fn test_decode_truncated_connack() {
+        let decode = |raw: &[u8]| ConnackPacket::decode(&mut BytesMut::from(raw));
+        assert!(decode(&[0x20]).is_err());
+        // remaining length of zero encoded in two bytes
+        assert!(decode(&[0x20, 0x80, 0x00, 0x00]).is_err());
+        assert!(decode(&[0x20, 0x01, 0x00, 0x00]).is_err());
+        assert!(decode(&[0x20, 0x03, 0x00, 0x00]).is_err());
+        // property length is past the remaining length
+        assert!(decode(&[0x20, 0x02, 0x00, 0x00, 0x00]).is_err());
+        assert!(decode(&[0x20, 0x03, 0x00, 0x00, 0x00]).is_ok());
+    }
 }
 
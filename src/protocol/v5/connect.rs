@@ -1,7 +1,33 @@
 
 #![allow(dead_code)]
 use bytes::{Buf, BytesMut};
-use super::{decode_binary_data, decode_string_pair, decode_utf8_string, RemainingLength};
+use crate::protocol::v3::PROTOCOL_LEVEL as PROTOCOL_LEVEL_V311;
+use super::{
+    decode_binary_data,
+    decode_utf8_string,
+    ensure,
+    property::{
+        PacketKind,
+        PropertyList,
+        Value,
+        AUTHENTICATION_DATA,
+        AUTHENTICATION_METHOD,
+        CONTENT_TYPE,
+        CORRELATION_DATA,
+        MAXIMUM_PACKET_SIZE,
+        MESSAGE_EXPIRY_INTERVAL,
+        PAYLOAD_FORMAT_INDICATOR,
+        RECEIVE_MAXIMUM,
+        REQUEST_PROBLEM_INFORMATION,
+        REQUEST_RESPONSE_INFORMATION,
+        RESPONSE_TOPIC,
+        SESSION_EXPIRY_INTERVAL,
+        TOPIC_ALIAS_MAXIMUM,
+        USER_PROPERTY,
+        WILL_DELAY_INTERVAL
+    },
+    RemainingLength
+};
 
 #[derive(Debug)]
 pub struct ConnectPacket {
@@ -11,18 +37,18 @@ pub struct ConnectPacket {
     pub keep_alive: u16,
     pub properties: Option<Properties>,
     pub client_id: String,
-    pub will_properties: Option<Properties>,
+    pub will_properties: Option<WillProperties>,
     pub will_topic: Option<String>,
     pub will_payload: Option<Vec<u8>>,
     pub username: Option<String>,
     pub password: Option<Vec<u8>>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Properties {
     pub session_expiry_interval: Option<u32>,
     pub receive_maximum: Option<u16>,
-    pub maximum_packet_size: Option<u16>,
+    pub maximum_packet_size: Option<u32>,
     pub topic_alias_maximum: Option<u16>,
     pub request_response_information: Option<u8>,
     pub request_problem_information: Option<u8>,
@@ -31,8 +57,24 @@ pub struct Properties {
     pub authentication_data: Option<Vec<u8>>,
 }
 
+/// properties of will message
+#[derive(Debug, Default)]
+pub struct WillProperties {
+    pub will_delay_interval: Option<u32>,
+    pub payload_format_indicator: Option<u8>,
+    pub message_expiry_interval: Option<u32>,
+    pub content_type: Option<String>,
+    pub response_topic: Option<String>,
+    pub correlation_data: Option<Vec<u8>>,
+    pub user_properties: Option<Vec<(String, String)>>,
+}
+
+pub const INVALID_PROTOCOL_NAME: &str = "Invalid protocol name";
+pub const UNSUPPORTED_PROTOCOL_LEVEL: &str = "Unsupported protocol level";
+
 impl ConnectPacket {
     pub fn decode(buffer: &mut BytesMut) -> Result<Self, String> {
+        ensure(buffer, 1)?;
         {
             // Fixed header
             let header = buffer.get_u8();
@@ -56,27 +98,33 @@ impl ConnectPacket {
             // Protocol Name
             let protocol_name = decode_utf8_string(buffer)?;
             if protocol_name != "MQTT" {
-                return Err("Invalid protocol name".to_string());
+                return Err(INVALID_PROTOCOL_NAME.to_string());
             }
 
             // Protocol Level
+            ensure(buffer, 1)?;
             let protocol_level = buffer.get_u8();
 
-            if protocol_level != 5 {
-                return Err("Unsupported protocol level".to_string());
+            if protocol_level != 5 && protocol_level != PROTOCOL_LEVEL_V311 {
+                return Err(UNSUPPORTED_PROTOCOL_LEVEL.to_string());
             }
             (protocol_name, protocol_level)
         };
 
 
         // Connect Flags
+        ensure(buffer, 3)?;
         let connect_flags = buffer.get_u8();
 
         // Keep Alive
         let keep_alive = buffer.get_u16();
 
-        // Properties
-        let properties = decode_properties(buffer)?;
+        // Properties, not exist on 3.1.1
+        let with_properties = protocol_level != PROTOCOL_LEVEL_V311;
+        let properties = match with_properties {
+            true => Some(decode_properties(buffer)?),
+            false => None
+        };
 
         // Client ID
         let client_id = decode_utf8_string(buffer)?;
@@ -89,8 +137,9 @@ impl ConnectPacket {
 
         if connect_flags & 0x04 > 0 {
             // Will Properties
-            let props = decode_properties(buffer)?;
-            will_properties = Some(props);
+            if with_properties {
+                will_properties = Some(decode_will_properties(buffer)?);
+            }
 
             // Will Topic
             let topic = decode_utf8_string(buffer)?;
@@ -118,7 +167,7 @@ impl ConnectPacket {
             protocol_level,
             connect_flags,
             keep_alive,
-            properties: Some(properties),
+            properties,
             client_id,
             will_properties,
             will_topic,
@@ -129,59 +178,44 @@ impl ConnectPacket {
     }
 
     pub fn clean_start(&self) -> bool {
-        let cs = self.connect_flags & 0x20;
+        let cs = self.connect_flags & 0x02;
         cs != 0
     }
 }
 
-// TODO: when double?
-fn  decode_properties(buffer: &mut BytesMut) -> Result<Properties, String> {
-    let property_length = RemainingLength::decode(buffer)?;
-    let mut bufprop = buffer.split_to(property_length as usize);
-    
-    let mut properties = Properties {
-        session_expiry_interval: None,
-        receive_maximum: None,
-        maximum_packet_size: None,
-        topic_alias_maximum: None,
-        request_response_information: None,
-        request_problem_information: None,
-        user_properties: None,
-        authentication_method: None,
-        authentication_data: None,
-    };
-
-    while bufprop.len() != 0 {
-        let identifier = bufprop.get_u8();
-        match identifier {
-            0x11 => properties.session_expiry_interval = Some(bufprop.get_u32()),
-            0x21 => properties.receive_maximum = Some(bufprop.get_u16()),
-            0x27 => properties.maximum_packet_size = Some(bufprop.get_u16()),
-            0x22 => properties.topic_alias_maximum = Some(bufprop.get_u16()),
-            0x19 => properties.request_response_information = Some(bufprop.get_u8()),
-            0x17 => properties.request_problem_information = Some(bufprop.get_u8()),
-            0x26 => {
-                let user_props = decode_string_pair(&mut bufprop)?;
-                match &mut properties.user_properties {
-                    None => properties.user_properties = Some(vec![user_props]),
-                    Some(v) => v.push(user_props)
-                }
-            }
-            0x15 => {
-                let auth_method = decode_utf8_string(&mut bufprop)?;
-                properties.authentication_method = Some(auth_method);
-            }
-            0x16 => {
-                let auth_data = decode_binary_data(&mut bufprop)?;
-                properties.authentication_data = Some(auth_data);
-            }
-            _ => {
-                return Err("Unknown property identifier".to_string());
-            }
+fn decode_properties(buffer: &mut BytesMut) -> Result<Properties, String> {
+    let mut properties = Properties::default();
+    for (id, value) in PropertyList::decode(buffer, PacketKind::Connect).map_err(|e| e.to_string())? {
+        match (id, value) {
+            (SESSION_EXPIRY_INTERVAL, Value::FourByte(v)) => properties.session_expiry_interval = Some(v),
+            (RECEIVE_MAXIMUM, Value::TwoByte(v)) => properties.receive_maximum = Some(v),
+            (MAXIMUM_PACKET_SIZE, Value::FourByte(v)) => properties.maximum_packet_size = Some(v),
+            (TOPIC_ALIAS_MAXIMUM, Value::TwoByte(v)) => properties.topic_alias_maximum = Some(v),
+            (REQUEST_RESPONSE_INFORMATION, Value::Byte(v)) => properties.request_response_information = Some(v),
+            (REQUEST_PROBLEM_INFORMATION, Value::Byte(v)) => properties.request_problem_information = Some(v),
+            (USER_PROPERTY, Value::StringPair(k, v)) => properties.user_properties.get_or_insert_with(Vec::new).push((k, v)),
+            (AUTHENTICATION_METHOD, Value::Utf8(v)) => properties.authentication_method = Some(v),
+            (AUTHENTICATION_DATA, Value::Binary(v)) => properties.authentication_data = Some(v),
+            _ => ()
         }
     }
-    println!("{:?}", properties);
+    Ok(properties)
+}
 
+fn decode_will_properties(buffer: &mut BytesMut) -> Result<WillProperties, String> {
+    let mut properties = WillProperties::default();
+    for (id, value) in PropertyList::decode(buffer, PacketKind::Will).map_err(|e| e.to_string())? {
+        match (id, value) {
+            (WILL_DELAY_INTERVAL, Value::FourByte(v)) => properties.will_delay_interval = Some(v),
+            (PAYLOAD_FORMAT_INDICATOR, Value::Byte(v)) => properties.payload_format_indicator = Some(v),
+            (MESSAGE_EXPIRY_INTERVAL, Value::FourByte(v)) => properties.message_expiry_interval = Some(v),
+            (CONTENT_TYPE, Value::Utf8(v)) => properties.content_type = Some(v),
+            (RESPONSE_TOPIC, Value::Utf8(v)) => properties.response_topic = Some(v),
+            (CORRELATION_DATA, Value::Binary(v)) => properties.correlation_data = Some(v),
+            (USER_PROPERTY, Value::StringPair(k, v)) => properties.user_properties.get_or_insert_with(Vec::new).push((k, v)),
+            _ => ()
+        }
+    }
     Ok(properties)
 }
 
@@ -284,7 +318,7 @@ mod tests {
             0x10, 0x0C, // Fixed header (packet type and remaining length)
             0x00, 0x04, // Protocol name length
             0x4D, 0x51, 0x54, 0x54, // Protocol name ("MQTT")
-            0x04, // Protocol level (4)
+            0x03, // Protocol level (3)
             0x02, // Connect flags
             0x00, 0x3C, // Keep alive (60 seconds)
             0x00, // Properties length (no properties)
@@ -299,6 +333,25 @@ mod tests {
         assert_eq!(error, "Unsupported protocol level");
     }
 
+    #[test]
+    fn test_decode_connect_packet_v311() {
+        let mut buffer = BytesMut::from(&[
+            0x10, 0x14, // Fixed header (packet type and remaining length)
+            0x00, 0x04, // Protocol name length
+            0x4D, 0x51, 0x54, 0x54, // Protocol name ("MQTT")
+            0x04, // Protocol level (4)
+            0x02, // Connect flags
+            0x00, 0x3C, // Keep alive (60 seconds)
+            0x00, 0x08, // Client ID length
+            0x63, 0x6C, 0x69, 0x65, 0x6E, 0x74, 0x49, 0x44, // Client ID ("clientID")
+        ][..]);
+
+        let packet = ConnectPacket::decode(&mut buffer).unwrap();
+        assert_eq!(packet.protocol_level, 4);
+        assert!(packet.properties.is_none());
+        assert_eq!(packet.client_id, "clientID");
+    }
+
     #[test]
     fn test_decode_connect_packet_with_will() {
         let mut buffer = BytesMut::from(&[
@@ -334,4 +387,19 @@ mod tests {
         assert!(packet.username.is_none());
         assert!(packet.password.is_none());
     }
+
+    #[test]
+    fn test_decode_connect_packet_truncated() {
+        // properties claim more than what is left
+        let mut buffer = BytesMut::from(&[
+            0x10, 0x0B, 0x00, 0x04, 0x4D, 0x51, 0x54, 0x54, 0x05, 0x02, 0x00, 0x3C, 0x09,
+        ][..]);
+        assert!(ConnectPacket::decode(&mut buffer).is_err());
+
+        // ends before keep alive
+        let mut buffer = BytesMut::from(&[
+            0x10, 0x07, 0x00, 0x04, 0x4D, 0x51, 0x54, 0x54, 0x05,
+        ][..]);
+        assert!(ConnectPacket::decode(&mut buffer).is_err());
+    }
 }
\ No newline at end of file
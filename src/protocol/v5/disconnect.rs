@@ -0,0 +1,100 @@
+#![allow(dead_code)]
+use bytes::{BufMut, BytesMut};
+use super::{
+    property::{PacketKind, PropertyList, Value, REASON_STRING, SERVER_REFERENCE, SESSION_EXPIRY_INTERVAL},
+    RemainingLength
+};
+
+pub const NORMAL_DISCONNECTION: u8 = 0x00;
+pub const PROTOCOL_ERROR: u8 = 0x82;
+pub const SESSION_TAKEN_OVER: u8 = 0x8E;
+pub const TOPIC_ALIAS_INVALID: u8 = 0x94;
+pub const QUOTA_EXCEEDED: u8 = 0x97;
+
+#[derive(Debug)]
+pub struct DisconnectPacket {
+    pub reason_code: u8,
+    pub properties: Option<Properties>,
+}
+
+#[derive(Debug, Default)]
+pub struct Properties {
+    pub session_expiry_interval: Option<u32>,
+    pub reason_string: Option<String>,
+    pub user_properties: Option<Vec<(String, String)>>,
+    pub server_reference: Option<String>,
+}
+
+impl DisconnectPacket {
+    pub fn new(reason_code: u8) -> Self {
+        Self { reason_code, properties: None }
+    }
+
+    pub fn encode(&self) -> Result<BytesMut, String> {
+        let list = self.properties
+            .as_ref()
+            .map(Properties::to_list)
+            .unwrap_or_default();
+        let mut buf_prop = BytesMut::new();
+        list.encode(&mut buf_prop, PacketKind::Disconnect)?;
+
+        // reason code | properties length | properties
+        let rml_num = 1 + buf_prop.len();
+        let (remaining_length, rmlen_size) = RemainingLength::encode(rml_num as u32)?;
+        let (remaining_length, _) = remaining_length.split_at(rmlen_size);
+
+        let mut buffer = BytesMut::with_capacity(rml_num + rmlen_size + 1);
+
+        // Fixed header
+        buffer.put_u8(0xE0);
+        buffer.put(remaining_length);
+
+        // Variable header
+        buffer.put_u8(self.reason_code);
+        buffer.put(buf_prop);
+
+        Ok(buffer)
+    }
+}
+
+impl Properties {
+    fn to_list(&self) -> PropertyList {
+        let mut list = PropertyList::default();
+        list.put(SESSION_EXPIRY_INTERVAL, self.session_expiry_interval.map(Value::FourByte));
+        list.put(REASON_STRING, self.reason_string.clone().map(Value::Utf8));
+        list.put_user_properties(&self.user_properties);
+        list.put(SERVER_REFERENCE, self.server_reference.clone().map(Value::Utf8));
+        list
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_disconnect() {
+        let packet = DisconnectPacket::new(SESSION_TAKEN_OVER);
+        let buffer = packet.encode().unwrap();
+        assert_eq!(&buffer[..], &[0xE0, 0x02, 0x8E, 0x00]);
+
+        let packet = DisconnectPacket {
+            reason_code: SESSION_TAKEN_OVER,
+            properties: Some(Properties {
+                reason_string: Some("taken".to_string()),
+                ..Default::default()
+            })
+        };
+        let buffer = packet.encode().unwrap();
+
+        let mut expected = BytesMut::new();
+        expected.put_u8(0xE0); // Packet type DISCONNECT
+        expected.put_u8(0x0A); // Remaining length
+        expected.put_u8(0x8E); // Reason code
+        expected.put_u8(0x08); // Properties length
+        expected.put_u8(0x1F); // Reason String identifier
+        expected.put_u16(5);
+        expected.put_slice(b"taken");
+        assert_eq!(&buffer[..], &expected[..]);
+    }
+}
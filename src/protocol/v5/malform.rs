@@ -37,4 +37,21 @@ impl Malformed {
             Malformed::WildcardSubsUnSupported => 0xA2,
         }
     }
-}
\ No newline at end of file
+}
+
+impl std::fmt::Display for Malformed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            Malformed::MalformedPacket => "malformed packet",
+            Malformed::ProtocolError => "protocol error",
+            Malformed::ReceiveMax => "receive maximum exceeded",
+            Malformed::PacketTooLarge => "packet too large",
+            Malformed::RetainNotSupported => "retain not supported",
+            Malformed::QoSNotSupported => "QoS not supported",
+            Malformed::SharedSubsUnsuppported => "shared subscriptions not supported",
+            Malformed::SubsIdUnSupported => "subscription identifiers not supported",
+            Malformed::WildcardSubsUnSupported => "wildcard subscriptions not supported",
+        };
+        write!(f, "{}", reason)
+    }
+}
@@ -4,7 +4,9 @@ pub mod subscribe;
 pub mod subsack;
 pub mod publish;
 pub mod puback;
+pub mod disconnect;
 pub mod malform;
+pub mod property;
 use bytes::{Buf, BufMut, BytesMut};
 use malform::Malformed;
 
@@ -54,6 +56,9 @@ impl RemainingLength {
         let mut value = 0;
         
         loop {
+            if !buffer.has_remaining() {
+                return Err("Malformed Remaining Length");
+            }
             let encoded_byte = buffer.get_u8();
             value += ((encoded_byte & 127) as u32) * multiplier;
             if (encoded_byte & 128) == 0 {
@@ -68,6 +73,21 @@ impl RemainingLength {
         Ok(value)
     }
     
+    /// size of whole packet from its fixed header,
+    /// `None` when the header is not complete
+    pub fn packet_len(buffer: &[u8]) -> Option<usize> {
+        let mut multiplier = 1;
+        let mut value = 0;
+        for (i, encoded_byte) in buffer.iter().skip(1).take(4).enumerate() {
+            value += ((encoded_byte & 127) as usize) * multiplier;
+            if (encoded_byte & 128) == 0 {
+                return Some(1 + i + 1 + value);
+            }
+            multiplier *= 128;
+        }
+        None
+    }
+    
     pub fn encode(mut target: u32) -> Result<([u8; 4], usize), &'static str> {
         const MAX_ENCODABLE: u32 = 268435455;
         const MOD: u32 = 128;
@@ -93,7 +113,18 @@ impl RemainingLength {
     }
 }
 
-fn decode_utf8_string(buffer: &mut BytesMut) -> Result<String, String> {
+/// fixed size field must fit in what is left of the packet
+fn ensure(buffer: &BytesMut, len: usize) -> Result<(), String> {
+    match buffer.len() < len {
+        true => Err("Malformed packet".to_string()),
+        false => Ok(())
+    }
+}
+
+pub(crate) fn decode_utf8_string(buffer: &mut BytesMut) -> Result<String, String> {
+    if buffer.remaining() < 2 {
+        return Err("buffer out of capacity".to_string());
+    }
     let len = buffer.get_u16() as usize;
     if len > buffer.remaining() {
         return Err("buffer out of capacity".to_string());
@@ -106,6 +137,9 @@ fn decode_utf8_string(buffer: &mut BytesMut) -> Result<String, String> {
 }
 
 fn decode_binary_data(buffer: &mut BytesMut) -> Result<Vec<u8>, String> {
+    if buffer.remaining() < 2 {
+        return Err("buffer out of capacity".to_string());
+    }
     let len = buffer.get_u16() as usize;
     if len > buffer.remaining() {
         return Err("buffer out of capacity".to_string());
@@ -136,8 +170,31 @@ fn encode_binary_data(buffer: &mut BytesMut, data: &[u8]) -> Result<(), String>
     Ok(())
 }
 
-pub(super) fn decode_string_pair(buffer: &mut BytesMut) -> Result<(String, String), String> {
-    let key = decode_utf8_string(buffer)?;
-    let value = decode_utf8_string(buffer)?;
-    Ok((key, value))
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use super::RemainingLength;
+
+    #[test]
+    fn packet_len_from_header() {
+        assert_eq!(RemainingLength::packet_len(&[0xC0, 0x00]), Some(2));
+        assert_eq!(RemainingLength::packet_len(&[0x30, 0x7F]), Some(129));
+
+        let (rml, sz) = RemainingLength::encode(300_000).unwrap();
+        let mut header = vec![0x30];
+        header.extend_from_slice(&rml[..sz]);
+        assert_eq!(RemainingLength::packet_len(&header), Some(1 + sz + 300_000));
+        assert_eq!(RemainingLength::packet_len(&header[..2]), None);
+    }
+
+    #[test]
+    fn decode_truncated_length() {
+        let decode = |raw: &[u8]| RemainingLength::decode(&mut BytesMut::from(raw));
+        assert_eq!(decode(&[0x80, 0x01]), Ok(128));
+        assert!(decode(&[]).is_err());
+        assert!(decode(&[0x80]).is_err());
+        assert!(decode(&[0xFF, 0xFF, 0xFF]).is_err());
+        // longer than four bytes
+        assert!(decode(&[0xFF, 0xFF, 0xFF, 0xFF, 0x01]).is_err());
+    }
+}
@@ -0,0 +1,374 @@
+#![allow(dead_code)]
+use bytes::{Buf, BufMut, BytesMut};
+use super::{
+    decode_binary_data,
+    decode_utf8_string,
+    encode_binary_data,
+    encode_utf8_string,
+    malform::Malformed,
+    RemainingLength
+};
+use PacketKind::*;
+
+pub const PAYLOAD_FORMAT_INDICATOR: u8 = 0x01;
+pub const MESSAGE_EXPIRY_INTERVAL: u8 = 0x02;
+pub const CONTENT_TYPE: u8 = 0x03;
+pub const RESPONSE_TOPIC: u8 = 0x08;
+pub const CORRELATION_DATA: u8 = 0x09;
+pub const SUBSCRIPTION_IDENTIFIER: u8 = 0x0B;
+pub const SESSION_EXPIRY_INTERVAL: u8 = 0x11;
+pub const ASSIGNED_CLIENT_IDENTIFIER: u8 = 0x12;
+pub const SERVER_KEEP_ALIVE: u8 = 0x13;
+pub const AUTHENTICATION_METHOD: u8 = 0x15;
+pub const AUTHENTICATION_DATA: u8 = 0x16;
+pub const REQUEST_PROBLEM_INFORMATION: u8 = 0x17;
+pub const WILL_DELAY_INTERVAL: u8 = 0x18;
+pub const REQUEST_RESPONSE_INFORMATION: u8 = 0x19;
+pub const RESPONSE_INFORMATION: u8 = 0x1A;
+pub const SERVER_REFERENCE: u8 = 0x1C;
+pub const REASON_STRING: u8 = 0x1F;
+pub const RECEIVE_MAXIMUM: u8 = 0x21;
+pub const TOPIC_ALIAS_MAXIMUM: u8 = 0x22;
+pub const TOPIC_ALIAS: u8 = 0x23;
+pub const MAXIMUM_QOS: u8 = 0x24;
+pub const RETAIN_AVAILABLE: u8 = 0x25;
+pub const USER_PROPERTY: u8 = 0x26;
+pub const MAXIMUM_PACKET_SIZE: u8 = 0x27;
+pub const WILDCARD_SUBSCRIPTION_AVAILABLE: u8 = 0x28;
+pub const SUBSCRIPTION_IDENTIFIER_AVAILABLE: u8 = 0x29;
+pub const SHARED_SUBSCRIPTION_AVAILABLE: u8 = 0x2A;
+
+/// Packet that carry properties, will message on CONNECT has its own set
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PacketKind {
+    Connect,
+    Will,
+    Connack,
+    Publish,
+    /// PUBACK, PUBREC, PUBREL or PUBCOMP
+    Ack,
+    Subscribe,
+    SubAck,
+    Unsubscribe,
+    UnsubAck,
+    Disconnect,
+    Auth
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ValueType {
+    Byte,
+    TwoByte,
+    FourByte,
+    VarInt,
+    Utf8,
+    Binary,
+    StringPair
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Byte(u8),
+    TwoByte(u16),
+    FourByte(u32),
+    VarInt(u32),
+    Utf8(String),
+    Binary(Vec<u8>),
+    StringPair(String, String)
+}
+
+impl Value {
+    fn value_type(&self) -> ValueType {
+        match self {
+            Self::Byte(_) => ValueType::Byte,
+            Self::TwoByte(_) => ValueType::TwoByte,
+            Self::FourByte(_) => ValueType::FourByte,
+            Self::VarInt(_) => ValueType::VarInt,
+            Self::Utf8(_) => ValueType::Utf8,
+            Self::Binary(_) => ValueType::Binary,
+            Self::StringPair(..) => ValueType::StringPair
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Self::Byte(_) => 1,
+            Self::TwoByte(_) => 2,
+            Self::FourByte(_) => 4,
+            Self::VarInt(v) => RemainingLength::encode(*v).map(|(_, sz)| sz.max(1)).unwrap_or(4),
+            Self::Utf8(s) => 2 + s.len(),
+            Self::Binary(b) => 2 + b.len(),
+            Self::StringPair(k, v) => 4 + k.len() + v.len()
+        }
+    }
+}
+
+struct Rule {
+    id: u8,
+    value: ValueType,
+    /// packet the property may appear in
+    packets: &'static [PacketKind],
+    /// packet the property may appear more than once
+    repeatable: &'static [PacketKind]
+}
+
+const EVERY_PACKET: &[PacketKind] = &[Connect, Will, Connack, Publish, Ack, Subscribe, SubAck, Unsubscribe, UnsubAck, Disconnect, Auth];
+const MESSAGE: &[PacketKind] = &[Publish, Will];
+const NEVER: &[PacketKind] = &[];
+
+const RULES: &[Rule] = &[
+    Rule { id: PAYLOAD_FORMAT_INDICATOR, value: ValueType::Byte, packets: MESSAGE, repeatable: NEVER },
+    Rule { id: MESSAGE_EXPIRY_INTERVAL, value: ValueType::FourByte, packets: MESSAGE, repeatable: NEVER },
+    Rule { id: CONTENT_TYPE, value: ValueType::Utf8, packets: MESSAGE, repeatable: NEVER },
+    Rule { id: RESPONSE_TOPIC, value: ValueType::Utf8, packets: MESSAGE, repeatable: NEVER },
+    Rule { id: CORRELATION_DATA, value: ValueType::Binary, packets: MESSAGE, repeatable: NEVER },
+    Rule { id: SUBSCRIPTION_IDENTIFIER, value: ValueType::VarInt, packets: &[Publish, Subscribe], repeatable: &[Publish] },
+    Rule { id: SESSION_EXPIRY_INTERVAL, value: ValueType::FourByte, packets: &[Connect, Connack, Disconnect], repeatable: NEVER },
+    Rule { id: ASSIGNED_CLIENT_IDENTIFIER, value: ValueType::Utf8, packets: &[Connack], repeatable: NEVER },
+    Rule { id: SERVER_KEEP_ALIVE, value: ValueType::TwoByte, packets: &[Connack], repeatable: NEVER },
+    Rule { id: AUTHENTICATION_METHOD, value: ValueType::Utf8, packets: &[Connect, Connack, Auth], repeatable: NEVER },
+    Rule { id: AUTHENTICATION_DATA, value: ValueType::Binary, packets: &[Connect, Connack, Auth], repeatable: NEVER },
+    Rule { id: REQUEST_PROBLEM_INFORMATION, value: ValueType::Byte, packets: &[Connect], repeatable: NEVER },
+    Rule { id: WILL_DELAY_INTERVAL, value: ValueType::FourByte, packets: &[Will], repeatable: NEVER },
+    Rule { id: REQUEST_RESPONSE_INFORMATION, value: ValueType::Byte, packets: &[Connect], repeatable: NEVER },
+    Rule { id: RESPONSE_INFORMATION, value: ValueType::Utf8, packets: &[Connack], repeatable: NEVER },
+    Rule { id: SERVER_REFERENCE, value: ValueType::Utf8, packets: &[Connack, Disconnect], repeatable: NEVER },
+    Rule { id: REASON_STRING, value: ValueType::Utf8, packets: &[Connack, Ack, SubAck, UnsubAck, Disconnect, Auth], repeatable: NEVER },
+    Rule { id: RECEIVE_MAXIMUM, value: ValueType::TwoByte, packets: &[Connect, Connack], repeatable: NEVER },
+    Rule { id: TOPIC_ALIAS_MAXIMUM, value: ValueType::TwoByte, packets: &[Connect, Connack], repeatable: NEVER },
+    Rule { id: TOPIC_ALIAS, value: ValueType::TwoByte, packets: &[Publish], repeatable: NEVER },
+    Rule { id: MAXIMUM_QOS, value: ValueType::Byte, packets: &[Connack], repeatable: NEVER },
+    Rule { id: RETAIN_AVAILABLE, value: ValueType::Byte, packets: &[Connack], repeatable: NEVER },
+    Rule { id: USER_PROPERTY, value: ValueType::StringPair, packets: EVERY_PACKET, repeatable: EVERY_PACKET },
+    Rule { id: MAXIMUM_PACKET_SIZE, value: ValueType::FourByte, packets: &[Connect, Connack], repeatable: NEVER },
+    Rule { id: WILDCARD_SUBSCRIPTION_AVAILABLE, value: ValueType::Byte, packets: &[Connack], repeatable: NEVER },
+    Rule { id: SUBSCRIPTION_IDENTIFIER_AVAILABLE, value: ValueType::Byte, packets: &[Connack], repeatable: NEVER },
+    Rule { id: SHARED_SUBSCRIPTION_AVAILABLE, value: ValueType::Byte, packets: &[Connack], repeatable: NEVER },
+];
+
+/// rule of the property allowed on the packet
+fn rule(id: u8, packet: PacketKind) -> Result<&'static Rule, Malformed> {
+    let rule = RULES.iter()
+        .find(|r| r.id == id)
+        .ok_or(Malformed::MalformedPacket)?;
+    match rule.packets.contains(&packet) {
+        true => Ok(rule),
+        false => Err(Malformed::ProtocolError)
+    }
+}
+
+/// size of encoded property value at the start of `rest`
+pub fn value_len(id: u8, rest: &[u8]) -> Result<usize, Malformed> {
+    let rule = RULES.iter()
+        .find(|r| r.id == id)
+        .ok_or(Malformed::MalformedPacket)?;
+    let u16_at = |at: usize| match rest.get(at..at + 2) {
+        Some(b) => Ok(u16::from_be_bytes([b[0], b[1]]) as usize),
+        None => Err(Malformed::MalformedPacket)
+    };
+
+    let len = match rule.value {
+        ValueType::Byte => 1,
+        ValueType::TwoByte => 2,
+        ValueType::FourByte => 4,
+        ValueType::VarInt => rest.iter()
+            .take(4)
+            .position(|b| b & 0x80 == 0)
+            .ok_or(Malformed::MalformedPacket)? + 1,
+        ValueType::Utf8 | ValueType::Binary => 2 + u16_at(0)?,
+        ValueType::StringPair => {
+            let key = u16_at(0)?;
+            4 + key + u16_at(2 + key)?
+        }
+    };
+
+    match rest.len() < len {
+        true => Err(Malformed::MalformedPacket),
+        false => Ok(len)
+    }
+}
+
+/// Properties of one packet in the order they are on the wire.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PropertyList(Vec<(u8, Value)>);
+
+impl PropertyList {
+    /// add the property when the value is set
+    pub fn put(&mut self, id: u8, value: Option<Value>) {
+        if let Some(value) = value {
+            self.0.push((id, value));
+        }
+    }
+
+    pub fn put_user_properties(&mut self, user_properties: &Option<Vec<(String, String)>>) {
+        for (key, value) in user_properties.iter().flatten() {
+            self.0.push((USER_PROPERTY, Value::StringPair(key.clone(), value.clone())));
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// size of encoded properties without the length
+    pub fn len(&self) -> usize {
+        self.0.iter().map(|(_, v)| 1 + v.len()).sum()
+    }
+
+    /// size of encoded properties with the length
+    pub fn encoded_len(&self) -> usize {
+        let len = self.len();
+        len + RemainingLength::encode(len as u32).map(|(_, sz)| sz.max(1)).unwrap_or(4)
+    }
+
+    /// read variable byte integer length then every property of the packet,
+    /// property repeated or not allowed on the packet is protocol error
+    pub fn decode(buffer: &mut BytesMut, packet: PacketKind) -> Result<Self, Malformed> {
+        let len = RemainingLength::decode(buffer)
+            .map_err(|_| Malformed::MalformedPacket)? as usize;
+        if buffer.len() < len {
+            return Err(Malformed::MalformedPacket);
+        }
+
+        let mut buffer = buffer.split_to(len);
+        let mut list = Vec::new();
+        while buffer.has_remaining() {
+            let id = buffer.get_u8();
+            let rule = rule(id, packet)?;
+            let repeated = list.iter().any(|(seen, _)| *seen == id);
+            if repeated && !rule.repeatable.contains(&packet) {
+                return Err(Malformed::ProtocolError);
+            }
+
+            let value = decode_value(&mut buffer, rule.value)?;
+            list.push((id, value));
+        }
+        Ok(Self(list))
+    }
+
+    /// write variable byte integer length then every property,
+    /// zero length still take one byte
+    pub fn encode(&self, buffer: &mut BytesMut, packet: PacketKind) -> Result<(), String> {
+        let len = self.len();
+        let (rml, sz) = RemainingLength::encode(len as u32)?;
+        buffer.reserve(sz.max(1) + len);
+        buffer.put(&rml[..sz.max(1)]);
+
+        for (i, (id, value)) in self.0.iter().enumerate() {
+            let rule = rule(*id, packet).map_err(|_| format!("Property {:#04x} is not allowed", id))?;
+            let repeated = self.0[..i].iter().any(|(seen, _)| seen == id);
+            if value.value_type() != rule.value || (repeated && !rule.repeatable.contains(&packet)) {
+                return Err(format!("Invalid property {:#04x}", id));
+            }
+
+            buffer.put_u8(*id);
+            encode_value(buffer, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl IntoIterator for PropertyList {
+    type Item = (u8, Value);
+    type IntoIter = std::vec::IntoIter<(u8, Value)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+fn decode_value(buffer: &mut BytesMut, value: ValueType) -> Result<Value, Malformed> {
+    let fixed = match value {
+        ValueType::Byte => 1,
+        ValueType::TwoByte => 2,
+        ValueType::FourByte => 4,
+        _ => 0
+    };
+    if buffer.len() < fixed {
+        return Err(Malformed::MalformedPacket);
+    }
+
+    let value = match value {
+        ValueType::Byte => Value::Byte(buffer.get_u8()),
+        ValueType::TwoByte => Value::TwoByte(buffer.get_u16()),
+        ValueType::FourByte => Value::FourByte(buffer.get_u32()),
+        ValueType::VarInt => Value::VarInt(RemainingLength::decode(buffer)
+            .map_err(|_| Malformed::MalformedPacket)?),
+        ValueType::Utf8 => Value::Utf8(decode_utf8_string(buffer)
+            .map_err(|_| Malformed::MalformedPacket)?),
+        ValueType::Binary => Value::Binary(decode_binary_data(buffer)
+            .map_err(|_| Malformed::MalformedPacket)?),
+        ValueType::StringPair => {
+            let key = decode_utf8_string(buffer).map_err(|_| Malformed::MalformedPacket)?;
+            let value = decode_utf8_string(buffer).map_err(|_| Malformed::MalformedPacket)?;
+            Value::StringPair(key, value)
+        }
+    };
+    Ok(value)
+}
+
+fn encode_value(buffer: &mut BytesMut, value: &Value) -> Result<(), String> {
+    match value {
+        Value::Byte(v) => buffer.put_u8(*v),
+        Value::TwoByte(v) => buffer.put_u16(*v),
+        Value::FourByte(v) => buffer.put_u32(*v),
+        Value::VarInt(v) => {
+            let (rml, sz) = RemainingLength::encode(*v)?;
+            buffer.put(&rml[..sz.max(1)]);
+        },
+        Value::Utf8(s) => encode_utf8_string(buffer, s)?,
+        Value::Binary(b) => encode_binary_data(buffer, b)?,
+        Value::StringPair(k, v) => {
+            encode_utf8_string(buffer, k)?;
+            encode_utf8_string(buffer, v)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use crate::protocol::v5::malform::Malformed;
+    use super::*;
+
+    fn decode(raw: &[u8], packet: PacketKind) -> Result<PropertyList, Malformed> {
+        PropertyList::decode(&mut BytesMut::from(raw), packet)
+    }
+
+    #[test]
+    fn property_rules() {
+        let list = decode(&[0x05, 0x0B, 0x01, 0x0B, 0x82, 0x01, 0xFF], Publish).unwrap();
+        assert_eq!(list, PropertyList(vec![
+            (SUBSCRIPTION_IDENTIFIER, Value::VarInt(1)),
+            (SUBSCRIPTION_IDENTIFIER, Value::VarInt(130))
+        ]));
+
+        // repeated only on publish
+        assert!(matches!(decode(&[0x04, 0x0B, 0x01, 0x0B, 0x02], Subscribe), Err(Malformed::ProtocolError)));
+        // not allowed on publish
+        assert!(matches!(decode(&[0x03, 0x21, 0x00, 0x0A], Publish), Err(Malformed::ProtocolError)));
+        // unknown identifier
+        assert!(matches!(decode(&[0x02, 0x7F, 0x00], Publish), Err(Malformed::MalformedPacket)));
+        // truncated value and length
+        assert!(matches!(decode(&[0x03, 0x02, 0x00, 0x01], Publish), Err(Malformed::MalformedPacket)));
+        assert!(matches!(decode(&[0x05, 0x02, 0x00], Publish), Err(Malformed::MalformedPacket)));
+        assert!(matches!(decode(&[], Publish), Err(Malformed::MalformedPacket)));
+        // variable byte integer stop at the property length
+        assert!(matches!(decode(&[0x80], Publish), Err(Malformed::MalformedPacket)));
+        assert!(matches!(decode(&[0x02, 0x0B, 0x80, 0x01], Publish), Err(Malformed::MalformedPacket)));
+
+        let mut list = PropertyList::default();
+        list.put(TOPIC_ALIAS, Some(Value::TwoByte(3)));
+        list.put(REASON_STRING, None);
+        let mut buffer = BytesMut::new();
+        list.encode(&mut buffer, Publish).unwrap();
+        assert_eq!(&buffer[..], &[0x03, 0x23, 0x00, 0x03]);
+        assert_eq!(list.encoded_len(), buffer.len());
+        assert!(list.encode(&mut BytesMut::new(), Connack).is_err());
+
+        let mut buffer = BytesMut::new();
+        PropertyList::default().encode(&mut buffer, Ack).unwrap();
+        assert_eq!(&buffer[..], &[0x00]);
+    }
+}
@@ -1,8 +1,12 @@
 #![allow(dead_code)]
-use bytes::{BytesMut, BufMut};
-use super::{encode_utf8_string, RemainingLength};
-
-#[derive(Debug)]
+use bytes::{Buf, BytesMut, BufMut};
+use super::{
+    malform::Malformed,
+    property::{PacketKind, PropertyList, Value, REASON_STRING, USER_PROPERTY},
+    RemainingLength
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PubACKType {
     PubAck,
     PubRec,
@@ -15,7 +19,7 @@ impl PubACKType {
         match self {
             PubACKType::PubAck => 0x40,
             PubACKType::PubRec => 0x50,
-            PubACKType::PubRel => 0x60,
+            PubACKType::PubRel => 0x62,
             PubACKType::PubComp => 0x70
         }
     }
@@ -36,18 +40,67 @@ pub struct Properties {
 }
 
 impl PubackPacket {
+    /// decode PUBACK, PUBREC, PUBREL or PUBCOMP
+    pub fn decode(buffer: &mut BytesMut) -> Result<Self, Malformed> {
+        if buffer.len() < 2 {
+            return Err(Malformed::MalformedPacket);
+        }
+
+        let header = buffer.get_u8();
+        let packet_type = match header {
+            0x40 => PubACKType::PubAck,
+            0x50 => PubACKType::PubRec,
+            0x62 => PubACKType::PubRel,
+            0x70 => PubACKType::PubComp,
+            _ => return Err(Malformed::MalformedPacket)
+        };
+
+        let remaining_length = RemainingLength::decode(buffer)
+            .map_err(|_| Malformed::MalformedPacket)? as usize;
+        if remaining_length < 2 || buffer.len() < remaining_length {
+            return Err(Malformed::MalformedPacket);
+        }
+
+        let mut packet = buffer.split_to(remaining_length);
+        let packet_id = packet.get_u16();
+        // reason code can be omitted when success
+        let reason_code = match packet.is_empty() {
+            true => 0x00,
+            false => packet.get_u8()
+        };
+
+        // properties can be omitted as well
+        let mut properties = None;
+        if !packet.is_empty() {
+            let mut props = Properties { reason_string: None, user_properties: None };
+            for (id, value) in PropertyList::decode(&mut packet, PacketKind::Ack)? {
+                match (id, value) {
+                    (REASON_STRING, Value::Utf8(v)) => props.reason_string = Some(v),
+                    (USER_PROPERTY, Value::StringPair(k, v)) => props.user_properties.get_or_insert_with(Vec::new).push((k, v)),
+                    _ => ()
+                }
+            }
+            properties = Some(props);
+        }
+
+        Ok(Self { packet_type, packet_id, reason_code, properties })
+    }
+
     pub fn encode(&self) -> Result<BytesMut, String> {
         let mut buffer = BytesMut::new();
-        // Properties
+        // Properties, omitted when there is none
+        let list = self.properties
+            .as_ref()
+            .map(Properties::to_list)
+            .unwrap_or_default();
         let mut buf_prop = BytesMut::new();
-        if let Some(properties) = &self.properties {
-            buf_prop = encode_properties(properties)?;
+        if !list.is_empty() {
+            list.encode(&mut buf_prop, PacketKind::Ack)?;
         }
 
-        let (prop_leng, plen_size) = RemainingLength::encode(buf_prop.len() as u32)?;
-        let (prop_leng, _) = prop_leng.split_at(plen_size);
-
-        let (remaining_length, rmlen_size) = RemainingLength::encode((plen_size + buf_prop.len() + 3) as u32)?;
+        // reason code is omitted on success without properties
+        let with_reason = self.reason_code != 0x00 || !buf_prop.is_empty();
+        let (remaining_length, rmlen_size) = RemainingLength::encode((buf_prop.len() + 2 + with_reason as usize) as u32)?;
         let (remaining_length, _) = remaining_length.split_at(rmlen_size);
 
         // Fixed header
@@ -59,38 +112,43 @@ impl PubackPacket {
 
         // Variable header
         buffer.put_u16(self.packet_id);
-        buffer.put_u8(self.reason_code);
+        if with_reason {
+            buffer.put_u8(self.reason_code);
+        }
 
-        buffer.put(prop_leng);
         buffer.put(buf_prop);
         
         Ok(buffer)
     }
 }
 
-fn encode_properties(properties: &Properties) -> Result<BytesMut, String> {
-    let mut props_buffer = BytesMut::new();
-
-    if let Some(reason_string) = &properties.reason_string {
-        props_buffer.put_u8(0x1F);
-        encode_utf8_string(&mut props_buffer, reason_string)?;
-    }
-
-    if let Some(user_properties) = &properties.user_properties {
-        for (key, value) in user_properties {
-            props_buffer.put_u8(0x26);
-            encode_utf8_string(&mut props_buffer, key)?;
-            encode_utf8_string(&mut props_buffer, value)?;
-        }
+impl Properties {
+    fn to_list(&self) -> PropertyList {
+        let mut list = PropertyList::default();
+        list.put(REASON_STRING, self.reason_string.clone().map(Value::Utf8));
+        list.put_user_properties(&self.user_properties);
+        list
     }
-
-    Ok(props_buffer)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_decode_pubrel() {
+        let mut buffer = BytesMut::from(&[0x62, 0x02, 0x00, 0x07][..]);
+        let packet = PubackPacket::decode(&mut buffer).unwrap();
+        assert_eq!(packet.packet_type, PubACKType::PubRel);
+        assert_eq!(packet.packet_id, 7);
+        assert_eq!(packet.reason_code, 0);
+
+        let mut buffer = BytesMut::from(&[0x50, 0x03, 0x00, 0x08, 0x10][..]);
+        let packet = PubackPacket::decode(&mut buffer).unwrap();
+        assert_eq!(packet.packet_type, PubACKType::PubRec);
+        assert_eq!(packet.reason_code, 0x10);
+    }
+
     #[test]
     fn test_encode_puback() {
         let properties = Properties {
@@ -1,168 +1,331 @@
 #![allow(dead_code)]
-use bytes::{Buf, BufMut, BytesMut};
-
-use super::{decode_binary_data, decode_string_pair, decode_utf8_string, encode_utf8_string, RemainingLength, ServiceLevel};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use super::{
+    decode_utf8_string,
+    encode_utf8_string,
+    malform::Malformed,
+    property::{
+        self,
+        PacketKind,
+        PropertyList,
+        Value,
+        CONTENT_TYPE,
+        CORRELATION_DATA,
+        MESSAGE_EXPIRY_INTERVAL,
+        PAYLOAD_FORMAT_INDICATOR,
+        RESPONSE_TOPIC,
+        SUBSCRIPTION_IDENTIFIER,
+        TOPIC_ALIAS,
+        USER_PROPERTY
+    },
+    RemainingLength,
+    ServiceLevel
+};
 
 #[cfg_attr(test, derive(PartialEq))]
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default)]
 pub struct PublishPacket {
     pub dup: bool,
     pub qos: ServiceLevel,
     pub retain: bool,
     pub topic: String,
     pub packet_id: Option<u16>,
-    pub payload: Vec<u8>,
+    pub payload: Bytes,
     pub properties: Option<Properties>,
 }
 
-#[cfg_attr(test, derive(PartialEq))]
-#[derive(Debug, Default)]
-pub struct Properties {
-    pub payload_format_indicator: Option<u8>,
-    pub message_expiry_interval: Option<u32>,
-    pub topic_alias: Option<u16>,
-    pub response_topic: Option<String>,
-    pub correlation_data: Option<Vec<u8>>,
-    pub user_properties: Option<Vec<(String, String)>>,
-    pub subscription_identifier: Option<Vec<u32>>,
-    pub content_type: Option<String>,
+/// Encoded publish, payload buffer is shared by every copy of the frame
+/// so the packet can be sent to many subscriber without copying it
+#[derive(Debug, Clone, Default)]
+pub struct PublishFrame {
+    /// fixed header and variable header
+    pub head: Bytes,
+    pub payload: Bytes,
+    /// time the message expire, set by broker from message expiry interval
+    pub expire_at: Option<u64>,
 }
 
-impl Properties {
-    fn decode(buffer: &mut BytesMut) -> Result<Option<Self>, String> {
-        let properties_length = RemainingLength::decode(buffer).map_err(|_| "Failed to decode properties length")? as usize;
-        if properties_length == 0 {
-            return Ok(None);
-        }
-    
-        if buffer.len() < properties_length {
-            return Err("Buffer too short for properties".to_string());
+impl PublishFrame {
+    pub fn len(&self) -> usize {
+        self.head.len() + self.payload.len()
+    }
+
+    /// whole packet on single buffer
+    pub fn to_bytes(&self) -> Bytes {
+        if self.payload.is_empty() {
+            return self.head.clone();
         }
-    
-        let mut properties = Properties {
-            payload_format_indicator: None,
-            message_expiry_interval: None,
-            topic_alias: None,
-            response_topic: None,
-            correlation_data: None,
-            user_properties: None,
-            subscription_identifier: None,
-            content_type: None,
+
+        let mut buffer = BytesMut::with_capacity(self.len());
+        buffer.put(self.head.as_ref());
+        buffer.put(self.payload.as_ref());
+        buffer.freeze()
+    }
+
+    /// copy of the frame with another packet identifier, only the head is copied
+    pub fn with_packet_id(&self, packet_id: u16, dup: bool) -> Result<Self, String> {
+        let mut head = BytesMut::from(self.head.as_ref());
+        set_packet_id(&mut head, packet_id, dup)?;
+        Ok(Self { head: head.freeze(), ..self.clone() })
+    }
+
+    pub fn topic(&self) -> Result<&str, String> {
+        let (at, len) = topic_position(&self.head)?;
+        std::str::from_utf8(&self.head[at..at + len])
+            .map_err(|_| "Invalid UTF-8 string".to_string())
+    }
+
+    /// copy of the frame that carry topic alias, topic is left empty
+    /// when the receiver already know the alias
+    pub fn with_topic_alias(&self, alias: u16, with_topic: bool) -> Result<Self, String> {
+        let [hi, lo] = alias.to_be_bytes();
+        self.with_property(&[TOPIC_ALIAS, hi, lo], with_topic)
+    }
+
+    /// copy of the frame without properties, for receiver that
+    /// speak MQTT 3.1.1
+    pub fn without_properties(&self) -> Result<Self, String> {
+        let head = self.head.as_ref();
+        let (at, topic_len) = topic_position(head)?;
+        let id_end = match (head[0] & 0x06) >> 1 {
+            0 => at + topic_len,
+            _ => at + topic_len + 2
         };
-    
-        let mut buf_prop = buffer.split_to(properties_length);
-        while buf_prop.len() != 0 {
-            let identifier = buf_prop.get_u8();
-    
-            match identifier {
-                0x01 => properties.payload_format_indicator = Some(buf_prop.get_u8()),
-                0x02 => properties.message_expiry_interval = Some(buf_prop.get_u32()),
-                0x23 => properties.topic_alias = Some(buf_prop.get_u16()),
-                0x08 => properties.response_topic = Some(decode_utf8_string(&mut buf_prop)?),
-                0x09 => properties.correlation_data = Some(decode_binary_data(&mut buf_prop)?),
-                0x26 => {
-                    let user_props = decode_string_pair(&mut buf_prop)?;
-                    match &mut properties.user_properties {
-                        None => properties.user_properties = Some(vec![user_props]),
-                        Some(prop) => prop.push(user_props),
-                    }
-                }
-                0x0B => properties.subscription_identifier = Some(vec![RemainingLength::decode(&mut buf_prop)?]),
-                0x03 => properties.content_type = Some(decode_utf8_string(&mut buf_prop)?),
-                _ => return Err("Unknown property identifier".to_string()),
-            }
+
+        let (props, prop_len) = properties_position(head)?;
+        if head.len() != props + prop_len {
+            return Err("Invalid properties length".to_string());
         }
-    
-        Ok(Some(properties))
+
+        let variable_header = &head[at - 2..id_end];
+        let len = variable_header.len() + self.payload.len();
+        let (rml, sz) = RemainingLength::encode(len as u32)?;
+
+        let mut buffer = BytesMut::with_capacity(1 + sz + variable_header.len());
+        buffer.put_u8(head[0]);
+        buffer.put(&rml[..sz]);
+        buffer.put(variable_header);
+        Ok(Self { head: buffer.freeze(), ..self.clone() })
     }
 
-    fn est_len(&self) -> usize {
-        let mut len = 0;
-        if self.payload_format_indicator.is_some() {
-            len += 2;
+    /// copy of the frame that carry identifier of every matched subscription
+    pub fn with_subscription_identifiers(&self, ids: &[u32]) -> Result<Self, String> {
+        let mut prop = BytesMut::with_capacity(ids.len() * 5);
+        for id in ids {
+            let (rml, sz) = RemainingLength::encode(*id)?;
+            prop.put_u8(SUBSCRIPTION_IDENTIFIER);
+            prop.put(&rml[..sz]);
         }
+        self.with_property(&prop, true)
+    }
 
-        if self.message_expiry_interval.is_some() {
-            len += 5;
-        }
+    /// copy of the frame with encoded property placed before the other properties
+    fn with_property(&self, prop: &[u8], with_topic: bool) -> Result<Self, String> {
+        let head = self.head.as_ref();
+        let (at, topic_len) = topic_position(head)?;
+        let id_at = at + topic_len;
+        let packet_id = match (head[0] & 0x06) >> 1 {
+            0 => &head[id_at..id_at],
+            _ => &head[id_at..id_at + 2]
+        };
 
-        if let Some(content_type) = &self.content_type {
-            len += 3 + content_type.len();
+        let (props, prop_len) = properties_position(head)?;
+        if head.len() != props + prop_len {
+            return Err("Invalid properties length".to_string());
         }
+        let rest = &head[props..];
 
-        if let Some(user_properties) = &self.user_properties {
-            for (key, value) in user_properties {
-                len += 5 + key.len() + value.len();
+        let topic = match with_topic {
+            true => &head[at..at + topic_len],
+            false => &head[at..at]
+        };
+        let (prop_rml, prop_sz) = RemainingLength::encode((prop_len + prop.len()) as u32)?;
+        let len = 2 + topic.len() + packet_id.len() + prop_sz + prop_len + prop.len() + self.payload.len();
+        let (rml, sz) = RemainingLength::encode(len as u32)?;
+
+        let mut buffer = BytesMut::with_capacity(1 + sz + len - self.payload.len());
+        buffer.put_u8(head[0]);
+        buffer.put(&rml[..sz]);
+        buffer.put_u16(topic.len() as u16);
+        buffer.put(topic);
+        buffer.put(packet_id);
+        buffer.put(&prop_rml[..prop_sz]);
+        buffer.put(prop);
+        buffer.put(rest);
+        Ok(Self { head: buffer.freeze(), ..self.clone() })
+    }
+
+    /// copy of the frame with another message expiry interval,
+    /// frame without the property is copied as is
+    pub fn with_message_expiry(&self, interval: u32) -> Result<Self, String> {
+        let (mut i, prop_len) = properties_position(&self.head)?;
+        let end = i + prop_len;
+        while i < end {
+            let identifier = self.head[i];
+            i += 1;
+            let len = property::value_len(identifier, &self.head[i..end]).map_err(|e| e.to_string())?;
+            if identifier == MESSAGE_EXPIRY_INTERVAL {
+                let mut head = BytesMut::from(self.head.as_ref());
+                head[i..i + len].copy_from_slice(&interval.to_be_bytes());
+                return Ok(Self { head: head.freeze(), ..self.clone() });
             }
+            i += len;
         }
+        Ok(self.clone())
+    }
+}
 
-        len
+/// start and length of properties on encoded publish
+fn properties_position(buffer: &[u8]) -> Result<(usize, usize), String> {
+    let (at, topic_len) = topic_position(buffer)?;
+    let mut i = match (buffer[0] & 0x06) >> 1 {
+        0 => at + topic_len,
+        _ => at + topic_len + 2
+    };
+
+    let mut multiplier = 1;
+    let mut prop_len = 0;
+    loop {
+        let encoded_byte = *buffer.get(i).ok_or("Buffer too short")?;
+        i += 1;
+        prop_len += (encoded_byte & 127) as usize * multiplier;
+        if encoded_byte & 128 == 0 {
+            break;
+        }
+        multiplier *= 128;
+        if multiplier > 128 * 128 * 128 {
+            return Err("Malformed properties length".to_string());
+        }
     }
 
-    fn encode(&self) -> Result<BytesMut, String> {
-        let prop_len = self.est_len();
-        let (rml, sz) = RemainingLength::encode(prop_len as u32)?;
-        let (rml, _) = rml.split_at(sz);
-        
-        let mut props_buffer = BytesMut::with_capacity(prop_len + rml.len());
-        props_buffer.put(rml);
+    if buffer.len() < i + prop_len {
+        return Err("Buffer too short".to_string());
+    }
+    Ok((i, prop_len))
+}
 
-        if let Some(indicator) = self.payload_format_indicator {
-            props_buffer.put_u8(0x01);
-            props_buffer.put_u8(indicator);
-        }
+/// start and length of topic name on encoded publish
+fn topic_position(buffer: &[u8]) -> Result<(usize, usize), String> {
+    // remaining length is 1 to 4 byte
+    let mut i = 1;
+    while buffer.get(i).ok_or("Buffer too short")? & 0x80 != 0 {
+        i += 1;
+    }
+    i += 1;
+
+    let topic_len = match buffer.get(i..i + 2) {
+        Some(b) => u16::from_be_bytes([b[0], b[1]]) as usize,
+        None => return Err("Buffer too short".to_string())
+    };
+    if buffer.len() < i + 2 + topic_len {
+        return Err("Buffer too short".to_string());
+    }
+    Ok((i + 2, topic_len))
+}
 
-        if let Some(interval) = self.message_expiry_interval {
-            props_buffer.put_u8(0x02);
-            props_buffer.put_u32(interval);
-        }
+/// packet that is already encoded in single buffer
+impl From<Bytes> for PublishFrame {
+    fn from(head: Bytes) -> Self {
+        Self { head, payload: Bytes::new(), expire_at: None }
+    }
+}
 
-        if let Some(content_type) = &self.content_type {
-            props_buffer.put_u8(0x03);
-            encode_utf8_string(&mut props_buffer, &content_type)?;
+#[cfg_attr(test, derive(PartialEq))]
+#[derive(Debug, Clone, Default)]
+pub struct Properties {
+    pub payload_format_indicator: Option<u8>,
+    pub message_expiry_interval: Option<u32>,
+    pub topic_alias: Option<u16>,
+    pub response_topic: Option<String>,
+    pub correlation_data: Option<Vec<u8>>,
+    pub user_properties: Option<Vec<(String, String)>>,
+    pub subscription_identifier: Option<Vec<u32>>,
+    pub content_type: Option<String>,
+}
+
+impl Properties {
+    fn decode(buffer: &mut BytesMut) -> Result<Option<Self>, Malformed> {
+        let list = PropertyList::decode(buffer, PacketKind::Publish)?;
+        if list.is_empty() {
+            return Ok(None);
         }
 
-        if let Some(user_properties) = &self.user_properties {
-            for (key, value) in user_properties {
-                props_buffer.put_u8(0x26);
-                encode_utf8_string(&mut props_buffer, key)?;
-                encode_utf8_string(&mut props_buffer, value)?;
+        let mut properties = Properties::default();
+        for (id, value) in list {
+            match (id, value) {
+                (PAYLOAD_FORMAT_INDICATOR, Value::Byte(v)) => properties.payload_format_indicator = Some(v),
+                (MESSAGE_EXPIRY_INTERVAL, Value::FourByte(v)) => properties.message_expiry_interval = Some(v),
+                (TOPIC_ALIAS, Value::TwoByte(v)) => properties.topic_alias = Some(v),
+                (RESPONSE_TOPIC, Value::Utf8(v)) => properties.response_topic = Some(v),
+                (CORRELATION_DATA, Value::Binary(v)) => properties.correlation_data = Some(v),
+                (USER_PROPERTY, Value::StringPair(k, v)) => properties.user_properties.get_or_insert_with(Vec::new).push((k, v)),
+                (SUBSCRIPTION_IDENTIFIER, Value::VarInt(v)) => properties.subscription_identifier.get_or_insert_with(Vec::new).push(v),
+                (CONTENT_TYPE, Value::Utf8(v)) => properties.content_type = Some(v),
+                _ => ()
             }
         }
+    
+        Ok(Some(properties))
+    }
 
+    fn to_list(&self) -> PropertyList {
+        let mut list = PropertyList::default();
+        list.put(PAYLOAD_FORMAT_INDICATOR, self.payload_format_indicator.map(Value::Byte));
+        list.put(MESSAGE_EXPIRY_INTERVAL, self.message_expiry_interval.map(Value::FourByte));
+        list.put(TOPIC_ALIAS, self.topic_alias.map(Value::TwoByte));
+        list.put(RESPONSE_TOPIC, self.response_topic.clone().map(Value::Utf8));
+        list.put(CORRELATION_DATA, self.correlation_data.clone().map(Value::Binary));
+        for id in self.subscription_identifier.iter().flatten() {
+            list.put(SUBSCRIPTION_IDENTIFIER, Some(Value::VarInt(*id)));
+        }
+        list.put(CONTENT_TYPE, self.content_type.clone().map(Value::Utf8));
+        list.put_user_properties(&self.user_properties);
+        list
+    }
+
+    fn encode(&self) -> Result<BytesMut, String> {
+        let list = self.to_list();
+        let mut props_buffer = BytesMut::with_capacity(list.encoded_len());
+        list.encode(&mut props_buffer, PacketKind::Publish)?;
         Ok(props_buffer)
     }
 }
 
 impl PublishPacket {
-    pub fn decode(buffer: &mut BytesMut) -> Result<Self, String> {
+    pub fn decode(buffer: &mut BytesMut) -> Result<Self, Malformed> {
         if buffer.len() < 2 {
-            return Err("Buffer too short".to_string());
+            return Err(Malformed::MalformedPacket);
         }
 
         // Fixed header
         let header = buffer.get_u8();
         let packet_type = header >> 4;
         if packet_type != 3 {
-            return Err("Invalid packet type for PUBLISH".to_string());
+            return Err(Malformed::ProtocolError);
         }
 
         let dup = (header & 0x08) != 0;
+        // both QoS bits set is malformed, not an unsupported QoS
         let qos = ServiceLevel::try_from((header & 0x06) >> 1)
-            .map_err(|_| "QoS not Supported")?;
+            .map_err(|_| Malformed::MalformedPacket)?;
         let retain = (header & 0x01) != 0;
 
         // Remaining length
-        let remaining_length = RemainingLength::decode(buffer).map_err(|_| "Failed to decode remaining length")? as usize;
+        let remaining_length = RemainingLength::decode(buffer)
+            .map_err(|_| Malformed::MalformedPacket)? as usize;
         if buffer.len() < remaining_length {
-            return Err("Buffer too short for remaining length".to_string());
+            return Err(Malformed::MalformedPacket);
         }
+        let end = buffer.len() - remaining_length;
 
         // Topic
-        let topic = decode_utf8_string(buffer)?;
+        let topic = decode_utf8_string(buffer)
+            .map_err(|_| Malformed::MalformedPacket)?;
 
         // Packet Identifier
         let packet_id = match qos.code() > 0 {
+            true if buffer.len() < 2 => return Err(Malformed::MalformedPacket),
             true => Some(buffer.get_u16()),
             false => None
         };
@@ -171,7 +334,10 @@ impl PublishPacket {
         let properties = Properties::decode(buffer)?;
 
         // Payload
-        let payload = buffer.to_vec();
+        let payload_len = buffer.len()
+            .checked_sub(end)
+            .ok_or(Malformed::MalformedPacket)?;
+        let payload = buffer.split_to(payload_len).freeze();
 
         Ok(PublishPacket {
             dup,
@@ -185,6 +351,15 @@ impl PublishPacket {
     }
 
     pub fn encode(&self) -> Result<BytesMut, String> {
+        let frame = self.encode_frame()?;
+        let mut buffer = BytesMut::with_capacity(frame.len());
+        buffer.put(frame.head);
+        buffer.put(frame.payload);
+        Ok(buffer)
+    }
+
+    /// encode header only, payload is shared with the packet
+    pub fn encode_frame(&self) -> Result<PublishFrame, String> {
         let prop = match &self.properties {
             Some(prop) => prop.encode()?,
             None => {
@@ -203,12 +378,12 @@ impl PublishPacket {
         let (rml, sz) = RemainingLength::encode(len as u32)?;
         let (rml, _) = rml.split_at(sz);
 
-        let buf_cap = len + rml.len() + 2;
+        let buf_cap = len - self.payload.len() + rml.len() + 1;
         let mut buffer = BytesMut::with_capacity(buf_cap);
 
         // Fixed header
         let mut fixed_header: u8 = 0x30; // Packet type PUBLISH
-        fixed_header |= (self.dup as u8) << 4;
+        fixed_header |= (self.dup as u8) << 3;
         fixed_header |= (self.qos.code()) << 1;
         fixed_header |= self.retain as u8;
         buffer.put_u8(fixed_header);
@@ -223,11 +398,31 @@ impl PublishPacket {
         // Properties
         buffer.put(prop);
 
-        // Payload
-        buffer.put(self.payload.as_slice());
+        Ok(PublishFrame { 
+            head: buffer.freeze(), 
+            payload: self.payload.clone(),
+            expire_at: None
+        })
+    }
+}
 
-        Ok(buffer)
+/// rewrite packet identifier of encoded QoS 1 or 2 publish,
+/// packet is forwarded with identifier owned by the receiver session
+pub fn set_packet_id(buffer: &mut [u8], packet_id: u16, dup: bool) -> Result<(), String> {
+    let qos = (buffer.first().ok_or("Buffer too short")? & 0x06) >> 1;
+    if qos == 0 {
+        return Err("QoS 0 has no packet identifier".to_string());
+    }
+
+    let (at, topic_len) = topic_position(buffer)?;
+    let at = at + topic_len;
+    if buffer.len() < at + 2 {
+        return Err("Buffer too short".to_string());
     }
+
+    buffer[0] = (buffer[0] & !0x08) | ((dup as u8) << 3);
+    buffer[at..at + 2].copy_from_slice(&packet_id.to_be_bytes());
+    Ok(())
 }
 
 #[cfg(test)]
@@ -277,7 +472,7 @@ mod tests {
             retain: false,
             topic: "topic".to_string(),
             packet_id: None,
-            payload: b"hello".to_vec(),
+            payload: Bytes::from_static(b"hello"),
             properties: Some(expected_properties),
         };
 
@@ -303,7 +498,7 @@ mod tests {
             retain: false,
             topic: "topic".to_string(),
             packet_id: Some(12345),
-            payload: b"Hello mqtt".to_vec(),
+            payload: Bytes::from_static(b"Hello mqtt"),
             properties: Some(prop),
         };
 
@@ -315,4 +510,163 @@ mod tests {
         assert_eq!(packet, decoded)
         
     }
+
+    #[test]
+    fn rewrite_packet_id() {
+        let packet = PublishPacket {
+            qos: ServiceLevel::QoS2,
+            topic: "topic".to_string(),
+            packet_id: Some(1),
+            payload: Bytes::from_static(b"data"),
+            ..Default::default()
+        };
+
+        let mut encoded = packet.encode().unwrap();
+        set_packet_id(&mut encoded, 513, true).unwrap();
+        let decoded = PublishPacket::decode(&mut encoded).unwrap();
+        assert_eq!(decoded.packet_id, Some(513));
+        assert!(decoded.dup);
+        assert_eq!(decoded.payload, Bytes::from_static(b"data"));
+    }
+
+    #[test]
+    fn frame_share_payload() {
+        let packet = PublishPacket {
+            qos: ServiceLevel::QoS1,
+            topic: "camera/1".to_string(),
+            packet_id: Some(0),
+            payload: Bytes::from(vec![7u8; 4096]),
+            ..Default::default()
+        };
+
+        let frame = packet.encode_frame().unwrap();
+        let copy = frame.with_packet_id(42, false).unwrap();
+        assert_eq!(copy.payload.as_ptr(), packet.payload.as_ptr());
+
+        let mut encoded = BytesMut::from(copy.to_bytes().as_ref());
+        assert_eq!(encoded.len(), packet.encode().unwrap().len());
+        let decoded = PublishPacket::decode(&mut encoded).unwrap();
+        assert_eq!(decoded.packet_id, Some(42));
+        assert_eq!(decoded.payload, packet.payload);
+    }
+
+    #[test]
+    fn frame_message_expiry() {
+        let packet = PublishPacket {
+            qos: ServiceLevel::QoS1,
+            topic: "a/b".to_string(),
+            packet_id: Some(1),
+            payload: Bytes::from_static(b"x"),
+            properties: Some(Properties {
+                payload_format_indicator: Some(1),
+                content_type: Some("text/plain".to_string()),
+                user_properties: Some(vec![("k".to_string(), "v".to_string())]),
+                message_expiry_interval: Some(60),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let frame = packet.encode_frame().unwrap().with_message_expiry(15).unwrap();
+        let decoded = PublishPacket::decode(&mut BytesMut::from(frame.to_bytes().as_ref())).unwrap();
+        let props = decoded.properties.unwrap();
+        assert_eq!(props.message_expiry_interval, Some(15));
+        assert_eq!(props.content_type.as_deref(), Some("text/plain"));
+
+        let plain = PublishPacket { topic: "a/b".to_string(), ..Default::default() };
+        let frame = plain.encode_frame().unwrap();
+        assert_eq!(frame.with_message_expiry(15).unwrap().head, frame.head);
+    }
+
+    #[test]
+    fn frame_topic_alias() {
+        let packet = PublishPacket {
+            qos: ServiceLevel::QoS1,
+            topic: "sensor/temp".to_string(),
+            packet_id: Some(7),
+            payload: Bytes::from_static(b"21.5"),
+            properties: Some(Properties {
+                message_expiry_interval: Some(30),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let frame = packet.encode_frame().unwrap();
+        assert_eq!(frame.topic().unwrap(), "sensor/temp");
+
+        let mut first = BytesMut::from(frame.with_topic_alias(3, true).unwrap().to_bytes().as_ref());
+        let first = PublishPacket::decode(&mut first).unwrap();
+        assert_eq!(first.topic, "sensor/temp");
+        assert_eq!(first.packet_id, Some(7));
+        let props = first.properties.unwrap();
+        assert_eq!(props.topic_alias, Some(3));
+        assert_eq!(props.message_expiry_interval, Some(30));
+
+        let mut next = BytesMut::from(frame.with_topic_alias(3, false).unwrap().to_bytes().as_ref());
+        let next = PublishPacket::decode(&mut next).unwrap();
+        assert_eq!(next.topic, "");
+        assert_eq!(next.properties.unwrap().topic_alias, Some(3));
+        assert_eq!(next.payload, packet.payload);
+    }
+
+    #[test]
+    fn request_response_properties() {
+        let packet = PublishPacket {
+            topic: "svc/req".to_string(),
+            payload: Bytes::from_static(b"x"),
+            properties: Some(Properties {
+                response_topic: Some("response/req/1".to_string()),
+                correlation_data: Some(vec![1, 2, 3]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let decoded = PublishPacket::decode(&mut packet.encode().unwrap()).unwrap();
+        let properties = decoded.properties.unwrap();
+        assert_eq!(properties.response_topic, Some("response/req/1".to_string()));
+        assert_eq!(properties.correlation_data, Some(vec![1, 2, 3]));
+
+        // properties left empty after topic alias is resolved
+        let packet = PublishPacket { properties: Some(Properties::default()), ..packet };
+        let decoded = PublishPacket::decode(&mut packet.encode().unwrap()).unwrap();
+        assert_eq!(decoded.payload, packet.payload);
+    }
+
+    #[test]
+    fn frame_subscription_identifiers() {
+        let packet = PublishPacket {
+            topic: "a/b".to_string(),
+            payload: Bytes::from_static(b"x"),
+            ..Default::default()
+        };
+
+        let frame = packet.encode_frame().unwrap()
+            .with_subscription_identifiers(&[1, 300]).unwrap();
+        let decoded = PublishPacket::decode(&mut BytesMut::from(frame.to_bytes().as_ref())).unwrap();
+        assert_eq!(decoded.properties.unwrap().subscription_identifier, Some(vec![1, 300]));
+        assert_eq!(decoded.topic, "a/b");
+    }
+
+    #[test]
+    fn decode_error_reason() {
+        use crate::protocol::mqtt::{ClientPacket, Protocol};
+
+        // payload format indicator sent twice
+        let raw = [0x30, 0x0A, 0x00, 0x01, b'a', 0x04, 0x01, 0x01, 0x01, 0x01, b'x', b'y'];
+        let res = ClientPacket::decode(&mut BytesMut::from(&raw[..]), Protocol::V5);
+        assert!(matches!(res, Err(Malformed::ProtocolError)));
+
+        // both QoS bits set
+        let raw = [0x36, 0x05, 0x00, 0x01, b'a', 0x00, 0x01];
+        let res = ClientPacket::decode(&mut BytesMut::from(&raw[..]), Protocol::V5);
+        assert!(matches!(res, Err(Malformed::MalformedPacket)));
+
+        let raw = [0x30, 0x05, 0x00, 0x01, b'a', 0x00, 0x01];
+        let res = ClientPacket::decode(&mut BytesMut::from(&raw[..]), Protocol::V311);
+        assert!(res.is_ok());
+        let res = ClientPacket::decode(&mut BytesMut::from(&raw[..4]), Protocol::V311);
+        assert!(matches!(res, Err(Malformed::MalformedPacket)));
+    }
 }
\ No newline at end of file
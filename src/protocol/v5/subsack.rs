@@ -1,7 +1,11 @@
 #![allow(dead_code)]
 use bytes::{BufMut, BytesMut};
 
-use super::{encode_utf8_string, RemainingLength, ServiceLevel};
+use super::{
+    property::{PacketKind, PropertyList, Value, REASON_STRING},
+    RemainingLength,
+    ServiceLevel
+};
 
 pub type SubAckResult = Result<ServiceLevel, SubAckInvalid>;
 // #[cfg_attr(test, derive(PartialEq))]
@@ -20,53 +24,25 @@ pub struct SubAckProperties {
 }
 
 impl SubAckProperties {
-    // estimate encode leng
-    fn len(&self) -> usize {
-        let mut leng = 0;
-        if let Some(ref reason) = self.reason_string {
-            leng += reason.len() + 3;
-        }
-
-        if let Some(ref uprop) = self.user_properties {
-            for (k, v) in uprop.iter() {
-                leng += k.len() + v.len() + 5;
-            }
-        }
-
-        leng
-    }
-
-    fn encode(&self) -> Result<BytesMut, String> {
-        let mut props_buffer = BytesMut::new();
-    
-        if let Some(reason_string) = &self.reason_string {
-            props_buffer.put_u8(0x1F);
-            encode_utf8_string(&mut props_buffer, reason_string)?;
-        }
-    
-        if let Some(user_properties) = &self.user_properties {
-            for (key, value) in user_properties {
-                props_buffer.put_u8(0x26);
-                encode_utf8_string(&mut props_buffer, key)?;
-                encode_utf8_string(&mut props_buffer, value)?;
-            }
-        }
-    
-        Ok(props_buffer)
+    fn to_list(&self) -> PropertyList {
+        let mut list = PropertyList::default();
+        list.put(REASON_STRING, self.reason_string.clone().map(Value::Utf8));
+        list.put_user_properties(&self.user_properties);
+        list
     }
 }
 
 impl SubsAck {
     pub fn encode(&self) -> Result<BytesMut, String> {
-        let prop = match &self.properties {
-            None => BytesMut::new(),
-            Some(p) => p.encode()?
-        };
-
-        let (pl, plsz) = RemainingLength::encode(prop.len() as u32)?;
-        let (prop_len, _) = pl.split_at(plsz);
-
-        let rml_num = prop.len() + plsz + self.return_codes.len() + 2;
+        // properties length is written even when there is none
+        let mut prop = BytesMut::new();
+        self.properties
+            .as_ref()
+            .map(SubAckProperties::to_list)
+            .unwrap_or_default()
+            .encode(&mut prop, PacketKind::SubAck)?;
+
+        let rml_num = prop.len() + self.return_codes.len() + 2;
         let (rml, rlsz) = RemainingLength::encode(rml_num as u32)?;
         let (remaining_leng, _) = rml.split_at(rlsz);
 
@@ -76,7 +52,6 @@ impl SubsAck {
         buf.put_u8(header);
         buf.put(remaining_leng);
         buf.put_u16(self.id);
-        buf.put(prop_len);
         buf.put(prop);
 
         for ack in self.return_codes.iter() {
@@ -132,7 +107,7 @@ mod tests {
             ]),
         };
 
-        let prop_len = properties.len();
+        let prop_len = properties.to_list().len();
 
         let packet = SubsAck {
             id: 1234,
@@ -176,5 +151,9 @@ mod tests {
 
         println!("len res: {}, len expect: {}", buffer.len(), expected.len());
         assert_eq!(&buffer[..], &expected[..]);
+
+        // empty properties still has its length
+        let packet = SubsAck { id: 1, properties: None, return_codes: vec![Ok(ServiceLevel::QoS1)] };
+        assert_eq!(&packet.encode().unwrap()[..], &[0x90, 0x04, 0x00, 0x01, 0x00, 0x01]);
     }
 }
\ No newline at end of file
@@ -1,7 +1,12 @@
 #![allow(dead_code)]
 use bytes::{Buf, BytesMut};
 
-use super::{malform::Malformed, RemainingLength, ServiceLevel};
+use super::{
+    malform::Malformed,
+    property::{PacketKind, PropertyList, Value, SUBSCRIPTION_IDENTIFIER},
+    RemainingLength,
+    ServiceLevel
+};
 
 #[derive(Debug)]
 pub struct SubscribePacket {
@@ -9,14 +14,74 @@ pub struct SubscribePacket {
     pub list: Vec<Subscribe>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Subscribe {
     pub topic: String,
-    pub max_qos: ServiceLevel 
+    pub max_qos: ServiceLevel,
+    pub options: SubscriptionOptions,
+    /// given back on every publish matched by this subscription
+    pub subscription_identifier: Option<u32>,
+}
+
+/// when retained message is sent on subscribe
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum RetainHandling {
+    #[default]
+    OnSubscribe,
+    /// only when the subscription does not exist yet
+    OnNewSubscribe,
+    Never,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SubscriptionOptions {
+    /// publish is not forwarded back to its publisher
+    pub no_local: bool,
+    /// keep retain flag of forwarded publish
+    pub retain_as_published: bool,
+    pub retain_handling: RetainHandling,
+}
+
+impl SubscriptionOptions {
+    /// parse subscription options byte, reserved bit must be zero
+    pub fn decode(options: u8) -> Result<(ServiceLevel, Self), Malformed> {
+        if options & 0xC0 != 0 {
+            return Err(Malformed::MalformedPacket);
+        }
+
+        let max_qos = ServiceLevel::try_from(options & 0x03)?;
+        let retain_handling = match (options >> 4) & 0x03 {
+            0 => RetainHandling::OnSubscribe,
+            1 => RetainHandling::OnNewSubscribe,
+            2 => RetainHandling::Never,
+            _ => return Err(Malformed::ProtocolError)
+        };
+
+        Ok((max_qos, Self {
+            no_local: options & 0x04 != 0,
+            retain_as_published: options & 0x08 != 0,
+            retain_handling
+        }))
+    }
+
+    pub fn encode(&self, max_qos: &ServiceLevel) -> u8 {
+        let retain_handling = match self.retain_handling {
+            RetainHandling::OnSubscribe => 0,
+            RetainHandling::OnNewSubscribe => 1,
+            RetainHandling::Never => 2,
+        };
+        max_qos.code()
+            | (self.no_local as u8) << 2
+            | (self.retain_as_published as u8) << 3
+            | retain_handling << 4
+    }
 }
 
 impl SubscribePacket {
     pub fn decode(buffer: &mut BytesMut) -> Result<Self, Malformed> {
+        if buffer.is_empty() {
+            return Err(Malformed::MalformedPacket);
+        }
         let header = buffer.get_u8();
         if  (header >> 0x04) != 0x08 {
             return Err(Malformed::ProtocolError);
@@ -26,25 +91,36 @@ impl SubscribePacket {
 
     pub(super) fn skip_header(buffer: &mut BytesMut) -> Result<Self, Malformed> {
         let remaining_length = RemainingLength::decode(buffer)
-            .map_err(|_| Malformed::ProtocolError)?;
+            .map_err(|_| Malformed::MalformedPacket)?;
 
+        // packet identifier and at least the property length
+        if remaining_length < 3 || buffer.len() < remaining_length as usize {
+            return Err(Malformed::MalformedPacket);
+        }
         *buffer = buffer.split_to(remaining_length as usize);
 
         let packet_identifier = buffer.get_u16();
-        let _prop_leng = buffer.get_u8();
+        let subscription_identifier = decode_properties(buffer)?;
 
         // Payload
         let mut subscriptions = Vec::new();
 
-        while buffer.len() != 0 {
+        while !buffer.is_empty() {
+            if buffer.len() < 2 {
+                return Err(Malformed::MalformedPacket);
+            }
             let topic_filter_len = buffer.get_u16() as usize;
+            // topic filter is followed by subscription options
+            if buffer.len() < topic_filter_len + 1 {
+                return Err(Malformed::MalformedPacket);
+            }
             let topic_filter_bytes = buffer.split_to(topic_filter_len);
 
             let topic = String::from_utf8(topic_filter_bytes.into())
                 .map_err(|_| Malformed::MalformedPacket)?;
 
-            let max_qos = ServiceLevel::try_from(buffer.get_u8() & 0x3)?;
-            subscriptions.push(Subscribe { topic, max_qos });
+            let (max_qos, options) = SubscriptionOptions::decode(buffer.get_u8())?;
+            subscriptions.push(Subscribe { topic, max_qos, options, subscription_identifier });
         }
         
         Ok(SubscribePacket {
@@ -54,11 +130,25 @@ impl SubscribePacket {
     }
 }
 
+/// subscription identifier is the only property that is kept,
+/// user property is skipped
+fn decode_properties(buffer: &mut BytesMut) -> Result<Option<u32>, Malformed> {
+    let mut subscription_identifier = None;
+    for (id, value) in PropertyList::decode(buffer, PacketKind::Subscribe)? {
+        match (id, value) {
+            (SUBSCRIPTION_IDENTIFIER, Value::VarInt(0)) => return Err(Malformed::ProtocolError),
+            (SUBSCRIPTION_IDENTIFIER, Value::VarInt(v)) => subscription_identifier = Some(v),
+            _ => ()
+        }
+    }
+    Ok(subscription_identifier)
+}
+
 #[cfg(test)]
 mod tests {
     use bytes::BytesMut;
 
-    use crate::protocol::v5::{subscribe::{Subscribe, SubscribePacket}, ServiceLevel};
+    use crate::protocol::v5::{malform::Malformed, subscribe::{RetainHandling, Subscribe, SubscribePacket, SubscriptionOptions}, ServiceLevel};
 
     #[test]
     fn test_subscribe_packet_deserialization() {
@@ -86,10 +176,14 @@ mod tests {
                         Subscribe {
                             topic: "sensor/temperature".to_string(),
                             max_qos: ServiceLevel::QoS1,
+                            options: SubscriptionOptions::default(),
+                            subscription_identifier: None,
                         },
                         Subscribe {
                             topic: "sensor/humidity".to_string(),
                             max_qos: ServiceLevel::QoS2,
+                            options: SubscriptionOptions::default(),
+                            subscription_identifier: None,
                         },
                     ],
                 },
@@ -107,7 +201,9 @@ mod tests {
                     id: 1, 
                     list: vec![Subscribe {
                         topic: String::from("test/topic"),
-                        max_qos: ServiceLevel::QoS0
+                        max_qos: ServiceLevel::QoS0,
+                        options: SubscriptionOptions::default(),
+                        subscription_identifier: None,
                     }]
                 }
             }
@@ -123,8 +219,47 @@ mod tests {
             for (deserialized_sub, expected_sub) in deserialized.list.iter().zip(test.exp.list.iter()) {
                 assert_eq!(deserialized_sub.topic, expected_sub.topic);
                 assert_eq!(deserialized_sub.max_qos, expected_sub.max_qos);
+                assert_eq!(deserialized_sub.options, expected_sub.options);
             }
             
         }
     }
+
+    #[test]
+    fn subscription_options() {
+        let mut buf = BytesMut::from([
+            0x82, 0x09,
+            0x00, 0x02,
+            0x02, 0x0B, 0x01, // property length 2, subscription identifier 1
+            0x00, 0x01, b'a',
+            0x2D, // retain handling 2, retain as published, no local, QoS 1
+        ].as_slice());
+        let packet = SubscribePacket::decode(&mut buf).unwrap();
+        let sub = &packet.list[0];
+        assert_eq!(sub.max_qos, ServiceLevel::QoS1);
+        assert_eq!(sub.options, SubscriptionOptions {
+            no_local: true,
+            retain_as_published: true,
+            retain_handling: RetainHandling::Never
+        });
+        assert_eq!(sub.options.encode(&sub.max_qos), 0x2D);
+        assert_eq!(sub.subscription_identifier, Some(1));
+
+        assert!(SubscriptionOptions::decode(0x41).is_err());
+        assert!(SubscriptionOptions::decode(0x30).is_err());
+    }
+
+    #[test]
+    fn truncated_subscribe() {
+        let decode = |raw: &[u8]| SubscribePacket::decode(&mut BytesMut::from(raw));
+        // no property length after packet identifier
+        assert!(matches!(decode(&[0x82, 0x02, 0x00, 0x01]), Err(Malformed::MalformedPacket)));
+        // remaining length longer than the buffer
+        assert!(matches!(decode(&[0x82, 0x03, 0x00, 0x01]), Err(Malformed::MalformedPacket)));
+        assert!(matches!(decode(&[0x82, 0x80]), Err(Malformed::MalformedPacket)));
+        // property length longer than the packet
+        assert!(matches!(decode(&[0x82, 0x04, 0x00, 0x01, 0x05, 0x0B]), Err(Malformed::MalformedPacket)));
+        // subscription identifier cut in the middle
+        assert!(matches!(decode(&[0x82, 0x05, 0x00, 0x01, 0x02, 0x0B, 0x80]), Err(Malformed::MalformedPacket)));
+    }
 }
\ No newline at end of file